@@ -0,0 +1,162 @@
+#!/usr/bin/env python3
+# テスト用のDHCPv6サーバー
+# MフラグのRAを送り、IA_NAのアドレスとIA_PDのプレフィックス、DNSサーバーとドメインリストを返す
+# 使い方: dhcpv6_server.py <インターフェース>
+
+import select
+import socket
+import struct
+import sys
+import time
+import zlib
+
+INTERFACE = sys.argv[1]
+
+ADDR_POOL = "2001:db8:0:1::100"  # IA_NAで割り当てるアドレスの先頭
+PD_POOL = "2001:db8:0:100::"  # IA_PDで委任するプレフィックスの先頭
+PD_PREFIX_LEN = 56
+ON_LINK_PREFIX = "2001:db8:0:1::"
+DNS_SERVER = "2001:db8:0:1::2"
+DOMAIN = "example.com"
+PREFERRED_LIFETIME = 1800
+VALID_LIFETIME = 3600
+
+SOLICIT, ADVERTISE, REQUEST, CONFIRM, RENEW, REBIND, REPLY = 1, 2, 3, 4, 5, 6, 7
+RELEASE, DECLINE, INFORMATION_REQUEST = 8, 9, 11
+
+OPTION_CLIENTID, OPTION_SERVERID, OPTION_IA_NA, OPTION_IAADDR = 1, 2, 3, 5
+OPTION_ORO, OPTION_PREFERENCE, OPTION_STATUS_CODE = 6, 7, 13
+OPTION_DNS_SERVERS, OPTION_DOMAIN_LIST, OPTION_IA_PD, OPTION_IAPREFIX = 23, 24, 25, 26
+
+ALL_DHCP_RELAY_AGENTS_AND_SERVERS = "ff02::1:2"
+ALL_ROUTERS = "ff02::2"
+ALL_NODES = "ff02::1"
+RA_INTERVAL = 10
+
+if_index = socket.if_nametoindex(INTERFACE)
+with open(f"/sys/class/net/{INTERFACE}/address") as f:
+    mac_addr = bytes.fromhex(f.read().strip().replace(":", ""))
+# DUID-LL
+server_duid = struct.pack("!HH", 3, 1) + mac_addr
+
+
+def option(code, data):
+    return struct.pack("!HH", code, len(data)) + data
+
+
+def parse_options(data):
+    options = []
+    while len(data) >= 4:
+        code, length = struct.unpack("!HH", data[:4])
+        options.append((code, data[4 : 4 + length]))
+        data = data[4 + length :]
+    return options
+
+
+def addr_offset(base, client_id, shift=0):
+    # クライアントごとに同じアドレス、プレフィックスを返す
+    index = zlib.crc32(client_id) & 0xFF
+    value = int.from_bytes(socket.inet_pton(socket.AF_INET6, base), "big")
+    return (value + (index << shift)).to_bytes(16, "big")
+
+
+def domain_list():
+    return b"".join(bytes([len(l)]) + l.encode() for l in DOMAIN.split(".")) + b"\0"
+
+
+def information():
+    return option(OPTION_DNS_SERVERS, socket.inet_pton(socket.AF_INET6, DNS_SERVER)) + option(
+        OPTION_DOMAIN_LIST, domain_list()
+    )
+
+
+def reply_to(msg_type, packet):
+    transaction_id = packet[1:4]
+    options = parse_options(packet[4:])
+    client_id = next((data for code, data in options if code == OPTION_CLIENTID), None)
+    if client_id is None:
+        return None
+    server_id = next((data for code, data in options if code == OPTION_SERVERID), None)
+    if server_id is not None and server_id != server_duid:
+        return None
+
+    reply_type = ADVERTISE if msg_type == SOLICIT else REPLY
+    buf = bytes([reply_type]) + transaction_id
+    buf += option(OPTION_CLIENTID, client_id) + option(OPTION_SERVERID, server_duid)
+    if msg_type == SOLICIT:
+        buf += option(OPTION_PREFERENCE, bytes([255]))
+    buf += information()
+    if msg_type == INFORMATION_REQUEST:
+        return buf
+    if msg_type in (RELEASE, DECLINE):
+        return buf + option(OPTION_STATUS_CODE, struct.pack("!H", 0))
+
+    for code, data in options:
+        if code == OPTION_IA_NA and len(data) >= 12:
+            iaid = data[:4]
+            addr = addr_offset(ADDR_POOL, client_id)
+            iaaddr = option(
+                OPTION_IAADDR, addr + struct.pack("!II", PREFERRED_LIFETIME, VALID_LIFETIME)
+            )
+            buf += option(OPTION_IA_NA, iaid + struct.pack("!II", 0, 0) + iaaddr)
+            print(f"ia_na {socket.inet_ntop(socket.AF_INET6, addr)}")
+        elif code == OPTION_IA_PD and len(data) >= 12:
+            iaid = data[:4]
+            prefix = addr_offset(PD_POOL, client_id, 128 - PD_PREFIX_LEN)
+            iaprefix = option(
+                OPTION_IAPREFIX,
+                struct.pack("!IIB", PREFERRED_LIFETIME, VALID_LIFETIME, PD_PREFIX_LEN) + prefix,
+            )
+            buf += option(OPTION_IA_PD, iaid + struct.pack("!II", 0, 0) + iaprefix)
+            print(f"ia_pd {socket.inet_ntop(socket.AF_INET6, prefix)}/{PD_PREFIX_LEN}")
+    return buf
+
+
+def router_advertisement():
+    # Mフラグ付き、アドレスはDHCPv6で配るのでPrefix InformationのAフラグは立てない
+    buf = struct.pack("!BBHBBHII", 134, 0, 0, 64, 0x80, 1800, 0, 0)
+    buf += struct.pack("!BB", 1, 1) + mac_addr
+    buf += struct.pack("!BBBBIII", 3, 4, 64, 0x80, VALID_LIFETIME, PREFERRED_LIFETIME, 0)
+    buf += socket.inet_pton(socket.AF_INET6, ON_LINK_PREFIX)
+    return buf
+
+
+def join(sock, group):
+    mreq = socket.inet_pton(socket.AF_INET6, group) + struct.pack("@I", if_index)
+    sock.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_JOIN_GROUP, mreq)
+
+
+dhcp_sock = socket.socket(socket.AF_INET6, socket.SOCK_DGRAM)
+dhcp_sock.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+dhcp_sock.setsockopt(socket.SOL_SOCKET, socket.SO_BINDTODEVICE, INTERFACE.encode())
+dhcp_sock.bind(("::", 547))
+join(dhcp_sock, ALL_DHCP_RELAY_AGENTS_AND_SERVERS)
+
+# ICMPv6のチェックサムはカーネルが計算する
+ra_sock = socket.socket(socket.AF_INET6, socket.SOCK_RAW, socket.IPPROTO_ICMPV6)
+ra_sock.setsockopt(socket.SOL_SOCKET, socket.SO_BINDTODEVICE, INTERFACE.encode())
+ra_sock.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_MULTICAST_HOPS, 255)
+ra_sock.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_UNICAST_HOPS, 255)
+ra_sock.setsockopt(socket.IPPROTO_IPV6, socket.IPV6_MULTICAST_IF, if_index)
+join(ra_sock, ALL_ROUTERS)
+
+next_ra = 0.0
+while True:
+    now = time.time()
+    if now >= next_ra:
+        ra_sock.sendto(router_advertisement(), (ALL_NODES, 0, 0, if_index))
+        next_ra = now + RA_INTERVAL
+    readable, _, _ = select.select([dhcp_sock, ra_sock], [], [], max(next_ra - now, 0))
+    if ra_sock in readable:
+        packet, _ = ra_sock.recvfrom(65535)
+        # Router Solicitationを受け取ったらすぐにRAを送る
+        if packet and packet[0] == 133:
+            next_ra = 0.0
+    if dhcp_sock in readable:
+        packet, (src, port, _, scope_id) = dhcp_sock.recvfrom(65535)
+        if len(packet) < 4:
+            continue
+        print(f"dhcpv6 message {packet[0]} from {src}")
+        reply = reply_to(packet[0], packet)
+        if reply is not None:
+            dhcp_sock.sendto(reply, (src, port, 0, scope_id or if_index))
@@ -0,0 +1,20 @@
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::addrselect::{select_source_addr, sort_destination_addrs};
+use tcpip_rs::socket::*;
+
+// 引数で渡した宛先アドレスを接続を試す順に並べ、それぞれの送信元アドレスを表示する
+fn main() {
+    let dsts: Vec<IpAddr> = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("invalid address"))
+        .collect();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        for dst in sort_destination_addrs(&dsts) {
+            println!("destination {dst} source {:?}", select_source_addr(dst));
+        }
+    });
+    recv_packet(Box::from("host2-host1"));
+}
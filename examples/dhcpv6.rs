@@ -0,0 +1,28 @@
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::addrconf::get_ipv6_addrs;
+use tcpip_rs::dhcpv6::{
+    get_delegated_prefixes, get_dhcpv6_dns_servers, get_dhcpv6_domain_list, set_dhcpv6_config,
+    Dhcpv6Config,
+};
+use tcpip_rs::socket::*;
+
+// RAのMフラグを見てDHCPv6でアドレスとプレフィックスを取得する
+fn main() {
+    set_dhcpv6_config(Dhcpv6Config {
+        enabled: true,
+        request_prefix: true,
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        for entry in get_ipv6_addrs() {
+            println!("addr {:x} {:?} {:?}", entry.addr, entry.origin, entry.state);
+        }
+        for prefix in get_delegated_prefixes() {
+            println!("delegated prefix {:x}/{}", prefix.prefix, prefix.prefix_len);
+        }
+        println!("dns servers {:x?}", get_dhcpv6_dns_servers());
+        println!("domain list {:?}", get_dhcpv6_domain_list());
+    });
+    recv_packet(Box::from("host2-host1"));
+}
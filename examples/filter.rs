@@ -0,0 +1,64 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use tcpip_rs::conntrack::CtState;
+use tcpip_rs::filter::*;
+use tcpip_rs::forward::{set_forwarding_config, ForwardingConfig};
+use tcpip_rs::socket::*;
+
+// host1からhost3への通信とその返りだけを転送し、host3から始まる通信は拒否する
+// host2宛てにはpingとその返りだけを受け取る
+fn main() {
+    set_forwarding_config(ForwardingConfig {
+        ipv4: true,
+        ipv6: true,
+        send_redirects: true,
+    });
+
+    add_filter_rule(
+        FilterChain::Forward,
+        FilterRule {
+            ct_states: Some(vec![CtState::Established, CtState::Related]),
+            ..Default::default()
+        },
+    );
+    add_filter_rule(
+        FilterChain::Forward,
+        FilterRule {
+            in_interface: Some(String::from("host2-host1")),
+            out_interface: Some(String::from("host2-host3")),
+            ..Default::default()
+        },
+    );
+    set_filter_policy(FilterChain::Forward, FilterAction::Reject);
+
+    add_filter_rule(
+        FilterChain::Input,
+        FilterRule {
+            protocol: Some(1),
+            icmp_type: Some(8),
+            ..Default::default()
+        },
+    );
+    // IPv6はNeighbor Discoveryも含めてICMPv6をすべて受け取る
+    add_filter_rule(
+        FilterChain::Input,
+        FilterRule {
+            protocol: Some(58),
+            ..Default::default()
+        },
+    );
+    add_filter_rule(
+        FilterChain::Input,
+        FilterRule {
+            src: Some((IpAddr::V4(Ipv4Addr::new(192, 168, 2, 0)), 24)),
+            protocol: Some(17),
+            dst_port: Some(9000..=9100),
+            action: FilterAction::Reject,
+            ..Default::default()
+        },
+    );
+    set_filter_policy(FilterChain::Input, FilterAction::Drop);
+
+    thread::spawn(|| recv_packet(Box::from("host2-host3")));
+    recv_packet(Box::from("host2-host1"));
+}
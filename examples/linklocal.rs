@@ -0,0 +1,20 @@
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::ipv4_link_local::get_ipv4_link_local_addrs;
+use tcpip_rs::socket::*;
+
+// IPv4のアドレスがないインターフェースで169.254.0.0/16のアドレスを自動で設定する
+// 引数でインターフェースを指定する
+fn main() {
+    let if_name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("host2-host1"));
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(2));
+        for ll in get_ipv4_link_local_addrs() {
+            println!("link-local {} {:?}", Ipv4Addr::from(ll.addr), ll.state);
+        }
+    });
+    recv_packet(Box::from(if_name));
+}
@@ -0,0 +1,46 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::socket::*;
+
+const ECHO_PORT: u16 = 7007;
+const CLIENT_PORT: u16 = 7008;
+
+// 同じスタックの中のエコーサーバーとクライアントがループバックと自分のアドレスで通信する
+// どれもEthernetには送られずにスタックの中で受信される
+fn main() {
+    bind_udp_port(ECHO_PORT);
+    bind_udp_port(CLIENT_PORT);
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(100));
+        while let Some(datagram) = recv_udp_datagram(ECHO_PORT) {
+            send_udp_datagram(
+                ECHO_PORT,
+                datagram.src_addr,
+                datagram.src_port,
+                datagram.payload,
+            );
+        }
+        while let Some(datagram) = recv_udp_datagram(CLIENT_PORT) {
+            println!(
+                "echo from {}:{} {:?}",
+                datagram.src_addr,
+                datagram.src_port,
+                String::from_utf8_lossy(&datagram.payload)
+            );
+        }
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        let dst_addrs = [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+        ];
+        for dst_addr in dst_addrs {
+            let payload = format!("hello {dst_addr}").into_bytes();
+            send_udp_datagram(CLIENT_PORT, dst_addr, ECHO_PORT, payload);
+        }
+    });
+    recv_packet(Box::from("host2-host1"));
+}
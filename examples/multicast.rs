@@ -0,0 +1,37 @@
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::igmp::{get_igmp_version, get_ipv4_multicast_groups};
+use tcpip_rs::socket::*;
+
+// 239.1.1.1:5000のグループに参加して受信し、同じグループに定期的に送信する
+fn main() {
+    let group = u32::from(Ipv4Addr::new(239, 1, 1, 1));
+    join_udp_multicast_group(5000, group);
+    set_udp_multicast_ttl(5000, 1);
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_millis(100));
+        while let Some(datagram) = recv_udp_datagram(5000) {
+            println!(
+                "recv from {}:{} to {} {:?}",
+                datagram.src_addr,
+                datagram.src_port,
+                datagram.dst_addr,
+                String::from_utf8_lossy(&datagram.payload)
+            );
+        }
+    });
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        send_udp_multicast(5000, group, 5000, b"hello multicast\n".to_vec());
+        println!(
+            "igmp {:?} groups {:?}",
+            get_igmp_version(),
+            get_ipv4_multicast_groups()
+                .iter()
+                .map(|(group, mode, _)| (Ipv4Addr::from(*group), *mode))
+                .collect::<Vec<_>>()
+        );
+    });
+    recv_packet(Box::from("host2-host1"));
+}
@@ -0,0 +1,27 @@
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::forward::{set_forwarding_config, ForwardingConfig};
+use tcpip_rs::nat::{get_nat_mappings, set_nat_config, NatConfig};
+use tcpip_rs::socket::*;
+
+// host1側を内側、host3側を外側にしてhost1からのIPv4の通信をhost2のアドレスに変換する
+fn main() {
+    set_forwarding_config(ForwardingConfig {
+        ipv4: true,
+        ipv6: true,
+        send_redirects: true,
+    });
+    set_nat_config(NatConfig {
+        outside_interface: Some(String::from("host2-host3")),
+        port_min: 49152,
+        port_max: 65535,
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        for mapping in get_nat_mappings() {
+            println!("{mapping:?}");
+        }
+    });
+    thread::spawn(|| recv_packet(Box::from("host2-host3")));
+    recv_packet(Box::from("host2-host1"));
+}
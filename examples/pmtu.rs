@@ -0,0 +1,21 @@
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::pmtu::{get_udp_plpmtud_paths, ipv4_path_mtu, start_udp_plpmtud};
+use tcpip_rs::socket::*;
+use tcpip_rs::tcp::ipv4_mss;
+
+// host3からhost1のUDPエコーサーバー (9000番) に向けてPLPMTUDでPath MTUを探す
+// host2のMTUを小さくしておくとFragmentation Neededでも小さくなる
+fn main() {
+    let dst = u32::from(Ipv4Addr::new(192, 168, 0, 2));
+    start_udp_plpmtud(9100, dst, 9000);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(5));
+        println!("path mtu {} mss {}", ipv4_path_mtu(dst), ipv4_mss(dst));
+        for path in get_udp_plpmtud_paths() {
+            println!("plpmtud {:?} plpmtu {}", path.state, path.plpmtu);
+        }
+    });
+    recv_packet(Box::from("host3-host2"));
+}
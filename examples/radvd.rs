@@ -0,0 +1,25 @@
+use std::time::Duration;
+use tcpip_rs::addrconf::PrefixInformation;
+use tcpip_rs::radvd::{start_radvd, RaConfig};
+use tcpip_rs::socket::*;
+
+// host2をIPv6ルーターとして2001:db8:0:1::/64を広告する
+fn main() {
+    start_radvd(RaConfig {
+        min_interval: Duration::from_secs(3),
+        max_interval: Duration::from_secs(10),
+        mtu: Some(1500),
+        prefixes: vec![PrefixInformation {
+            prefix_len: 64,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: 86400,
+            preferred_lifetime: 14400,
+            prefix: 0x2001_0db8_0000_0001_0000_0000_0000_0000,
+        }],
+        rdnss: vec![0x2001_0db8_0000_0001_0000_0000_0000_0001],
+        dnssl: vec![String::from("example.com")],
+        ..Default::default()
+    });
+    recv_packet(Box::from("host2-host1"));
+}
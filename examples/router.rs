@@ -0,0 +1,21 @@
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::conntrack::dump_connections;
+use tcpip_rs::forward::{set_forwarding_config, ForwardingConfig};
+use tcpip_rs::socket::*;
+
+// host2-host1とhost2-host3の間でIPv4/IPv6のパケットを転送する
+// 通過しているフローを定期的に表示する
+fn main() {
+    set_forwarding_config(ForwardingConfig {
+        ipv4: true,
+        ipv6: true,
+        send_redirects: true,
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        dump_connections();
+    });
+    thread::spawn(|| recv_packet(Box::from("host2-host3")));
+    recv_packet(Box::from("host2-host1"));
+}
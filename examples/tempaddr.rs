@@ -0,0 +1,30 @@
+use std::thread;
+use std::time::Duration;
+use tcpip_rs::addrconf::{get_ipv6_addrs, select_ipv6_src, set_temp_addr_config, TempAddrConfig};
+use tcpip_rs::socket::*;
+
+// RAのプレフィックスから一時アドレスを作り、送信元アドレスに優先して使う
+// 再生成の様子を見るために寿命を短くしている
+fn main() {
+    set_temp_addr_config(TempAddrConfig {
+        enabled: true,
+        prefer: true,
+        valid_lifetime: Duration::from_secs(300),
+        preferred_lifetime: Duration::from_secs(60),
+    });
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(5));
+        for entry in get_ipv6_addrs() {
+            println!(
+                "addr {:x} {:?} {:?} deprecated={}",
+                entry.addr,
+                entry.origin,
+                entry.state,
+                entry.is_deprecated(std::time::Instant::now())
+            );
+        }
+        let dst = 0x2001_0db8_0000_0001_0000_0000_0000_0002;
+        println!("source address for {:x} is {:x}", dst, select_ipv6_src(dst));
+    });
+    recv_packet(Box::from("host2-host1"));
+}
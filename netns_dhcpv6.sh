@@ -0,0 +1,14 @@
+#!/bin/bash
+
+# rootユーザーが必要
+if [ $UID -ne 0 ]; then
+  echo "Root privileges are required"
+  exit 1;
+fi
+
+# IPv6のnetnsを作成
+./netns_ipv6.sh
+
+# host1でMフラグ付きのRAとDHCPv6サーバーを動かす
+# dnsmasqはIA_PDに対応していないので、IA_NAとIA_PDの両方を返すテスト用のサーバーを使う
+ip netns exec host1 ./dhcpv6_server.py host1-host2
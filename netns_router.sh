@@ -0,0 +1,55 @@
+#!/bin/bash
+
+# rootユーザーが必要
+if [ $UID -ne 0 ]; then
+  echo "Root privileges are required"
+  exit 1;
+fi
+
+# 全てのnetnsを削除
+ip -all netns delete
+
+# host2をルーターにしてhost1とhost3をつなぐ
+ip netns add host1
+ip netns add host2
+ip netns add host3
+
+ip link add name host1-host2 type veth peer name host2-host1
+ip link add name host3-host2 type veth peer name host2-host3
+
+ip link set host1-host2 netns host1
+ip link set host2-host1 netns host2
+ip link set host2-host3 netns host2
+ip link set host3-host2 netns host3
+
+# host1のリンクの設定
+ip netns exec host1 ip addr add 192.168.0.2/24 dev host1-host2
+ip netns exec host1 ip -6 addr add 2001:db8:1::2/64 dev host1-host2 nodad
+ip netns exec host1 ip link set host1-host2 up
+ip netns exec host1 ethtool -K host1-host2 rx off tx off
+ip netns exec host1 ip route add default via 192.168.0.1
+ip netns exec host1 ip -6 route add default via 2001:db8:1::1
+
+# host3のリンクの設定
+ip netns exec host3 ip addr add 192.168.2.2/24 dev host3-host2
+ip netns exec host3 ip -6 addr add 2001:db8:2::2/64 dev host3-host2 nodad
+ip netns exec host3 ip link set host3-host2 up
+ip netns exec host3 ethtool -K host3-host2 rx off tx off
+ip netns exec host3 ip route add default via 192.168.2.1
+ip netns exec host3 ip -6 route add default via 2001:db8:2::1
+
+# host2のリンクの設定
+# 転送はプロトコルスタックが行うので、カーネルは転送も応答もしない
+for link in host2-host1 host2-host3; do
+  ip netns exec host2 ip link set $link up
+  ip netns exec host2 ethtool -K $link rx off tx off
+done
+ip netns exec host2 ip addr add 192.168.0.1/24 dev host2-host1
+ip netns exec host2 ip addr add 192.168.2.1/24 dev host2-host3
+ip netns exec host2 ip -6 addr add 2001:db8:1::1/64 dev host2-host1 nodad
+ip netns exec host2 ip -6 addr add 2001:db8:2::1/64 dev host2-host3 nodad
+ip netns exec host2 sysctl net.ipv4.ip_forward=0
+ip netns exec host2 sysctl net.ipv6.conf.all.forwarding=0
+ip netns exec host2 sysctl net.ipv4.icmp_echo_ignore_all=1
+ip netns exec host2 sysctl net.ipv6.icmp.echo_ignore_all=1
+ip netns exec host2 sysctl net.ipv4.conf.all.arp_ignore=8
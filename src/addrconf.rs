@@ -0,0 +1,685 @@
+use crate::addrselect::select_ipv6_source;
+use crate::ipv6::{is_ipv6_multicast, solicited_node_addr, IPV6_ALL_NODES_ADDR};
+use crate::mld::{join_ipv6_multicast_group, leave_ipv6_multicast_group};
+use crate::ndp::RETRANS_TIMER;
+use crate::radvd::is_radvd_running;
+use crate::util::random_u64;
+use log::warn;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// RFC 4861 10. Protocol Constants
+const MAX_RTR_SOLICITATIONS: u8 = 3;
+const RTR_SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+// RFC 4862 5.5.3 e) 有効期間を短くする攻撃への対策
+const TWO_HOURS: Duration = Duration::from_secs(2 * 60 * 60);
+const INFINITE_LIFETIME: u32 = 0xffffffff;
+
+// RFC 8981 3.8. Defined Protocol Parameters and Configuration Variables
+const TEMP_IDGEN_RETRIES: usize = 3;
+
+// fe80::/64 リンクローカルアドレス
+const LINK_LOCAL_PREFIX: u128 = 0xfe80 << 112;
+
+// アドレスがどのように設定されたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6AddrOrigin {
+    Static,    // インターフェースに設定済みのアドレス
+    LinkLocal, // MACアドレスから生成したリンクローカルアドレス
+    Slaac,     // RAのプレフィックスから生成したアドレス
+    Dhcpv6,    // DHCPv6のIA_NAで割り当てられたアドレス
+    Temporary, // ランダムなインターフェースIDで生成した一時アドレス (RFC 8981)
+    Loopback,  // ループバックデバイスの::1
+}
+
+// 重複アドレス検出 (DAD) の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6AddrState {
+    Tentative, // DAD中で、まだ使えない
+    Preferred, // DADが完了して使える
+    Duplicate, // DADで重複が見つかったので使えない
+}
+
+#[derive(Debug, Clone)]
+pub struct Ipv6AddrEntry {
+    pub addr: u128,
+    pub prefix_len: u8,
+    pub origin: Ipv6AddrOrigin,
+    pub state: Ipv6AddrState,
+    pub valid_until: Option<Instant>,     // Noneなら無期限
+    pub preferred_until: Option<Instant>, // Noneなら無期限
+    dad_sent: u8,                         // 送信済みのDAD用NS数
+    dad_last_sent: Option<Instant>,
+}
+
+impl Ipv6AddrEntry {
+    // 設定されたアドレスもSLAACのアドレスも使う前にDADを行う (RFC 4862 5.4)
+    // ループバックのアドレスはリンクで使わないので行わない
+    fn new(
+        addr: u128,
+        prefix_len: u8,
+        origin: Ipv6AddrOrigin,
+        valid_until: Option<Instant>,
+        preferred_until: Option<Instant>,
+    ) -> Self {
+        let dad = match origin {
+            Ipv6AddrOrigin::Loopback => false,
+            Ipv6AddrOrigin::Static => *STATIC_ADDR_DAD.lock().unwrap(),
+            _ => true,
+        } && *DUP_ADDR_DETECT_TRANSMITS.lock().unwrap() > 0;
+        Ipv6AddrEntry {
+            addr,
+            prefix_len,
+            origin,
+            state: if dad {
+                Ipv6AddrState::Tentative
+            } else {
+                Ipv6AddrState::Preferred
+            },
+            valid_until,
+            preferred_until,
+            dad_sent: 0,
+            dad_last_sent: None,
+        }
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.state == Ipv6AddrState::Preferred
+    }
+
+    // 推奨期間を過ぎたアドレスは新しい通信の送信元に使わない
+    pub fn is_deprecated(&self, now: Instant) -> bool {
+        self.preferred_until.is_some_and(|until| until <= now)
+    }
+}
+
+// RAで受け取るリンクのパラメータ
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6LinkParams {
+    pub mtu: u32,
+    pub hop_limit: u8,
+    pub managed: bool, // Mフラグ
+    pub other: bool,   // Oフラグ
+}
+
+// RAのPrefix Informationオプション
+#[derive(Debug, Clone)]
+pub struct PrefixInformation {
+    pub prefix_len: u8,
+    pub on_link: bool,    // Lフラグ
+    pub autonomous: bool, // Aフラグ
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+    pub prefix: u128,
+}
+
+#[derive(Debug)]
+pub struct RouterAdvertisement {
+    pub cur_hop_limit: u8,
+    pub managed: bool,
+    pub other: bool,
+    pub router_lifetime: u16,
+    pub prefixes: Vec<PrefixInformation>,
+    pub mtu: Option<u32>,
+}
+
+struct DefaultRouter {
+    addr: u128,
+    expires_at: Instant,
+}
+
+struct OnLinkPrefix {
+    prefix: u128,
+    prefix_len: u8,
+    expires_at: Option<Instant>,
+}
+
+// 一時アドレスの設定 (RFC 8981)
+#[derive(Debug, Clone, Copy)]
+pub struct TempAddrConfig {
+    pub enabled: bool,                // SLAACのプレフィックスに一時アドレスを作るか
+    pub prefer: bool,                 // 送信元アドレスに一時アドレスを優先して使うか
+    pub valid_lifetime: Duration,     // TEMP_VALID_LIFETIME
+    pub preferred_lifetime: Duration, // TEMP_PREFERRED_LIFETIME
+}
+
+struct RouterSolicitState {
+    sent: u8,
+    last_sent: Option<Instant>,
+    done: bool, // RAを受信したらやめる
+}
+
+static IPV6_ADDRS: Mutex<Vec<Ipv6AddrEntry>> = Mutex::new(Vec::new());
+static DEFAULT_ROUTERS: Mutex<Vec<DefaultRouter>> = Mutex::new(Vec::new());
+static ONLINK_PREFIXES: Mutex<Vec<OnLinkPrefix>> = Mutex::new(Vec::new());
+static LINK_PARAMS: Mutex<Ipv6LinkParams> = Mutex::new(Ipv6LinkParams {
+    mtu: 1500,
+    hop_limit: 64,
+    managed: false,
+    other: false,
+});
+// DupAddrDetectTransmits 0ならDADを行わない
+static DUP_ADDR_DETECT_TRANSMITS: Mutex<u8> = Mutex::new(1);
+// インターフェースに設定されていたアドレスもDADするか
+static STATIC_ADDR_DAD: Mutex<bool> = Mutex::new(true);
+static TEMP_ADDR_CONFIG: Mutex<TempAddrConfig> = Mutex::new(TempAddrConfig {
+    enabled: false,
+    prefer: true,
+    valid_lifetime: Duration::from_secs(2 * 24 * 60 * 60),
+    preferred_lifetime: Duration::from_secs(24 * 60 * 60),
+});
+static RS_STATE: Mutex<RouterSolicitState> = Mutex::new(RouterSolicitState {
+    sent: 0,
+    last_sent: None,
+    done: false,
+});
+
+// MACアドレスからModified EUI-64形式のインターフェースIDを生成する
+pub fn eui64_interface_id(mac_addr: [u8; 6]) -> u64 {
+    u64::from_be_bytes([
+        mac_addr[0] ^ 0x02,
+        mac_addr[1],
+        mac_addr[2],
+        0xff,
+        0xfe,
+        mac_addr[3],
+        mac_addr[4],
+        mac_addr[5],
+    ])
+}
+
+fn prefix_mask(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix_len as u32)
+    }
+}
+
+fn lifetime_to_instant(now: Instant, lifetime: u32) -> Option<Instant> {
+    if lifetime == INFINITE_LIFETIME {
+        None
+    } else {
+        Some(now + Duration::from_secs(lifetime as u64))
+    }
+}
+
+pub fn is_link_local(addr: u128) -> bool {
+    addr & prefix_mask(10) == LINK_LOCAL_PREFIX
+}
+
+// MACアドレスから生成したリンクローカルアドレス
+pub fn link_local_addr(mac_addr: [u8; 6]) -> u128 {
+    LINK_LOCAL_PREFIX | eui64_interface_id(mac_addr) as u128
+}
+
+// リンクローカルアドレスを生成してRouter Solicitationの送信を始める
+pub fn start_addrconf(my_mac_addr: [u8; 6]) {
+    add_ipv6_addr(link_local_addr(my_mac_addr), 64, Ipv6AddrOrigin::LinkLocal);
+    *RS_STATE.lock().unwrap() = RouterSolicitState {
+        sent: 0,
+        last_sent: None,
+        done: false,
+    };
+}
+
+pub fn add_ipv6_addr(addr: u128, prefix_len: u8, origin: Ipv6AddrOrigin) {
+    let mut addrs = IPV6_ADDRS.lock().unwrap();
+    if addrs.iter().any(|entry| entry.addr == addr) {
+        return;
+    }
+    addrs.push(Ipv6AddrEntry::new(addr, prefix_len, origin, None, None));
+    println!("add ipv6 addr {:x}/{prefix_len} {origin:?}", addr);
+    // DAD中も他のノードのDADを受け取るために、すぐにSolicited-Nodeマルチキャストに参加する
+    // ループバックのアドレスはリンクで使わないので参加しない
+    if origin != Ipv6AddrOrigin::Loopback {
+        join_ipv6_multicast_group(solicited_node_addr(addr));
+    }
+}
+
+// DHCPv6で割り当てられたアドレスを追加するか、有効期間を更新する
+// IA_NAのアドレスはプレフィックスを持たないので/128として扱う (RFC 8415 21.6)
+pub fn set_dhcpv6_addr(addr: u128, preferred_lifetime: u32, valid_lifetime: u32) {
+    let now = Instant::now();
+    let valid_until = lifetime_to_instant(now, valid_lifetime);
+    let preferred_until = lifetime_to_instant(now, preferred_lifetime);
+    let mut addrs = IPV6_ADDRS.lock().unwrap();
+    if valid_lifetime == 0 {
+        remove_addr(&mut addrs, addr);
+        return;
+    }
+    match addrs.iter_mut().find(|entry| entry.addr == addr) {
+        Some(entry) => {
+            entry.valid_until = valid_until;
+            entry.preferred_until = preferred_until;
+        }
+        None => {
+            addrs.push(Ipv6AddrEntry::new(
+                addr,
+                128,
+                Ipv6AddrOrigin::Dhcpv6,
+                valid_until,
+                preferred_until,
+            ));
+            println!("add ipv6 addr {:x}/128 Dhcpv6", addr);
+            join_ipv6_multicast_group(solicited_node_addr(addr));
+        }
+    }
+}
+
+pub fn remove_ipv6_addr(addr: u128) {
+    remove_addr(&mut IPV6_ADDRS.lock().unwrap(), addr);
+}
+
+fn remove_addr(addrs: &mut Vec<Ipv6AddrEntry>, addr: u128) {
+    let len = addrs.len();
+    addrs.retain(|entry| entry.addr != addr);
+    if addrs.len() != len {
+        println!("remove ipv6 addr {:x}", addr);
+        leave_ipv6_multicast_group(solicited_node_addr(addr));
+    }
+}
+
+pub fn get_ipv6_addrs() -> Vec<Ipv6AddrEntry> {
+    IPV6_ADDRS.lock().unwrap().clone()
+}
+
+pub fn get_ipv6_addr_state(addr: u128) -> Option<Ipv6AddrState> {
+    IPV6_ADDRS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|entry| entry.addr == addr)
+        .map(|entry| entry.state)
+}
+
+pub fn set_dup_addr_detect_transmits(transmits: u8) {
+    *DUP_ADDR_DETECT_TRANSMITS.lock().unwrap() = transmits;
+}
+
+// OSがDADを済ませたアドレスだけを設定している場合などに、設定されたアドレスのDADを省く
+// recv_packetより前に呼ぶ
+pub fn set_static_addr_dad(enabled: bool) {
+    *STATIC_ADDR_DAD.lock().unwrap() = enabled;
+}
+
+pub fn set_temp_addr_config(config: TempAddrConfig) {
+    *TEMP_ADDR_CONFIG.lock().unwrap() = config;
+}
+
+pub fn get_temp_addr_config() -> TempAddrConfig {
+    *TEMP_ADDR_CONFIG.lock().unwrap()
+}
+
+pub fn get_ipv6_link_params() -> Ipv6LinkParams {
+    *LINK_PARAMS.lock().unwrap()
+}
+
+// DAD中や重複したアドレスは自分のアドレスとして扱わない
+pub fn is_my_ipv6_addr(addr: u128) -> bool {
+    IPV6_ADDRS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.addr == addr && entry.is_usable())
+}
+
+// 受信すべきユニキャストの宛先アドレスか
+// マルチキャストグループ宛てかどうかはmldで判定する
+pub fn is_my_ipv6_dst(dst_addr: u128) -> bool {
+    dst_addr == IPV6_ALL_NODES_ADDR || is_my_ipv6_addr(dst_addr)
+}
+
+// 宛先に対して使う送信元アドレスをRFC 6724の規則で選ぶ
+// 使えるアドレスがなければ未指定アドレスを返す
+pub fn select_ipv6_src(dst_addr: u128) -> u128 {
+    select_ipv6_source(dst_addr).unwrap_or(0)
+}
+
+// 受信したパケットへの返信に使う送信元アドレス
+pub fn select_ipv6_reply_src(recv_dst_addr: u128, peer_addr: u128) -> u128 {
+    if is_my_ipv6_addr(recv_dst_addr) {
+        return recv_dst_addr;
+    }
+    select_ipv6_src(peer_addr)
+}
+
+// 宛先へのネクストホップを決める
+// オンリンクなら宛先そのもの、そうでなければデフォルトルーター
+pub fn ipv6_next_hop(dst_addr: u128) -> u128 {
+    if is_link_local(dst_addr) || is_ipv6_multicast(dst_addr) {
+        return dst_addr;
+    }
+    let on_link = ONLINK_PREFIXES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|p| dst_addr & prefix_mask(p.prefix_len) == p.prefix)
+        || IPV6_ADDRS.lock().unwrap().iter().any(|entry| {
+            entry.origin == Ipv6AddrOrigin::Static
+                && (dst_addr ^ entry.addr) & prefix_mask(entry.prefix_len) == 0
+        });
+    if on_link {
+        return dst_addr;
+    }
+    match DEFAULT_ROUTERS.lock().unwrap().first() {
+        Some(router) => router.addr,
+        // ルーターを知らなければオンリンクとみなす
+        None => dst_addr,
+    }
+}
+
+// Router Advertisementの処理 (RFC 4861 6.3.4, RFC 4862 5.5.3)
+pub fn process_router_advertisement(src_addr: u128, ra: RouterAdvertisement, my_mac_addr: [u8; 6]) {
+    // ルーターとして動作している時は他のルーターのRAで自分を設定しない
+    if is_radvd_running() {
+        println!(
+            "ignore router advertisement from other router {:x}",
+            src_addr
+        );
+        return;
+    }
+    let now = Instant::now();
+    RS_STATE.lock().unwrap().done = true;
+
+    {
+        let mut params = LINK_PARAMS.lock().unwrap();
+        if ra.cur_hop_limit != 0 {
+            params.hop_limit = ra.cur_hop_limit;
+        }
+        // 最小MTU 1280未満は無視する
+        if let Some(mtu) = ra.mtu.filter(|mtu| *mtu >= 1280) {
+            params.mtu = mtu;
+        }
+        params.managed = ra.managed;
+        params.other = ra.other;
+    }
+
+    {
+        let mut routers = DEFAULT_ROUTERS.lock().unwrap();
+        routers.retain(|router| router.addr != src_addr);
+        if ra.router_lifetime != 0 {
+            routers.push(DefaultRouter {
+                addr: src_addr,
+                expires_at: now + Duration::from_secs(ra.router_lifetime as u64),
+            });
+        }
+    }
+
+    for prefix in ra.prefixes {
+        if is_link_local(prefix.prefix) {
+            continue;
+        }
+        let prefix_addr = prefix.prefix & prefix_mask(prefix.prefix_len);
+        if prefix.on_link {
+            process_onlink_prefix(now, prefix_addr, &prefix);
+        }
+        if prefix.autonomous && prefix.preferred_lifetime <= prefix.valid_lifetime {
+            process_autonomous_prefix(now, prefix_addr, &prefix, my_mac_addr);
+        }
+    }
+}
+
+fn process_onlink_prefix(now: Instant, prefix_addr: u128, prefix: &PrefixInformation) {
+    let mut prefixes = ONLINK_PREFIXES.lock().unwrap();
+    prefixes.retain(|p| !(p.prefix == prefix_addr && p.prefix_len == prefix.prefix_len));
+    if prefix.valid_lifetime != 0 {
+        prefixes.push(OnLinkPrefix {
+            prefix: prefix_addr,
+            prefix_len: prefix.prefix_len,
+            expires_at: lifetime_to_instant(now, prefix.valid_lifetime),
+        });
+    }
+}
+
+fn process_autonomous_prefix(
+    now: Instant,
+    prefix_addr: u128,
+    prefix: &PrefixInformation,
+    my_mac_addr: [u8; 6],
+) {
+    let mut addrs = IPV6_ADDRS.lock().unwrap();
+    let existing = addrs.iter_mut().find(|entry| {
+        entry.origin == Ipv6AddrOrigin::Slaac
+            && entry.prefix_len == prefix.prefix_len
+            && entry.addr & prefix_mask(prefix.prefix_len) == prefix_addr
+    });
+
+    let Some(entry) = existing else {
+        // インターフェースIDは64bitなので/64のプレフィックスだけ使える
+        if prefix.valid_lifetime == 0 || prefix.prefix_len != 64 {
+            return;
+        }
+        let addr = prefix_addr | eui64_interface_id(my_mac_addr) as u128;
+        println!("add ipv6 addr {:x}/64 Slaac", addr);
+        addrs.push(Ipv6AddrEntry::new(
+            addr,
+            prefix.prefix_len,
+            Ipv6AddrOrigin::Slaac,
+            lifetime_to_instant(now, prefix.valid_lifetime),
+            lifetime_to_instant(now, prefix.preferred_lifetime),
+        ));
+        join_ipv6_multicast_group(solicited_node_addr(addr));
+        return;
+    };
+
+    entry.preferred_until = lifetime_to_instant(now, prefix.preferred_lifetime);
+    // 2時間を超えるか残りより長ければそのまま更新、残りが2時間以下なら無視、それ以外は2時間にする
+    let received = lifetime_to_instant(now, prefix.valid_lifetime);
+    let remaining = entry
+        .valid_until
+        .map(|until| until.saturating_duration_since(now));
+    let received_secs = Duration::from_secs(prefix.valid_lifetime as u64);
+    if received.is_none()
+        || received_secs > TWO_HOURS
+        || remaining.is_some_and(|remaining| received_secs > remaining)
+    {
+        entry.valid_until = received;
+    } else if remaining.is_some_and(|remaining| remaining <= TWO_HOURS) {
+        // 何もしない
+    } else {
+        entry.valid_until = Some(now + TWO_HOURS);
+    }
+}
+
+// Noneを無期限として早い方の期限を返す
+fn min_until(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// 新しい一時アドレスを推奨期間が切れる前に作っておく時間 (RFC 8981 3.8)
+fn temp_regen_advance() -> Duration {
+    let transmits = *DUP_ADDR_DETECT_TRANSMITS.lock().unwrap() as u32;
+    Duration::from_secs(2) + RETRANS_TIMER * (TEMP_IDGEN_RETRIES as u32 * transmits)
+}
+
+// ランダムなインターフェースIDを生成する (RFC 8981 3.3.1)
+// 予約されたインターフェースID (RFC 5453) と使用中のアドレスは避ける
+fn temp_interface_id(addrs: &[Ipv6AddrEntry], prefix: u128) -> u64 {
+    loop {
+        let id = random_u64();
+        let reserved = id == 0
+            // 0200:5eff:fe00:0000 - 0200:5eff:feff:ffff
+            || id >> 24 == 0x02_005e_fffe
+            // fdff:ffff:ffff:ff80 - fdff:ffff:ffff:ffff
+            || id >= 0xfdff_ffff_ffff_ff80;
+        if !reserved && !addrs.iter().any(|entry| entry.addr == prefix | id as u128) {
+            return id;
+        }
+    }
+}
+
+// 一時アドレスのタイマー処理 (RFC 8981 3.4, 3.5)
+// SLAACアドレスの期限を超えないように一時アドレスの期限を縮め、
+// 推奨期間内の一時アドレスがなくなりそうなプレフィックスには新しい一時アドレスを作る
+fn temp_addr_timer(addrs: &mut Vec<Ipv6AddrEntry>, now: Instant) {
+    let config = get_temp_addr_config();
+    if !config.enabled {
+        return;
+    }
+    let regen_advance = temp_regen_advance();
+    let mask = prefix_mask(64);
+    let publics: Vec<(u128, Option<Instant>, Option<Instant>)> = addrs
+        .iter()
+        .filter(|entry| entry.origin == Ipv6AddrOrigin::Slaac && entry.is_usable())
+        .map(|entry| (entry.addr & mask, entry.valid_until, entry.preferred_until))
+        .collect();
+
+    for (prefix, valid_until, preferred_until) in publics {
+        let mut usable = false;
+        let mut duplicates = 0;
+        for entry in addrs.iter_mut().filter(|entry| {
+            entry.origin == Ipv6AddrOrigin::Temporary && entry.addr & mask == prefix
+        }) {
+            entry.valid_until = min_until(entry.valid_until, valid_until);
+            entry.preferred_until = min_until(entry.preferred_until, preferred_until);
+            if entry.state == Ipv6AddrState::Duplicate {
+                duplicates += 1;
+            } else if entry
+                .preferred_until
+                .is_none_or(|until| until > now + regen_advance)
+            {
+                usable = true;
+            }
+        }
+        // DADで重複し続けたら諦める (RFC 8981 3.3.2)
+        if usable || duplicates >= TEMP_IDGEN_RETRIES {
+            continue;
+        }
+
+        // 推奨期間をDESYNC_FACTORだけ短くして、同じリンクのノードが同時に再生成しないようにする
+        let max_desync = config.preferred_lifetime.mul_f64(0.4);
+        let desync = Duration::from_millis(random_u64() % (max_desync.as_millis() as u64 + 1));
+        let temp_preferred_until = min_until(
+            preferred_until,
+            Some(now + config.preferred_lifetime.saturating_sub(desync)),
+        );
+        let temp_valid_until = min_until(valid_until, Some(now + config.valid_lifetime));
+        // 推奨期間がREGEN_ADVANCEより短いなら作らない (RFC 8981 3.4 5.)
+        if temp_preferred_until.is_some_and(|until| until <= now + regen_advance) {
+            continue;
+        }
+
+        let addr = prefix | temp_interface_id(addrs, prefix) as u128;
+        println!("add ipv6 addr {:x}/64 Temporary", addr);
+        addrs.push(Ipv6AddrEntry::new(
+            addr,
+            64,
+            Ipv6AddrOrigin::Temporary,
+            temp_valid_until,
+            temp_preferred_until,
+        ));
+        join_ipv6_multicast_group(solicited_node_addr(addr));
+    }
+}
+
+// アドレス自動設定のタイマー処理
+// 有効期限切れのアドレス・ルーター・プレフィックスを削除し、RSを送るべきならtrueを返す
+pub fn addrconf_timer() -> bool {
+    let now = Instant::now();
+
+    {
+        let mut addrs = IPV6_ADDRS.lock().unwrap();
+        addrs.retain(|entry| {
+            let expired = entry.valid_until.is_some_and(|until| until <= now);
+            if expired {
+                println!("ipv6 addr {:x} expired", entry.addr);
+                leave_ipv6_multicast_group(solicited_node_addr(entry.addr));
+            }
+            !expired
+        });
+        temp_addr_timer(&mut addrs, now);
+    }
+    DEFAULT_ROUTERS
+        .lock()
+        .unwrap()
+        .retain(|router| router.expires_at > now);
+    ONLINK_PREFIXES
+        .lock()
+        .unwrap()
+        .retain(|p| p.expires_at.is_none_or(|until| until > now));
+
+    // リンクローカルアドレスのDADが終わってからRSを送る
+    if IPV6_ADDRS.lock().unwrap().iter().any(|entry| {
+        entry.origin == Ipv6AddrOrigin::LinkLocal && entry.state == Ipv6AddrState::Tentative
+    }) {
+        return false;
+    }
+
+    let mut rs = RS_STATE.lock().unwrap();
+    if rs.done || is_radvd_running() || rs.sent >= MAX_RTR_SOLICITATIONS {
+        return false;
+    }
+    if rs
+        .last_sent
+        .is_some_and(|last| now - last < RTR_SOLICITATION_INTERVAL)
+    {
+        return false;
+    }
+    rs.sent += 1;
+    rs.last_sent = Some(now);
+    true
+}
+
+// DADのタイマー処理 (RFC 4862 5.4)
+// DAD用のNSを送るべきアドレスを返し、最後のNSからRetransTimer経過したものは使用可能にする
+pub fn dad_timer() -> Vec<u128> {
+    let transmits = *DUP_ADDR_DETECT_TRANSMITS.lock().unwrap();
+    let now = Instant::now();
+    let mut targets = vec![];
+
+    let mut addrs = IPV6_ADDRS.lock().unwrap();
+    for entry in addrs
+        .iter_mut()
+        .filter(|entry| entry.state == Ipv6AddrState::Tentative)
+    {
+        if entry
+            .dad_last_sent
+            .is_some_and(|last| now - last < RETRANS_TIMER)
+        {
+            continue;
+        }
+        if entry.dad_sent >= transmits {
+            entry.state = Ipv6AddrState::Preferred;
+            println!("ipv6 addr {:x} dad completed", entry.addr);
+            continue;
+        }
+        entry.dad_sent += 1;
+        entry.dad_last_sent = Some(now);
+        targets.push(entry.addr);
+    }
+    targets
+}
+
+// DAD中のアドレスが他のノードに使われていた (RFC 4862 5.4.5)
+pub fn dad_failed(addr: u128) {
+    let mut addrs = IPV6_ADDRS.lock().unwrap();
+    let Some(entry) = addrs.iter_mut().find(|entry| entry.addr == addr) else {
+        return;
+    };
+    entry.state = Ipv6AddrState::Duplicate;
+    warn!("duplicate ipv6 address detected {:x}", addr);
+    if entry.origin == Ipv6AddrOrigin::LinkLocal {
+        // MACアドレスから生成したリンクローカルが重複したらインターフェースIDが重複している
+        warn!("link-local address is duplicated, interface id is not unique on this link");
+    }
+    if entry.origin == Ipv6AddrOrigin::Temporary {
+        // 別のインターフェースIDで作り直すのはタイマーに任せる
+        let prefix = addr & prefix_mask(64);
+        let duplicates = addrs
+            .iter()
+            .filter(|entry| {
+                entry.origin == Ipv6AddrOrigin::Temporary
+                    && entry.state == Ipv6AddrState::Duplicate
+                    && entry.addr & prefix_mask(64) == prefix
+            })
+            .count();
+        if duplicates >= TEMP_IDGEN_RETRIES {
+            warn!("temporary address generation for {:x}/64 failed", prefix);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::addrconf::{get_ipv6_addrs, get_temp_addr_config, Ipv6AddrOrigin};
+use crate::ipv4_addr::get_ipv4_addrs;
+use crate::loopback::{is_ipv4_loopback, IPV6_LOOPBACK_ADDR};
+use std::cmp::Ordering;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Instant;
+
+// RFC 6724 3.1. Scope Comparisons
+const SCOPE_LINK_LOCAL: u8 = 0x2;
+const SCOPE_GLOBAL: u8 = 0xe;
+
+// ::ffff:0:0/96 IPv4射影アドレス
+const IPV4_MAPPED_PREFIX: u128 = 0xffff << 32;
+
+struct Policy {
+    prefix: u128,
+    prefix_len: u8,
+    precedence: u8,
+    label: u8,
+}
+
+// RFC 6724 2.1. Policy Table のデフォルト
+const POLICY_TABLE: [Policy; 9] = [
+    // ::1/128 ループバック
+    Policy {
+        prefix: 1,
+        prefix_len: 128,
+        precedence: 50,
+        label: 0,
+    },
+    // ::/0
+    Policy {
+        prefix: 0,
+        prefix_len: 0,
+        precedence: 40,
+        label: 1,
+    },
+    // ::ffff:0:0/96 IPv4
+    Policy {
+        prefix: IPV4_MAPPED_PREFIX,
+        prefix_len: 96,
+        precedence: 35,
+        label: 4,
+    },
+    // 2002::/16 6to4
+    Policy {
+        prefix: 0x2002 << 112,
+        prefix_len: 16,
+        precedence: 30,
+        label: 2,
+    },
+    // 2001::/32 Teredo
+    Policy {
+        prefix: 0x2001 << 112,
+        prefix_len: 32,
+        precedence: 5,
+        label: 5,
+    },
+    // fc00::/7 ULA
+    Policy {
+        prefix: 0xfc00 << 112,
+        prefix_len: 7,
+        precedence: 3,
+        label: 13,
+    },
+    // ::/96 IPv4互換アドレス
+    Policy {
+        prefix: 0,
+        prefix_len: 96,
+        precedence: 1,
+        label: 3,
+    },
+    // fec0::/10 サイトローカル
+    Policy {
+        prefix: 0xfec0 << 112,
+        prefix_len: 10,
+        precedence: 1,
+        label: 11,
+    },
+    // 3ffe::/16 6bone
+    Policy {
+        prefix: 0x3ffe << 112,
+        prefix_len: 16,
+        precedence: 1,
+        label: 12,
+    },
+];
+
+// 送信元アドレスの候補
+// IPv4アドレスはIPv4射影アドレスとして扱う
+#[derive(Debug, Clone, Copy)]
+struct SourceCandidate {
+    addr: u128,
+    prefix_len: u8,
+    deprecated: bool,
+    temporary: bool,
+}
+
+fn prefix_mask(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix_len as u32)
+    }
+}
+
+fn to_u128(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(ipv4) => ipv4.to_ipv6_mapped().into(),
+        IpAddr::V6(ipv6) => ipv6.into(),
+    }
+}
+
+fn from_u128(addr: u128) -> IpAddr {
+    let ipv6 = Ipv6Addr::from(addr);
+    match ipv6.to_ipv4_mapped() {
+        Some(ipv4) => IpAddr::V4(ipv4),
+        None => IpAddr::V6(ipv6),
+    }
+}
+
+fn is_ipv4_mapped(addr: u128) -> bool {
+    addr & prefix_mask(96) == IPV4_MAPPED_PREFIX
+}
+
+// 最長一致するポリシーを返す、::/0があるので必ず見つかる
+fn policy(addr: u128) -> &'static Policy {
+    POLICY_TABLE
+        .iter()
+        .filter(|p| addr & prefix_mask(p.prefix_len) == p.prefix)
+        .max_by_key(|p| p.prefix_len)
+        .unwrap()
+}
+
+// RFC 6724 3.2. IPv4 Addresses and IPv4-Mapped Addresses
+// 127.0.0.0/8と169.254.0.0/16はリンクローカル、それ以外のIPv4はグローバルとして扱う
+fn scope(addr: u128) -> u8 {
+    if is_ipv4_mapped(addr) {
+        let ipv4 = addr as u32;
+        return if ipv4 >> 24 == 127 || ipv4 >> 16 == 0xa9fe {
+            SCOPE_LINK_LOCAL
+        } else {
+            SCOPE_GLOBAL
+        };
+    }
+    if addr >> 120 == 0xff {
+        // マルチキャストはアドレスにスコープを持つ
+        return (addr >> 112) as u8 & 0x0f;
+    }
+    // fe80::/10 リンクローカルと::1 ループバック
+    if addr & prefix_mask(10) == 0xfe80 << 112 || addr == 1 {
+        return SCOPE_LINK_LOCAL;
+    }
+    SCOPE_GLOBAL
+}
+
+// 先頭から一致するbit数、送信元のプレフィックス長までしか数えない (RFC 6724 2.2)
+fn common_prefix_len(source: &SourceCandidate, dst_addr: u128) -> u32 {
+    (source.addr ^ dst_addr)
+        .leading_zeros()
+        .min(source.prefix_len as u32)
+}
+
+// 127.0.0.0/8と::1
+fn is_loopback(addr: u128) -> bool {
+    if is_ipv4_mapped(addr) {
+        return is_ipv4_loopback(addr as u32);
+    }
+    addr == IPV6_LOOPBACK_ADDR
+}
+
+// 宛先と同じアドレスファミリーの使用可能なアドレスを候補にする
+// 送信するインターフェースのアドレスだけを候補にするので (RFC 6724 4.)
+// ループバックのアドレスはループバックの宛先にだけ使う
+fn source_candidates(dst_addr: u128) -> Vec<SourceCandidate> {
+    let to_loopback = is_loopback(dst_addr);
+    if is_ipv4_mapped(dst_addr) {
+        return get_ipv4_addrs()
+            .iter()
+            .filter(|entry| is_ipv4_loopback(entry.addr) == to_loopback)
+            .map(|entry| SourceCandidate {
+                addr: IPV4_MAPPED_PREFIX | entry.addr as u128,
+                prefix_len: 96 + entry.prefix_len,
+                deprecated: false,
+                temporary: false,
+            })
+            .collect();
+    }
+    let now = Instant::now();
+    get_ipv6_addrs()
+        .iter()
+        .filter(|entry| entry.is_usable() && (entry.addr == IPV6_LOOPBACK_ADDR) == to_loopback)
+        .map(|entry| SourceCandidate {
+            addr: entry.addr,
+            prefix_len: entry.prefix_len,
+            deprecated: entry.is_deprecated(now),
+            temporary: entry.origin == Ipv6AddrOrigin::Temporary,
+        })
+        .collect()
+}
+
+// RFC 6724 5. Source Address Selection
+// aの方が良ければGreaterを返す
+fn compare_sources(
+    a: &SourceCandidate,
+    b: &SourceCandidate,
+    dst_addr: u128,
+    prefer_temporary: bool,
+) -> Ordering {
+    // Rule 1: 宛先と同じアドレス
+    if a.addr == dst_addr || b.addr == dst_addr {
+        return (a.addr == dst_addr).cmp(&(b.addr == dst_addr));
+    }
+    // Rule 2: 適切なスコープ
+    // 宛先のスコープ以上で最小のもの、なければ最大のもの
+    let (scope_a, scope_b, scope_d) = (scope(a.addr), scope(b.addr), scope(dst_addr));
+    if scope_a != scope_b {
+        let (smaller_is_a, smaller) = if scope_a < scope_b {
+            (true, scope_a)
+        } else {
+            (false, scope_b)
+        };
+        let prefer_smaller = smaller >= scope_d;
+        return if smaller_is_a == prefer_smaller {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+    }
+    // Rule 3: 推奨期間を過ぎたアドレスを避ける
+    if a.deprecated != b.deprecated {
+        return b.deprecated.cmp(&a.deprecated);
+    }
+    // Rule 4, 5, 5.5: Mobile IPv6と複数インターフェースには対応していない
+    // Rule 6: ラベルが一致する
+    let label_d = policy(dst_addr).label;
+    let (match_a, match_b) = (
+        policy(a.addr).label == label_d,
+        policy(b.addr).label == label_d,
+    );
+    if match_a != match_b {
+        return match_a.cmp(&match_b);
+    }
+    // Rule 7: 一時アドレスを優先する (設定で逆にできる)
+    if a.temporary != b.temporary {
+        return (a.temporary == prefer_temporary).cmp(&(b.temporary == prefer_temporary));
+    }
+    // Rule 8: 最長一致
+    common_prefix_len(a, dst_addr).cmp(&common_prefix_len(b, dst_addr))
+}
+
+fn select_source(dst_addr: u128) -> Option<SourceCandidate> {
+    let prefer_temporary = get_temp_addr_config().prefer;
+    source_candidates(dst_addr)
+        .into_iter()
+        .max_by(|a, b| compare_sources(a, b, dst_addr, prefer_temporary))
+}
+
+// 宛先に対して使う送信元アドレスを選ぶ
+// IPv4の宛先にはIPv4、IPv6の宛先にはIPv6のアドレスを返す
+pub fn select_source_addr(dst_addr: IpAddr) -> Option<IpAddr> {
+    select_source(to_u128(dst_addr)).map(|source| from_u128(source.addr))
+}
+
+pub(crate) fn select_ipv6_source(dst_addr: u128) -> Option<u128> {
+    select_source(dst_addr).map(|source| source.addr)
+}
+
+pub(crate) fn select_ipv4_source(dst_addr: u32) -> Option<u32> {
+    select_source(IPV4_MAPPED_PREFIX | dst_addr as u128).map(|source| source.addr as u32)
+}
+
+// RFC 6724 6. Destination Address Selection
+// aを先にするべきならLessを返す
+fn compare_destinations(
+    a: u128,
+    source_a: Option<&SourceCandidate>,
+    b: u128,
+    source_b: Option<&SourceCandidate>,
+) -> Ordering {
+    // Rule 1: 送信元アドレスがない宛先を避ける
+    let (Some(source_a), Some(source_b)) = (source_a, source_b) else {
+        return source_b.is_some().cmp(&source_a.is_some());
+    };
+    // Rule 2: スコープが一致する
+    let (match_a, match_b) = (
+        scope(a) == scope(source_a.addr),
+        scope(b) == scope(source_b.addr),
+    );
+    if match_a != match_b {
+        return match_b.cmp(&match_a);
+    }
+    // Rule 3: 送信元が推奨期間を過ぎた宛先を避ける
+    if source_a.deprecated != source_b.deprecated {
+        return source_a.deprecated.cmp(&source_b.deprecated);
+    }
+    // Rule 4: Mobile IPv6には対応していない
+    // Rule 5: ラベルが一致する
+    let (match_a, match_b) = (
+        policy(a).label == policy(source_a.addr).label,
+        policy(b).label == policy(source_b.addr).label,
+    );
+    if match_a != match_b {
+        return match_b.cmp(&match_a);
+    }
+    // Rule 6: 優先度が高い
+    let (precedence_a, precedence_b) = (policy(a).precedence, policy(b).precedence);
+    if precedence_a != precedence_b {
+        return precedence_b.cmp(&precedence_a);
+    }
+    // Rule 7: トンネルは使っていないので全てネイティブ
+    // Rule 8: スコープが小さい
+    if scope(a) != scope(b) {
+        return scope(a).cmp(&scope(b));
+    }
+    // Rule 9: 同じアドレスファミリーなら最長一致
+    if is_ipv4_mapped(a) == is_ipv4_mapped(b) {
+        return common_prefix_len(source_b, b).cmp(&common_prefix_len(source_a, a));
+    }
+    // Rule 10: 元の順番を保つ
+    Ordering::Equal
+}
+
+// 名前解決で得た宛先アドレスを接続を試すべき順に並べる
+pub fn sort_destination_addrs(dst_addrs: &[IpAddr]) -> Vec<IpAddr> {
+    let mut dsts: Vec<(u128, Option<SourceCandidate>)> = dst_addrs
+        .iter()
+        .map(|addr| {
+            let addr = to_u128(*addr);
+            (addr, select_source(addr))
+        })
+        .collect();
+    dsts.sort_by(|(a, source_a), (b, source_b)| {
+        compare_destinations(*a, source_a.as_ref(), *b, source_b.as_ref())
+    });
+    dsts.into_iter().map(|(addr, _)| from_u128(addr)).collect()
+}
@@ -1,7 +1,13 @@
 use crate::ethernet::ETHERNET_TYPE_IPV4;
+use crate::ipv4_addr::is_my_ipv4_addr;
+use crate::ipv4_link_local::read_ipv4_link_local_arp;
 use crate::util::to_u32;
 use bytes::{Buf, BufMut};
+use log::warn;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct ArpTable {
@@ -9,16 +15,18 @@ pub struct ArpTable {
     ip_addr: u32,
 }
 
-#[derive(Debug)]
-pub struct ArpTableV6 {
-    mac_addr: [u8; 6],
-    ip_addr: u128,
-}
-
 const ARP_HARDWARE_TYPE: u16 = 0x0001;
 const ARP_OPERATION_TYPE_REQUEST: u16 = 0x0001;
 const ARP_OPERATION_TYPE_REPLY: u16 = 0x0002;
 
+// ARPリクエストの再送間隔と回数
+const ARP_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const ARP_MAX_REQUESTS: u8 = 3;
+// アドレス解決待ちの間にキューイングするパケット数
+const ARP_MAX_QUEUED_PACKETS: usize = 64;
+// 取り出されていない検知イベントの上限、超えたら古いものから捨てる
+const ARP_MAX_SPOOF_EVENTS: usize = 256;
+
 #[derive(Debug)]
 struct ArpMessage {
     hardware_type: u16,
@@ -32,8 +40,83 @@ struct ArpMessage {
     dst_ip_addr: u32,
 }
 
+// ARPスプーフィング検知の設定
+#[derive(Debug, Clone, Copy)]
+pub struct ArpGuardConfig {
+    pub enabled: bool,          // 検知モードを有効にするか
+    pub refuse_overwrite: bool, // 既存エントリの上書きを拒否するか
+    pub max_ips_per_mac: usize, // 1つのMACアドレスが名乗ってよいIPアドレスの数
+}
+
+// ARPスプーフィング検知で発生したイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArpSpoofEvent {
+    // 既知のIPアドレスが別のMACアドレスを名乗った
+    MacChanged {
+        ip_addr: Ipv4Addr,
+        old_mac_addr: [u8; 6],
+        new_mac_addr: [u8; 6],
+        overwritten: bool,
+    },
+    // 1つのMACアドレスが多数のIPアドレスを名乗った
+    TooManyIps {
+        mac_addr: [u8; 6],
+        ip_addrs: Vec<Ipv4Addr>,
+    },
+    // 自分のIPアドレスを他のMACアドレスが名乗った
+    OwnIpClaimed {
+        ip_addr: Ipv4Addr,
+        mac_addr: [u8; 6],
+    },
+}
+
+// アドレス解決待ちのIPv4アドレス
+struct ArpPending {
+    ip_addr: u32,
+    requests: u8,        // 送信済みのARPリクエスト数
+    last_sent: Instant,  // 最後にARPリクエストを送信した時刻
+    queue: Vec<Vec<u8>>, // アドレス解決待ちのIPv4パケット
+}
+
+// 送信時のアドレス解決の結果
+pub enum ArpResolution {
+    Resolved([u8; 6], Vec<u8>), // 解決済みなのでそのまま送信する
+    Request,                    // 新たにARPリクエストを送信する必要がある
+    Queued,                     // アドレス解決中なのでキューに積んだ
+}
+
 static ARP_TABLES: Mutex<Vec<ArpTable>> = Mutex::new(Vec::new());
-static ARP_TABLES_V6: Mutex<Vec<ArpTableV6>> = Mutex::new(Vec::new());
+static ARP_PENDING: Mutex<Vec<ArpPending>> = Mutex::new(Vec::new());
+// アドレス解決が完了して送信できるようになったパケット
+static ARP_READY_PACKETS: Mutex<Vec<([u8; 6], Vec<u8>)>> = Mutex::new(Vec::new());
+static ARP_GUARD: Mutex<ArpGuardConfig> = Mutex::new(ArpGuardConfig {
+    enabled: false,
+    refuse_overwrite: false,
+    max_ips_per_mac: 4,
+});
+static ARP_SPOOF_EVENTS: Mutex<VecDeque<ArpSpoofEvent>> = Mutex::new(VecDeque::new());
+
+pub fn set_arp_guard(config: ArpGuardConfig) {
+    *ARP_GUARD.lock().unwrap() = config;
+}
+
+pub fn get_arp_guard() -> ArpGuardConfig {
+    *ARP_GUARD.lock().unwrap()
+}
+
+// 溜まっている検知イベントを取り出す
+pub fn take_arp_spoof_events() -> Vec<ArpSpoofEvent> {
+    ARP_SPOOF_EVENTS.lock().unwrap().drain(..).collect()
+}
+
+fn raise_arp_spoof_event(event: ArpSpoofEvent) {
+    warn!("arp spoofing detected: {event:?}");
+    let mut events = ARP_SPOOF_EVENTS.lock().unwrap();
+    if events.len() >= ARP_MAX_SPOOF_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
 
 pub fn search_arp_tables(ip_addr: u32) -> [u8; 6] {
     let arp = ARP_TABLES.lock().unwrap();
@@ -52,24 +135,77 @@ pub fn add_arp_tables(mac_addr: [u8; 6], ip_addr: u32) {
     println!("add arp tables entry is OK")
 }
 
-pub fn search_arp_tables_v6(ip_addr: u128) -> [u8; 6] {
-    let arp = ARP_TABLES_V6.lock().unwrap();
-    for i in 0..arp.len() {
-        println!("search_arp_tables v6 {:?}, {:?}", arp[i], ip_addr);
-        if arp[i].ip_addr == ip_addr {
-            return arp[i].mac_addr;
+// 送信先のMACアドレスを解決する
+// 分からなければパケットをキューに積んでARPリクエストの送信を促す
+pub fn resolve_arp(ip_addr: u32, packet: Vec<u8>) -> ArpResolution {
+    let mac_addr = search_arp_tables(ip_addr);
+    if mac_addr != [0, 0, 0, 0, 0, 0] {
+        return ArpResolution::Resolved(mac_addr, packet);
+    }
+    let mut pending = ARP_PENDING.lock().unwrap();
+    match pending.iter_mut().find(|entry| entry.ip_addr == ip_addr) {
+        Some(entry) => {
+            if entry.queue.len() >= ARP_MAX_QUEUED_PACKETS {
+                entry.queue.remove(0);
+            }
+            entry.queue.push(packet);
+            ArpResolution::Queued
+        }
+        None => {
+            pending.push(ArpPending {
+                ip_addr,
+                requests: 1,
+                last_sent: Instant::now(),
+                queue: vec![packet],
+            });
+            ArpResolution::Request
         }
     }
-    [0, 0, 0, 0, 0, 0]
 }
 
-pub fn add_arp_tables_v6(mac_addr: [u8; 6], ip_addr: u128) {
-    let mut arp = ARP_TABLES_V6.lock().unwrap();
-    arp.push(ArpTableV6 { mac_addr, ip_addr });
-    println!("add arp tables v6 entry is OK")
+// ARPテーブルに載ったアドレスを待っていたパケットを送信可能にする
+fn complete_arp_pending(ip_addr: u32) {
+    let mac_addr = search_arp_tables(ip_addr);
+    if mac_addr == [0, 0, 0, 0, 0, 0] {
+        return;
+    }
+    let mut pending = ARP_PENDING.lock().unwrap();
+    let Some(index) = pending.iter().position(|entry| entry.ip_addr == ip_addr) else {
+        return;
+    };
+    let entry = pending.remove(index);
+    let mut ready = ARP_READY_PACKETS.lock().unwrap();
+    for packet in entry.queue {
+        ready.push((mac_addr, packet));
+    }
+}
+
+pub fn take_arp_ready_packets() -> Vec<([u8; 6], Vec<u8>)> {
+    std::mem::take(&mut *ARP_READY_PACKETS.lock().unwrap())
+}
+
+// アドレス解決のタイマー処理
+// ARPリクエストを再送するべきアドレスを返し、応答がなければ待っていたパケットを捨てる
+pub fn arp_timer() -> Vec<u32> {
+    let now = Instant::now();
+    let mut targets = vec![];
+    ARP_PENDING.lock().unwrap().retain_mut(|entry| {
+        if now - entry.last_sent < ARP_REQUEST_INTERVAL {
+            return true;
+        }
+        if entry.requests >= ARP_MAX_REQUESTS {
+            println!("arp resolution failed {}", Ipv4Addr::from(entry.ip_addr));
+            return false;
+        }
+        entry.requests += 1;
+        entry.last_sent = now;
+        targets.push(entry.ip_addr);
+        true
+    });
+    targets
 }
 
-pub fn read_arp_packet(packet: Vec<u8>, my_mac_addr: [u8; 6], my_ip_addr: u32) -> (u32, Vec<u8>) {
+pub fn read_arp_packet(packet: Vec<u8>, my_mac_addr: [u8; 6]) -> (u32, Vec<u8>) {
     let mut arp = &packet[..];
     let arp_message = ArpMessage {
         hardware_type: arp.get_u16(),
@@ -84,14 +220,30 @@ pub fn read_arp_packet(packet: Vec<u8>, my_mac_addr: [u8; 6], my_ip_addr: u32) -
         dst_ip_addr: to_u32(&arp[16..20]),
     };
 
-    // ARPテーブルを検索して存在していなければ追加
-    if search_arp_tables(arp_message.src_ip_addr) == [0, 0, 0, 0, 0, 0] {
-        add_arp_tables(arp_message.src_mac_addr, arp_message.src_ip_addr)
+    // リンクローカルアドレスが他のホストと衝突していないか調べる
+    // 守るためのAnnouncementはブロードキャストする
+    if let Some(announcement) = read_ipv4_link_local_arp(
+        my_mac_addr,
+        arp_message.src_mac_addr,
+        arp_message.src_ip_addr,
+        arp_message.dst_ip_addr,
+        arp_message.operation_type == ARP_OPERATION_TYPE_REQUEST,
+    ) {
+        return (u32::MAX, announcement);
     }
 
+    learn_arp_sender(
+        arp_message.src_mac_addr,
+        arp_message.src_ip_addr,
+        my_mac_addr,
+    );
+    complete_arp_pending(arp_message.src_ip_addr);
+
     if arp_message.operation_type == ARP_OPERATION_TYPE_REQUEST
-        && arp_message.dst_ip_addr == my_ip_addr
+        && is_my_ipv4_addr(arp_message.dst_ip_addr)
     {
+        // 問い合わせられたアドレスで応答する
+        let my_ip_addr = arp_message.dst_ip_addr;
         return (
             arp_message.src_ip_addr,
             out_arp_reply(arp_message, my_mac_addr, my_ip_addr),
@@ -100,6 +252,71 @@ pub fn read_arp_packet(packet: Vec<u8>, my_mac_addr: [u8; 6], my_ip_addr: u32) -
     (0, vec![])
 }
 
+// ARPやIPパケットの送信元のMACアドレスとIPアドレスの対応を学習する
+// 検知モードならIPパケットからの学習も同じように検査する
+pub(crate) fn learn_arp_sender(src_mac_addr: [u8; 6], src_ip_addr: u32, my_mac_addr: [u8; 6]) {
+    // 0.0.0.0はARP Probeなので学習しない
+    if src_ip_addr == 0 {
+        return;
+    }
+    if get_arp_guard().enabled {
+        inspect_arp_sender(src_mac_addr, src_ip_addr, my_mac_addr);
+    } else if search_arp_tables(src_ip_addr) == [0, 0, 0, 0, 0, 0] {
+        // ARPテーブルを検索して存在していなければ追加
+        add_arp_tables(src_mac_addr, src_ip_addr)
+    }
+}
+
+// 検知モード時の送信元の学習
+// IPアドレスとMACアドレスの対応の変化、MACアドレスによるIPアドレスの大量取得を検知する
+fn inspect_arp_sender(src_mac_addr: [u8; 6], src_ip_addr: u32, my_mac_addr: [u8; 6]) {
+    let config = get_arp_guard();
+    if is_my_ipv4_addr(src_ip_addr) && src_mac_addr != my_mac_addr {
+        raise_arp_spoof_event(ArpSpoofEvent::OwnIpClaimed {
+            ip_addr: Ipv4Addr::from(src_ip_addr),
+            mac_addr: src_mac_addr,
+        });
+        return;
+    }
+
+    let mut arp = ARP_TABLES.lock().unwrap();
+    match arp.iter_mut().find(|entry| entry.ip_addr == src_ip_addr) {
+        Some(entry) if entry.mac_addr != src_mac_addr => {
+            let old_mac_addr = entry.mac_addr;
+            if !config.refuse_overwrite {
+                entry.mac_addr = src_mac_addr;
+            }
+            drop(arp);
+            raise_arp_spoof_event(ArpSpoofEvent::MacChanged {
+                ip_addr: Ipv4Addr::from(src_ip_addr),
+                old_mac_addr,
+                new_mac_addr: src_mac_addr,
+                overwritten: !config.refuse_overwrite,
+            });
+        }
+        Some(_) => {}
+        None => {
+            arp.push(ArpTable {
+                mac_addr: src_mac_addr,
+                ip_addr: src_ip_addr,
+            });
+            let ip_addrs: Vec<Ipv4Addr> = arp
+                .iter()
+                .filter(|entry| entry.mac_addr == src_mac_addr)
+                .map(|entry| Ipv4Addr::from(entry.ip_addr))
+                .collect();
+            drop(arp);
+            // 閾値を超えた瞬間に一度だけ通知する
+            if ip_addrs.len() == config.max_ips_per_mac + 1 {
+                raise_arp_spoof_event(ArpSpoofEvent::TooManyIps {
+                    mac_addr: src_mac_addr,
+                    ip_addrs,
+                });
+            }
+        }
+    }
+}
+
 fn out_arp_reply(arp_req: ArpMessage, my_mac_addr: [u8; 6], my_ip_addr: u32) -> Vec<u8> {
     let reply = ArpMessage {
         hardware_type: ARP_HARDWARE_TYPE,
@@ -112,6 +329,26 @@ fn out_arp_reply(arp_req: ArpMessage, my_mac_addr: [u8; 6], my_ip_addr: u32) ->
         dst_mac_addr: arp_req.src_mac_addr,
         dst_ip_addr: arp_req.src_ip_addr,
     };
+    write_arp_message(reply)
+}
+
+// ブロードキャストで送るARPリクエスト
+pub fn out_arp_request(my_mac_addr: [u8; 6], my_ip_addr: u32, target: u32) -> Vec<u8> {
+    let request = ArpMessage {
+        hardware_type: ARP_HARDWARE_TYPE,
+        protocol_type: ETHERNET_TYPE_IPV4,
+        hardware_addr_len: 6,
+        protocol_addr_len: 4,
+        operation_type: ARP_OPERATION_TYPE_REQUEST,
+        src_mac_addr: my_mac_addr,
+        src_ip_addr: my_ip_addr,
+        dst_mac_addr: [0, 0, 0, 0, 0, 0],
+        dst_ip_addr: target,
+    };
+    write_arp_message(request)
+}
+
+fn write_arp_message(reply: ArpMessage) -> Vec<u8> {
     let mut buf = Vec::new();
     buf.put_u16(reply.hardware_type);
     buf.put_u16(reply.protocol_type);
@@ -0,0 +1,498 @@
+use crate::ipv4::{IP_PROTOCOL_NUMBER_ICMP, IP_PROTOCOL_NUMBER_TCP, IP_PROTOCOL_NUMBER_UDP};
+use crate::ipv6::{
+    IP_PROTOCOL_NUMBER_DEST_OPTIONS, IP_PROTOCOL_NUMBER_FRAGMENT, IP_PROTOCOL_NUMBER_HOP_BY_HOP,
+    IP_PROTOCOL_NUMBER_ICMPV6, IP_PROTOCOL_NUMBER_ROUTING,
+};
+use crate::util::{to_u16, to_u32};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// フローの有効期間、NATやフィルタとは別に通過するすべてのフローを追う
+// TCPは状態ごとに決める (Linuxのnf_conntrackの既定値に合わせる)
+const CT_TCP_SYN_SENT_TIMEOUT: Duration = Duration::from_secs(120);
+const CT_TCP_SYN_RECV_TIMEOUT: Duration = Duration::from_secs(60);
+const CT_TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(5 * 24 * 60 * 60);
+const CT_TCP_FIN_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+const CT_TCP_CLOSE_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+const CT_TCP_LAST_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+const CT_TCP_TIME_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+const CT_TCP_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+// UDPは応答を見るまでは短くする
+const CT_UDP_UNREPLIED_TIMEOUT: Duration = Duration::from_secs(30);
+const CT_UDP_TIMEOUT: Duration = Duration::from_secs(120);
+const CT_ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+const CT_GENERIC_TIMEOUT: Duration = Duration::from_secs(600);
+// 追跡するフロー数の上限
+const MAX_CONNECTIONS: usize = 8192;
+
+// ICMPのクエリ (Echo) と対応する応答
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+// TCPのフラグ
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+// フローを識別する5-tuple
+// ICMP Echoは要求の送信元ポートにID、宛先ポートにタイプを入れる
+// 応答はその逆にして、要求と応答を同じフローの両方向として扱う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowTuple {
+    pub protocol: u8,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+}
+
+impl FlowTuple {
+    // 応答方向のフロー
+    pub fn reverse(&self) -> FlowTuple {
+        FlowTuple {
+            protocol: self.protocol,
+            src_addr: self.dst_addr,
+            src_port: self.dst_port,
+            dst_addr: self.src_addr,
+            dst_port: self.src_port,
+        }
+    }
+}
+
+// パケットが属するフローの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtState {
+    New,         // 新しいフローの最初のパケット、または応答をまだ見ていないフロー
+    Established, // 両方向のパケットを見たフロー
+    Related,     // 追跡しているフローについてのICMPエラー
+    Invalid,     // フローを識別できないパケット
+}
+
+// TCPコネクションの状態 (RFC 9293 3.3.2 を通過する側から見たもの)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtTcpState {
+    SynSent,     // 最初の方向のSYNだけを見た
+    SynRecv,     // 応答方向のSYN+ACKを見た
+    Established, // 3way handshakeが終わった
+    FinWait,     // 片方がFINを送った
+    CloseWait,   // 相手がFINにACKを返した
+    LastAck,     // 両方がFINを送った
+    TimeWait,    // 最後のFINにACKが返った
+    Close,       // RSTで閉じた
+}
+
+impl CtTcpState {
+    fn timeout(self) -> Duration {
+        match self {
+            CtTcpState::SynSent => CT_TCP_SYN_SENT_TIMEOUT,
+            CtTcpState::SynRecv => CT_TCP_SYN_RECV_TIMEOUT,
+            CtTcpState::Established => CT_TCP_ESTABLISHED_TIMEOUT,
+            CtTcpState::FinWait => CT_TCP_FIN_WAIT_TIMEOUT,
+            CtTcpState::CloseWait => CT_TCP_CLOSE_WAIT_TIMEOUT,
+            CtTcpState::LastAck => CT_TCP_LAST_ACK_TIMEOUT,
+            CtTcpState::TimeWait => CT_TCP_TIME_WAIT_TIMEOUT,
+            CtTcpState::Close => CT_TCP_CLOSE_TIMEOUT,
+        }
+    }
+}
+
+// 追跡している1つのフロー
+// originalは最初に見たパケットの方向、パケット数とバイト数 (IPヘッダを含む) は方向ごとに数える
+// 再構築せずに転送するフラグメントは、ポートのない先頭以外のものを数えない
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub original: FlowTuple,
+    pub replied: bool,
+    pub tcp_state: Option<CtTcpState>, // TCP以外はNone
+    pub original_packets: u64,
+    pub original_bytes: u64,
+    pub reply_packets: u64,
+    pub reply_bytes: u64,
+    pub expires_at: Instant,
+    fin_sent: [bool; 2], // 方向ごとにFINを見たか、0が最初の方向
+}
+
+impl Connection {
+    fn new(original: FlowTuple, tcp_flags: u8, now: Instant) -> Connection {
+        // SYNで始まらないTCPは途中から追い始めたものとして確立済みとする
+        let tcp_state = if original.protocol != IP_PROTOCOL_NUMBER_TCP {
+            None
+        } else if tcp_flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN {
+            Some(CtTcpState::SynSent)
+        } else {
+            Some(CtTcpState::Established)
+        };
+        Connection {
+            original,
+            replied: false,
+            tcp_state,
+            original_packets: 0,
+            original_bytes: 0,
+            reply_packets: 0,
+            reply_bytes: 0,
+            expires_at: now,
+            fin_sent: [false; 2],
+        }
+    }
+
+    fn refresh(&mut self, now: Instant) {
+        let timeout = match (self.original.protocol, self.tcp_state) {
+            (_, Some(state)) => state.timeout(),
+            (IP_PROTOCOL_NUMBER_UDP, _) if !self.replied => CT_UDP_UNREPLIED_TIMEOUT,
+            (IP_PROTOCOL_NUMBER_UDP, _) => CT_UDP_TIMEOUT,
+            (IP_PROTOCOL_NUMBER_ICMP | IP_PROTOCOL_NUMBER_ICMPV6, _) => CT_ICMP_TIMEOUT,
+            _ => CT_GENERIC_TIMEOUT,
+        };
+        self.expires_at = now + timeout;
+    }
+
+    // 閉じたコネクションと同じポートの組で新しいSYNが来たか
+    fn is_tcp_reopened(&self, tcp_flags: u8, reply: bool) -> bool {
+        !reply
+            && tcp_flags & (TCP_FLAG_SYN | TCP_FLAG_ACK) == TCP_FLAG_SYN
+            && matches!(
+                self.tcp_state,
+                Some(CtTcpState::TimeWait | CtTcpState::Close)
+            )
+    }
+
+    // TCPのフラグと方向で状態を進める
+    fn update_tcp_state(&mut self, tcp_flags: u8, reply: bool) {
+        let Some(state) = self.tcp_state else {
+            return;
+        };
+        let dir = reply as usize;
+        let next = if tcp_flags & TCP_FLAG_RST != 0 {
+            CtTcpState::Close
+        } else if tcp_flags & TCP_FLAG_SYN != 0 {
+            match (state, reply, tcp_flags & TCP_FLAG_ACK != 0) {
+                (CtTcpState::SynSent, true, true) => CtTcpState::SynRecv,
+                _ => state,
+            }
+        } else if tcp_flags & TCP_FLAG_FIN != 0 {
+            self.fin_sent[dir] = true;
+            if self.fin_sent[1 - dir] {
+                CtTcpState::LastAck
+            } else {
+                CtTcpState::FinWait
+            }
+        } else if tcp_flags & TCP_FLAG_ACK != 0 {
+            match state {
+                CtTcpState::SynRecv if !reply => CtTcpState::Established,
+                // FINを送っていない側のACK
+                CtTcpState::FinWait if !self.fin_sent[dir] => CtTcpState::CloseWait,
+                // 後からFINを送った側へのACK
+                CtTcpState::LastAck => CtTcpState::TimeWait,
+                _ => state,
+            }
+        } else {
+            state
+        };
+        if next != state {
+            println!(
+                "conntrack {} {:?} -> {:?}",
+                self.original_string(),
+                state,
+                next
+            );
+        }
+        self.tcp_state = Some(next);
+    }
+
+    fn count(&mut self, len: usize, reply: bool) {
+        if reply {
+            self.reply_packets += 1;
+            self.reply_bytes += len as u64;
+        } else {
+            self.original_packets += 1;
+            self.original_bytes += len as u64;
+        }
+    }
+
+    fn original_string(&self) -> String {
+        let t = &self.original;
+        format!(
+            "{}:{} -> {}:{}",
+            t.src_addr, t.src_port, t.dst_addr, t.dst_port
+        )
+    }
+}
+
+// conntrack -L のような1行の表示
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.original.protocol {
+            IP_PROTOCOL_NUMBER_TCP => "tcp",
+            IP_PROTOCOL_NUMBER_UDP => "udp",
+            IP_PROTOCOL_NUMBER_ICMP => "icmp",
+            IP_PROTOCOL_NUMBER_ICMPV6 => "icmpv6",
+            _ => "unknown",
+        };
+        let expires_in = self
+            .expires_at
+            .saturating_duration_since(Instant::now())
+            .as_secs();
+        write!(f, "{protocol} {} {expires_in}", self.original.protocol)?;
+        if let Some(state) = self.tcp_state {
+            write!(f, " {state:?}")?;
+        }
+        let tuple = |f: &mut fmt::Formatter<'_>, t: &FlowTuple| -> fmt::Result {
+            write!(f, " src={} dst={}", t.src_addr, t.dst_addr)?;
+            match t.protocol {
+                IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP => {
+                    write!(f, " sport={} dport={}", t.src_port, t.dst_port)
+                }
+                _ => Ok(()),
+            }
+        };
+        tuple(f, &self.original)?;
+        write!(
+            f,
+            " packets={} bytes={}",
+            self.original_packets, self.original_bytes
+        )?;
+        if !self.replied {
+            write!(f, " [UNREPLIED]")?;
+        }
+        tuple(f, &self.original.reverse())?;
+        write!(
+            f,
+            " packets={} bytes={}",
+            self.reply_packets, self.reply_bytes
+        )
+    }
+}
+
+static CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+// 有効期間内のフローの一覧
+pub fn get_connections() -> Vec<Connection> {
+    let now = Instant::now();
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.retain(|c| c.expires_at > now);
+    connections.clone()
+}
+
+pub fn dump_connections() {
+    for connection in get_connections() {
+        println!("{connection}");
+    }
+}
+
+pub fn flush_connections() {
+    CONNECTIONS.lock().unwrap().clear();
+}
+
+// パケットの種類ごとの解析結果
+enum ParsedPacket {
+    Flow(FlowTuple, u8), // TCPならフラグ、それ以外は0
+    // ICMPエラーと、含まれている元のパケットのフロー
+    Error(FlowTuple),
+    Unknown,
+}
+
+fn is_icmp_error(protocol: u8, icmp_type: u8) -> bool {
+    match protocol {
+        IP_PROTOCOL_NUMBER_ICMP => matches!(icmp_type, 3 | 4 | 5 | 11 | 12),
+        _ => icmp_type < 128,
+    }
+}
+
+// トランスポート層のヘッダからフローを作る
+// ICMPエラーなら含まれているパケットのフローを返す
+fn parse_transport(
+    protocol: u8,
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    l4: &[u8],
+    nested: bool,
+) -> ParsedPacket {
+    let (src_port, dst_port) = match protocol {
+        IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP if l4.len() >= 4 => {
+            (to_u16(&l4[0..]), to_u16(&l4[2..]))
+        }
+        IP_PROTOCOL_NUMBER_ICMP | IP_PROTOCOL_NUMBER_ICMPV6 if l4.len() >= 8 => {
+            if is_icmp_error(protocol, l4[0]) {
+                if nested {
+                    return ParsedPacket::Unknown;
+                }
+                let inner = match parse_ip_packet(&l4[8..], true) {
+                    ParsedPacket::Flow(tuple, _) => tuple,
+                    _ => return ParsedPacket::Unknown,
+                };
+                return ParsedPacket::Error(inner);
+            }
+            let id = to_u16(&l4[4..]);
+            match (protocol, l4[0]) {
+                (IP_PROTOCOL_NUMBER_ICMP, ICMP_ECHO_REQUEST) => (id, ICMP_ECHO_REQUEST as u16),
+                (IP_PROTOCOL_NUMBER_ICMP, ICMP_ECHO_REPLY) => (ICMP_ECHO_REQUEST as u16, id),
+                (IP_PROTOCOL_NUMBER_ICMPV6, ICMPV6_ECHO_REQUEST) => {
+                    (id, ICMPV6_ECHO_REQUEST as u16)
+                }
+                (IP_PROTOCOL_NUMBER_ICMPV6, ICMPV6_ECHO_REPLY) => (ICMPV6_ECHO_REQUEST as u16, id),
+                // Neighbor Discoveryなどはアドレスの組だけで識別する
+                _ => (0, 0),
+            }
+        }
+        IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP | IP_PROTOCOL_NUMBER_ICMP => {
+            return ParsedPacket::Unknown
+        }
+        _ => (0, 0),
+    };
+    let tcp_flags = match protocol {
+        IP_PROTOCOL_NUMBER_TCP => l4.get(13).copied().unwrap_or(0),
+        _ => 0,
+    };
+    ParsedPacket::Flow(
+        FlowTuple {
+            protocol,
+            src_addr,
+            src_port,
+            dst_addr,
+            dst_port,
+        },
+        tcp_flags,
+    )
+}
+
+// IPv4かIPv6のパケットを解析する
+// ICMPエラーに含まれるパケットは途中で切れていてもよい
+fn parse_ip_packet(packet: &[u8], nested: bool) -> ParsedPacket {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_length = (packet[0] & 0x0f) as usize * 4;
+            // 先頭以外のフラグメントにはポートがない
+            if to_u16(&packet[6..8]) & 0x1fff != 0 || packet.len() < header_length {
+                return ParsedPacket::Unknown;
+            }
+            let src_addr = IpAddr::V4(Ipv4Addr::from(to_u32(&packet[12..16])));
+            let dst_addr = IpAddr::V4(Ipv4Addr::from(to_u32(&packet[16..20])));
+            parse_transport(
+                packet[9],
+                src_addr,
+                dst_addr,
+                &packet[header_length..],
+                nested,
+            )
+        }
+        Some(6) if packet.len() >= 40 => {
+            let src_addr = IpAddr::V6(Ipv6Addr::from(u128::from_be_bytes(
+                packet[8..24].try_into().unwrap(),
+            )));
+            let dst_addr = IpAddr::V6(Ipv6Addr::from(u128::from_be_bytes(
+                packet[24..40].try_into().unwrap(),
+            )));
+            let Some((protocol, offset)) = ipv6_upper_layer(packet) else {
+                return ParsedPacket::Unknown;
+            };
+            parse_transport(protocol, src_addr, dst_addr, &packet[offset..], nested)
+        }
+        _ => ParsedPacket::Unknown,
+    }
+}
+
+// 拡張ヘッダを読み飛ばして上位層のプロトコルと位置を探す
+// 先頭以外のフラグメントならNone
+pub(crate) fn ipv6_upper_layer(packet: &[u8]) -> Option<(u8, usize)> {
+    let mut next_header = packet[6];
+    let mut offset = 40;
+    loop {
+        match next_header {
+            IP_PROTOCOL_NUMBER_HOP_BY_HOP
+            | IP_PROTOCOL_NUMBER_ROUTING
+            | IP_PROTOCOL_NUMBER_DEST_OPTIONS => {
+                let header = packet.get(offset..offset + 2)?;
+                next_header = header[0];
+                offset += (header[1] as usize + 1) * 8;
+            }
+            IP_PROTOCOL_NUMBER_FRAGMENT => {
+                let header = packet.get(offset..offset + 4)?;
+                if to_u16(&header[2..]) & 0xfff8 != 0 {
+                    return None;
+                }
+                next_header = header[0];
+                offset += 8;
+            }
+            _ => return (offset <= packet.len()).then_some((next_header, offset)),
+        }
+    }
+}
+
+// パケットが属するフローの状態を調べて、フローを作るか状態とカウンタを更新する
+// NATやフィルタとは関係なく、受信、送信、転送のそれぞれの経路から呼び出す
+// 別のインターフェースの受信スレッドが同じフローのパケットを同時に処理しても
+// 重複して作らないように、検索と登録を同じロックの中で行う
+pub(crate) fn ct_track(packet: &[u8]) -> CtState {
+    let now = Instant::now();
+    let mut connections = CONNECTIONS.lock().unwrap();
+    connections.retain(|c| c.expires_at > now);
+    let (tuple, tcp_flags) = match parse_ip_packet(packet, false) {
+        ParsedPacket::Flow(tuple, tcp_flags) => (tuple, tcp_flags),
+        ParsedPacket::Error(inner) => {
+            return if connections
+                .iter()
+                .any(|c| c.original == inner || c.original == inner.reverse())
+            {
+                CtState::Related
+            } else {
+                CtState::Invalid
+            };
+        }
+        ParsedPacket::Unknown => return CtState::Invalid,
+    };
+    let found = connections
+        .iter()
+        .position(|c| c.original == tuple || c.original == tuple.reverse());
+    let reopened = found.is_some_and(|index| {
+        let c = &connections[index];
+        c.is_tcp_reopened(tcp_flags, c.original != tuple)
+    });
+    let (index, state) = match found {
+        Some(index) if !reopened => {
+            let c = &connections[index];
+            let reply = c.original != tuple;
+            let state = if c.replied || reply {
+                CtState::Established
+            } else {
+                CtState::New
+            };
+            (index, state)
+        }
+        _ => {
+            if let Some(index) = found {
+                connections.swap_remove(index);
+            }
+            // RSTだけのフローや上限を超えたフローは追わない
+            if tcp_flags & TCP_FLAG_RST != 0 || connections.len() >= MAX_CONNECTIONS {
+                return CtState::New;
+            }
+            connections.push(Connection::new(tuple, tcp_flags, now));
+            (connections.len() - 1, CtState::New)
+        }
+    };
+    let connection = &mut connections[index];
+    let reply = connection.original != tuple;
+    if reply {
+        connection.replied = true;
+    }
+    connection.count(packet.len(), reply);
+    connection.update_tcp_state(tcp_flags, reply);
+    connection.refresh(now);
+    state
+}
+
+// 捨てたパケットで作ったばかりのフローを取り消す
+// 既にあったフローはそのまま残す
+pub(crate) fn ct_discard(packet: &[u8]) {
+    let ParsedPacket::Flow(tuple, _) = parse_ip_packet(packet, false) else {
+        return;
+    };
+    CONNECTIONS.lock().unwrap().retain(|c| {
+        !(c.original == tuple && !c.replied && c.original_packets == 1 && c.reply_packets == 0)
+    });
+}
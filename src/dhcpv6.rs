@@ -0,0 +1,811 @@
+use crate::addrconf::{
+    get_ipv6_addr_state, get_ipv6_link_params, is_link_local, remove_ipv6_addr, select_ipv6_src,
+    set_dhcpv6_addr, Ipv6AddrState,
+};
+use crate::util::{random_u64, to_u16, to_u32};
+use bytes::BufMut;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DHCPV6_CLIENT_PORT: u16 = 546;
+pub const DHCPV6_SERVER_PORT: u16 = 547;
+// ff02::1:2 All_DHCP_Relay_Agents_and_Servers
+pub const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: u128 = 0xff02_0000_0000_0000_0000_0000_0001_0002;
+
+// メッセージタイプ (RFC 8415 7.3)
+const DHCPV6_SOLICIT: u8 = 1;
+const DHCPV6_ADVERTISE: u8 = 2;
+const DHCPV6_REQUEST: u8 = 3;
+const DHCPV6_RENEW: u8 = 5;
+const DHCPV6_REBIND: u8 = 6;
+const DHCPV6_REPLY: u8 = 7;
+const DHCPV6_DECLINE: u8 = 9;
+const DHCPV6_INFORMATION_REQUEST: u8 = 11;
+
+// オプション (RFC 8415 21., RFC 3646)
+const OPTION_CLIENTID: u16 = 1;
+const OPTION_SERVERID: u16 = 2;
+const OPTION_IA_NA: u16 = 3;
+const OPTION_IAADDR: u16 = 5;
+const OPTION_ORO: u16 = 6;
+const OPTION_PREFERENCE: u16 = 7;
+const OPTION_ELAPSED_TIME: u16 = 8;
+const OPTION_STATUS_CODE: u16 = 13;
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_DOMAIN_LIST: u16 = 24;
+const OPTION_IA_PD: u16 = 25;
+const OPTION_IAPREFIX: u16 = 26;
+const OPTION_INFORMATION_REFRESH_TIME: u16 = 32;
+
+// Status Code
+const STATUS_SUCCESS: u16 = 0;
+const STATUS_NO_BINDING: u16 = 3;
+
+// DUID-LL (RFC 8415 11.4) ハードウェアタイプ1はEthernet
+const DUID_LL: u16 = 3;
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+
+// 送信間隔のパラメータ (RFC 8415 7.6)
+const SOL_MAX_DELAY: Duration = Duration::from_secs(1);
+const SOL_TIMEOUT: Duration = Duration::from_secs(1);
+const SOL_MAX_RT: Duration = Duration::from_secs(3600);
+const REQ_TIMEOUT: Duration = Duration::from_secs(1);
+const REQ_MAX_RT: Duration = Duration::from_secs(30);
+const REQ_MAX_RC: u32 = 10;
+const DEC_TIMEOUT: Duration = Duration::from_secs(1);
+const DEC_MAX_RC: u32 = 4;
+const REN_TIMEOUT: Duration = Duration::from_secs(10);
+const REN_MAX_RT: Duration = Duration::from_secs(600);
+const REB_TIMEOUT: Duration = Duration::from_secs(10);
+const REB_MAX_RT: Duration = Duration::from_secs(600);
+const INF_MAX_DELAY: Duration = Duration::from_secs(1);
+const INF_TIMEOUT: Duration = Duration::from_secs(1);
+const INF_MAX_RT: Duration = Duration::from_secs(3600);
+// Information Refresh Time (RFC 8415 21.23)
+const IRT_DEFAULT: u32 = 86400;
+const IRT_MINIMUM: u32 = 600;
+const INFINITY: u32 = 0xffffffff;
+
+// DHCPv6クライアントの設定
+#[derive(Debug, Clone, Copy)]
+pub struct Dhcpv6Config {
+    pub enabled: bool,        // RAのM/Oフラグを見てDHCPv6を使うか
+    pub request_prefix: bool, // IA_PDでプレフィックスの委任を要求するか
+}
+
+// IA_PDで委任されたプレフィックス
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DelegatedPrefix {
+    pub prefix: u128,
+    pub prefix_len: u8,
+    pub preferred_until: Option<Instant>, // Noneなら無期限
+    pub valid_until: Option<Instant>,     // Noneなら無期限
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dhcpv6State {
+    Idle,
+    Soliciting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+    Declining,
+    InformationRequesting,
+    Informed,
+}
+
+// メッセージの再送制御 (RFC 8415 15.)
+struct Exchange {
+    msg_type: u8,
+    transaction_id: u32,
+    started_at: Instant,
+    next_at: Instant,
+    rt: Duration,
+    count: u32,
+    irt: Duration,
+    mrt: Option<Duration>,
+    mrc: Option<u32>,
+    deadline: Option<Instant>, // MRDで決まる再送をやめる時刻
+}
+
+// IA_NAのアドレス、IA_PDのプレフィックス
+#[derive(Debug, Clone)]
+struct IaLease {
+    addr: u128,
+    prefix_len: u8, // IA_NAなら128
+    preferred_lifetime: u32,
+    valid_lifetime: u32,
+}
+
+// Advertise、Replyの内容
+#[derive(Debug, Default)]
+struct Dhcpv6Reply {
+    server_id: Vec<u8>,
+    preference: u8,
+    status: u16,
+    addrs: Vec<IaLease>,
+    prefixes: Vec<IaLease>,
+    no_binding: bool,
+    t1: Option<u32>,
+    t2: Option<u32>,
+    dns_servers: Vec<u128>,
+    domain_list: Vec<String>,
+    refresh_time: Option<u32>,
+}
+
+struct Dhcpv6Client {
+    mac_addr: [u8; 6],
+    state: Dhcpv6State,
+    exchange: Option<Exchange>,
+    server_id: Vec<u8>,
+    advertise: Option<Dhcpv6Reply>, // Solicit中に受け取った一番よいAdvertise
+    requested: Vec<u128>,           // Requestで要求するアドレス
+    addrs: Vec<u128>,
+    prefixes: Vec<DelegatedPrefix>,
+    t1_at: Option<Instant>,
+    t2_at: Option<Instant>,
+    valid_until: Option<Instant>,
+    refresh_at: Option<Instant>,
+    dns_servers: Vec<u128>,
+    domain_list: Vec<String>,
+}
+
+static DHCPV6_CONFIG: Mutex<Dhcpv6Config> = Mutex::new(Dhcpv6Config {
+    enabled: true,
+    request_prefix: false,
+});
+static DHCPV6_CLIENT: Mutex<Dhcpv6Client> = Mutex::new(Dhcpv6Client {
+    mac_addr: [0; 6],
+    state: Dhcpv6State::Idle,
+    exchange: None,
+    server_id: Vec::new(),
+    advertise: None,
+    requested: Vec::new(),
+    addrs: Vec::new(),
+    prefixes: Vec::new(),
+    t1_at: None,
+    t2_at: None,
+    valid_until: None,
+    refresh_at: None,
+    dns_servers: Vec::new(),
+    domain_list: Vec::new(),
+});
+
+pub fn set_dhcpv6_config(config: Dhcpv6Config) {
+    *DHCPV6_CONFIG.lock().unwrap() = config;
+}
+
+pub fn get_dhcpv6_config() -> Dhcpv6Config {
+    *DHCPV6_CONFIG.lock().unwrap()
+}
+
+pub fn is_dhcpv6_client_running() -> bool {
+    DHCPV6_CLIENT.lock().unwrap().state != Dhcpv6State::Idle
+}
+
+pub fn get_dhcpv6_dns_servers() -> Vec<u128> {
+    DHCPV6_CLIENT.lock().unwrap().dns_servers.clone()
+}
+
+pub fn get_dhcpv6_domain_list() -> Vec<String> {
+    DHCPV6_CLIENT.lock().unwrap().domain_list.clone()
+}
+
+pub fn get_delegated_prefixes() -> Vec<DelegatedPrefix> {
+    DHCPV6_CLIENT.lock().unwrap().prefixes.clone()
+}
+
+// RTに加える -0.1〜+0.1 倍の揺らぎ
+// Solicitの初回は正の値だけにする (RFC 8415 18.2.1)
+fn rand_factor(rt: Duration, positive_only: bool) -> Duration {
+    let permille = (random_u64() % 101) as u32;
+    let delta = rt * permille / 1000;
+    if positive_only || random_u64().is_multiple_of(2) {
+        rt + delta
+    } else {
+        rt - delta
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    Duration::from_millis(random_u64() % (max.as_millis() as u64 + 1))
+}
+
+impl Exchange {
+    fn new(msg_type: u8, irt: Duration, mrt: Option<Duration>, mrc: Option<u32>) -> Self {
+        let now = Instant::now();
+        Exchange {
+            msg_type,
+            // Transaction IDは24bit
+            transaction_id: (random_u64() & 0xff_ffff) as u32,
+            started_at: now,
+            next_at: now,
+            rt: Duration::ZERO,
+            count: 0,
+            irt,
+            mrt,
+            mrc,
+            deadline: None,
+        }
+    }
+
+    // 最初の送信を遅らせる
+    fn with_initial_delay(mut self, max_delay: Duration) -> Self {
+        self.next_at += random_delay(max_delay);
+        self
+    }
+
+    fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    // 再送回数か再送期間の上限に達したか
+    fn is_exhausted(&self, now: Instant) -> bool {
+        self.mrc.is_some_and(|mrc| self.count >= mrc) || self.deadline.is_some_and(|d| now >= d)
+    }
+
+    // 送信したので次の再送時刻を決める
+    fn sent(&mut self, now: Instant) {
+        self.rt = if self.count == 0 {
+            rand_factor(self.irt, self.msg_type == DHCPV6_SOLICIT)
+        } else {
+            // 2*RTprev + RAND*RTprev
+            self.rt + rand_factor(self.rt, false)
+        };
+        if let Some(mrt) = self.mrt.filter(|mrt| self.rt > *mrt) {
+            self.rt = rand_factor(mrt, false);
+        }
+        self.count += 1;
+        self.next_at = now + self.rt;
+        if let Some(deadline) = self.deadline {
+            self.next_at = self.next_at.min(deadline);
+        }
+    }
+
+    // Elapsed Timeは1/100秒単位で、最初の送信は0 (RFC 8415 21.9)
+    fn elapsed_time(&self, now: Instant) -> u16 {
+        if self.count == 0 {
+            return 0;
+        }
+        ((now - self.started_at).as_millis() / 10).min(0xffff) as u16
+    }
+}
+
+impl Dhcpv6Client {
+    fn start(&mut self, state: Dhcpv6State, exchange: Exchange) {
+        println!("dhcpv6 {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.exchange = Some(exchange);
+    }
+
+    fn start_solicit(&mut self) {
+        self.advertise = None;
+        self.server_id.clear();
+        let exchange = Exchange::new(DHCPV6_SOLICIT, SOL_TIMEOUT, Some(SOL_MAX_RT), None)
+            .with_initial_delay(SOL_MAX_DELAY);
+        self.start(Dhcpv6State::Soliciting, exchange);
+    }
+
+    fn start_request(&mut self, server_id: Vec<u8>, requested: Vec<u128>) {
+        self.server_id = server_id;
+        self.requested = requested;
+        let exchange = Exchange::new(
+            DHCPV6_REQUEST,
+            REQ_TIMEOUT,
+            Some(REQ_MAX_RT),
+            Some(REQ_MAX_RC),
+        );
+        self.start(Dhcpv6State::Requesting, exchange);
+    }
+
+    fn start_information_request(&mut self) {
+        let exchange = Exchange::new(
+            DHCPV6_INFORMATION_REQUEST,
+            INF_TIMEOUT,
+            Some(INF_MAX_RT),
+            None,
+        )
+        .with_initial_delay(INF_MAX_DELAY);
+        self.start(Dhcpv6State::InformationRequesting, exchange);
+    }
+
+    // RAのフラグとリースの期限を見て次の動作を決める
+    fn update(&mut self, now: Instant) {
+        let params = get_ipv6_link_params();
+        match self.state {
+            Dhcpv6State::Idle | Dhcpv6State::Informed if params.managed => self.start_solicit(),
+            Dhcpv6State::Idle if params.other => self.start_information_request(),
+            Dhcpv6State::Informed if self.refresh_at.is_some_and(|at| now >= at) => {
+                self.start_information_request()
+            }
+            Dhcpv6State::Bound => {
+                // DADで重複が見つかったアドレスは返す (RFC 8415 18.2.8)
+                if self
+                    .addrs
+                    .iter()
+                    .any(|addr| get_ipv6_addr_state(*addr) == Some(Ipv6AddrState::Duplicate))
+                {
+                    let exchange =
+                        Exchange::new(DHCPV6_DECLINE, DEC_TIMEOUT, None, Some(DEC_MAX_RC));
+                    self.start(Dhcpv6State::Declining, exchange);
+                } else if self.valid_until.is_some_and(|at| now >= at) {
+                    self.start_solicit();
+                } else if self.t2_at.is_some_and(|at| now >= at) {
+                    self.start_rebind();
+                } else if self.t1_at.is_some_and(|at| now >= at) {
+                    let exchange = Exchange::new(DHCPV6_RENEW, REN_TIMEOUT, Some(REN_MAX_RT), None)
+                        .with_deadline(self.t2_at);
+                    self.start(Dhcpv6State::Renewing, exchange);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn start_rebind(&mut self) {
+        let exchange = Exchange::new(DHCPV6_REBIND, REB_TIMEOUT, Some(REB_MAX_RT), None)
+            .with_deadline(self.valid_until);
+        self.start(Dhcpv6State::Rebinding, exchange);
+    }
+
+    // 再送の上限に達した
+    fn exchange_failed(&mut self) {
+        println!("dhcpv6 {:?} timed out", self.state);
+        match self.state {
+            // Renewに応答がなければ他のサーバーにRebindする
+            Dhcpv6State::Renewing => self.start_rebind(),
+            Dhcpv6State::Rebinding => {
+                self.release_leases();
+                self.start_solicit();
+            }
+            _ => self.start_solicit(),
+        }
+    }
+
+    fn release_leases(&mut self) {
+        for addr in self.addrs.drain(..) {
+            remove_ipv6_addr(addr);
+        }
+        self.prefixes.clear();
+        self.t1_at = None;
+        self.t2_at = None;
+        self.valid_until = None;
+    }
+
+    // Replyで受け取ったリースを反映する (RFC 8415 18.2.10.1)
+    fn apply_reply(&mut self, reply: Dhcpv6Reply, now: Instant) {
+        for lease in &reply.addrs {
+            set_dhcpv6_addr(lease.addr, lease.preferred_lifetime, lease.valid_lifetime);
+            self.addrs.retain(|addr| *addr != lease.addr);
+            if lease.valid_lifetime != 0 {
+                self.addrs.push(lease.addr);
+            }
+        }
+        for lease in &reply.prefixes {
+            self.prefixes
+                .retain(|p| !(p.prefix == lease.addr && p.prefix_len == lease.prefix_len));
+            if lease.valid_lifetime != 0 {
+                self.prefixes.push(DelegatedPrefix {
+                    prefix: lease.addr,
+                    prefix_len: lease.prefix_len,
+                    preferred_until: lifetime_to_instant(now, lease.preferred_lifetime),
+                    valid_until: lifetime_to_instant(now, lease.valid_lifetime),
+                });
+            }
+        }
+
+        let leases = reply.addrs.iter().chain(reply.prefixes.iter());
+        let min_preferred = leases
+            .clone()
+            .map(|lease| lease.preferred_lifetime)
+            .min()
+            .unwrap_or(INFINITY);
+        let max_valid = leases
+            .map(|lease| lease.valid_lifetime)
+            .max()
+            .unwrap_or(INFINITY);
+        // T1/T2が0ならクライアントが決める、推奨期間の0.5倍と0.8倍 (RFC 8415 21.4)
+        let t1 = reply
+            .t1
+            .filter(|t1| *t1 != 0)
+            .unwrap_or(scale_lifetime(min_preferred, 5));
+        let t2 = reply
+            .t2
+            .filter(|t2| *t2 != 0)
+            .unwrap_or(scale_lifetime(min_preferred, 8));
+        self.t1_at = lifetime_to_instant(now, t1);
+        self.t2_at = lifetime_to_instant(now, t2);
+        self.valid_until = lifetime_to_instant(now, max_valid);
+        self.server_id = reply.server_id;
+        self.exchange = None;
+        self.state = Dhcpv6State::Bound;
+        println!("dhcpv6 bound, renew after {t1}s, rebind after {t2}s");
+    }
+}
+
+fn scale_lifetime(lifetime: u32, tenths: u32) -> u32 {
+    if lifetime == INFINITY {
+        return INFINITY;
+    }
+    (lifetime as u64 * tenths as u64 / 10) as u32
+}
+
+fn lifetime_to_instant(now: Instant, lifetime: u32) -> Option<Instant> {
+    if lifetime == INFINITY {
+        None
+    } else {
+        Some(now + Duration::from_secs(lifetime as u64))
+    }
+}
+
+// DHCPv6のタイマー処理
+// 送信すべきメッセージがあればUDPのペイロードを返す
+// 宛先は常に ALL_DHCP_RELAY_AGENTS_AND_SERVERS
+pub fn dhcpv6_timer(my_mac_addr: [u8; 6]) -> Option<Vec<u8>> {
+    if !get_dhcpv6_config().enabled {
+        return None;
+    }
+    // リンクローカルアドレスのDADが終わるまでは送れない
+    if !is_link_local(select_ipv6_src(ALL_DHCP_RELAY_AGENTS_AND_SERVERS)) {
+        return None;
+    }
+    let now = Instant::now();
+    let mut client = DHCPV6_CLIENT.lock().unwrap();
+    client.mac_addr = my_mac_addr;
+    client
+        .prefixes
+        .retain(|p| p.valid_until.is_none_or(|until| until > now));
+    client.update(now);
+
+    let exchange = client.exchange.as_ref()?;
+    if now < exchange.next_at {
+        return None;
+    }
+    // 最初のSolicitのRTが過ぎるまでにAdvertiseを受け取っていれば、その中から選ぶ
+    if client.state == Dhcpv6State::Soliciting {
+        if let Some(advertise) = client.advertise.take() {
+            let requested = advertise.addrs.iter().map(|lease| lease.addr).collect();
+            client.start_request(advertise.server_id, requested);
+        }
+    }
+    if client
+        .exchange
+        .as_ref()
+        .is_some_and(|exchange| exchange.is_exhausted(now))
+    {
+        client.exchange_failed();
+        return None;
+    }
+
+    let message = out_dhcpv6_message(&client, now);
+    client.exchange.as_mut()?.sent(now);
+    Some(message)
+}
+
+fn out_dhcpv6_message(client: &Dhcpv6Client, now: Instant) -> Vec<u8> {
+    let exchange = client.exchange.as_ref().unwrap();
+    let config = get_dhcpv6_config();
+    let mut buf = Vec::new();
+    buf.put_u32((exchange.msg_type as u32) << 24 | exchange.transaction_id);
+
+    put_option(&mut buf, OPTION_CLIENTID, &client_duid(client.mac_addr));
+    // Request、Renew、Declineは相手のサーバーを指定する
+    if matches!(
+        exchange.msg_type,
+        DHCPV6_REQUEST | DHCPV6_RENEW | DHCPV6_DECLINE
+    ) {
+        put_option(&mut buf, OPTION_SERVERID, &client.server_id);
+    }
+    put_option(
+        &mut buf,
+        OPTION_ELAPSED_TIME,
+        &exchange.elapsed_time(now).to_be_bytes(),
+    );
+
+    let mut oro = vec![];
+    oro.put_u16(OPTION_DNS_SERVERS);
+    oro.put_u16(OPTION_DOMAIN_LIST);
+    if exchange.msg_type == DHCPV6_INFORMATION_REQUEST {
+        oro.put_u16(OPTION_INFORMATION_REFRESH_TIME);
+    }
+    if exchange.msg_type != DHCPV6_DECLINE {
+        put_option(&mut buf, OPTION_ORO, &oro);
+    }
+
+    if exchange.msg_type == DHCPV6_INFORMATION_REQUEST {
+        return buf;
+    }
+    let iaid = iaid(client.mac_addr);
+    let declined: Vec<u128> = client
+        .addrs
+        .iter()
+        .copied()
+        .filter(|addr| get_ipv6_addr_state(*addr) == Some(Ipv6AddrState::Duplicate))
+        .collect();
+    // IA_NA: IAID、T1、T2の後ろに保持しているアドレス
+    let mut ia_na = vec![];
+    ia_na.put_u32(iaid);
+    ia_na.put_u32(0);
+    ia_na.put_u32(0);
+    let addrs = match exchange.msg_type {
+        DHCPV6_SOLICIT => vec![],
+        DHCPV6_DECLINE => declined,
+        // Requestでは選んだAdvertiseのアドレスを要求する
+        DHCPV6_REQUEST => client.requested.clone(),
+        _ => client.addrs.clone(),
+    };
+    for addr in addrs {
+        let mut iaaddr = vec![];
+        iaaddr.put_u128(addr);
+        iaaddr.put_u32(0);
+        iaaddr.put_u32(0);
+        put_option(&mut ia_na, OPTION_IAADDR, &iaaddr);
+    }
+    put_option(&mut buf, OPTION_IA_NA, &ia_na);
+
+    if config.request_prefix && exchange.msg_type != DHCPV6_DECLINE {
+        let mut ia_pd = vec![];
+        ia_pd.put_u32(iaid);
+        ia_pd.put_u32(0);
+        ia_pd.put_u32(0);
+        for prefix in &client.prefixes {
+            let mut iaprefix = vec![];
+            iaprefix.put_u32(0);
+            iaprefix.put_u32(0);
+            iaprefix.put_u8(prefix.prefix_len);
+            iaprefix.put_u128(prefix.prefix);
+            put_option(&mut ia_pd, OPTION_IAPREFIX, &iaprefix);
+        }
+        put_option(&mut buf, OPTION_IA_PD, &ia_pd);
+    }
+    buf
+}
+
+fn put_option(buf: &mut Vec<u8>, code: u16, data: &[u8]) {
+    buf.put_u16(code);
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+}
+
+fn client_duid(my_mac_addr: [u8; 6]) -> Vec<u8> {
+    let mut duid = vec![];
+    duid.put_u16(DUID_LL);
+    duid.put_u16(HARDWARE_TYPE_ETHERNET);
+    duid.put_slice(&my_mac_addr);
+    duid
+}
+
+// IAIDはインターフェースごとに一意であればよいのでMACアドレスの下位32bitを使う
+fn iaid(my_mac_addr: [u8; 6]) -> u32 {
+    to_u32(&my_mac_addr[2..])
+}
+
+// オプションを (コード, データ) に分ける
+fn parse_options(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut options = vec![];
+    while buf.len() >= 4 {
+        let code = to_u16(&buf[0..]);
+        let len = to_u16(&buf[2..]) as usize;
+        if buf.len() < 4 + len {
+            break;
+        }
+        options.push((code, &buf[4..4 + len]));
+        buf = &buf[4 + len..];
+    }
+    options
+}
+
+fn status_code(data: &[u8]) -> u16 {
+    if data.len() < 2 {
+        return STATUS_SUCCESS;
+    }
+    to_u16(data)
+}
+
+// DNSのドメイン名の形式 (RFC 1035 3.1) のリストを文字列にする
+fn parse_domain_list(mut data: &[u8]) -> Vec<String> {
+    let mut domains = vec![];
+    let mut labels: Vec<String> = vec![];
+    while let Some((&len, rest)) = data.split_first() {
+        let len = len as usize;
+        if len == 0 {
+            if !labels.is_empty() {
+                domains.push(labels.join("."));
+            }
+            labels.clear();
+            data = rest;
+            continue;
+        }
+        if rest.len() < len {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(&rest[..len]).into_owned());
+        data = &rest[len..];
+    }
+    domains
+}
+
+// IA_NA、IA_PDの中身を読む
+// 戻り値は (T1, T2, リース, NoBindingか)
+fn parse_ia(data: &[u8], prefix: bool) -> Option<(u32, u32, Vec<IaLease>, bool)> {
+    if data.len() < 12 {
+        return None;
+    }
+    let t1 = to_u32(&data[4..]);
+    let t2 = to_u32(&data[8..]);
+    let mut leases = vec![];
+    let mut no_binding = false;
+    for (code, option) in parse_options(&data[12..]) {
+        match code {
+            OPTION_IAADDR if !prefix && option.len() >= 24 => {
+                let status = parse_options(&option[24..])
+                    .iter()
+                    .find(|(code, _)| *code == OPTION_STATUS_CODE)
+                    .map_or(STATUS_SUCCESS, |(_, data)| status_code(data));
+                if status == STATUS_SUCCESS {
+                    leases.push(IaLease {
+                        addr: u128::from_be_bytes(option[0..16].try_into().unwrap()),
+                        prefix_len: 128,
+                        preferred_lifetime: to_u32(&option[16..]),
+                        valid_lifetime: to_u32(&option[20..]),
+                    });
+                }
+            }
+            OPTION_IAPREFIX if prefix && option.len() >= 25 => {
+                leases.push(IaLease {
+                    addr: u128::from_be_bytes(option[9..25].try_into().unwrap()),
+                    prefix_len: option[8],
+                    preferred_lifetime: to_u32(&option[0..]),
+                    valid_lifetime: to_u32(&option[4..]),
+                });
+            }
+            OPTION_STATUS_CODE => no_binding |= status_code(option) == STATUS_NO_BINDING,
+            _ => {}
+        }
+    }
+    // 推奨期間が有効期間より長いものは無視する (RFC 8415 21.6)
+    leases.retain(|lease| lease.preferred_lifetime <= lease.valid_lifetime);
+    Some((t1, t2, leases, no_binding))
+}
+
+fn parse_reply(options: &[(u16, &[u8])], my_iaid: u32) -> Dhcpv6Reply {
+    let mut reply = Dhcpv6Reply::default();
+    for (code, data) in options {
+        match *code {
+            OPTION_SERVERID => reply.server_id = data.to_vec(),
+            OPTION_PREFERENCE if !data.is_empty() => reply.preference = data[0],
+            OPTION_STATUS_CODE => reply.status = status_code(data),
+            OPTION_IA_NA | OPTION_IA_PD if data.len() >= 4 && to_u32(data) == my_iaid => {
+                let prefix = *code == OPTION_IA_PD;
+                let Some((t1, t2, leases, no_binding)) = parse_ia(data, prefix) else {
+                    continue;
+                };
+                // T1がT2より大きいIAは無視する (RFC 8415 21.4)
+                if t1 > t2 && t2 != 0 {
+                    continue;
+                }
+                reply.t1 = Some(reply.t1.map_or(t1, |t| t.min(t1)));
+                reply.t2 = Some(reply.t2.map_or(t2, |t| t.min(t2)));
+                reply.no_binding |= no_binding;
+                if prefix {
+                    reply.prefixes.extend(leases);
+                } else {
+                    reply.addrs.extend(leases);
+                }
+            }
+            OPTION_DNS_SERVERS => {
+                reply.dns_servers = data
+                    .chunks_exact(16)
+                    .map(|chunk| u128::from_be_bytes(chunk.try_into().unwrap()))
+                    .collect();
+            }
+            OPTION_DOMAIN_LIST => reply.domain_list = parse_domain_list(data),
+            OPTION_INFORMATION_REFRESH_TIME if data.len() >= 4 => {
+                reply.refresh_time = Some(to_u32(data));
+            }
+            _ => {}
+        }
+    }
+    reply
+}
+
+// サーバーからのメッセージを処理する
+// 送信が必要になったものは次のタイマー処理で送る
+pub fn read_dhcpv6_packet(src_addr: u128, packet: &[u8]) {
+    if packet.len() < 4 {
+        return;
+    }
+    let msg_type = packet[0];
+    let transaction_id = to_u32(packet) & 0xff_ffff;
+    let options = parse_options(&packet[4..]);
+
+    let mut client = DHCPV6_CLIENT.lock().unwrap();
+    let Some(exchange) = client.exchange.as_ref() else {
+        return;
+    };
+    if exchange.transaction_id != transaction_id || exchange.count == 0 {
+        return;
+    }
+    // 自分宛てでなければ捨てる
+    let my_duid = client_duid(client.mac_addr);
+    if !options
+        .iter()
+        .any(|(code, duid)| *code == OPTION_CLIENTID && *duid == my_duid.as_slice())
+    {
+        return;
+    }
+    let reply = parse_reply(&options, iaid(client.mac_addr));
+    if reply.server_id.is_empty() {
+        return;
+    }
+    println!("dhcpv6 message {msg_type} from {src_addr:x}");
+    let now = Instant::now();
+    let request_prefix = get_dhcpv6_config().request_prefix;
+
+    match (client.state, msg_type) {
+        (Dhcpv6State::Soliciting, DHCPV6_ADVERTISE) => {
+            // アドレスもプレフィックスも提示されないAdvertiseは無視する (RFC 8415 18.2.9)
+            let offered = !reply.addrs.is_empty() || (request_prefix && !reply.prefixes.is_empty());
+            if reply.status != STATUS_SUCCESS || !offered {
+                return;
+            }
+            let preference = reply.preference;
+            if client
+                .advertise
+                .as_ref()
+                .is_some_and(|best| best.preference >= preference)
+            {
+                return;
+            }
+            client.advertise = Some(reply);
+            // Preference 255ならすぐにRequestを送る
+            if preference == 255 || client.exchange.as_ref().is_some_and(|ex| ex.count > 1) {
+                if let Some(exchange) = client.exchange.as_mut() {
+                    exchange.next_at = now;
+                }
+            }
+        }
+        (
+            Dhcpv6State::Requesting | Dhcpv6State::Renewing | Dhcpv6State::Rebinding,
+            DHCPV6_REPLY,
+        ) => {
+            if reply.status != STATUS_SUCCESS {
+                println!("dhcpv6 reply status {}", reply.status);
+                client.start_solicit();
+                return;
+            }
+            // Renew/Rebindでサーバーがバインディングを知らなければRequestからやり直す
+            if reply.no_binding {
+                let requested = client.addrs.clone();
+                client.start_request(reply.server_id, requested);
+                return;
+            }
+            set_information(&mut client, &reply);
+            client.apply_reply(reply, now);
+        }
+        (Dhcpv6State::Declining, DHCPV6_REPLY) => {
+            client.release_leases();
+            client.start_solicit();
+        }
+        (Dhcpv6State::InformationRequesting, DHCPV6_REPLY) => {
+            set_information(&mut client, &reply);
+            let refresh = reply.refresh_time.unwrap_or(IRT_DEFAULT).max(IRT_MINIMUM);
+            client.refresh_at = lifetime_to_instant(now, refresh);
+            client.exchange = None;
+            client.state = Dhcpv6State::Informed;
+            println!("dhcpv6 informed, refresh after {refresh}s");
+        }
+        _ => {}
+    }
+}
+
+fn set_information(client: &mut Dhcpv6Client, reply: &Dhcpv6Reply) {
+    if !reply.dns_servers.is_empty() {
+        client.dns_servers = reply.dns_servers.clone();
+    }
+    if !reply.domain_list.is_empty() {
+        client.domain_list = reply.domain_list.clone();
+    }
+}
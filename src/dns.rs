@@ -1,4 +1,5 @@
-use crate::util::{dump_packet, get_ipaddr};
+use crate::addrselect::select_source_addr;
+use crate::util::dump_packet;
 use bytes::{Buf, BufMut};
 use nix::NixPath;
 use std::net::IpAddr;
@@ -57,7 +58,8 @@ struct Answer {
     rd_data: Vec<u8>,
 }
 
-pub fn read_dns_packet(dns_packet: Vec<u8>) -> Vec<u8> {
+// 問い合わせ元に対して選んだ自分のアドレスを答える
+pub fn read_dns_packet(dns_packet: Vec<u8>, querier: IpAddr) -> Vec<u8> {
     let mut packet = &dns_packet[..];
     let id = packet.get_u16();
     let qr = packet.get_u8();
@@ -91,12 +93,12 @@ pub fn read_dns_packet(dns_packet: Vec<u8>) -> Vec<u8> {
             record_type: u16::from_be_bytes([packet[domain_length - 4], packet[domain_length - 3]]),
             class: u16::from_be_bytes([packet[domain_length - 2], packet[domain_length - 1]]),
         };
-        return dns_response(dns_header.id, question);
+        return dns_response(dns_header.id, question, querier);
     }
     vec![]
 }
 
-fn dns_response(id: u16, question: Question) -> Vec<u8> {
+fn dns_response(id: u16, question: Question, querier: IpAddr) -> Vec<u8> {
     let mut buf = Vec::new();
 
     let dns_header = DNSHeader {
@@ -125,7 +127,10 @@ fn dns_response(id: u16, question: Question) -> Vec<u8> {
     ra_byte += dns_header.z << 6 & 0x70;
     ra_byte += dns_header.rcode << 3 & 0xf;
 
-    let ip_addr = get_ipaddr(Box::from("host2-host1")).unwrap();
+    // DADやリンクローカルアドレスの設定が終わる前は答えるアドレスがないので返信しない
+    let Some(ip_addr) = select_source_addr(querier) else {
+        return vec![];
+    };
     let mut ip_addr_vec = vec![];
     match ip_addr {
         IpAddr::V4(ip) => {
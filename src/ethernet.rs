@@ -1,16 +1,66 @@
-use crate::arp::{read_arp_packet, search_arp_tables, search_arp_tables_v6};
-use crate::ipv4::read_ipv4_packet;
-use crate::ipv6::read_ipv6_packet;
-use crate::util::to_u16;
+use crate::addrconf::{
+    addrconf_timer, dad_timer, get_ipv6_addr_state, ipv6_next_hop, is_link_local, link_local_addr,
+    select_ipv6_src, Ipv6AddrState,
+};
+use crate::arp::{
+    arp_timer, out_arp_request, read_arp_packet, resolve_arp, search_arp_tables,
+    take_arp_ready_packets, ArpResolution,
+};
+use crate::dhcpv6::{
+    dhcpv6_timer, ALL_DHCP_RELAY_AGENTS_AND_SERVERS, DHCPV6_CLIENT_PORT, DHCPV6_SERVER_PORT,
+};
+use crate::forward::{is_forwarded_ipv6, out_ipv4_redirect, out_ipv6_redirect};
+use crate::icmp::{
+    out_icmp_error_reply, ICMP_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+    ICMP_MESSAGE_TYPE_TIME_EXCEEDED,
+};
+use crate::icmpv6::{
+    out_icmpv6_error_reply, out_mld_report, out_neighbor_solicitation, out_router_advertisement,
+    out_router_solicitation, ICMPV6_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+    ICMPV6_TYPE_TIME_EXCEEDED,
+};
+use crate::igmp::{igmp_timer, is_ipv4_multicast_mac_joined};
+use crate::ipv4::{
+    ipv4_multicast_mac_addr, out_ipv4_igmp_packet, out_ipv4_packet, out_ipv4_packet_with_ttl,
+    read_ipv4_packet, IP_PROTOCOL_NUMBER_UDP,
+};
+use crate::ipv4_addr::{is_ipv4_broadcast, is_ipv4_multicast, is_my_ipv4_addr, select_ipv4_src};
+use crate::ipv4_frag::{fragment_ipv4_packet, ipv4_reassembly_timer};
+use crate::ipv4_link_local::ipv4_link_local_timer;
+use crate::ipv6::{
+    ipv6_multicast_mac_addr, is_ipv6_multicast, out_ipv6_mld_packet, out_ipv6_packet,
+    read_ipv6_packet, solicited_node_addr, IPV6_ALL_NODES_ADDR, IPV6_ALL_ROUTERS_ADDR,
+    IP_PROTOCOL_NUMBER_ICMPV6,
+};
+use crate::ipv6_frag::{fragment_ipv6_packet, ipv6_reassembly_timer};
+use crate::loopback::{
+    is_local_ipv4_dst, is_local_ipv6_dst, send_loopback_packet, take_loopback_packets,
+    LOOPBACK_MAC_ADDR,
+};
+use crate::mld::{is_ipv6_multicast_mac_joined, mld_timer, IPV6_ALL_MLDV2_ROUTERS_ADDR};
+use crate::ndp::{neighbor_timer, resolve_neighbor, take_ready_packets, NeighborResolution};
+use crate::pmtu::{ipv4_egress_mtu, ipv4_path_mtu, ipv6_egress_mtu, ipv6_path_mtu, plpmtud_timer};
+use crate::radvd::radvd_timer;
+use crate::route::{lookup_ipv4_route, lookup_ipv6_route};
+use crate::socket::{
+    get_ipv4_config, interface_mac_addr, send_on_interface, take_multicast_datagrams,
+    take_udp_datagrams, DontFragmentPolicy,
+};
+use crate::tcp::{clamp_ipv4_tcp_mss, clamp_ipv6_tcp_mss};
+use crate::udp::{out_udp6_datagram, out_udp_datagram};
+use crate::util::{to_u16, to_u32};
 use bytes::BufMut;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::mpsc::SyncSender;
 
 pub const ETHERNET_TYPE_IPV4: u16 = 0x0800;
 const ETHERNET_TYPE_ARP: u16 = 0x0806;
 const ETHERNET_TYPE_IPV6: u16 = 0x86DD;
 
-// const ETHERNET_BRD_ADDR: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+// Ethernetのペイロードの最大長
+pub(crate) const ETHERNET_MTU: usize = 1500;
+
+const ETHERNET_BRD_ADDR: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
 
 pub struct EthernetHeader {
     pub dst_mac_addr: [u8; 6], // 宛先MACアドレス
@@ -18,71 +68,642 @@ pub struct EthernetHeader {
     pub ethernet_type: u16,    // Ethernetタイプ
 }
 
-pub fn read_ethernet(
-    packet: Vec<u8>,
-    tx: SyncSender<Vec<u8>>,
-    my_mac_addr: [u8; 6],
-    my_ip_addr: Option<IpAddr>,
-) {
+pub fn read_ethernet(packet: Vec<u8>, tx: SyncSender<Vec<u8>>, my_mac_addr: [u8; 6]) {
     let eth_header = EthernetHeader {
         dst_mac_addr: packet[0..6].try_into().unwrap(),
         src_mac_addr: packet[6..12].try_into().unwrap(),
         ethernet_type: to_u16(packet.get(12..14).unwrap()),
     };
 
-    if my_mac_addr != eth_header.dst_mac_addr {
-        tx.send(vec![]).unwrap();
+    if !is_accepted_mac_addr(my_mac_addr, eth_header.dst_mac_addr) {
+        return;
     }
 
-    let mut ipv4_addr: u32 = 0;
-    let mut ipv6_addr: u128 = 0;
-    if let Some(ip_addr) = my_ip_addr {
-        match ip_addr {
-            IpAddr::V4(ipv4) => {
-                ipv4_addr = ipv4.into();
-            }
-            IpAddr::V6(ipv6) => {
-                ipv6_addr = ipv6.into();
-            }
-        }
+    // ETH_P_ALLのソケットは自分が送信したパケットも受信するので無視する
+    // 自分のDAD用のNSを他のノードのものと誤認しないため
+    if my_mac_addr == eth_header.src_mac_addr {
+        return;
     }
 
     match eth_header.ethernet_type {
         ETHERNET_TYPE_IPV4 => {
             println!("receive ipv4 packet");
-            let mac = eth_header.dst_mac_addr;
             let (dest_ip_addr, packet) =
-                read_ipv4_packet(eth_header, packet[14..].to_owned(), ipv4_addr);
+                read_ipv4_packet(eth_header, packet[14..].to_owned(), my_mac_addr);
             if dest_ip_addr != 0 {
-                let dest_mac_addr = search_arp_tables(dest_ip_addr);
-                println!("out_ethernet dest_mac_addr {dest_mac_addr:?}");
-                out_ethernet(tx, mac, dest_mac_addr, packet, ETHERNET_TYPE_IPV4);
+                let (redirect_dest, redirect) = out_ipv4_redirect(&packet, my_mac_addr);
+                if redirect_dest != 0 {
+                    out_ipv4_ethernet(&tx, my_mac_addr, redirect_dest, redirect);
+                }
+                out_ipv4_ethernet(&tx, my_mac_addr, dest_ip_addr, packet);
             }
         }
         ETHERNET_TYPE_ARP => {
             println!("receive arp packet");
-            let (dest_ip_addr, packet) =
-                read_arp_packet(packet[14..].to_owned(), my_mac_addr, ipv4_addr);
+            let (dest_ip_addr, packet) = read_arp_packet(packet[14..].to_owned(), my_mac_addr);
             if dest_ip_addr != 0 {
-                let dest_mac_addr = search_arp_tables(dest_ip_addr);
+                let dest_mac_addr = if is_ipv4_broadcast(dest_ip_addr) {
+                    ETHERNET_BRD_ADDR
+                } else {
+                    search_arp_tables(dest_ip_addr)
+                };
                 println!("out_ethernet dest_mac_addr {dest_mac_addr:?}");
-                out_ethernet(tx, my_mac_addr, dest_mac_addr, packet, ETHERNET_TYPE_ARP);
+                out_ethernet(
+                    tx.clone(),
+                    my_mac_addr,
+                    dest_mac_addr,
+                    packet,
+                    ETHERNET_TYPE_ARP,
+                );
             }
+            // ARPの受信でアドレス解決が完了したパケットを送信
+            send_ready_packets(&tx, my_mac_addr);
         }
         ETHERNET_TYPE_IPV6 => {
             println!("receive ipv6 packet");
             let (dest_ipv6_addr, packet) =
-                read_ipv6_packet(eth_header, packet[14..].to_owned(), ipv6_addr);
-            let dest_mac_addr = search_arp_tables_v6(dest_ipv6_addr);
-            println!("out_ethernet dest_mac_addr {dest_mac_addr:?}");
+                read_ipv6_packet(eth_header, packet[14..].to_owned(), my_mac_addr);
             if dest_ipv6_addr != 0 {
-                out_ethernet(tx, my_mac_addr, dest_mac_addr, packet, ETHERNET_TYPE_IPV6);
+                let (redirect_dest, redirect) = out_ipv6_redirect(&packet, my_mac_addr);
+                if redirect_dest != 0 {
+                    out_ipv6_ethernet(&tx, my_mac_addr, redirect_dest, redirect);
+                }
+                out_ipv6_ethernet(&tx, my_mac_addr, dest_ipv6_addr, packet);
             };
+            // NAの受信でアドレス解決が完了したパケットを送信
+            send_ready_packets(&tx, my_mac_addr);
         }
         _ => {}
     }
 }
 
+// タイマー処理
+// 受信とは別の1つのスレッドから定期的に呼び出され、送信が必要なパケットをtxに流す
+// txは先頭のインターフェースから送信する
+// タイマーの状態は全インターフェースで共有しているので1回だけ進めて、
+// リンクごとに送るメッセージはそれぞれのインターフェースから送信する
+pub fn ethernet_timer(tx: SyncSender<Vec<u8>>, interfaces: Vec<(String, [u8; 6])>) {
+    let Some((_, my_mac_addr)) = interfaces.first().cloned() else {
+        return;
+    };
+    for target in arp_timer() {
+        out_arp_request_ethernet(&tx, my_mac_addr, target);
+    }
+    // リンクローカルアドレスのProbeとAnnouncementはブロードキャストする
+    for (if_name, mac_addr) in &interfaces {
+        for message in ipv4_link_local_timer(*mac_addr) {
+            out_ethernet_on(
+                &tx,
+                my_mac_addr,
+                Some(if_name),
+                ETHERNET_BRD_ADDR,
+                message,
+                ETHERNET_TYPE_ARP,
+            );
+        }
+    }
+    for probe in neighbor_timer() {
+        out_neighbor_probe(&tx, my_mac_addr, probe.target, probe.mac_addr);
+    }
+    for target in dad_timer() {
+        // DAD用のNSは未指定アドレスから、アドレスを使うリンクに送る
+        let (if_name, mac_addr) = ipv6_addr_interface(&interfaces, target);
+        let dest_ipv6_addr = solicited_node_addr(target);
+        let ns = out_neighbor_solicitation(0, dest_ipv6_addr, target, mac_addr);
+        let packet = out_ipv6_packet(0, dest_ipv6_addr, IP_PROTOCOL_NUMBER_ICMPV6, ns);
+        out_ipv6_multicast_on(&tx, my_mac_addr, &if_name, dest_ipv6_addr, packet);
+    }
+    if addrconf_timer() {
+        for (if_name, mac_addr) in &interfaces {
+            let src_addr = link_local_src(*mac_addr, IPV6_ALL_ROUTERS_ADDR);
+            let rs = out_router_solicitation(src_addr, IPV6_ALL_ROUTERS_ADDR, *mac_addr);
+            let packet = out_ipv6_packet(
+                src_addr,
+                IPV6_ALL_ROUTERS_ADDR,
+                IP_PROTOCOL_NUMBER_ICMPV6,
+                rs,
+            );
+            out_ipv6_multicast_on(&tx, my_mac_addr, if_name, IPV6_ALL_ROUTERS_ADDR, packet);
+        }
+    }
+    if let Some((config, router_lifetime)) = radvd_timer() {
+        for (if_name, mac_addr) in &interfaces {
+            // RAはリンクローカルアドレスから送るので、DADが終わるまでは送らない
+            let ra_src_addr = link_local_src(*mac_addr, IPV6_ALL_NODES_ADDR);
+            if !is_link_local(ra_src_addr) {
+                continue;
+            }
+            let ra = out_router_advertisement(
+                ra_src_addr,
+                IPV6_ALL_NODES_ADDR,
+                *mac_addr,
+                &config,
+                router_lifetime,
+            );
+            let packet = out_ipv6_packet(
+                ra_src_addr,
+                IPV6_ALL_NODES_ADDR,
+                IP_PROTOCOL_NUMBER_ICMPV6,
+                ra,
+            );
+            out_ipv6_multicast_on(&tx, my_mac_addr, if_name, IPV6_ALL_NODES_ADDR, packet);
+        }
+    }
+    // 参加しているグループは全インターフェースで共通なので、全てのリンクに報告する
+    let records = mld_timer();
+    if !records.is_empty() {
+        for (if_name, mac_addr) in &interfaces {
+            // DAD中でリンクローカルアドレスが使えなければ未指定アドレスから送る
+            let src_addr = link_local_src(*mac_addr, IPV6_ALL_MLDV2_ROUTERS_ADDR);
+            for report in out_mld_report(src_addr, IPV6_ALL_MLDV2_ROUTERS_ADDR, &records) {
+                let packet = out_ipv6_mld_packet(src_addr, IPV6_ALL_MLDV2_ROUTERS_ADDR, report);
+                out_ipv6_multicast_on(
+                    &tx,
+                    my_mac_addr,
+                    if_name,
+                    IPV6_ALL_MLDV2_ROUTERS_ADDR,
+                    packet,
+                );
+            }
+        }
+    }
+    for (dest_ip_addr, igmp) in igmp_timer() {
+        let src_addr = select_ipv4_src(dest_ip_addr);
+        let packet = out_ipv4_igmp_packet(src_addr, dest_ip_addr, igmp);
+        for (if_name, _) in &interfaces {
+            out_ethernet_on(
+                &tx,
+                my_mac_addr,
+                Some(if_name),
+                ipv4_multicast_mac_addr(dest_ip_addr),
+                packet.clone(),
+                ETHERNET_TYPE_IPV4,
+            );
+        }
+    }
+    for datagram in take_multicast_datagrams() {
+        let src_addr = select_ipv4_src(datagram.group);
+        let udp = out_udp_datagram(
+            src_addr,
+            datagram.group,
+            datagram.src_port,
+            datagram.dst_port,
+            datagram.payload,
+        );
+        let packet = out_ipv4_packet_with_ttl(
+            src_addr,
+            datagram.group,
+            IP_PROTOCOL_NUMBER_UDP,
+            datagram.ttl,
+            DontFragmentPolicy::Dont,
+            &[],
+            udp,
+        );
+        out_ipv4_ethernet(&tx, my_mac_addr, datagram.group, packet);
+    }
+    for datagram in take_udp_datagrams() {
+        match datagram.dst_addr {
+            IpAddr::V4(dst_addr) => {
+                let dst_addr = u32::from(dst_addr);
+                let src_addr = select_ipv4_src(dst_addr);
+                let udp = out_udp_datagram(
+                    src_addr,
+                    dst_addr,
+                    datagram.src_port,
+                    datagram.dst_port,
+                    datagram.payload,
+                );
+                let packet = out_ipv4_packet(src_addr, dst_addr, IP_PROTOCOL_NUMBER_UDP, udp);
+                out_ipv4_ethernet(&tx, my_mac_addr, dst_addr, packet);
+            }
+            IpAddr::V6(dst_addr) => {
+                let dst_addr = u128::from(dst_addr);
+                let src_addr = select_ipv6_src(dst_addr);
+                let udp = out_udp6_datagram(
+                    src_addr,
+                    dst_addr,
+                    datagram.src_port,
+                    datagram.dst_port,
+                    datagram.payload,
+                );
+                let packet = out_ipv6_packet(src_addr, dst_addr, IP_PROTOCOL_NUMBER_UDP, udp);
+                out_ipv6_ethernet(&tx, my_mac_addr, dst_addr, packet);
+            }
+        }
+    }
+    // PLPMTUDのプローブはフラグメントさせずに送る
+    for (src_port, dest_ip_addr, dst_port, payload) in plpmtud_timer() {
+        let src_addr = select_ipv4_src(dest_ip_addr);
+        let udp = out_udp_datagram(src_addr, dest_ip_addr, src_port, dst_port, payload);
+        let packet = out_ipv4_packet_with_ttl(
+            src_addr,
+            dest_ip_addr,
+            IP_PROTOCOL_NUMBER_UDP,
+            get_ipv4_config().default_ttl,
+            DontFragmentPolicy::Do,
+            &[],
+            udp,
+        );
+        out_ipv4_ethernet(&tx, my_mac_addr, dest_ip_addr, packet);
+    }
+    if let Some(message) = dhcpv6_timer(my_mac_addr) {
+        let src_addr = select_ipv6_src(ALL_DHCP_RELAY_AGENTS_AND_SERVERS);
+        let udp = out_udp6_datagram(
+            src_addr,
+            ALL_DHCP_RELAY_AGENTS_AND_SERVERS,
+            DHCPV6_CLIENT_PORT,
+            DHCPV6_SERVER_PORT,
+            message,
+        );
+        let packet = out_ipv6_packet(
+            src_addr,
+            ALL_DHCP_RELAY_AGENTS_AND_SERVERS,
+            IP_PROTOCOL_NUMBER_UDP,
+            udp,
+        );
+        out_ipv6_ethernet(&tx, my_mac_addr, ALL_DHCP_RELAY_AGENTS_AND_SERVERS, packet);
+    }
+    for first_fragment in ipv6_reassembly_timer() {
+        out_reassembly_time_exceeded(&tx, my_mac_addr, first_fragment);
+    }
+    for first_fragment in ipv4_reassembly_timer() {
+        let (dest_ip_addr, packet) = out_icmp_error_reply(
+            &first_fragment,
+            ICMP_MESSAGE_TYPE_TIME_EXCEEDED,
+            ICMP_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+            0,
+        );
+        if dest_ip_addr != 0 {
+            out_ipv4_ethernet(&tx, my_mac_addr, dest_ip_addr, packet);
+        }
+    }
+    send_ready_packets(&tx, my_mac_addr);
+    // ループバックに送られたパケットを受信する
+    // 自分宛ての返信は次のタイマー処理で受信する
+    for packet in take_loopback_packets() {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) => loopback_ipv4(&tx, my_mac_addr, packet),
+            Some(6) => loopback_ipv6(&tx, my_mac_addr, packet),
+            _ => {}
+        }
+    }
+}
+
+// アドレスを使うインターフェース
+// リンクローカルはMACアドレスから作ったもの、それ以外は経路のインターフェース
+// 分からなければ先頭のインターフェースとする
+fn ipv6_addr_interface(interfaces: &[(String, [u8; 6])], addr: u128) -> (String, [u8; 6]) {
+    let if_name = lookup_ipv6_route(addr).map(|route| route.interface);
+    interfaces
+        .iter()
+        .find(|(name, mac_addr)| {
+            if is_link_local(addr) {
+                link_local_addr(*mac_addr) == addr
+            } else {
+                if_name.as_deref() == Some(name.as_str())
+            }
+        })
+        .unwrap_or(&interfaces[0])
+        .clone()
+}
+
+// インターフェースのリンクローカルアドレス、DADが終わっていなければ宛先から選んだアドレス
+fn link_local_src(mac_addr: [u8; 6], dest_ipv6_addr: u128) -> u128 {
+    let addr = link_local_addr(mac_addr);
+    if get_ipv6_addr_state(addr) == Some(Ipv6AddrState::Preferred) {
+        addr
+    } else {
+        select_ipv6_src(dest_ipv6_addr)
+    }
+}
+
+// リンクスコープのマルチキャストを指定したインターフェースから送信する
+fn out_ipv6_multicast_on(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    if_name: &str,
+    dest_ipv6_addr: u128,
+    packet: Vec<u8>,
+) {
+    out_ethernet_on(
+        tx,
+        my_mac_addr,
+        Some(if_name),
+        ipv6_multicast_mac_addr(dest_ipv6_addr),
+        packet,
+        ETHERNET_TYPE_IPV6,
+    );
+}
+
+// 再構築がタイムアウトしたことを先頭フラグメントの送信元に知らせる (RFC 8200 4.5)
+fn out_reassembly_time_exceeded(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    first_fragment: Vec<u8>,
+) {
+    let (dest_ipv6_addr, packet) = out_icmpv6_error_reply(
+        &first_fragment,
+        ICMPV6_TYPE_TIME_EXCEEDED,
+        ICMPV6_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED,
+        0,
+        false,
+    );
+    if dest_ipv6_addr != 0 {
+        out_ipv6_ethernet(tx, my_mac_addr, dest_ipv6_addr, packet);
+    }
+}
+
+// 受信するフレームの宛先MACアドレスか
+// 自分宛てとブロードキャスト、マルチキャストは参加しているグループのものだけ受け取る
+fn is_accepted_mac_addr(my_mac_addr: [u8; 6], dst_mac_addr: [u8; 6]) -> bool {
+    if dst_mac_addr == my_mac_addr || dst_mac_addr == ETHERNET_BRD_ADDR {
+        return true;
+    }
+    if dst_mac_addr[0..2] == [0x33, 0x33] {
+        return is_ipv6_multicast_mac_joined(dst_mac_addr);
+    }
+    if dst_mac_addr[0..3] == [0x01, 0x00, 0x5e] {
+        return is_ipv4_multicast_mac_joined(dst_mac_addr);
+    }
+    false
+}
+
+// IPv4パケットを経路表で決めたネクストホップのMACアドレスを解決してから送信する
+// 未解決ならパケットをキューに積んでARPリクエストを送信する
+// Path MTUを超える場合はフラグメントに分割する
+// TCPのSYNはMSSをPath MTUに収まるように下げる
+fn out_ipv4_ethernet(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    dest_ip_addr: u32,
+    mut packet: Vec<u8>,
+) {
+    // フィルタで捨てたパケットは空になっている
+    if packet.is_empty() {
+        return;
+    }
+    if is_local_ipv4_dst(dest_ip_addr) {
+        send_loopback_packet(packet);
+        return;
+    }
+    clamp_ipv4_tcp_mss(&mut packet);
+    // 転送するパケットは送信するインターフェースのMTUで分割する
+    let src_addr = to_u32(&packet[12..16]);
+    let mtu = if is_my_ipv4_addr(src_addr) {
+        ipv4_path_mtu(dest_ip_addr) as usize
+    } else {
+        ipv4_egress_mtu(dest_ip_addr) as usize
+    };
+    let Some(fragments) = fragment_ipv4_packet(packet, mtu) else {
+        return;
+    };
+    for fragment in fragments {
+        out_ipv4_fragment(tx, my_mac_addr, dest_ip_addr, fragment);
+    }
+}
+
+fn out_ipv4_fragment(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    dest_ip_addr: u32,
+    packet: Vec<u8>,
+) {
+    let dest_mac_addr = if is_ipv4_broadcast(dest_ip_addr) {
+        ETHERNET_BRD_ADDR
+    } else if is_ipv4_multicast(dest_ip_addr) {
+        ipv4_multicast_mac_addr(dest_ip_addr)
+    } else {
+        let Some(route) = lookup_ipv4_route(dest_ip_addr) else {
+            println!("no route to {}", Ipv4Addr::from(dest_ip_addr));
+            return;
+        };
+        let next_hop = route.gateway.unwrap_or(dest_ip_addr);
+        match resolve_arp(next_hop, packet) {
+            ArpResolution::Resolved(dest_mac_addr, packet) => {
+                println!("out_ethernet dest_mac_addr {dest_mac_addr:?}");
+                out_ethernet_on(
+                    tx,
+                    my_mac_addr,
+                    Some(&route.interface),
+                    dest_mac_addr,
+                    packet,
+                    ETHERNET_TYPE_IPV4,
+                );
+            }
+            ArpResolution::Request => out_arp_request_ethernet(tx, my_mac_addr, next_hop),
+            ArpResolution::Queued => {}
+        }
+        return;
+    };
+    out_ethernet(
+        tx.clone(),
+        my_mac_addr,
+        dest_mac_addr,
+        packet,
+        ETHERNET_TYPE_IPV4,
+    );
+}
+
+// 自分宛てのパケットはEthernetに送らず、ループバックデバイスで受信したものとして処理する
+// 返信は通常の送信と同じく送り、自分宛てならまたループバックのキューに積まれる
+fn loopback_ipv4(tx: &SyncSender<Vec<u8>>, my_mac_addr: [u8; 6], packet: Vec<u8>) {
+    let eth_header = EthernetHeader {
+        dst_mac_addr: LOOPBACK_MAC_ADDR,
+        src_mac_addr: LOOPBACK_MAC_ADDR,
+        ethernet_type: ETHERNET_TYPE_IPV4,
+    };
+    let (dest_ip_addr, packet) = read_ipv4_packet(eth_header, packet, LOOPBACK_MAC_ADDR);
+    if dest_ip_addr != 0 {
+        out_ipv4_ethernet(tx, my_mac_addr, dest_ip_addr, packet);
+    }
+}
+
+fn loopback_ipv6(tx: &SyncSender<Vec<u8>>, my_mac_addr: [u8; 6], packet: Vec<u8>) {
+    let eth_header = EthernetHeader {
+        dst_mac_addr: LOOPBACK_MAC_ADDR,
+        src_mac_addr: LOOPBACK_MAC_ADDR,
+        ethernet_type: ETHERNET_TYPE_IPV6,
+    };
+    let (dest_ipv6_addr, packet) = read_ipv6_packet(eth_header, packet, LOOPBACK_MAC_ADDR);
+    if dest_ipv6_addr != 0 {
+        out_ipv6_ethernet(tx, my_mac_addr, dest_ipv6_addr, packet);
+    }
+}
+
+// ARPリクエストを宛先のリンクのインターフェースからブロードキャストで送信する
+fn out_arp_request_ethernet(tx: &SyncSender<Vec<u8>>, my_mac_addr: [u8; 6], target: u32) {
+    let if_name = lookup_ipv4_route(target).map(|route| route.interface);
+    let mac_addr = if_name
+        .as_deref()
+        .and_then(interface_mac_addr)
+        .unwrap_or(my_mac_addr);
+    let request = out_arp_request(mac_addr, select_ipv4_src(target), target);
+    out_ethernet_on(
+        tx,
+        my_mac_addr,
+        if_name.as_deref(),
+        ETHERNET_BRD_ADDR,
+        request,
+        ETHERNET_TYPE_ARP,
+    );
+}
+
+// IPv6パケットをネクストホップのMACアドレスを解決してから送信する
+// 未解決ならパケットをキューに積んでNSを送信する
+// Path MTUを超える場合はフラグメントに分割する
+// TCPのSYNはMSSをPath MTUに収まるように下げる
+fn out_ipv6_ethernet(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    dest_ipv6_addr: u128,
+    mut packet: Vec<u8>,
+) {
+    if packet.is_empty() {
+        return;
+    }
+    if is_local_ipv6_dst(dest_ipv6_addr) {
+        send_loopback_packet(packet);
+        return;
+    }
+    clamp_ipv6_tcp_mss(&mut packet);
+    // 転送するパケットはforward_ipv6_packetでリンクのMTUに収まることを確認済み
+    let mtu = if is_forwarded_ipv6(&packet) {
+        ipv6_egress_mtu(dest_ipv6_addr) as usize
+    } else {
+        ipv6_path_mtu(dest_ipv6_addr) as usize
+    };
+    for fragment in fragment_ipv6_packet(packet, mtu) {
+        out_ipv6_fragment(tx, my_mac_addr, dest_ipv6_addr, fragment);
+    }
+}
+
+fn out_ipv6_fragment(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    dest_ipv6_addr: u128,
+    packet: Vec<u8>,
+) {
+    if is_ipv6_multicast(dest_ipv6_addr) {
+        let dest_mac_addr = ipv6_multicast_mac_addr(dest_ipv6_addr);
+        out_ethernet(
+            tx.clone(),
+            my_mac_addr,
+            dest_mac_addr,
+            packet,
+            ETHERNET_TYPE_IPV6,
+        );
+        return;
+    }
+    // 経路表になければRAで学習したルーターとプレフィックスで受信したリンクに送る
+    let (next_hop, if_name) = match lookup_ipv6_route(dest_ipv6_addr) {
+        Some(route) => (
+            route.gateway.unwrap_or(dest_ipv6_addr),
+            Some(route.interface),
+        ),
+        None => (ipv6_next_hop(dest_ipv6_addr), None),
+    };
+    match resolve_neighbor(next_hop, packet) {
+        NeighborResolution::Resolved(dest_mac_addr, packet) => {
+            println!("out_ethernet dest_mac_addr {dest_mac_addr:?}");
+            out_ethernet_on(
+                tx,
+                my_mac_addr,
+                if_name.as_deref(),
+                dest_mac_addr,
+                packet,
+                ETHERNET_TYPE_IPV6,
+            );
+        }
+        NeighborResolution::Solicit => {
+            out_neighbor_probe(tx, my_mac_addr, next_hop, None);
+        }
+        NeighborResolution::Queued => {}
+    }
+}
+
+// NSを送信する
+// mac_addrがNoneならSolicited-Nodeマルチキャスト宛て
+fn out_neighbor_probe(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    target: u128,
+    mac_addr: Option<[u8; 6]>,
+) {
+    let if_name = lookup_ipv6_route(target).map(|route| route.interface);
+    let my_mac_addr_on_link = if_name
+        .as_deref()
+        .and_then(interface_mac_addr)
+        .unwrap_or(my_mac_addr);
+    let my_ipv6_addr = select_ipv6_src(target);
+    let (dest_ipv6_addr, dest_mac_addr) = match mac_addr {
+        Some(mac_addr) => (target, mac_addr),
+        None => {
+            let dest_ipv6_addr = solicited_node_addr(target);
+            (dest_ipv6_addr, ipv6_multicast_mac_addr(dest_ipv6_addr))
+        }
+    };
+    let ns = out_neighbor_solicitation(my_ipv6_addr, dest_ipv6_addr, target, my_mac_addr_on_link);
+    let packet = out_ipv6_packet(my_ipv6_addr, dest_ipv6_addr, IP_PROTOCOL_NUMBER_ICMPV6, ns);
+    out_ethernet_on(
+        tx,
+        my_mac_addr,
+        if_name.as_deref(),
+        dest_mac_addr,
+        packet,
+        ETHERNET_TYPE_IPV6,
+    );
+}
+
+// アドレス解決が完了したパケットを、宛先の経路のインターフェースから送信する
+fn send_ready_packets(tx: &SyncSender<Vec<u8>>, my_mac_addr: [u8; 6]) {
+    for (dest_mac_addr, packet) in take_arp_ready_packets() {
+        let if_name = lookup_ipv4_route(to_u32(&packet[16..20])).map(|route| route.interface);
+        out_ethernet_on(
+            tx,
+            my_mac_addr,
+            if_name.as_deref(),
+            dest_mac_addr,
+            packet,
+            ETHERNET_TYPE_IPV4,
+        );
+    }
+    for (dest_mac_addr, packet) in take_ready_packets() {
+        let dest_ipv6_addr = u128::from_be_bytes(packet[24..40].try_into().unwrap());
+        let if_name = lookup_ipv6_route(dest_ipv6_addr).map(|route| route.interface);
+        out_ethernet_on(
+            tx,
+            my_mac_addr,
+            if_name.as_deref(),
+            dest_mac_addr,
+            packet,
+            ETHERNET_TYPE_IPV6,
+        );
+    }
+}
+
+// 経路のインターフェースからフレームを送信する
+// 受信したインターフェースか経路がなければtxに流し、別のインターフェースなら直接送信する
+fn out_ethernet_on(
+    tx: &SyncSender<Vec<u8>>,
+    my_mac_addr: [u8; 6],
+    if_name: Option<&str>,
+    dest_mac_addr: [u8; 6],
+    packet: Vec<u8>,
+    ether_type: u16,
+) {
+    if packet.is_empty() {
+        return;
+    }
+    if let Some(if_name) = if_name {
+        if let Some(mac_addr) = interface_mac_addr(if_name).filter(|mac| *mac != my_mac_addr) {
+            let frame = ethernet_frame(mac_addr, dest_mac_addr, packet, ether_type);
+            send_on_interface(if_name, &frame);
+            return;
+        }
+    }
+    out_ethernet(tx.clone(), my_mac_addr, dest_mac_addr, packet, ether_type);
+}
+
 pub fn out_ethernet(
     tx: SyncSender<Vec<u8>>,
     src_mac_addr: [u8; 6],
@@ -90,12 +711,30 @@ pub fn out_ethernet(
     packet: Vec<u8>,
     ether_type: u16,
 ) {
+    // フィルタで捨てたパケットは空になっている
+    if packet.is_empty() {
+        return;
+    }
+    tx.send(ethernet_frame(
+        src_mac_addr,
+        dest_mac_addr,
+        packet,
+        ether_type,
+    ))
+    .unwrap();
+}
+
+fn ethernet_frame(
+    src_mac_addr: [u8; 6],
+    dest_mac_addr: [u8; 6],
+    packet: Vec<u8>,
+    ether_type: u16,
+) -> Vec<u8> {
     let mut buf = Vec::new();
     // Ethernetヘッダを生成
     buf.append(&mut dest_mac_addr.to_vec());
     buf.append(&mut src_mac_addr.to_vec());
     buf.put_u16(ether_type);
     buf.append(&mut packet.to_vec());
-
-    tx.send(buf).unwrap();
+    buf
 }
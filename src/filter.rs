@@ -0,0 +1,260 @@
+use crate::conntrack::{ct_discard, ipv6_upper_layer, CtState};
+use crate::ipv4::{IP_PROTOCOL_NUMBER_ICMP, IP_PROTOCOL_NUMBER_TCP, IP_PROTOCOL_NUMBER_UDP};
+use crate::ipv6::IP_PROTOCOL_NUMBER_ICMPV6;
+use crate::util::{to_u16, to_u32};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+
+// パケットを検査する位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterChain {
+    Input,   // 自分宛てに受信したパケット
+    Output,  // 自分が送信するパケット
+    Forward, // ルーターとして転送するパケット
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Accept,
+    Drop,
+    Reject, // ICMPで管理上の禁止を知らせて捨てる、送信するパケットはDropと同じ
+}
+
+// Noneの条件はすべてのパケットに一致する
+// アドレスは (アドレス, プレフィックス長) で、IPv4とIPv6のどちらかのパケットだけに一致する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterRule {
+    pub in_interface: Option<String>,
+    pub out_interface: Option<String>,
+    pub src: Option<(IpAddr, u8)>,
+    pub dst: Option<(IpAddr, u8)>,
+    pub protocol: Option<u8>,
+    pub src_port: Option<RangeInclusive<u16>>,
+    pub dst_port: Option<RangeInclusive<u16>>,
+    pub icmp_type: Option<u8>,
+    pub ct_states: Option<Vec<CtState>>, // conntrackで調べたフローの状態のどれか
+    pub action: FilterAction,
+}
+
+impl Default for FilterRule {
+    fn default() -> Self {
+        FilterRule {
+            in_interface: None,
+            out_interface: None,
+            src: None,
+            dst: None,
+            protocol: None,
+            src_port: None,
+            dst_port: None,
+            icmp_type: None,
+            ct_states: None,
+            action: FilterAction::Accept,
+        }
+    }
+}
+
+struct Chain {
+    rules: Vec<FilterRule>,
+    policy: FilterAction, // どのルールにも一致しなかったときの動作
+}
+
+const EMPTY_CHAIN: Chain = Chain {
+    rules: Vec::new(),
+    policy: FilterAction::Accept,
+};
+
+static FILTER_CHAINS: Mutex<[Chain; 3]> = Mutex::new([EMPTY_CHAIN; 3]);
+
+// ルールは先頭から順に調べ、最初に一致したルールの動作を使う
+pub fn add_filter_rule(chain: FilterChain, rule: FilterRule) {
+    println!("add filter rule {chain:?} {rule:?}");
+    FILTER_CHAINS.lock().unwrap()[chain as usize]
+        .rules
+        .push(rule);
+}
+
+// indexがルールの数より大きければ末尾に追加する
+pub fn insert_filter_rule(chain: FilterChain, index: usize, rule: FilterRule) {
+    println!("insert filter rule {chain:?} {index} {rule:?}");
+    let mut chains = FILTER_CHAINS.lock().unwrap();
+    let rules = &mut chains[chain as usize].rules;
+    rules.insert(index.min(rules.len()), rule);
+}
+
+pub fn remove_filter_rule(chain: FilterChain, index: usize) -> Option<FilterRule> {
+    let mut chains = FILTER_CHAINS.lock().unwrap();
+    let rules = &mut chains[chain as usize].rules;
+    (index < rules.len()).then(|| rules.remove(index))
+}
+
+pub fn flush_filter_rules(chain: FilterChain) {
+    FILTER_CHAINS.lock().unwrap()[chain as usize].rules.clear();
+}
+
+pub fn get_filter_rules(chain: FilterChain) -> Vec<FilterRule> {
+    FILTER_CHAINS.lock().unwrap()[chain as usize].rules.clone()
+}
+
+pub fn set_filter_policy(chain: FilterChain, policy: FilterAction) {
+    FILTER_CHAINS.lock().unwrap()[chain as usize].policy = policy;
+}
+
+pub fn get_filter_policy(chain: FilterChain) -> FilterAction {
+    FILTER_CHAINS.lock().unwrap()[chain as usize].policy
+}
+
+// ルールかAccept以外のポリシーが設定されているか
+pub(crate) fn is_filter_enabled() -> bool {
+    FILTER_CHAINS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|c| !c.rules.is_empty() || c.policy != FilterAction::Accept)
+}
+
+// ルールと照合するパケットの情報
+struct PacketInfo {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    protocol: u8,
+    ports: Option<(u16, u16)>,
+    icmp_type: Option<u8>,
+}
+
+impl PacketInfo {
+    // 先頭以外のフラグメントにはポート番号とICMPタイプがない
+    fn parse(packet: &[u8]) -> Option<PacketInfo> {
+        let (src_addr, dst_addr, protocol, l4) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let header_length = (packet[0] & 0x0f) as usize * 4;
+                let l4 = match to_u16(&packet[6..8]) & 0x1fff {
+                    0 => packet.get(header_length..).unwrap_or_default(),
+                    _ => &[],
+                };
+                (
+                    IpAddr::V4(Ipv4Addr::from(to_u32(&packet[12..16]))),
+                    IpAddr::V4(Ipv4Addr::from(to_u32(&packet[16..20]))),
+                    packet[9],
+                    l4,
+                )
+            }
+            6 if packet.len() >= 40 => {
+                let (protocol, l4) = match ipv6_upper_layer(packet) {
+                    Some((protocol, offset)) => (protocol, &packet[offset..]),
+                    None => (0, &[][..]),
+                };
+                (
+                    IpAddr::V6(Ipv6Addr::from(u128::from_be_bytes(
+                        packet[8..24].try_into().unwrap(),
+                    ))),
+                    IpAddr::V6(Ipv6Addr::from(u128::from_be_bytes(
+                        packet[24..40].try_into().unwrap(),
+                    ))),
+                    protocol,
+                    l4,
+                )
+            }
+            _ => return None,
+        };
+        let ports = match protocol {
+            IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP if l4.len() >= 4 => {
+                Some((to_u16(&l4[0..]), to_u16(&l4[2..])))
+            }
+            _ => None,
+        };
+        let icmp_type = match protocol {
+            IP_PROTOCOL_NUMBER_ICMP | IP_PROTOCOL_NUMBER_ICMPV6 => l4.first().copied(),
+            _ => None,
+        };
+        Some(PacketInfo {
+            src_addr,
+            dst_addr,
+            protocol,
+            ports,
+            icmp_type,
+        })
+    }
+}
+
+fn prefix_matches(addr: IpAddr, prefix: (IpAddr, u8)) -> bool {
+    match (addr, prefix.0) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - prefix.1.min(32) as u32)
+                .unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.1.min(128) as u32)
+                .unwrap_or(0);
+            u128::from(addr) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl FilterRule {
+    fn matches(
+        &self,
+        info: &PacketInfo,
+        state: CtState,
+        in_interface: Option<&str>,
+        out_interface: Option<&str>,
+    ) -> bool {
+        let port = |range: &Option<RangeInclusive<u16>>, port: Option<u16>| {
+            range
+                .as_ref()
+                .is_none_or(|r| port.is_some_and(|p| r.contains(&p)))
+        };
+        self.in_interface
+            .as_deref()
+            .is_none_or(|name| in_interface == Some(name))
+            && self
+                .out_interface
+                .as_deref()
+                .is_none_or(|name| out_interface == Some(name))
+            && self.src.is_none_or(|p| prefix_matches(info.src_addr, p))
+            && self.dst.is_none_or(|p| prefix_matches(info.dst_addr, p))
+            && self.protocol.is_none_or(|p| p == info.protocol)
+            && port(&self.src_port, info.ports.map(|p| p.0))
+            && port(&self.dst_port, info.ports.map(|p| p.1))
+            && self.icmp_type.is_none_or(|t| info.icmp_type == Some(t))
+            && self
+                .ct_states
+                .as_ref()
+                .is_none_or(|states| states.contains(&state))
+    }
+}
+
+// チェインのルールでパケットを検査する
+// stateは呼び出し元がct_trackで調べたconntrackの状態
+// 捨てたパケットで作られたフローは取り消して、返りのパケットをEstablishedとして扱わない
+pub(crate) fn filter_packet(
+    chain: FilterChain,
+    packet: &[u8],
+    state: CtState,
+    in_interface: Option<&str>,
+    out_interface: Option<&str>,
+) -> FilterAction {
+    let action = {
+        let chains = FILTER_CHAINS.lock().unwrap();
+        let chain = &chains[chain as usize];
+        match PacketInfo::parse(packet) {
+            Some(info) => chain
+                .rules
+                .iter()
+                .find(|r| r.matches(&info, state, in_interface, out_interface))
+                .map_or(chain.policy, |r| r.action),
+            None => chain.policy,
+        }
+    };
+    if action != FilterAction::Accept {
+        println!("filter {chain:?} {action:?} packet ({state:?})");
+        if state == CtState::New {
+            ct_discard(packet);
+        }
+    }
+    action
+}
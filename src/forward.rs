@@ -0,0 +1,326 @@
+use crate::addrconf::{get_ipv6_addr_state, is_link_local, link_local_addr};
+use crate::conntrack::ct_track;
+use crate::filter::{filter_packet, FilterAction, FilterChain};
+use crate::icmp::{
+    out_icmp_error_reply, ICMP_CODE_ADMIN_PROHIBITED, ICMP_CODE_FRAGMENTATION_NEEDED,
+    ICMP_CODE_NET_UNREACHABLE, ICMP_CODE_REDIRECT_HOST, ICMP_CODE_TTL_EXCEEDED_IN_TRANSIT,
+    ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE, ICMP_MESSAGE_TYPE_REDIRECT,
+    ICMP_MESSAGE_TYPE_TIME_EXCEEDED,
+};
+use crate::icmpv6::{
+    out_icmpv6_error_reply, out_redirect, take_error_token, ICMPV6_CODE_ADMIN_PROHIBITED,
+    ICMPV6_CODE_BEYOND_SCOPE, ICMPV6_CODE_HOP_LIMIT_EXCEEDED, ICMPV6_CODE_NO_ROUTE,
+    ICMPV6_TYPE_DESTINATION_UNREACHABLE, ICMPV6_TYPE_PACKET_TOO_BIG, ICMPV6_TYPE_TIME_EXCEEDED,
+};
+use crate::ipv4_addr::{is_ipv4_broadcast, is_ipv4_multicast, is_my_ipv4_addr};
+use crate::ipv4_frag::IPV4_FLAG_DONT_FRAGMENT;
+use crate::ipv6::{is_ipv6_multicast, out_ipv6_packet, IP_PROTOCOL_NUMBER_ICMPV6};
+use crate::nat::translate_outbound;
+use crate::ndp::search_neighbor_cache;
+use crate::route::{lookup_ipv4_route, lookup_ipv6_route};
+use crate::socket::{interface_mtu, interface_name};
+use crate::util::{to_u16, to_u32, update_checksum};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+// ルーターとして自分宛てでないパケットを転送するかの設定
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardingConfig {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub send_redirects: bool, // 受信したリンクに送り返すときに送信元にRedirectを送るか
+}
+
+static FORWARDING_CONFIG: Mutex<ForwardingConfig> = Mutex::new(ForwardingConfig {
+    ipv4: false,
+    ipv6: false,
+    send_redirects: true,
+});
+
+pub fn set_forwarding_config(config: ForwardingConfig) {
+    *FORWARDING_CONFIG.lock().unwrap() = config;
+}
+
+pub fn get_forwarding_config() -> ForwardingConfig {
+    *FORWARDING_CONFIG.lock().unwrap()
+}
+
+// 自分宛てでないIPv4パケットを転送するか
+// ブロードキャストとマルチキャストはルーティングしない
+pub(crate) fn should_forward_ipv4(dst_addr: u32) -> bool {
+    get_forwarding_config().ipv4 && !is_ipv4_broadcast(dst_addr) && !is_ipv4_multicast(dst_addr)
+}
+
+// 自分宛てでないIPv6パケットを転送するか
+// DAD中のアドレス宛てのものは転送せずに捨てる
+pub(crate) fn should_forward_ipv6(dst_addr: u128) -> bool {
+    get_forwarding_config().ipv6
+        && !is_ipv6_multicast(dst_addr)
+        && get_ipv6_addr_state(dst_addr).is_none()
+}
+
+// 0.0.0.0/8, 127.0.0.0/8, 169.254.0.0/16, 224.0.0.0/3は転送しない (RFC 1812 5.3.7, RFC 3927 2.7)
+fn is_forwardable_ipv4(addr: u32) -> bool {
+    let first = addr >> 24;
+    first != 0 && first != 127 && first < 224 && addr >> 16 != 0xa9fe && !is_ipv4_broadcast(addr)
+}
+
+// リンクローカルとマルチキャスト、未指定アドレスとループバックは転送しない
+fn is_forwardable_ipv6(addr: u128) -> bool {
+    addr > 1 && !is_link_local(addr) && !is_ipv6_multicast(addr)
+}
+
+// 自分宛てでないIPv4パケットを転送する (RFC 1812 5.2.1)
+// TTLを1減らしたパケットか、転送できない理由を知らせるICMPエラーを返す
+// in_interfaceは受信したインターフェース、フィルタのルールとの照合に使う
+pub(crate) fn forward_ipv4_packet(
+    mut packet: Vec<u8>,
+    in_interface: Option<&str>,
+) -> (u32, Vec<u8>) {
+    let src_addr = to_u32(&packet[12..16]);
+    let dst_addr = to_u32(&packet[16..20]);
+    if !is_forwardable_ipv4(src_addr) || !is_forwardable_ipv4(dst_addr) || is_my_ipv4_addr(src_addr)
+    {
+        println!(
+            "not forwarding {} -> {}",
+            Ipv4Addr::from(src_addr),
+            Ipv4Addr::from(dst_addr)
+        );
+        return (0, vec![]);
+    }
+    if packet[8] <= 1 {
+        return out_icmp_error_reply(
+            &packet,
+            ICMP_MESSAGE_TYPE_TIME_EXCEEDED,
+            ICMP_CODE_TTL_EXCEEDED_IN_TRANSIT,
+            0,
+        );
+    }
+    let Some(route) = lookup_ipv4_route(dst_addr) else {
+        return out_icmp_error_reply(
+            &packet,
+            ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE,
+            ICMP_CODE_NET_UNREACHABLE,
+            0,
+        );
+    };
+    let ct_state = ct_track(&packet);
+    match filter_packet(
+        FilterChain::Forward,
+        &packet,
+        ct_state,
+        in_interface,
+        Some(&route.interface),
+    ) {
+        FilterAction::Accept => {}
+        FilterAction::Drop => return (0, vec![]),
+        FilterAction::Reject => {
+            return out_icmp_error_reply(
+                &packet,
+                ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE,
+                ICMP_CODE_ADMIN_PROHIBITED,
+                0,
+            );
+        }
+    }
+    // DFが立っていれば分割せずに次のホップのMTUを知らせる (RFC 1191 4.)
+    let flags = to_u16(&packet[6..8]);
+    let mtu = interface_mtu(&route.interface);
+    if packet.len() > mtu as usize && flags & IPV4_FLAG_DONT_FRAGMENT != 0 {
+        return out_icmp_error_reply(
+            &packet,
+            ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE,
+            ICMP_CODE_FRAGMENTATION_NEEDED,
+            mtu,
+        );
+    }
+
+    // TTLはプロトコル番号と合わせた16bitとしてチェックサムを差分で更新する
+    let old = to_u16(&packet[8..10]);
+    packet[8] -= 1;
+    let new = to_u16(&packet[8..10]);
+    let checksum = update_checksum(to_u16(&packet[10..12]), old, new);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    if !translate_outbound(&mut packet, &route.interface) {
+        return (0, vec![]);
+    }
+    println!(
+        "forward {} -> {}",
+        Ipv4Addr::from(src_addr),
+        Ipv4Addr::from(dst_addr)
+    );
+    (dst_addr, packet)
+}
+
+// 受信したリンクに転送するパケットの送信元に、直接届けられる次のホップを知らせる (RFC 1812 5.2.7.2)
+// 送信元が同じサブネットにいなければ次のホップに直接送れないので送らない
+pub(crate) fn out_ipv4_redirect(packet: &[u8], in_mac_addr: [u8; 6]) -> (u32, Vec<u8>) {
+    let src_addr = to_u32(&packet[12..16]);
+    let dst_addr = to_u32(&packet[16..20]);
+    if !get_forwarding_config().send_redirects || is_my_ipv4_addr(src_addr) {
+        return (0, vec![]);
+    }
+    let (Some(route), Some(in_interface)) =
+        (lookup_ipv4_route(dst_addr), interface_name(in_mac_addr))
+    else {
+        return (0, vec![]);
+    };
+    let src_on_link = lookup_ipv4_route(src_addr)
+        .is_some_and(|r| r.gateway.is_none() && r.interface == in_interface);
+    if route.interface != in_interface || !src_on_link {
+        return (0, vec![]);
+    }
+    let next_hop = route.gateway.unwrap_or(dst_addr);
+    if next_hop == src_addr {
+        return (0, vec![]);
+    }
+    println!(
+        "redirect {} to {} via {}",
+        Ipv4Addr::from(src_addr),
+        Ipv4Addr::from(dst_addr),
+        Ipv4Addr::from(next_hop)
+    );
+    out_icmp_error_reply(
+        packet,
+        ICMP_MESSAGE_TYPE_REDIRECT,
+        ICMP_CODE_REDIRECT_HOST,
+        next_hop,
+    )
+}
+
+// 自分が送信元ではない、転送中のIPv6パケットか
+pub(crate) fn is_forwarded_ipv6(packet: &[u8]) -> bool {
+    let src_addr = u128::from_be_bytes(packet[8..24].try_into().unwrap());
+    src_addr != 0 && get_ipv6_addr_state(src_addr).is_none()
+}
+
+// 自分宛てでないIPv6パケットを転送する (RFC 8200 3.)
+// Hop Limitを1減らしたパケットか、転送できない理由を知らせるICMPv6エラーを返す
+// ルーターはフラグメントしないので、リンクのMTUを超えるならPacket Too Bigを返す
+pub(crate) fn forward_ipv6_packet(
+    mut packet: Vec<u8>,
+    in_interface: Option<&str>,
+) -> (u128, Vec<u8>) {
+    let src_addr = u128::from_be_bytes(packet[8..24].try_into().unwrap());
+    let dst_addr = u128::from_be_bytes(packet[24..40].try_into().unwrap());
+    // リンクローカルの送信元はリンクの外に出せない (RFC 4007 9.)
+    if is_link_local(src_addr) && is_forwardable_ipv6(dst_addr) {
+        return out_icmpv6_error_reply(
+            &packet,
+            ICMPV6_TYPE_DESTINATION_UNREACHABLE,
+            ICMPV6_CODE_BEYOND_SCOPE,
+            0,
+            false,
+        );
+    }
+    if !is_forwardable_ipv6(src_addr)
+        || !is_forwardable_ipv6(dst_addr)
+        || get_ipv6_addr_state(src_addr).is_some()
+    {
+        println!(
+            "not forwarding {} -> {}",
+            Ipv6Addr::from(src_addr),
+            Ipv6Addr::from(dst_addr)
+        );
+        return (0, vec![]);
+    }
+    if packet[7] <= 1 {
+        return out_icmpv6_error_reply(
+            &packet,
+            ICMPV6_TYPE_TIME_EXCEEDED,
+            ICMPV6_CODE_HOP_LIMIT_EXCEEDED,
+            0,
+            false,
+        );
+    }
+    let Some(route) = lookup_ipv6_route(dst_addr) else {
+        return out_icmpv6_error_reply(
+            &packet,
+            ICMPV6_TYPE_DESTINATION_UNREACHABLE,
+            ICMPV6_CODE_NO_ROUTE,
+            0,
+            false,
+        );
+    };
+    let ct_state = ct_track(&packet);
+    match filter_packet(
+        FilterChain::Forward,
+        &packet,
+        ct_state,
+        in_interface,
+        Some(&route.interface),
+    ) {
+        FilterAction::Accept => {}
+        FilterAction::Drop => return (0, vec![]),
+        FilterAction::Reject => {
+            return out_icmpv6_error_reply(
+                &packet,
+                ICMPV6_TYPE_DESTINATION_UNREACHABLE,
+                ICMPV6_CODE_ADMIN_PROHIBITED,
+                0,
+                false,
+            );
+        }
+    }
+    let mtu = interface_mtu(&route.interface);
+    if packet.len() > mtu as usize {
+        return out_icmpv6_error_reply(&packet, ICMPV6_TYPE_PACKET_TOO_BIG, 0, mtu, false);
+    }
+
+    // IPv6ヘッダにはチェックサムがないのでHop Limitを減らすだけでよい
+    packet[7] -= 1;
+    println!(
+        "forward {} -> {}",
+        Ipv6Addr::from(src_addr),
+        Ipv6Addr::from(dst_addr)
+    );
+    (dst_addr, packet)
+}
+
+// 受信したリンクに転送するパケットの送信元にRedirectを送る (RFC 4861 8.2)
+// Redirectは受信したインターフェースのリンクローカルアドレスから送る
+pub(crate) fn out_ipv6_redirect(packet: &[u8], in_mac_addr: [u8; 6]) -> (u128, Vec<u8>) {
+    if !get_forwarding_config().send_redirects || !is_forwarded_ipv6(packet) {
+        return (0, vec![]);
+    }
+    let src_addr = u128::from_be_bytes(packet[8..24].try_into().unwrap());
+    let dst_addr = u128::from_be_bytes(packet[24..40].try_into().unwrap());
+    let (Some(route), Some(in_interface)) =
+        (lookup_ipv6_route(dst_addr), interface_name(in_mac_addr))
+    else {
+        return (0, vec![]);
+    };
+    let src_on_link = lookup_ipv6_route(src_addr)
+        .is_some_and(|r| r.gateway.is_none() && r.interface == in_interface);
+    if route.interface != in_interface || !src_on_link {
+        return (0, vec![]);
+    }
+    let target = route.gateway.unwrap_or(dst_addr);
+    let my_link_local = link_local_addr(in_mac_addr);
+    if target == src_addr || get_ipv6_addr_state(my_link_local).is_none() {
+        return (0, vec![]);
+    }
+    // Redirectもエラーメッセージと同じレート制限にかける
+    if !take_error_token() {
+        return (0, vec![]);
+    }
+    println!(
+        "redirect {} to {} via {}",
+        Ipv6Addr::from(src_addr),
+        Ipv6Addr::from(dst_addr),
+        Ipv6Addr::from(target)
+    );
+    let redirect = out_redirect(
+        my_link_local,
+        src_addr,
+        target,
+        dst_addr,
+        search_neighbor_cache(target),
+        packet,
+    );
+    (
+        src_addr,
+        out_ipv6_packet(my_link_local, src_addr, IP_PROTOCOL_NUMBER_ICMPV6, redirect),
+    )
+}
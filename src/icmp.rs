@@ -1,7 +1,50 @@
+use crate::ipv4::{out_ipv4_packet, IP_PROTOCOL_NUMBER_ICMP};
+use crate::ipv4_addr::{
+    is_ipv4_broadcast, is_ipv4_multicast, is_my_ipv4_addr, select_ipv4_reply_src,
+};
+use crate::pmtu::{next_lower_ipv4_mtu, update_ipv4_path_mtu};
+use crate::util::{checksum, to_u16, to_u32};
 use bytes::{Buf, BufMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-const ICMP_MESSAGE_TYPE_ECHO_REPLY: u8 = 0;
-const ICMP_MESSAGE_TYPE_ECHO_REQUEST: u8 = 8;
+pub const ICMP_MESSAGE_TYPE_ECHO_REPLY: u8 = 0;
+pub const ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const ICMP_MESSAGE_TYPE_REDIRECT: u8 = 5;
+pub const ICMP_MESSAGE_TYPE_ECHO_REQUEST: u8 = 8;
+pub const ICMP_MESSAGE_TYPE_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM: u8 = 12;
+
+// Destination Unreachableのコード
+pub const ICMP_CODE_NET_UNREACHABLE: u8 = 0;
+pub const ICMP_CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const ICMP_CODE_SOURCE_ROUTE_FAILED: u8 = 5;
+pub const ICMP_CODE_ADMIN_PROHIBITED: u8 = 13;
+
+// Redirectのコード
+pub const ICMP_CODE_REDIRECT_HOST: u8 = 1;
+
+// Time Exceededのコード
+pub const ICMP_CODE_TTL_EXCEEDED_IN_TRANSIT: u8 = 0;
+pub const ICMP_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+// Parameter Problemのコード
+pub const ICMP_CODE_POINTER_INDICATES_ERROR: u8 = 0;
+
+// エラーメッセージを含むIPv4パケットの最大長 (RFC 1812 4.3.2.3)
+const ICMP_ERROR_MAX_LEN: usize = 576;
+
+// エラーメッセージのレート制限 (RFC 1812 4.3.2.8)
+// トークンバケットで、ICMP_ERROR_INTERVALごとに1つ、最大ICMP_ERROR_BURSTまで溜まる
+const ICMP_ERROR_BURST: u32 = 10;
+const ICMP_ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+struct ErrorRateLimit {
+    tokens: u32,
+    updated_at: Instant,
+}
+
+static ICMP_ERROR_RATE_LIMIT: Mutex<Option<ErrorRateLimit>> = Mutex::new(None);
 
 struct ICMPHeader {
     icmp_type: u8, // メッセージタイプ
@@ -16,6 +59,10 @@ struct ICMPEchoMessage<'a> {
 }
 
 pub fn read_icmp_packet(icmp_packet: Vec<u8>) -> Vec<u8> {
+    // ヘッダに満たないメッセージは捨てる
+    if icmp_packet.len() < 4 {
+        return vec![];
+    }
     let mut packet = &icmp_packet[..];
 
     let icmp_header = ICMPHeader {
@@ -27,6 +74,10 @@ pub fn read_icmp_packet(icmp_packet: Vec<u8>) -> Vec<u8> {
     match icmp_header.icmp_type {
         // echo要求だけ応答
         ICMP_MESSAGE_TYPE_ECHO_REQUEST => {
+            // IDとシーケンス番号に満たないecho要求は捨てる
+            if icmp_packet.len() < 8 {
+                return vec![];
+            }
             println!("icmp echo request");
             let echo = ICMPEchoMessage {
                 identity_number: packet.get_u16(),
@@ -35,6 +86,12 @@ pub fn read_icmp_packet(icmp_packet: Vec<u8>) -> Vec<u8> {
             };
             icmp_echo_reply(icmp_header, echo)
         }
+        ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE
+            if icmp_header.icmp_code == ICMP_CODE_FRAGMENTATION_NEEDED =>
+        {
+            read_fragmentation_needed(packet);
+            vec![]
+        }
         _ => {
             println!("other icmp message");
             vec![]
@@ -42,6 +99,30 @@ pub fn read_icmp_packet(icmp_packet: Vec<u8>) -> Vec<u8> {
     }
 }
 
+// Fragmentation Neededで通知されたNext-Hop MTUをPath MTUに反映する (RFC 1191 4.)
+fn read_fragmentation_needed(message: &[u8]) {
+    // 未使用の2byteとNext-Hop MTUの後ろに原因となったパケットのIPv4ヘッダが入っている
+    if message.len() < 4 + 20 {
+        return;
+    }
+    let next_hop_mtu = to_u16(&message[2..]) as u32;
+    let invoking = &message[4..];
+    let invoking_len = to_u16(&invoking[2..]) as u32;
+    let invoking_src = to_u32(&invoking[12..16]);
+    let invoking_dst = to_u32(&invoking[16..20]);
+    // 自分が送ったパケットに対するものでなければ無視する
+    if !is_my_ipv4_addr(invoking_src) {
+        return;
+    }
+    // MTUを通知しない古いルーターの場合は送ったパケットの長さから推測する (RFC 1191 5.)
+    let mtu = if next_hop_mtu == 0 || next_hop_mtu >= invoking_len {
+        next_lower_ipv4_mtu(invoking_len)
+    } else {
+        next_hop_mtu
+    };
+    update_ipv4_path_mtu(invoking_dst, mtu);
+}
+
 fn icmp_echo_reply(mut header: ICMPHeader, mes: ICMPEchoMessage) -> Vec<u8> {
     header.icmp_type = ICMP_MESSAGE_TYPE_ECHO_REPLY;
     // 本当はchecksumを計算するべきだがめんどくさいので800を足す
@@ -60,3 +141,87 @@ fn icmp_echo_reply(mut header: ICMPHeader, mes: ICMPEchoMessage) -> Vec<u8> {
 
     buf
 }
+
+// 受信したパケットに対するICMPエラーメッセージを生成する
+// 返してはいけないパケットなら空のパケットを返す (RFC 1122 3.2.2)
+pub fn out_icmp_error_reply(
+    invoking_packet: &[u8],
+    icmp_type: u8,
+    icmp_code: u8,
+    parameter: u32,
+) -> (u32, Vec<u8>) {
+    if invoking_packet.len() < 20 {
+        return (0, vec![]);
+    }
+    let header_length = (invoking_packet[0] & 0x0f) as usize * 4;
+    let frag_offset = to_u16(&invoking_packet[6..8]) & 0x1fff;
+    let src = to_u32(&invoking_packet[12..16]);
+    let dst = to_u32(&invoking_packet[16..20]);
+    // 送信元が一意に決まらないパケットには返さない
+    if src == 0 || src >> 24 == 127 || is_ipv4_broadcast(src) || is_ipv4_multicast(src) {
+        return (0, vec![]);
+    }
+    // ブロードキャストやマルチキャスト宛て、先頭以外のフラグメントには返さない
+    if is_ipv4_broadcast(dst) || is_ipv4_multicast(dst) || frag_offset != 0 {
+        return (0, vec![]);
+    }
+    // ICMPエラーメッセージにはエラーを返さない
+    if invoking_packet[9] == IP_PROTOCOL_NUMBER_ICMP
+        && invoking_packet.get(header_length).is_some_and(|t| {
+            matches!(
+                *t,
+                ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE
+                    | ICMP_MESSAGE_TYPE_REDIRECT
+                    | ICMP_MESSAGE_TYPE_TIME_EXCEEDED
+                    | ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM
+            )
+        })
+    {
+        return (0, vec![]);
+    }
+    if !take_error_token() {
+        println!("icmp error rate limited");
+        return (0, vec![]);
+    }
+
+    let src_addr = select_ipv4_reply_src(dst, src);
+    let icmp = out_icmp_error(icmp_type, icmp_code, parameter, invoking_packet);
+    (
+        src,
+        out_ipv4_packet(src_addr, src, IP_PROTOCOL_NUMBER_ICMP, icmp),
+    )
+}
+
+// レート制限のトークンを1つ取る
+fn take_error_token() -> bool {
+    let now = Instant::now();
+    let mut limit = ICMP_ERROR_RATE_LIMIT.lock().unwrap();
+    let limit = limit.get_or_insert(ErrorRateLimit {
+        tokens: ICMP_ERROR_BURST,
+        updated_at: now,
+    });
+    let refill = (now - limit.updated_at).as_millis() / ICMP_ERROR_INTERVAL.as_millis();
+    if refill > 0 {
+        limit.tokens = (limit.tokens as u128 + refill).min(ICMP_ERROR_BURST as u128) as u32;
+        limit.updated_at = now;
+    }
+    if limit.tokens == 0 {
+        return false;
+    }
+    limit.tokens -= 1;
+    true
+}
+
+fn out_icmp_error(icmp_type: u8, icmp_code: u8, parameter: u32, invoking_packet: &[u8]) -> Vec<u8> {
+    let max_len = ICMP_ERROR_MAX_LEN - 20 - 8;
+    let mut buf = Vec::new();
+    buf.put_u8(icmp_type);
+    buf.put_u8(icmp_code);
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(parameter);
+    buf.put_slice(&invoking_packet[..invoking_packet.len().min(max_len)]);
+
+    let checksum = checksum(&buf);
+    buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+    buf
+}
@@ -1,9 +1,86 @@
-use crate::ipv6::IP_PROTOCOL_NUMBER_ICMPV6;
-use crate::util::{checksum, dump_packet};
+use crate::addrconf::{
+    dad_failed, get_ipv6_addr_state, is_link_local, is_my_ipv6_addr, process_router_advertisement,
+    select_ipv6_reply_src, Ipv6AddrState, PrefixInformation, RouterAdvertisement,
+};
+use crate::ipv6::{
+    is_ipv6_multicast, out_ipv6_packet, solicited_node_addr, IPv6Header, IPV6_ALL_NODES_ADDR,
+    IP_PROTOCOL_NUMBER_ICMPV6,
+};
+use crate::mld::{read_mld_query, MulticastAddressRecord};
+use crate::ndp::{update_neighbor_from_advert, update_neighbor_from_solicit};
+use crate::pmtu::{update_ipv6_path_mtu, IPV6_MIN_MTU};
+use crate::radvd::{router_solicited, RaConfig};
+use crate::util::{checksum, to_u32};
 use bytes::{Buf, BufMut};
+use log::warn;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+pub const ICMPV6_TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+pub const ICMPV6_TYPE_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TYPE_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_TYPE_PARAMETER_PROBLEM: u8 = 4;
 const ICMPV6_TYPE_ECHO_REQUEST: u8 = 128;
 const ICMPV6_TYPE_ECHO_REPLY: u8 = 129;
+const ICMPV6_TYPE_MULTICAST_LISTENER_QUERY: u8 = 130;
+const ICMPV6_TYPE_ROUTER_SOLICITATION: u8 = 133;
+const ICMPV6_TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+const ICMPV6_TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const ICMPV6_TYPE_REDIRECT: u8 = 137;
+const ICMPV6_TYPE_MULTICAST_LISTENER_REPORT_V2: u8 = 143;
+
+// Destination Unreachableのコード
+pub const ICMPV6_CODE_NO_ROUTE: u8 = 0;
+pub const ICMPV6_CODE_ADMIN_PROHIBITED: u8 = 1;
+pub const ICMPV6_CODE_BEYOND_SCOPE: u8 = 2;
+pub const ICMPV6_CODE_PORT_UNREACHABLE: u8 = 4;
+
+// Time Exceededのコード
+pub const ICMPV6_CODE_HOP_LIMIT_EXCEEDED: u8 = 0;
+pub const ICMPV6_CODE_FRAGMENT_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+// Parameter Problemのコード
+pub const ICMPV6_CODE_ERRONEOUS_HEADER_FIELD: u8 = 0;
+pub const ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub const ICMPV6_CODE_UNRECOGNIZED_OPTION: u8 = 2;
+
+// エラーメッセージのレート制限 (RFC 4443 2.4 (f))
+// トークンバケットで、ICMPV6_ERROR_INTERVALごとに1つ、最大ICMPV6_ERROR_BURSTまで溜まる
+const ICMPV6_ERROR_BURST: u32 = 10;
+const ICMPV6_ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+// Neighbor Discoveryのオプション
+const ND_OPTION_SOURCE_LINK_LAYER_ADDR: u8 = 1;
+const ND_OPTION_TARGET_LINK_LAYER_ADDR: u8 = 2;
+const ND_OPTION_PREFIX_INFORMATION: u8 = 3;
+const ND_OPTION_REDIRECTED_HEADER: u8 = 4;
+const ND_OPTION_MTU: u8 = 5;
+const ND_OPTION_RDNSS: u8 = 25;
+const ND_OPTION_DNSSL: u8 = 31;
+
+// Router Advertisementのフラグ
+const RA_FLAG_MANAGED: u8 = 0x80;
+const RA_FLAG_OTHER: u8 = 0x40;
+
+// Prefix Informationのフラグ
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+// Neighbor Advertisementのフラグ
+const NA_FLAG_ROUTER: u32 = 0x80000000;
+const NA_FLAG_SOLICITED: u32 = 0x40000000;
+const NA_FLAG_OVERRIDE: u32 = 0x20000000;
+
+// Neighbor Discoveryのメッセージは必ずHop Limit 255で送受信する
+pub const ND_HOP_LIMIT: u8 = 255;
+
+struct ErrorRateLimit {
+    tokens: u32,
+    updated_at: Instant,
+}
+
+static ICMPV6_ERROR_RATE_LIMIT: Mutex<Option<ErrorRateLimit>> = Mutex::new(None);
 
 struct ICMPV6Message {
     icmp_type: u8, // メッセージタイプ
@@ -12,13 +89,24 @@ struct ICMPV6Message {
     message: Vec<u8>,
 }
 
+// Identifierとsequenceの後ろはそのまま返すデータ (RFC 4443 4.1)
 struct ICMPV6Echo {
     identify: u16,
     sequence: u16,
-    timestamp: u128,
     data: Vec<u8>,
 }
 
+struct NeighborSolicitation {
+    target: u128,
+    src_mac_addr: Option<[u8; 6]>,
+}
+
+struct NeighborAdvertisement {
+    flags: u32,
+    target: u128,
+    target_mac_addr: Option<[u8; 6]>,
+}
+
 struct IPV6DummyHeader {
     src_addr: u128,
     dst_addr: u128,
@@ -26,7 +114,16 @@ struct IPV6DummyHeader {
     protocol: u32,
 }
 
-pub fn read_icmpv6_packet(src_addr: u128, dst_addr: u128, icmp_packet: Vec<u8>) -> Vec<u8> {
+// 返信の送信元アドレス、宛先アドレスとICMPv6パケットを返す
+pub fn read_icmpv6_packet(
+    ipv6_header: &IPv6Header,
+    icmp_packet: Vec<u8>,
+    my_mac_addr: [u8; 6],
+) -> (u128, u128, Vec<u8>) {
+    // ヘッダに満たないメッセージは捨てる
+    if icmp_packet.len() < 4 {
+        return (0, 0, vec![]);
+    }
     let mut packet = &icmp_packet[..];
 
     let icmp_header = ICMPV6Message {
@@ -37,23 +134,609 @@ pub fn read_icmpv6_packet(src_addr: u128, dst_addr: u128, icmp_packet: Vec<u8>)
     };
 
     match icmp_header.icmp_type {
-        // echo要求だけ応答
         ICMPV6_TYPE_ECHO_REQUEST => {
             println!("icmpv6 echo request");
+            if icmp_packet.len() < 8 {
+                return (0, 0, vec![]);
+            }
             let mut message = &icmp_header.message[..];
             let echo = ICMPV6Echo {
                 identify: message.get_u16(),
                 sequence: message.get_u16(),
-                timestamp: message.get_u128(),
                 data: message.to_owned(),
             };
-            icmpv6_echo_reply(src_addr, dst_addr, echo)
+            let src_addr = select_ipv6_reply_src(ipv6_header.dst_addr, ipv6_header.src_addr);
+            (
+                src_addr,
+                ipv6_header.src_addr,
+                icmpv6_echo_reply(src_addr, ipv6_header.src_addr, echo),
+            )
+        }
+        ICMPV6_TYPE_NEIGHBOR_SOLICITATION => {
+            println!("icmpv6 neighbor solicitation");
+            if !is_valid_nd_message(ipv6_header, &icmp_header, 20) {
+                return (0, 0, vec![]);
+            }
+            let mut message = &icmp_header.message[4..];
+            let ns = NeighborSolicitation {
+                target: message.get_u128(),
+                src_mac_addr: find_link_layer_option(message, ND_OPTION_SOURCE_LINK_LAYER_ADDR),
+            };
+            read_neighbor_solicitation(ipv6_header, ns, my_mac_addr)
+        }
+        ICMPV6_TYPE_NEIGHBOR_ADVERTISEMENT => {
+            println!("icmpv6 neighbor advertisement");
+            if !is_valid_nd_message(ipv6_header, &icmp_header, 20) {
+                return (0, 0, vec![]);
+            }
+            let mut message = &icmp_header.message[..];
+            let na = NeighborAdvertisement {
+                flags: message.get_u32(),
+                target: message.get_u128(),
+                target_mac_addr: find_link_layer_option(message, ND_OPTION_TARGET_LINK_LAYER_ADDR),
+            };
+            read_neighbor_advertisement(ipv6_header, na);
+            (0, 0, vec![])
+        }
+        ICMPV6_TYPE_ROUTER_SOLICITATION => {
+            println!("icmpv6 router solicitation");
+            if !is_valid_nd_message(ipv6_header, &icmp_header, 4) {
+                return (0, 0, vec![]);
+            }
+            let src_mac_addr =
+                find_link_layer_option(&icmp_header.message[4..], ND_OPTION_SOURCE_LINK_LAYER_ADDR);
+            if ipv6_header.src_addr == 0 {
+                // 未指定アドレスからのRSにはSource Link-Layer Addressは付かない
+                if src_mac_addr.is_some() {
+                    return (0, 0, vec![]);
+                }
+            } else if let Some(mac_addr) = src_mac_addr {
+                update_neighbor_from_solicit(ipv6_header.src_addr, mac_addr);
+            }
+            router_solicited();
+            (0, 0, vec![])
+        }
+        ICMPV6_TYPE_ROUTER_ADVERTISEMENT => {
+            println!("icmpv6 router advertisement");
+            // ルーターのアドレスは必ずリンクローカル
+            if !is_valid_nd_message(ipv6_header, &icmp_header, 12)
+                || !is_link_local(ipv6_header.src_addr)
+            {
+                return (0, 0, vec![]);
+            }
+            read_router_advertisement(ipv6_header, &icmp_header.message, my_mac_addr);
+            (0, 0, vec![])
+        }
+        ICMPV6_TYPE_MULTICAST_LISTENER_QUERY => {
+            println!("icmpv6 multicast listener query");
+            read_mld_query(
+                ipv6_header.src_addr,
+                ipv6_header.hop_limit,
+                &icmp_header.message,
+            );
+            (0, 0, vec![])
+        }
+        ICMPV6_TYPE_PACKET_TOO_BIG => {
+            println!("icmpv6 packet too big");
+            read_packet_too_big(&icmp_header);
+            (0, 0, vec![])
         }
         _ => {
             println!("other icmp message");
-            vec![]
+            (0, 0, vec![])
+        }
+    }
+}
+
+// Packet Too Bigで通知されたMTUをPath MTUに反映する (RFC 8201 4.)
+fn read_packet_too_big(icmp_header: &ICMPV6Message) {
+    // MTUの後ろに原因となったパケットのIPv6ヘッダが入っている
+    if icmp_header.icmp_code != 0 || icmp_header.message.len() < 4 + 40 {
+        return;
+    }
+    let message = &icmp_header.message;
+    let mtu = to_u32(&message[0..]);
+    let invoking_src = u128::from_be_bytes(message[12..28].try_into().unwrap());
+    let invoking_dst = u128::from_be_bytes(message[28..44].try_into().unwrap());
+    // 自分が送ったパケットに対するものでなければ無視する
+    if !is_my_ipv6_addr(invoking_src) {
+        return;
+    }
+    update_ipv6_path_mtu(invoking_dst, mtu);
+}
+
+// 受信したパケットに対するICMPv6エラーを返す (RFC 4443 2.4)
+// 送ってはいけない場合とレート制限にかかった場合は送らない
+// to_multicastはマルチキャスト宛てのパケットにも返してよいエラーか
+pub fn out_icmpv6_error_reply(
+    invoking_packet: &[u8],
+    icmp_type: u8,
+    icmp_code: u8,
+    parameter: u32,
+    to_multicast: bool,
+) -> (u128, Vec<u8>) {
+    if invoking_packet.len() < 40 {
+        return (0, vec![]);
+    }
+    let src = u128::from_be_bytes(invoking_packet[8..24].try_into().unwrap());
+    let dst = u128::from_be_bytes(invoking_packet[24..40].try_into().unwrap());
+    // 送信元が一意に決まらないパケットには返さない
+    if src == 0 || is_ipv6_multicast(src) {
+        return (0, vec![]);
+    }
+    // マルチキャスト宛てにはPacket Too Bigと一部のParameter Problemしか返さない
+    if is_ipv6_multicast(dst) && !(to_multicast || icmp_type == ICMPV6_TYPE_PACKET_TOO_BIG) {
+        return (0, vec![]);
+    }
+    // ICMPv6エラーメッセージにはエラーを返さない
+    if invoking_packet[6] == IP_PROTOCOL_NUMBER_ICMPV6
+        && invoking_packet.get(40).is_some_and(|t| *t < 128)
+    {
+        return (0, vec![]);
+    }
+    if !take_error_token() {
+        println!("icmpv6 error rate limited");
+        return (0, vec![]);
+    }
+
+    let src_addr = select_ipv6_reply_src(dst, src);
+    let icmp = out_icmpv6_error(
+        src_addr,
+        src,
+        icmp_type,
+        icmp_code,
+        parameter,
+        invoking_packet,
+    );
+    (
+        src,
+        out_ipv6_packet(src_addr, src, IP_PROTOCOL_NUMBER_ICMPV6, icmp),
+    )
+}
+
+// レート制限のトークンを1つ取る
+pub(crate) fn take_error_token() -> bool {
+    let now = Instant::now();
+    let mut limit = ICMPV6_ERROR_RATE_LIMIT.lock().unwrap();
+    let limit = limit.get_or_insert(ErrorRateLimit {
+        tokens: ICMPV6_ERROR_BURST,
+        updated_at: now,
+    });
+    let refill = (now - limit.updated_at).as_millis() / ICMPV6_ERROR_INTERVAL.as_millis();
+    if refill > 0 {
+        limit.tokens = (limit.tokens as u128 + refill).min(ICMPV6_ERROR_BURST as u128) as u32;
+        limit.updated_at = now;
+    }
+    if limit.tokens == 0 {
+        return false;
+    }
+    limit.tokens -= 1;
+    true
+}
+
+// RFC 4861 7.1 メッセージの検証
+fn is_valid_nd_message(ipv6_header: &IPv6Header, icmp: &ICMPV6Message, min_len: usize) -> bool {
+    ipv6_header.hop_limit == ND_HOP_LIMIT
+        && icmp.icmp_code == 0
+        && icmp.message.len() >= min_len
+        && is_valid_nd_options(&icmp.message[min_len..])
+}
+
+// オプションの長さが0のものがあれば不正なメッセージ
+fn is_valid_nd_options(mut options: &[u8]) -> bool {
+    while options.len() >= 2 {
+        let length = options[1] as usize * 8;
+        if length == 0 || options.len() < length {
+            return false;
+        }
+        options = &options[length..];
+    }
+    options.is_empty()
+}
+
+// オプションをタイプとオプション全体のバイト列に分割する
+fn nd_options(mut options: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut result = vec![];
+    while options.len() >= 2 {
+        let length = options[1] as usize * 8;
+        if length == 0 || options.len() < length {
+            break;
+        }
+        result.push((options[0], &options[..length]));
+        options = &options[length..];
+    }
+    result
+}
+
+// オプションからリンク層アドレスを取り出す
+fn find_link_layer_option(options: &[u8], option_type: u8) -> Option<[u8; 6]> {
+    nd_options(options)
+        .into_iter()
+        .find(|(t, option)| *t == option_type && option.len() >= 8)
+        .map(|(_, option)| option[2..8].try_into().unwrap())
+}
+
+fn read_neighbor_solicitation(
+    ipv6_header: &IPv6Header,
+    ns: NeighborSolicitation,
+    my_mac_addr: [u8; 6],
+) -> (u128, u128, Vec<u8>) {
+    let from_unspecified = ipv6_header.src_addr == 0;
+    // 送信元が未指定アドレスなら宛先はSolicited-Nodeマルチキャストで、オプションは付かない
+    if is_ipv6_multicast(ns.target)
+        || (from_unspecified
+            && (ipv6_header.dst_addr != solicited_node_addr(ns.target)
+                || ns.src_mac_addr.is_some()))
+    {
+        return (0, 0, vec![]);
+    }
+
+    // DAD中のアドレスに対するNS (RFC 4862 5.4.3)
+    // 未指定アドレスからなら他のノードも同じアドレスでDAD中なので重複、それ以外は応答しない
+    if get_ipv6_addr_state(ns.target) == Some(Ipv6AddrState::Tentative) {
+        if from_unspecified {
+            dad_failed(ns.target);
+        }
+        return (0, 0, vec![]);
+    }
+
+    if !from_unspecified {
+        if let Some(mac_addr) = ns.src_mac_addr {
+            update_neighbor_from_solicit(ipv6_header.src_addr, mac_addr);
+        }
+    }
+
+    // 自分のアドレス宛てのNSにだけ応答する
+    if !is_my_ipv6_addr(ns.target) {
+        return (0, 0, vec![]);
+    }
+
+    if from_unspecified {
+        // DADへの応答は全ノードマルチキャストに送る
+        let packet = out_neighbor_advertisement(
+            ns.target,
+            IPV6_ALL_NODES_ADDR,
+            my_mac_addr,
+            NA_FLAG_OVERRIDE,
+        );
+        return (ns.target, IPV6_ALL_NODES_ADDR, packet);
+    }
+    let packet = out_neighbor_advertisement(
+        ns.target,
+        ipv6_header.src_addr,
+        my_mac_addr,
+        NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE,
+    );
+    (ns.target, ipv6_header.src_addr, packet)
+}
+
+fn read_neighbor_advertisement(ipv6_header: &IPv6Header, na: NeighborAdvertisement) {
+    let solicited = na.flags & NA_FLAG_SOLICITED != 0;
+    // マルチキャスト宛てのNAにSolicitedフラグが立っていてはいけない
+    if is_ipv6_multicast(na.target) || (solicited && is_ipv6_multicast(ipv6_header.dst_addr)) {
+        return;
+    }
+    // 自分のアドレスに対するNA (RFC 4862 5.4.4)
+    match get_ipv6_addr_state(na.target) {
+        Some(Ipv6AddrState::Tentative) => {
+            dad_failed(na.target);
+            return;
+        }
+        Some(_) => {
+            warn!("another node advertised my ipv6 address {:x}", na.target);
+            return;
+        }
+        None => {}
+    }
+    update_neighbor_from_advert(
+        na.target,
+        na.target_mac_addr,
+        na.flags & NA_FLAG_ROUTER != 0,
+        solicited,
+        na.flags & NA_FLAG_OVERRIDE != 0,
+    );
+}
+
+fn read_router_advertisement(ipv6_header: &IPv6Header, message: &[u8], my_mac_addr: [u8; 6]) {
+    let mut buf = message;
+    let cur_hop_limit = buf.get_u8();
+    let flags = buf.get_u8();
+    let router_lifetime = buf.get_u16();
+    // Reachable TimeとRetrans Timerは使わない
+    buf.advance(8);
+
+    let mut ra = RouterAdvertisement {
+        cur_hop_limit,
+        managed: flags & RA_FLAG_MANAGED != 0,
+        other: flags & RA_FLAG_OTHER != 0,
+        router_lifetime,
+        prefixes: vec![],
+        mtu: None,
+    };
+    for (option_type, mut option) in nd_options(buf) {
+        match option_type {
+            ND_OPTION_SOURCE_LINK_LAYER_ADDR if option.len() >= 8 => {
+                update_neighbor_from_solicit(
+                    ipv6_header.src_addr,
+                    option[2..8].try_into().unwrap(),
+                );
+            }
+            ND_OPTION_PREFIX_INFORMATION if option.len() == 32 => {
+                option.advance(2);
+                let prefix_len = option.get_u8();
+                let flags = option.get_u8();
+                let valid_lifetime = option.get_u32();
+                let preferred_lifetime = option.get_u32();
+                option.advance(4);
+                if prefix_len > 128 {
+                    continue;
+                }
+                ra.prefixes.push(PrefixInformation {
+                    prefix_len,
+                    on_link: flags & PREFIX_FLAG_ON_LINK != 0,
+                    autonomous: flags & PREFIX_FLAG_AUTONOMOUS != 0,
+                    valid_lifetime,
+                    preferred_lifetime,
+                    prefix: option.get_u128(),
+                });
+            }
+            ND_OPTION_MTU if option.len() == 8 => {
+                option.advance(4);
+                ra.mtu = Some(option.get_u32());
+            }
+            _ => {}
         }
     }
+    println!("router advertisement {ra:?}");
+    process_router_advertisement(ipv6_header.src_addr, ra, my_mac_addr);
+}
+
+// Router Solicitationを生成する
+pub fn out_router_solicitation(src_addr: u128, dst_addr: u128, my_mac_addr: [u8; 6]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_u8(ICMPV6_TYPE_ROUTER_SOLICITATION);
+    buf.put_u8(0x00); // code
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(0); // reserved
+    if src_addr != 0 {
+        buf.put_u8(ND_OPTION_SOURCE_LINK_LAYER_ADDR);
+        buf.put_u8(1);
+        buf.put_slice(&my_mac_addr);
+    }
+
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
+}
+
+// Router Advertisementを生成する
+pub fn out_router_advertisement(
+    src_addr: u128,
+    dst_addr: u128,
+    my_mac_addr: [u8; 6],
+    config: &RaConfig,
+    router_lifetime: u16,
+) -> Vec<u8> {
+    let mut flags = 0;
+    if config.managed {
+        flags |= RA_FLAG_MANAGED;
+    }
+    if config.other {
+        flags |= RA_FLAG_OTHER;
+    }
+
+    let mut buf = Vec::new();
+    buf.put_u8(ICMPV6_TYPE_ROUTER_ADVERTISEMENT);
+    buf.put_u8(0x00); // code
+    buf.put_u16(0x00); // checksum
+    buf.put_u8(config.cur_hop_limit);
+    buf.put_u8(flags);
+    buf.put_u16(router_lifetime);
+    buf.put_u32(0); // Reachable Time 未指定
+    buf.put_u32(0); // Retrans Timer 未指定
+
+    buf.put_u8(ND_OPTION_SOURCE_LINK_LAYER_ADDR);
+    buf.put_u8(1);
+    buf.put_slice(&my_mac_addr);
+
+    if let Some(mtu) = config.mtu {
+        buf.put_u8(ND_OPTION_MTU);
+        buf.put_u8(1);
+        buf.put_u16(0); // reserved
+        buf.put_u32(mtu);
+    }
+
+    for prefix in &config.prefixes {
+        let mut prefix_flags = 0;
+        if prefix.on_link {
+            prefix_flags |= PREFIX_FLAG_ON_LINK;
+        }
+        if prefix.autonomous {
+            prefix_flags |= PREFIX_FLAG_AUTONOMOUS;
+        }
+        buf.put_u8(ND_OPTION_PREFIX_INFORMATION);
+        buf.put_u8(4);
+        buf.put_u8(prefix.prefix_len);
+        buf.put_u8(prefix_flags);
+        buf.put_u32(prefix.valid_lifetime);
+        buf.put_u32(prefix.preferred_lifetime);
+        buf.put_u32(0); // reserved
+        buf.put_u128(prefix.prefix);
+    }
+
+    if !config.rdnss.is_empty() {
+        buf.put_u8(ND_OPTION_RDNSS);
+        buf.put_u8(1 + 2 * config.rdnss.len() as u8);
+        buf.put_u16(0); // reserved
+        buf.put_u32(config.rdnss_lifetime);
+        for addr in &config.rdnss {
+            buf.put_u128(*addr);
+        }
+    }
+
+    if !config.dnssl.is_empty() {
+        // ドメイン名はDNSと同じラベル形式で並べて8byte境界まで0で埋める
+        let mut names = Vec::new();
+        for domain in &config.dnssl {
+            for label in domain.split('.').filter(|label| !label.is_empty()) {
+                names.put_u8(label.len() as u8);
+                names.put_slice(label.as_bytes());
+            }
+            names.put_u8(0);
+        }
+        let length = (8 + names.len()).div_ceil(8);
+        names.resize(length * 8 - 8, 0);
+        buf.put_u8(ND_OPTION_DNSSL);
+        buf.put_u8(length as u8);
+        buf.put_u16(0); // reserved
+        buf.put_u32(config.dnssl_lifetime);
+        buf.put_slice(&names);
+    }
+
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
+}
+
+// Neighbor Solicitationを生成する
+// アドレス解決ではSolicited-Nodeマルチキャストに、到達性確認ではユニキャストで送る
+pub fn out_neighbor_solicitation(
+    src_addr: u128,
+    dst_addr: u128,
+    target: u128,
+    my_mac_addr: [u8; 6],
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_u8(ICMPV6_TYPE_NEIGHBOR_SOLICITATION);
+    buf.put_u8(0x00); // code
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(0); // reserved
+    buf.put_u128(target);
+    // 未指定アドレスから送る場合はSource Link-Layer Addressを付けない
+    if src_addr != 0 {
+        buf.put_u8(ND_OPTION_SOURCE_LINK_LAYER_ADDR);
+        buf.put_u8(1);
+        buf.put_slice(&my_mac_addr);
+    }
+
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
+}
+
+// Redirectを生成する (RFC 4861 4.5, 8.2)
+// targetがdestinationと同じならdestinationはオンリンク、違えばより良いルーター
+pub fn out_redirect(
+    src_addr: u128,
+    dst_addr: u128,
+    target: u128,
+    destination: u128,
+    target_mac_addr: Option<[u8; 6]>,
+    invoking_packet: &[u8],
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_u8(ICMPV6_TYPE_REDIRECT);
+    buf.put_u8(0x00); // code
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(0); // reserved
+    buf.put_u128(target);
+    buf.put_u128(destination);
+    if let Some(mac_addr) = target_mac_addr {
+        buf.put_u8(ND_OPTION_TARGET_LINK_LAYER_ADDR);
+        buf.put_u8(1);
+        buf.put_slice(&mac_addr);
+    }
+    // Redirected Headerは最小MTUに収まるだけ、8byte単位に切り詰めて付ける
+    let max_len = (IPV6_MIN_MTU as usize - 40 - buf.len() - 8) & !7;
+    let redirected = &invoking_packet[..invoking_packet.len().min(max_len) & !7];
+    buf.put_u8(ND_OPTION_REDIRECTED_HEADER);
+    buf.put_u8((redirected.len() / 8 + 1) as u8);
+    buf.put_slice(&[0; 6]); // reserved
+    buf.put_slice(redirected);
+
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
+}
+
+fn out_neighbor_advertisement(
+    target: u128,
+    dst_addr: u128,
+    my_mac_addr: [u8; 6],
+    flags: u32,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_u8(ICMPV6_TYPE_NEIGHBOR_ADVERTISEMENT);
+    buf.put_u8(0x00); // code
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(flags);
+    buf.put_u128(target);
+    buf.put_u8(ND_OPTION_TARGET_LINK_LAYER_ADDR);
+    buf.put_u8(1);
+    buf.put_slice(&my_mac_addr);
+
+    set_icmpv6_checksum(target, dst_addr, &mut buf);
+    buf
+}
+
+// Multicast Listener Report (MLDv2) を生成する (RFC 3810 5.2)
+// 1つのパケットに収まらない場合は複数に分ける
+pub fn out_mld_report(
+    src_addr: u128,
+    dst_addr: u128,
+    records: &[MulticastAddressRecord],
+) -> Vec<Vec<u8>> {
+    // IPv6ヘッダとHop-by-Hopヘッダを除いて最小MTUに収める
+    let max_len = IPV6_MIN_MTU as usize - 40 - 8;
+    let mut reports = vec![];
+    let mut records = records.iter().peekable();
+    while records.peek().is_some() {
+        let mut buf = Vec::new();
+        buf.put_u8(ICMPV6_TYPE_MULTICAST_LISTENER_REPORT_V2);
+        buf.put_u8(0);
+        buf.put_u16(0x00); // checksum
+        buf.put_u16(0x00); // reserved
+        buf.put_u16(0x00); // Nr of Mcast Address Records
+        let mut num_records: u16 = 0;
+        while let Some(record) = records.peek() {
+            let record_len = 20 + record.sources.len() * 16;
+            if num_records > 0 && buf.len() + record_len > max_len {
+                break;
+            }
+            buf.put_u8(record.record_type);
+            buf.put_u8(0); // Aux Data Len
+            buf.put_u16(record.sources.len() as u16);
+            buf.put_u128(record.multicast_addr);
+            for source in &record.sources {
+                buf.put_u128(*source);
+            }
+            num_records += 1;
+            records.next();
+        }
+        buf[6..8].copy_from_slice(&num_records.to_be_bytes());
+        set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+        reports.push(buf);
+    }
+    reports
+}
+
+// ICMPv6エラーメッセージを生成する
+// 4byteのパラメータの後ろに、最小MTUに収まる範囲で原因となったパケットを付ける
+pub fn out_icmpv6_error(
+    src_addr: u128,
+    dst_addr: u128,
+    icmp_type: u8,
+    icmp_code: u8,
+    parameter: u32,
+    invoking_packet: &[u8],
+) -> Vec<u8> {
+    // ICMPv6エラーメッセージを含むパケットはIPv6の最小MTUを超えてはいけない
+    let max_len = IPV6_MIN_MTU as usize - 40 - 8;
+    let mut buf = Vec::new();
+    buf.put_u8(icmp_type);
+    buf.put_u8(icmp_code);
+    buf.put_u16(0x00); // checksum
+    buf.put_u32(parameter);
+    buf.put_slice(&invoking_packet[..invoking_packet.len().min(max_len)]);
+
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
 }
 
 fn icmpv6_echo_reply(src_addr: u128, dst_addr: u128, icmpv6echo: ICMPV6Echo) -> Vec<u8> {
@@ -65,10 +748,14 @@ fn icmpv6_echo_reply(src_addr: u128, dst_addr: u128, icmpv6echo: ICMPV6Echo) ->
                        // ICMPv6 EchoReply メッセージ
     buf.put_u16(icmpv6echo.identify);
     buf.put_u16(icmpv6echo.sequence);
-    buf.put_u128(icmpv6echo.timestamp);
     buf.append(&mut icmpv6echo.data.to_vec());
 
-    // IPv6ダミーヘッダをセット
+    set_icmpv6_checksum(src_addr, dst_addr, &mut buf);
+    buf
+}
+
+// IPv6ダミーヘッダを付けてchecksumを計算してセット
+fn set_icmpv6_checksum(src_addr: u128, dst_addr: u128, buf: &mut Vec<u8>) {
     let dummy = IPV6DummyHeader {
         src_addr,
         dst_addr,
@@ -80,12 +767,9 @@ fn icmpv6_echo_reply(src_addr: u128, dst_addr: u128, icmpv6echo: ICMPV6Echo) ->
     calc_checksum_buf.put_u128(dummy.dst_addr);
     calc_checksum_buf.put_u32(dummy.length);
     calc_checksum_buf.put_u32(dummy.protocol);
-    calc_checksum_buf.put_slice(&buf.as_slice());
+    calc_checksum_buf.put_slice(buf.as_slice());
 
-    // checksumを計算してセット
     let checksum = checksum(&calc_checksum_buf).to_be_bytes().to_vec();
     buf[2] = checksum[0];
     buf[3] = checksum[1];
-
-    buf
 }
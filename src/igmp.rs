@@ -0,0 +1,577 @@
+use crate::ipv4::ipv4_multicast_mac_addr;
+use crate::mld::MulticastFilterMode;
+use crate::util::{checksum, random_u64, to_u16, to_u32};
+use bytes::BufMut;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 224.0.0.1 全システム、224.0.0.2 全ルーター、224.0.0.22 IGMPv3対応ルーター
+pub const IPV4_ALL_SYSTEMS_ADDR: u32 = 0xe000_0001;
+const IPV4_ALL_ROUTERS_ADDR: u32 = 0xe000_0002;
+const IPV4_ALL_IGMPV3_ROUTERS_ADDR: u32 = 0xe000_0016;
+
+// IGMPのメッセージタイプ (RFC 3376 4., RFC 2236 2.1)
+const IGMP_TYPE_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMP_TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const IGMP_TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_TYPE_V2_LEAVE_GROUP: u8 = 0x17;
+const IGMP_TYPE_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+// IGMPv1/v2のメッセージとIGMPv3のQueryの最小長
+const IGMP_MESSAGE_LENGTH: usize = 8;
+const IGMPV3_QUERY_MIN_LENGTH: usize = 12;
+// IGMPのメッセージは必ずTTL 1で送受信する
+pub const IGMP_TTL: u8 = 1;
+
+// 状態変化レポートを送る回数と間隔 (RFC 3376 8.1, 8.11, RFC 2236 8.10)
+const ROBUSTNESS_VARIABLE: u8 = 2;
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const V2_UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+// 古いバージョンのQuerierがいるとみなす時間 (RFC 3376 8.12)
+// Robustness Variable * Query Interval + Query Response Interval
+const OLDER_VERSION_QUERIER_PRESENT_TIMEOUT: Duration = Duration::from_secs(2 * 125 + 10);
+// IGMPv1のQueryにはMax Resp Timeがないので10秒とする (RFC 2236 4.)
+const V1_MAX_RESPONSE_TIME: Duration = Duration::from_secs(10);
+
+// Group Recordのタイプ (RFC 3376 4.2.12)
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+const ALLOW_NEW_SOURCES: u8 = 5;
+const BLOCK_OLD_SOURCES: u8 = 6;
+
+// Membership Reportに入れるレコード
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GroupRecord {
+    record_type: u8,
+    multicast_addr: u32,
+    sources: Vec<u32>,
+}
+
+// Querierのバージョンに合わせて送るレポートのバージョン (RFC 3376 7.2.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgmpVersion {
+    V1,
+    V2,
+    V3,
+}
+
+#[derive(Debug)]
+struct MulticastGroup {
+    addr: u32,
+    users: u32, // join_ipv4_multicast_groupを呼んだ数
+    mode: MulticastFilterMode,
+    sources: Vec<u32>,
+    // 状態変化レポートの残りの送信回数と次に送る時刻
+    change_records: Vec<GroupRecord>,
+    change_retransmits: u8,
+    change_at: Instant,
+    // Group Specific Queryへの応答予定
+    // 送信元の指定がなければNone
+    query_at: Option<Instant>,
+    query_sources: Option<Vec<u32>>,
+}
+
+impl MulticastGroup {
+    fn new(addr: u32) -> MulticastGroup {
+        MulticastGroup {
+            addr,
+            users: 0,
+            mode: MulticastFilterMode::Include,
+            sources: vec![],
+            change_records: vec![],
+            change_retransmits: 0,
+            change_at: Instant::now(),
+            query_at: None,
+            query_sources: None,
+        }
+    }
+
+    // INCLUDE {} はどの送信元からも受信しない、つまり参加していない
+    fn is_listening(&self) -> bool {
+        !(self.mode == MulticastFilterMode::Include && self.sources.is_empty())
+    }
+
+    fn accepts(&self, src_addr: u32) -> bool {
+        match self.mode {
+            MulticastFilterMode::Include => self.sources.contains(&src_addr),
+            MulticastFilterMode::Exclude => !self.sources.contains(&src_addr),
+        }
+    }
+
+    // 現在の状態を表すレコード
+    fn current_state_record(&self) -> GroupRecord {
+        let record_type = match self.mode {
+            MulticastFilterMode::Include => MODE_IS_INCLUDE,
+            MulticastFilterMode::Exclude => MODE_IS_EXCLUDE,
+        };
+        GroupRecord {
+            record_type,
+            multicast_addr: self.addr,
+            sources: self.sources.clone(),
+        }
+    }
+
+    // Group-and-Source-Specific Queryへの応答 (RFC 3376 5.2)
+    // 問い合わせられた送信元のうち受信するものを返す
+    fn source_specific_record(&self, query_sources: &[u32]) -> Option<GroupRecord> {
+        let sources: Vec<u32> = query_sources
+            .iter()
+            .copied()
+            .filter(|src| self.accepts(*src))
+            .collect();
+        if sources.is_empty() {
+            return None;
+        }
+        Some(GroupRecord {
+            record_type: MODE_IS_INCLUDE,
+            multicast_addr: self.addr,
+            sources,
+        })
+    }
+
+    // フィルタを変更して、状態変化レポートを送る準備をする (RFC 3376 5.1)
+    fn change_filter(&mut self, mode: MulticastFilterMode, sources: Vec<u32>) {
+        if self.mode == mode && self.sources == sources {
+            return;
+        }
+        let mut records = vec![];
+        if self.mode != mode {
+            let record_type = match mode {
+                MulticastFilterMode::Include => CHANGE_TO_INCLUDE_MODE,
+                MulticastFilterMode::Exclude => CHANGE_TO_EXCLUDE_MODE,
+            };
+            records.push(GroupRecord {
+                record_type,
+                multicast_addr: self.addr,
+                sources: sources.clone(),
+            });
+        } else {
+            let added: Vec<u32> = sources
+                .iter()
+                .copied()
+                .filter(|src| !self.sources.contains(src))
+                .collect();
+            let removed: Vec<u32> = self
+                .sources
+                .iter()
+                .copied()
+                .filter(|src| !sources.contains(src))
+                .collect();
+            // Excludeでは除外する送信元が増えるとブロック、減ると許可になる
+            let (allow, block) = match mode {
+                MulticastFilterMode::Include => (added, removed),
+                MulticastFilterMode::Exclude => (removed, added),
+            };
+            for (record_type, sources) in [(ALLOW_NEW_SOURCES, allow), (BLOCK_OLD_SOURCES, block)] {
+                if !sources.is_empty() {
+                    records.push(GroupRecord {
+                        record_type,
+                        multicast_addr: self.addr,
+                        sources,
+                    });
+                }
+            }
+        }
+        self.mode = mode;
+        self.sources = sources;
+        // 送信中の状態変化レポートは新しいものに置き換える
+        self.change_records = records;
+        self.change_retransmits = ROBUSTNESS_VARIABLE;
+        self.change_at = Instant::now();
+    }
+}
+
+// 古いバージョンのQuerierを最後に見てからの期限
+struct OlderQuerierPresent {
+    v1_until: Option<Instant>,
+    v2_until: Option<Instant>,
+}
+
+static MULTICAST_GROUPS: Mutex<Vec<MulticastGroup>> = Mutex::new(Vec::new());
+// IGMPv3のGeneral Queryへの応答予定
+static GENERAL_QUERY_AT: Mutex<Option<Instant>> = Mutex::new(None);
+static OLDER_QUERIER_PRESENT: Mutex<OlderQuerierPresent> = Mutex::new(OlderQuerierPresent {
+    v1_until: None,
+    v2_until: None,
+});
+
+// 全システムマルチキャストは常に参加していて、レポートも送らない (RFC 3376 6.)
+fn is_reported_group(addr: u32) -> bool {
+    addr != IPV4_ALL_SYSTEMS_ADDR
+}
+
+// 古いバージョンのQuerierがいればそのバージョンで動作する
+pub fn get_igmp_version() -> IgmpVersion {
+    let now = Instant::now();
+    let present = OLDER_QUERIER_PRESENT.lock().unwrap();
+    if present.v1_until.is_some_and(|until| until > now) {
+        IgmpVersion::V1
+    } else if present.v2_until.is_some_and(|until| until > now) {
+        IgmpVersion::V2
+    } else {
+        IgmpVersion::V3
+    }
+}
+
+// 全ての送信元から受信するグループに参加する
+pub fn join_ipv4_multicast_group(group: u32) {
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let index = match groups.iter().position(|g| g.addr == group) {
+        Some(index) => index,
+        None => {
+            groups.push(MulticastGroup::new(group));
+            println!("join ipv4 multicast group {}", Ipv4Addr::from(group));
+            groups.len() - 1
+        }
+    };
+    let entry = &mut groups[index];
+    entry.users += 1;
+    if !entry.is_listening() {
+        entry.change_filter(MulticastFilterMode::Exclude, vec![]);
+    }
+}
+
+// join_ipv4_multicast_groupを呼んだ全員が離脱したらグループから抜ける
+pub fn leave_ipv4_multicast_group(group: u32) {
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let Some(entry) = groups.iter_mut().find(|g| g.addr == group) else {
+        return;
+    };
+    entry.users = entry.users.saturating_sub(1);
+    if entry.users == 0 {
+        entry.change_filter(MulticastFilterMode::Include, vec![]);
+        println!("leave ipv4 multicast group {}", Ipv4Addr::from(group));
+    }
+}
+
+// 送信元フィルタを設定する (Source-Specific Multicast)
+// INCLUDE {} を設定するとグループから抜ける
+// IGMPv1/v2のQuerierしかいなければ、送信元を区別せずにグループ全体に参加する
+pub fn set_ipv4_multicast_source_filter(
+    group: u32,
+    mode: MulticastFilterMode,
+    mut sources: Vec<u32>,
+) {
+    sources.sort_unstable();
+    sources.dedup();
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let index = match groups.iter().position(|g| g.addr == group) {
+        Some(index) => index,
+        None => {
+            groups.push(MulticastGroup::new(group));
+            groups.len() - 1
+        }
+    };
+    let entry = &mut groups[index];
+    entry.change_filter(mode, sources);
+    entry.users = if entry.is_listening() {
+        entry.users.max(1)
+    } else {
+        0
+    };
+}
+
+// 参加しているグループの一覧
+pub fn get_ipv4_multicast_groups() -> Vec<(u32, MulticastFilterMode, Vec<u32>)> {
+    MULTICAST_GROUPS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|g| g.is_listening())
+        .map(|g| (g.addr, g.mode, g.sources.clone()))
+        .collect()
+}
+
+// 送信元フィルタを考慮して、マルチキャスト宛てのパケットを受信するか
+pub fn is_ipv4_multicast_member(group: u32, src_addr: u32) -> bool {
+    group == IPV4_ALL_SYSTEMS_ADDR
+        || MULTICAST_GROUPS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|g| g.addr == group && g.is_listening() && g.accepts(src_addr))
+}
+
+// 01:00:5e:xx:xx:xx のうち参加しているグループに対応するMACアドレスか
+// 下位23bitだけで対応付けるので、32個のグループが同じMACアドレスになる (RFC 1112 6.4)
+pub fn is_ipv4_multicast_mac_joined(mac_addr: [u8; 6]) -> bool {
+    mac_addr == ipv4_multicast_mac_addr(IPV4_ALL_SYSTEMS_ADDR)
+        || MULTICAST_GROUPS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|g| g.is_listening() && ipv4_multicast_mac_addr(g.addr) == mac_addr)
+}
+
+// Max Resp Codeから応答までの最大遅延を求める、単位は1/10秒 (RFC 3376 4.1.1)
+fn max_response_delay(code: u8) -> Duration {
+    let tenths = if code < 0x80 {
+        code as u64
+    } else {
+        let exp = (code >> 4) & 0x7;
+        let mant = code & 0x0f;
+        ((mant | 0x10) as u64) << (exp + 3)
+    };
+    Duration::from_millis(tenths * 100)
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max = max.as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(random_u64() % max)
+}
+
+// IGMPのメッセージを受信する
+// IGMPはホストとしてQueryに応答するだけで、ルーターとしては動作しない
+pub fn read_igmp_packet(src_addr: u32, ttl: u8, message: &[u8]) {
+    if ttl != IGMP_TTL || message.len() < IGMP_MESSAGE_LENGTH || checksum(&message.to_vec()) != 0 {
+        return;
+    }
+    match message[0] {
+        IGMP_TYPE_MEMBERSHIP_QUERY => read_igmp_query(src_addr, message),
+        // IGMPv1/v2では他のホストが同じグループのレポートを送ったら自分は送らない (RFC 2236 3.)
+        IGMP_TYPE_V1_MEMBERSHIP_REPORT | IGMP_TYPE_V2_MEMBERSHIP_REPORT => {
+            if get_igmp_version() == IgmpVersion::V3 {
+                return;
+            }
+            let group = to_u32(&message[4..]);
+            let mut groups = MULTICAST_GROUPS.lock().unwrap();
+            if let Some(entry) = groups.iter_mut().find(|g| g.addr == group) {
+                entry.query_at = None;
+            }
+        }
+        _ => {}
+    }
+}
+
+// Membership Queryを受信して応答を予約する (RFC 3376 5.2, 7.1)
+// メッセージの長さとMax Resp CodeでIGMPv1/v2/v3のどれのQueryかを区別する
+fn read_igmp_query(src_addr: u32, message: &[u8]) {
+    let now = Instant::now();
+    let group = to_u32(&message[4..]);
+    let query_version = if message.len() >= IGMPV3_QUERY_MIN_LENGTH {
+        IgmpVersion::V3
+    } else if message[1] == 0 {
+        IgmpVersion::V1
+    } else {
+        IgmpVersion::V2
+    };
+    let (max_delay, sources) = match query_version {
+        IgmpVersion::V1 => (V1_MAX_RESPONSE_TIME, vec![]),
+        IgmpVersion::V2 => (Duration::from_millis(message[1] as u64 * 100), vec![]),
+        IgmpVersion::V3 => {
+            let num_sources = to_u16(&message[10..]) as usize;
+            if message.len() < IGMPV3_QUERY_MIN_LENGTH + num_sources * 4 {
+                return;
+            }
+            let sources: Vec<u32> = message[12..12 + num_sources * 4]
+                .chunks(4)
+                .map(to_u32)
+                .collect();
+            (max_response_delay(message[1]), sources)
+        }
+    };
+    println!(
+        "igmp {query_version:?} query from {} for {}",
+        Ipv4Addr::from(src_addr),
+        Ipv4Addr::from(group)
+    );
+
+    // 古いバージョンのQueryを受信したら、しばらくそのバージョンで応答する
+    {
+        let mut present = OLDER_QUERIER_PRESENT.lock().unwrap();
+        match query_version {
+            IgmpVersion::V1 => present.v1_until = Some(now + OLDER_VERSION_QUERIER_PRESENT_TIMEOUT),
+            IgmpVersion::V2 => present.v2_until = Some(now + OLDER_VERSION_QUERIER_PRESENT_TIMEOUT),
+            IgmpVersion::V3 => {}
+        }
+    }
+
+    let respond_at = now + random_delay(max_delay);
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    // IGMPv1/v2ではグループごとにタイマーを持ち、送信元は区別しない (RFC 2236 3.)
+    if get_igmp_version() != IgmpVersion::V3 {
+        for entry in groups.iter_mut().filter(|g| {
+            (group == 0 || g.addr == group) && g.is_listening() && is_reported_group(g.addr)
+        }) {
+            entry.query_sources = None;
+            entry.query_at = Some(entry.query_at.map_or(respond_at, |at| at.min(respond_at)));
+        }
+        return;
+    }
+
+    let mut general_query_at = GENERAL_QUERY_AT.lock().unwrap();
+    // もっと早いGeneral Queryへの応答が予定されていれば、それで足りる
+    if general_query_at.is_some_and(|at| at <= respond_at) {
+        return;
+    }
+    if group == 0 {
+        *general_query_at = Some(respond_at);
+        return;
+    }
+    let Some(entry) = groups
+        .iter_mut()
+        .find(|g| g.addr == group && g.is_listening() && is_reported_group(g.addr))
+    else {
+        return;
+    };
+    entry.query_sources = match (entry.query_at, entry.query_sources.take()) {
+        // 予定がなければ今回のQueryの送信元
+        (None, _) => (!sources.is_empty()).then_some(sources),
+        // どちらかが送信元の指定がないQueryならグループ全体について応答する
+        (Some(_), None) => None,
+        (Some(_), Some(_)) if sources.is_empty() => None,
+        // 両方とも送信元を指定したQueryなら送信元をまとめる
+        (Some(_), Some(mut pending)) => {
+            for src in sources {
+                if !pending.contains(&src) {
+                    pending.push(src);
+                }
+            }
+            Some(pending)
+        }
+    };
+    entry.query_at = Some(entry.query_at.map_or(respond_at, |at| at.min(respond_at)));
+}
+
+// IGMPのタイマー処理
+// 送信するIGMPメッセージと宛先を返す
+pub fn igmp_timer() -> Vec<(u32, Vec<u8>)> {
+    let now = Instant::now();
+    let version = get_igmp_version();
+    let mut records = vec![];
+    let mut messages = vec![];
+
+    let general_query = {
+        let mut general_query_at = GENERAL_QUERY_AT.lock().unwrap();
+        let due = general_query_at.is_some_and(|at| at <= now);
+        if due {
+            *general_query_at = None;
+        }
+        due
+    };
+
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    for entry in groups.iter_mut().filter(|g| is_reported_group(g.addr)) {
+        if general_query && entry.is_listening() {
+            records.push(entry.current_state_record());
+        }
+        if entry.query_at.is_some_and(|at| at <= now) {
+            entry.query_at = None;
+            match (version, entry.query_sources.take()) {
+                (IgmpVersion::V3, None) if entry.is_listening() => {
+                    records.push(entry.current_state_record())
+                }
+                (IgmpVersion::V3, Some(sources)) => {
+                    records.extend(entry.source_specific_record(&sources))
+                }
+                (_, _) if entry.is_listening() => {
+                    messages.push(out_igmp_v1v2_report(version, entry.addr))
+                }
+                _ => {}
+            }
+        }
+        if entry.change_retransmits > 0 && entry.change_at <= now {
+            entry.change_retransmits -= 1;
+            let interval = match version {
+                IgmpVersion::V3 => {
+                    records.extend(entry.change_records.iter().cloned());
+                    UNSOLICITED_REPORT_INTERVAL
+                }
+                _ if entry.is_listening() => {
+                    messages.push(out_igmp_v1v2_report(version, entry.addr));
+                    V2_UNSOLICITED_REPORT_INTERVAL
+                }
+                // IGMPv2のLeaveは1回だけ送り、IGMPv1には離脱を知らせる方法がない
+                IgmpVersion::V2 => {
+                    messages.push(out_igmp_message(
+                        IGMP_TYPE_V2_LEAVE_GROUP,
+                        IPV4_ALL_ROUTERS_ADDR,
+                        entry.addr,
+                    ));
+                    entry.change_retransmits = 0;
+                    Duration::ZERO
+                }
+                IgmpVersion::V1 => {
+                    entry.change_retransmits = 0;
+                    Duration::ZERO
+                }
+            };
+            entry.change_at = now + random_delay(interval);
+        }
+    }
+    // 抜けたグループは状態変化レポートを送り終えたら消す
+    groups.retain(|g| g.is_listening() || g.change_retransmits > 0);
+    drop(groups);
+
+    messages.extend(
+        out_igmpv3_reports(&records)
+            .into_iter()
+            .map(|report| (IPV4_ALL_IGMPV3_ROUTERS_ADDR, report)),
+    );
+    messages
+}
+
+// IGMPv1/v2のメッセージ (RFC 2236 2.)
+// Max Resp Timeはホストからのメッセージでは0
+fn out_igmp_message(igmp_type: u8, dst_addr: u32, group: u32) -> (u32, Vec<u8>) {
+    let mut buf = Vec::new();
+    buf.put_u8(igmp_type);
+    buf.put_u8(0);
+    buf.put_u16(0); // checksum
+    buf.put_u32(group);
+    let checksum = checksum(&buf);
+    buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+    (dst_addr, buf)
+}
+
+// IGMPv1/v2のMembership Reportはグループ宛てに送る
+fn out_igmp_v1v2_report(version: IgmpVersion, group: u32) -> (u32, Vec<u8>) {
+    let igmp_type = match version {
+        IgmpVersion::V1 => IGMP_TYPE_V1_MEMBERSHIP_REPORT,
+        _ => IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+    };
+    out_igmp_message(igmp_type, group, group)
+}
+
+// IGMPv3のMembership Reportを生成する (RFC 3376 4.2)
+// IPヘッダとRouter Alertオプションを除いてEthernetのMTUに収まるように分割する
+fn out_igmpv3_reports(records: &[GroupRecord]) -> Vec<Vec<u8>> {
+    let max_len = 1500 - 24;
+    let mut reports = vec![];
+    let mut records = records.iter().peekable();
+    while records.peek().is_some() {
+        let mut buf = Vec::new();
+        buf.put_u8(IGMP_TYPE_V3_MEMBERSHIP_REPORT);
+        buf.put_u8(0);
+        buf.put_u16(0x00); // checksum
+        buf.put_u16(0x00); // reserved
+        buf.put_u16(0x00); // Number of Group Records
+        let mut num_records: u16 = 0;
+        while let Some(record) = records.peek() {
+            let record_len = 8 + record.sources.len() * 4;
+            if num_records > 0 && buf.len() + record_len > max_len {
+                break;
+            }
+            buf.put_u8(record.record_type);
+            buf.put_u8(0); // Aux Data Len
+            buf.put_u16(record.sources.len() as u16);
+            buf.put_u32(record.multicast_addr);
+            for source in &record.sources {
+                buf.put_u32(*source);
+            }
+            num_records += 1;
+            records.next();
+        }
+        buf[6..8].copy_from_slice(&num_records.to_be_bytes());
+        let checksum = checksum(&buf);
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        reports.push(buf);
+    }
+    reports
+}
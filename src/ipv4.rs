@@ -1,14 +1,50 @@
-use crate::arp::{add_arp_tables, search_arp_tables};
+use crate::arp::learn_arp_sender;
+use crate::conntrack::ct_track;
 use crate::ethernet::EthernetHeader;
-use crate::icmp::read_icmp_packet;
+use crate::filter::{filter_packet, is_filter_enabled, FilterAction, FilterChain};
+use crate::forward::{forward_ipv4_packet, should_forward_ipv4};
+use crate::icmp::{
+    out_icmp_error_reply, read_icmp_packet, ICMP_CODE_ADMIN_PROHIBITED,
+    ICMP_CODE_POINTER_INDICATES_ERROR, ICMP_CODE_SOURCE_ROUTE_FAILED,
+    ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE, ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM,
+};
+use crate::igmp::{is_ipv4_multicast_member, read_igmp_packet, IGMP_TTL};
+use crate::ipv4_addr::{is_ipv4_multicast, is_my_ipv4_addr, select_ipv4_reply_src};
+use crate::ipv4_frag::{is_ipv4_fragment, next_ipv4_id, reassemble_ipv4, IPV4_FLAG_DONT_FRAGMENT};
+use crate::loopback::{is_ipv4_loopback, is_ipv4_martian, is_loopback_interface};
+use crate::nat::{is_nat_enabled, translate_inbound};
+use crate::pmtu::ipv4_path_mtu;
+use crate::route::lookup_ipv4_route;
+use crate::socket::{
+    get_dont_fragment_policy, get_ipv4_config, get_socket_ttl, interface_name,
+    is_udp_multicast_member, socket_traffic_class, DontFragmentPolicy, SocketProtocol,
+};
 use crate::udp::read_udp_packet;
-use crate::util::checksum;
+use crate::util::{checksum, to_u16, to_u32};
 use bytes::{Buf, BufMut};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub const IP_PROTOCOL_NUMBER_ICMP: u8 = 1;
+pub const IP_PROTOCOL_NUMBER_IGMP: u8 = 2;
 pub const IP_PROTOCOL_NUMBER_TCP: u8 = 6;
 pub const IP_PROTOCOL_NUMBER_UDP: u8 = 17;
 
+const IPV4_HEADER_MIN_LEN: usize = 20;
+
+// IPv4オプションのタイプ (コピーフラグ、クラス、番号を含む)
+const IPV4_OPTION_END_OF_LIST: u8 = 0;
+const IPV4_OPTION_NOP: u8 = 1;
+const IPV4_OPTION_RECORD_ROUTE: u8 = 7;
+const IPV4_OPTION_TIMESTAMP: u8 = 68;
+const IPV4_OPTION_LOOSE_SOURCE_ROUTE: u8 = 131;
+const IPV4_OPTION_STRICT_SOURCE_ROUTE: u8 = 137;
+const IPV4_OPTION_ROUTER_ALERT: u8 = 148;
+
+// Timestampオプションのフラグ
+const IPV4_TIMESTAMP_ONLY: u8 = 0;
+const IPV4_TIMESTAMP_AND_ADDR: u8 = 1;
+const IPV4_TIMESTAMP_PRESPECIFIED: u8 = 3;
+
 pub struct IPv4Header {
     version: u8,              // バージョン
     header_length: u8,        // ヘッダ長
@@ -21,14 +57,220 @@ pub struct IPv4Header {
     checksum: u16,            // チェックサム
     pub(crate) src_addr: u32, // 送信元IPアドレス
     pub(crate) dst_addr: u32, // 宛先IPアドレス
+    options: Ipv4Options,     // オプション
+}
+
+// Record Route, Loose/Strict Source Routeのルートデータ (RFC 791)
+#[derive(Debug, Clone)]
+struct RouteOption {
+    pointer: u8,     // 次のアドレスの位置、オプションの先頭を1とする
+    route: Vec<u32>, // 未記録のものも含めた全てのアドレス
+}
+
+impl RouteOption {
+    fn parse(option: &[u8]) -> Result<Self, usize> {
+        if option.len() < 3 || !(option.len() - 3).is_multiple_of(4) {
+            return Err(1);
+        }
+        let pointer = option[2];
+        if pointer < 4 || !(pointer - 4).is_multiple_of(4) {
+            return Err(2);
+        }
+        Ok(RouteOption {
+            pointer,
+            route: option[3..].chunks(4).map(to_u32).collect(),
+        })
+    }
+
+    // 全てのアドレスを使い切ったか
+    fn is_exhausted(&self) -> bool {
+        self.pointer as usize > 3 + self.route.len() * 4
+    }
+
+    // 空きがあれば自分のアドレスを記録する
+    fn record(&mut self, addr: u32) {
+        if self.is_exhausted() {
+            return;
+        }
+        self.route[(self.pointer as usize - 4) / 4] = addr;
+        self.pointer += 4;
+    }
+
+    fn write(&self, option_type: u8, buf: &mut Vec<u8>) {
+        buf.put_u8(option_type);
+        buf.put_u8(3 + self.route.len() as u8 * 4);
+        buf.put_u8(self.pointer);
+        for addr in &self.route {
+            buf.put_u32(*addr);
+        }
+    }
+}
+
+// Timestampのデータ (RFC 791)
+#[derive(Debug, Clone)]
+struct TimestampOption {
+    pointer: u8,    // 次の記録位置、オプションの先頭を1とする
+    overflow: u8,   // 空きがなくて記録できなかったモジュールの数
+    flag: u8,       // 記録する内容
+    data: Vec<u32>, // 未記録のものも含めたタイムスタンプとアドレス
+}
+
+impl TimestampOption {
+    fn parse(option: &[u8]) -> Result<Self, usize> {
+        if option.len() < 4 {
+            return Err(1);
+        }
+        let flag = option[3] & 0x0f;
+        let entry_len = match flag {
+            IPV4_TIMESTAMP_ONLY => 4,
+            IPV4_TIMESTAMP_AND_ADDR | IPV4_TIMESTAMP_PRESPECIFIED => 8,
+            _ => return Err(3),
+        };
+        if !(option.len() - 4).is_multiple_of(entry_len) {
+            return Err(1);
+        }
+        let pointer = option[2];
+        if pointer < 5 || !(pointer as usize - 5).is_multiple_of(entry_len) {
+            return Err(2);
+        }
+        Ok(TimestampOption {
+            pointer,
+            overflow: option[3] >> 4,
+            flag,
+            data: option[4..].chunks(4).map(to_u32).collect(),
+        })
+    }
+
+    // UTの0時からのミリ秒でタイムスタンプを記録する
+    // 空きがなければオーバーフローを数える
+    fn record(&mut self, addr: u32) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let timestamp = (now.as_millis() % (24 * 60 * 60 * 1000)) as u32;
+        let index = (self.pointer as usize - 5) / 4;
+        let entry_len = if self.flag == IPV4_TIMESTAMP_ONLY {
+            1
+        } else {
+            2
+        };
+        if index + entry_len > self.data.len() {
+            self.overflow = (self.overflow + 1).min(0x0f);
+            return;
+        }
+        match self.flag {
+            IPV4_TIMESTAMP_ONLY => self.data[index] = timestamp,
+            IPV4_TIMESTAMP_AND_ADDR => {
+                self.data[index] = addr;
+                self.data[index + 1] = timestamp;
+            }
+            // 指定されたアドレスが自分の時だけ記録する
+            _ if self.data[index] == addr => self.data[index + 1] = timestamp,
+            _ => return,
+        }
+        self.pointer += entry_len as u8 * 4;
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.put_u8(IPV4_OPTION_TIMESTAMP);
+        buf.put_u8(4 + self.data.len() as u8 * 4);
+        buf.put_u8(self.pointer);
+        buf.put_u8(self.overflow << 4 | self.flag);
+        for data in &self.data {
+            buf.put_u32(*data);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Ipv4Options {
+    record_route: Option<RouteOption>,
+    timestamp: Option<TimestampOption>,
+    router_alert: bool,
+    source_route: Option<(u8, RouteOption)>, // オプションタイプとルート
+}
+
+impl Ipv4Options {
+    // オプション部分を解析する
+    // 不正なオプションがあればオプション部分の先頭からのエラーの位置を返す
+    fn parse(options: &[u8]) -> Result<Self, usize> {
+        let mut result = Ipv4Options::default();
+        let mut pos = 0;
+        while pos < options.len() {
+            let option_type = options[pos];
+            if option_type == IPV4_OPTION_END_OF_LIST {
+                break;
+            }
+            if option_type == IPV4_OPTION_NOP {
+                pos += 1;
+                continue;
+            }
+            let length = options.get(pos + 1).copied().unwrap_or(0) as usize;
+            if length < 2 || pos + length > options.len() {
+                return Err(pos + 1);
+            }
+            let option = &options[pos..pos + length];
+            match option_type {
+                IPV4_OPTION_RECORD_ROUTE => {
+                    result.record_route = Some(RouteOption::parse(option).map_err(|e| pos + e)?);
+                }
+                IPV4_OPTION_TIMESTAMP => {
+                    result.timestamp = Some(TimestampOption::parse(option).map_err(|e| pos + e)?);
+                }
+                IPV4_OPTION_LOOSE_SOURCE_ROUTE | IPV4_OPTION_STRICT_SOURCE_ROUTE => {
+                    let route = RouteOption::parse(option).map_err(|e| pos + e)?;
+                    result.source_route = Some((option_type, route));
+                }
+                IPV4_OPTION_ROUTER_ALERT => {
+                    if length != 4 {
+                        return Err(pos + 1);
+                    }
+                    result.router_alert = true;
+                }
+                // 知らないオプションは無視する (RFC 1122 3.2.1.8)
+                _ => println!("ignore unknown ipv4 option {option_type}"),
+            }
+            pos += length;
+        }
+        Ok(result)
+    }
+
+    // Echo Replyに付けるオプション (RFC 1122 3.2.2.6)
+    // Record RouteとTimestampに自分を記録して返す
+    fn echo_reply_options(&self, my_addr: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if let Some(record_route) = &self.record_route {
+            let mut record_route = record_route.clone();
+            record_route.record(my_addr);
+            record_route.write(IPV4_OPTION_RECORD_ROUTE, &mut buf);
+        }
+        if let Some(timestamp) = &self.timestamp {
+            let mut timestamp = timestamp.clone();
+            timestamp.record(my_addr);
+            timestamp.write(&mut buf);
+        }
+        // オプション部分は4byte単位にする
+        while !buf.len().is_multiple_of(4) {
+            buf.put_u8(IPV4_OPTION_END_OF_LIST);
+        }
+        buf
+    }
 }
 
-pub fn read_ipv4_packet(eth_header: EthernetHeader, packet: Vec<u8>, ipv4: u32) -> (u32, Vec<u8>) {
+pub fn read_ipv4_packet(
+    eth_header: EthernetHeader,
+    mut packet: Vec<u8>,
+    my_mac_addr: [u8; 6],
+) -> (u32, Vec<u8>) {
+    if packet.len() < IPV4_HEADER_MIN_LEN {
+        return (0, vec![]);
+    }
     let mut buf = &packet[..];
 
+    let version_ihl = buf.get_u8();
     let mut ipv4_header = IPv4Header {
-        version: buf.get_u8(),
-        header_length: 0,
+        version: version_ihl >> 4,
+        header_length: (version_ihl & 0x0f) * 4,
         tos: buf.get_u8(),
         total_len: buf.get_u16(),
         identity_num: buf.get_u16(),
@@ -38,31 +280,148 @@ pub fn read_ipv4_packet(eth_header: EthernetHeader, packet: Vec<u8>, ipv4: u32)
         checksum: buf.get_u16(),
         src_addr: buf.get_u32(),
         dst_addr: buf.get_u32(),
+        options: Ipv4Options::default(),
     };
-    ipv4_header.header_length = (ipv4_header.version >> 4) * 5;
-    ipv4_header.version = ipv4_header.version >> 4;
 
-    // 自分宛てのパケットでなければreturn
-    if ipv4 != ipv4_header.dst_addr {
+    if ipv4_header.version != 4 {
+        println!("invalid ip version {}", ipv4_header.version);
         return (0, vec![]);
     }
 
-    // ARPテーブルを検索して存在していなければ追加
-    if search_arp_tables(ipv4_header.src_addr) == [0, 0, 0, 0, 0, 0] {
-        add_arp_tables(eth_header.src_mac_addr, ipv4_header.src_addr)
+    if is_ipv4_martian(my_mac_addr, ipv4_header.src_addr, ipv4_header.dst_addr) {
+        return (0, vec![]);
+    }
+
+    // 自分宛てのパケットか、参加しているマルチキャストグループ宛てでなければ
+    // ルーターとして転送するか捨てる
+    // リンク層でブロードキャストされたパケットは転送しない (RFC 1812 5.3.4)
+    // ループバックデバイスでは127.0.0.0/8の全てのアドレスが自分宛て
+    let from_loopback = is_loopback_interface(my_mac_addr);
+    let is_mine = is_my_ipv4_addr(ipv4_header.dst_addr)
+        || (from_loopback && is_ipv4_loopback(ipv4_header.dst_addr))
+        || is_ipv4_multicast_member(ipv4_header.dst_addr, ipv4_header.src_addr);
+    let forward = !is_mine
+        && eth_header.dst_mac_addr[0] & 0x01 == 0
+        && should_forward_ipv4(ipv4_header.dst_addr);
+    if !is_mine && !forward {
+        return (0, vec![]);
     }
 
+    let header_length = ipv4_header.header_length as usize;
+    if header_length < IPV4_HEADER_MIN_LEN || header_length > packet.len() {
+        println!("invalid ipv4 header length {header_length}");
+        return out_ipv4_parameter_problem(&packet, 0);
+    }
+    // チェックサムが合わなければヘッダを信用できないので黙って捨てる
+    if checksum(&packet[..header_length].to_vec()) != 0 {
+        println!("invalid ipv4 header checksum {:x}", ipv4_header.checksum);
+        return (0, vec![]);
+    }
+    let total_len = ipv4_header.total_len as usize;
+    if total_len < header_length || total_len > packet.len() {
+        println!("invalid ipv4 total length {total_len}");
+        return out_ipv4_parameter_problem(&packet, 2);
+    }
+    // Ethernetの最小フレーム長に合わせたパディングを取り除く
+    packet.truncate(total_len);
+
+    // 転送するパケットはフラグメントのまま次のホップに送る
+    // NATとフィルタはポート番号を見るので再構築してから転送する
+    let in_interface = interface_name(my_mac_addr);
+    if forward {
+        if (is_nat_enabled() || is_filter_enabled()) && is_ipv4_fragment(&packet) {
+            return match reassemble_ipv4(&packet) {
+                Some(reassembled) => forward_ipv4_packet(reassembled, in_interface.as_deref()),
+                None => (0, vec![]),
+            };
+        }
+        return forward_ipv4_packet(packet, in_interface.as_deref());
+    }
+
+    if is_ipv4_fragment(&packet) {
+        return match reassemble_ipv4(&packet) {
+            // 再構築したパケットをもう一度最初から処理する
+            Some(reassembled) => read_ipv4_packet(eth_header, reassembled, my_mac_addr),
+            None => (0, vec![]),
+        };
+    }
+
+    // NATで変換したフローの返りのパケットなら内側のホストに転送する
+    if translate_inbound(&mut packet) {
+        return forward_ipv4_packet(packet, in_interface.as_deref());
+    }
+
+    let ct_state = ct_track(&packet);
+    match filter_packet(
+        FilterChain::Input,
+        &packet,
+        ct_state,
+        in_interface.as_deref(),
+        None,
+    ) {
+        FilterAction::Accept => {}
+        FilterAction::Drop => return (0, vec![]),
+        FilterAction::Reject => {
+            return out_icmp_error_reply(
+                &packet,
+                ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE,
+                ICMP_CODE_ADMIN_PROHIBITED,
+                0,
+            );
+        }
+    }
+
+    match Ipv4Options::parse(&packet[IPV4_HEADER_MIN_LEN..header_length]) {
+        Ok(options) => ipv4_header.options = options,
+        Err(pos) => {
+            println!("invalid ipv4 option at {}", IPV4_HEADER_MIN_LEN + pos);
+            return out_ipv4_parameter_problem(&packet, IPV4_HEADER_MIN_LEN + pos);
+        }
+    }
+    if ipv4_header.options.router_alert {
+        println!("receive ipv4 packet with router alert");
+    }
+
+    // オンリンクの送信元ならARPと同じようにMACアドレスを学習する
+    // オフリンクの送信元のMACアドレスはルーターのものなので学習しない
+    let on_link = !from_loopback
+        && lookup_ipv4_route(ipv4_header.src_addr).is_some_and(|r| r.gateway.is_none());
+    if on_link {
+        learn_arp_sender(eth_header.src_mac_addr, ipv4_header.src_addr, my_mac_addr);
+    }
+
+    // ルーターとして転送しないので、使い切っていないソースルートは受け取れない (RFC 1122 3.3.5)
+    if let Some((option_type, route)) = &ipv4_header.options.source_route {
+        if !route.is_exhausted() {
+            println!("source route option {option_type} is not exhausted");
+            return out_icmp_error_reply(
+                &packet,
+                ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE,
+                ICMP_CODE_SOURCE_ROUTE_FAILED,
+                0,
+            );
+        }
+    }
+
+    let payload = packet[header_length..].to_vec();
+    // マルチキャスト宛てのパケットにはインターフェースのアドレスから返す
+    let reply_src_addr = select_ipv4_reply_src(ipv4_header.dst_addr, ipv4_header.src_addr);
     match ipv4_header.protocol {
         IP_PROTOCOL_NUMBER_ICMP => {
             println!("receive icmp packet");
             // replyパケットを生成
-            let packet = read_icmp_packet(buf[..].to_owned());
+            let packet = read_icmp_packet(payload);
+            if packet.is_empty() {
+                return (0, vec![]);
+            }
+            let options = ipv4_header.options.echo_reply_options(reply_src_addr);
             return (
                 ipv4_header.src_addr,
-                out_ipv4_packet(
-                    ipv4_header.dst_addr,
+                out_ipv4_packet_with_options(
+                    reply_src_addr,
                     ipv4_header.src_addr,
                     IP_PROTOCOL_NUMBER_ICMP,
+                    &options,
                     packet,
                 ),
             );
@@ -70,13 +429,27 @@ pub fn read_ipv4_packet(eth_header: EthernetHeader, packet: Vec<u8>, ipv4: u32)
         IP_PROTOCOL_NUMBER_TCP => {
             println!("receive tcp packet")
         }
+        IP_PROTOCOL_NUMBER_IGMP => {
+            println!("receive igmp packet");
+            read_igmp_packet(ipv4_header.src_addr, ipv4_header.ttl, &payload);
+        }
         IP_PROTOCOL_NUMBER_UDP => {
             println!("receive udp packet");
-            let packet = read_udp_packet(&ipv4_header, buf[..].to_owned());
+            // マルチキャストはそのグループに参加したソケットのポート宛てのものだけ受け取る
+            if is_ipv4_multicast(ipv4_header.dst_addr)
+                && (payload.len() < 4
+                    || !is_udp_multicast_member(to_u16(&payload[2..]), ipv4_header.dst_addr))
+            {
+                return (0, vec![]);
+            }
+            let packet = read_udp_packet(&ipv4_header, payload);
+            if packet.is_empty() {
+                return (0, vec![]);
+            }
             return (
                 ipv4_header.src_addr,
                 out_ipv4_packet(
-                    ipv4_header.dst_addr,
+                    reply_src_addr,
                     ipv4_header.src_addr,
                     IP_PROTOCOL_NUMBER_UDP,
                     packet,
@@ -91,26 +464,115 @@ pub fn read_ipv4_packet(eth_header: EthernetHeader, packet: Vec<u8>, ipv4: u32)
     (0, vec![])
 }
 
-pub fn out_ipv4_packet(
+// 01:00:5e に下位23bitを付けたマルチキャストMACアドレス (RFC 1112 6.4)
+pub fn ipv4_multicast_mac_addr(addr: u32) -> [u8; 6] {
+    let b = (addr & 0x007f_ffff).to_be_bytes();
+    [0x01, 0x00, 0x5e, b[1], b[2], b[3]]
+}
+
+// ヘッダの不正な位置を指すParameter Problemを返す
+fn out_ipv4_parameter_problem(packet: &[u8], pointer: usize) -> (u32, Vec<u8>) {
+    out_icmp_error_reply(
+        packet,
+        ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM,
+        ICMP_CODE_POINTER_INDICATES_ERROR,
+        (pointer as u32) << 24,
+    )
+}
+
+pub fn out_ipv4_packet(src_addr: u32, dst_addr: u32, protocol: u8, payload: Vec<u8>) -> Vec<u8> {
+    out_ipv4_packet_with_options(src_addr, dst_addr, protocol, &[], payload)
+}
+
+// optionsは4byte単位にパディング済みのオプション部分
+// TTLとDFは送信元ポートのソケットの設定に従う
+pub fn out_ipv4_packet_with_options(
     src_addr: u32,
     dst_addr: u32,
     protocol: u8,
+    options: &[u8],
+    payload: Vec<u8>,
+) -> Vec<u8> {
+    let socket = socket_of(protocol, &payload);
+    let ttl = socket
+        .and_then(|(protocol, port)| get_socket_ttl(protocol, port))
+        .unwrap_or(get_ipv4_config().default_ttl);
+    let dont_fragment = socket.map_or(DontFragmentPolicy::Dont, |(protocol, port)| {
+        get_dont_fragment_policy(protocol, port)
+    });
+    out_ipv4_packet_with_ttl(
+        src_addr,
+        dst_addr,
+        protocol,
+        ttl,
+        dont_fragment,
+        options,
+        payload,
+    )
+}
+
+// TCP/UDPなら送信元の (プロトコル, ポート)
+fn socket_of(protocol: u8, payload: &[u8]) -> Option<(SocketProtocol, u16)> {
+    let protocol = match protocol {
+        IP_PROTOCOL_NUMBER_TCP => SocketProtocol::Tcp,
+        IP_PROTOCOL_NUMBER_UDP => SocketProtocol::Udp,
+        _ => return None,
+    };
+    (payload.len() >= 4).then(|| (protocol, to_u16(&payload[0..])))
+}
+
+// IGMPのメッセージはRouter Alertオプションを付けてTTL 1で送る (RFC 3376 4.)
+pub fn out_ipv4_igmp_packet(src_addr: u32, dst_addr: u32, igmp: Vec<u8>) -> Vec<u8> {
+    let options = [IPV4_OPTION_ROUTER_ALERT, 4, 0, 0];
+    out_ipv4_packet_with_ttl(
+        src_addr,
+        dst_addr,
+        IP_PROTOCOL_NUMBER_IGMP,
+        IGMP_TTL,
+        DontFragmentPolicy::Dont,
+        &options,
+        igmp,
+    )
+}
+
+pub(crate) fn out_ipv4_packet_with_ttl(
+    src_addr: u32,
+    dst_addr: u32,
+    protocol: u8,
+    ttl: u8,
+    dont_fragment: DontFragmentPolicy,
+    options: &[u8],
     mut payload: Vec<u8>,
 ) -> Vec<u8> {
+    // ソケットにTraffic Classが設定されていればTOSに使う
+    let tos = socket_of(protocol, &payload)
+        .and_then(|(protocol, port)| socket_traffic_class(protocol, port))
+        .map_or(get_ipv4_config().default_tos, |tc| tc.to_u8());
+    let total_len = IPV4_HEADER_MIN_LEN + options.len() + payload.len();
+    let dont_fragment = match dont_fragment {
+        DontFragmentPolicy::Want => total_len <= ipv4_path_mtu(dst_addr) as usize,
+        DontFragmentPolicy::Do => true,
+        DontFragmentPolicy::Dont => false,
+    };
     let mut ipv4_header = IPv4Header {
         version: 4,
-        header_length: 20,
-        tos: 0,
+        header_length: (IPV4_HEADER_MIN_LEN + options.len()) as u8,
+        tos,
         total_len: 0,
-        identity_num: 0,
-        frag_offset: 0,
-        ttl: 64,
+        identity_num: next_ipv4_id(src_addr, dst_addr, protocol),
+        frag_offset: if dont_fragment {
+            IPV4_FLAG_DONT_FRAGMENT
+        } else {
+            0
+        },
+        ttl,
         protocol,
         checksum: 0,
         src_addr,
         dst_addr,
+        options: Ipv4Options::default(),
     };
-    ipv4_header.total_len = (ipv4_header.header_length + payload.len() as u8) as u16;
+    ipv4_header.total_len = (ipv4_header.header_length as usize + payload.len()) as u16;
 
     let mut buf = Vec::new();
     buf.put_u8((ipv4_header.version << 4) + (ipv4_header.header_length >> 2));
@@ -123,6 +585,7 @@ pub fn out_ipv4_packet(
     buf.put_u16(ipv4_header.checksum);
     buf.put_u32(ipv4_header.src_addr);
     buf.put_u32(ipv4_header.dst_addr);
+    buf.put_slice(options);
 
     // checksumを計算してセット
     let checksum = checksum(buf.as_ref()).to_be_bytes().to_vec();
@@ -131,5 +594,19 @@ pub fn out_ipv4_packet(
     // ヘッダの後ろにpayloadを追加
     buf.append(&mut payload);
 
+    // 送信するパケットは拒否しても自分にICMPを返せないので捨てるだけ
+    let out_interface = lookup_ipv4_route(dst_addr).map(|route| route.interface);
+    let ct_state = ct_track(&buf);
+    if filter_packet(
+        FilterChain::Output,
+        &buf,
+        ct_state,
+        None,
+        out_interface.as_deref(),
+    ) != FilterAction::Accept
+    {
+        return vec![];
+    }
+
     buf
 }
@@ -0,0 +1,69 @@
+use crate::addrselect::select_ipv4_source;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4AddrEntry {
+    pub addr: u32,
+    pub prefix_len: u8,
+}
+
+static IPV4_ADDRS: Mutex<Vec<Ipv4AddrEntry>> = Mutex::new(Vec::new());
+
+pub fn add_ipv4_addr(addr: u32, prefix_len: u8) {
+    let mut addrs = IPV4_ADDRS.lock().unwrap();
+    if addrs.iter().any(|entry| entry.addr == addr) {
+        return;
+    }
+    addrs.push(Ipv4AddrEntry { addr, prefix_len });
+    println!("add ipv4 addr {}/{prefix_len}", Ipv4Addr::from(addr));
+}
+
+pub fn remove_ipv4_addr(addr: u32) {
+    let mut addrs = IPV4_ADDRS.lock().unwrap();
+    let len = addrs.len();
+    addrs.retain(|entry| entry.addr != addr);
+    if addrs.len() != len {
+        println!("remove ipv4 addr {}", Ipv4Addr::from(addr));
+    }
+}
+
+pub fn get_ipv4_addrs() -> Vec<Ipv4AddrEntry> {
+    IPV4_ADDRS.lock().unwrap().clone()
+}
+
+// 255.255.255.255か、自分のサブネットのブロードキャストアドレスか
+pub fn is_ipv4_broadcast(addr: u32) -> bool {
+    addr == u32::MAX
+        || IPV4_ADDRS.lock().unwrap().iter().any(|entry| {
+            let host_mask = u32::MAX.checked_shr(entry.prefix_len as u32).unwrap_or(0);
+            entry.prefix_len < 31 && entry.addr | host_mask == addr
+        })
+}
+
+// 224.0.0.0/4
+pub fn is_ipv4_multicast(addr: u32) -> bool {
+    addr >> 28 == 0xe
+}
+
+// 宛先に対して使う送信元アドレスをRFC 6724の規則で選ぶ
+// 使えるアドレスがなければ0.0.0.0を返す
+pub fn select_ipv4_src(dst_addr: u32) -> u32 {
+    select_ipv4_source(dst_addr).unwrap_or(0)
+}
+
+// 受信したパケットへの返信に使う送信元アドレス
+pub fn select_ipv4_reply_src(recv_dst_addr: u32, peer_addr: u32) -> u32 {
+    if is_my_ipv4_addr(recv_dst_addr) {
+        return recv_dst_addr;
+    }
+    select_ipv4_src(peer_addr)
+}
+
+pub fn is_my_ipv4_addr(addr: u32) -> bool {
+    IPV4_ADDRS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.addr == addr)
+}
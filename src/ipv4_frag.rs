@@ -0,0 +1,321 @@
+use crate::util::{checksum, random_u64, to_u16, to_u32};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 再構築の待ち時間 (RFC 1122 3.3.2 は60秒から120秒を推奨)
+const IPV4_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+// 同時に再構築するデータグラム数と、保持するフラグメントの合計サイズの上限
+const MAX_REASSEMBLIES: usize = 64;
+const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+const IPV4_MAX_TOTAL_LENGTH: usize = 65535;
+
+// Flags and Fragment Offset フィールド
+pub const IPV4_FLAG_DONT_FRAGMENT: u16 = 0x4000;
+pub const IPV4_FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+// オプションタイプのコピーフラグ、立っていれば全てのフラグメントにコピーする
+const IPV4_OPTION_COPIED: u8 = 0x80;
+
+// 再構築中のデータグラム
+struct Ipv4Reassembly {
+    src_addr: u32,
+    dst_addr: u32,
+    protocol: u8,
+    identification: u16,
+    header: Vec<u8>,                  // 先頭フラグメントのヘッダ
+    first_fragment: Vec<u8>,          // Time Exceededに付ける先頭フラグメント
+    fragments: Vec<(usize, Vec<u8>)>, // (オフセット, データ)
+    total_len: Option<usize>,         // 最後のフラグメントを受信したら判明する
+    created_at: Instant,
+    discarded: bool, // 重複を検知して破棄したデータグラム
+}
+
+impl Ipv4Reassembly {
+    fn received_bytes(&self) -> usize {
+        self.fragments.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+static IPV4_REASSEMBLY: Mutex<Vec<Ipv4Reassembly>> = Mutex::new(Vec::new());
+static IPV4_ID: Mutex<Option<Ipv4IdCounters>> = Mutex::new(None);
+
+// Identificationのカウンタの数、(送信元, 宛先, プロトコル) のハッシュで選ぶ
+const IPV4_ID_BUCKETS: usize = 2048;
+
+struct Ipv4IdCounters {
+    secret: u64,
+    counters: Vec<u16>,
+}
+
+// フラグメントならtrue
+pub fn is_ipv4_fragment(packet: &[u8]) -> bool {
+    to_u16(&packet[6..8]) & (IPV4_FLAG_MORE_FRAGMENTS | IPV4_FRAG_OFFSET_MASK) != 0
+}
+
+// フラグメントを再構築する (RFC 791 3.2, RFC 815)
+// packetはヘッダを検証してTotal Lengthに切り詰めたもの
+// 揃ったら再構築したパケットを返す
+pub fn reassemble_ipv4(packet: &[u8]) -> Option<Vec<u8>> {
+    let header_length = (packet[0] & 0x0f) as usize * 4;
+    let identification = to_u16(&packet[4..6]);
+    let frag = to_u16(&packet[6..8]);
+    let protocol = packet[9];
+    let src_addr = to_u32(&packet[12..16]);
+    let dst_addr = to_u32(&packet[16..20]);
+    let offset = (frag & IPV4_FRAG_OFFSET_MASK) as usize * 8;
+    let more = frag & IPV4_FLAG_MORE_FRAGMENTS != 0;
+    let data = &packet[header_length..];
+
+    // 最後以外のフラグメントは8byteの倍数でなければならない
+    if more && !data.len().is_multiple_of(8) {
+        println!("invalid ipv4 fragment length {}", data.len());
+        return None;
+    }
+    if header_length + offset + data.len() > IPV4_MAX_TOTAL_LENGTH {
+        println!("ipv4 fragment exceeds maximum length");
+        return None;
+    }
+
+    let mut reassembly = IPV4_REASSEMBLY.lock().unwrap();
+    let mut index = match reassembly.iter().position(|r| {
+        r.src_addr == src_addr
+            && r.dst_addr == dst_addr
+            && r.protocol == protocol
+            && r.identification == identification
+    }) {
+        Some(index) => index,
+        None => {
+            // 上限を超えるなら一番古いものを捨てる
+            if reassembly.len() >= MAX_REASSEMBLIES {
+                reassembly.remove(0);
+            }
+            reassembly.push(Ipv4Reassembly {
+                src_addr,
+                dst_addr,
+                protocol,
+                identification,
+                header: vec![],
+                first_fragment: vec![],
+                fragments: vec![],
+                total_len: None,
+                created_at: Instant::now(),
+                discarded: false,
+            });
+            reassembly.len() - 1
+        }
+    };
+    let entry = &mut reassembly[index];
+    if entry.discarded {
+        return None;
+    }
+
+    let end = offset + data.len();
+    // 完全に同じフラグメントは経路上で複製されたものなので、それだけ捨てる
+    if entry
+        .fragments
+        .iter()
+        .any(|(o, d)| *o == offset && d.as_slice() == data)
+    {
+        return None;
+    }
+    // 重複するフラグメントがあれば、書き換えによる攻撃を避けるためにデータグラムごと破棄する
+    let overlapped = entry
+        .fragments
+        .iter()
+        .any(|(o, d)| offset < o + d.len() && *o < end)
+        || entry
+            .total_len
+            .is_some_and(|total| end > total || (!more && end != total))
+        || (!more && entry.fragments.iter().any(|(o, d)| o + d.len() > end));
+    if overlapped {
+        println!("ipv4 fragment overlapped, discard id {identification:x}");
+        entry.discarded = true;
+        entry.fragments.clear();
+        entry.header.clear();
+        entry.first_fragment.clear();
+        return None;
+    }
+
+    // 保持するフラグメントの合計サイズが上限を超えるなら、他のデータグラムを古いものから捨てる
+    // 他になければこのデータグラムを捨てる
+    while reassembly.iter().map(|r| r.received_bytes()).sum::<usize>() + data.len()
+        > MAX_REASSEMBLY_BYTES
+    {
+        let Some(oldest) = (0..reassembly.len()).find(|&i| i != index) else {
+            println!("ipv4 reassembly buffer is full, discard id {identification:x}");
+            reassembly.remove(index);
+            return None;
+        };
+        reassembly.remove(oldest);
+        if oldest < index {
+            index -= 1;
+        }
+    }
+
+    let entry = &mut reassembly[index];
+    if offset == 0 {
+        entry.header = packet[..header_length].to_vec();
+        entry.first_fragment = packet.to_vec();
+    }
+    if !more {
+        entry.total_len = Some(end);
+    }
+    entry.fragments.push((offset, data.to_vec()));
+
+    let total_len = entry.total_len?;
+    if entry.header.is_empty() || entry.received_bytes() != total_len {
+        return None;
+    }
+
+    // 全てのフラグメントが揃ったのでつなげる
+    let mut entry = reassembly.remove(index);
+    entry.fragments.sort_by_key(|(offset, _)| *offset);
+    let mut buf = entry.header;
+    for (_, data) in entry.fragments {
+        buf.extend_from_slice(&data);
+    }
+    let total_len = buf.len() as u16;
+    buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+    // フラグとオフセットを消す、DFは先頭フラグメントのものを残す
+    let flags = to_u16(&buf[6..8]) & IPV4_FLAG_DONT_FRAGMENT;
+    buf[6..8].copy_from_slice(&flags.to_be_bytes());
+    set_ipv4_header_checksum(&mut buf);
+    println!(
+        "ipv4 reassembly completed id {identification:x} len {}",
+        buf.len()
+    );
+    Some(buf)
+}
+
+// 再構築のタイマー処理
+// タイムアウトしたデータグラムを破棄し、先頭フラグメントを受信していたものはTime Exceededを返すために返す
+pub fn ipv4_reassembly_timer() -> Vec<Vec<u8>> {
+    let now = Instant::now();
+    let mut expired = vec![];
+    IPV4_REASSEMBLY.lock().unwrap().retain_mut(|r| {
+        if now - r.created_at < IPV4_REASSEMBLY_TIMEOUT {
+            return true;
+        }
+        println!("ipv4 reassembly timeout id {:x}", r.identification);
+        if !r.first_fragment.is_empty() {
+            expired.push(std::mem::take(&mut r.first_fragment));
+        }
+        false
+    });
+    expired
+}
+
+// IPv4ヘッダのIdentification (RFC 6864 4.)
+// 同じ (送信元, 宛先, プロトコル) のパケットでは再構築で取り違えないように連番にする
+// 他の宛先に送ったIDから推測されないように、秘密の値を混ぜたハッシュで
+// それぞれ乱数で始まるカウンタを選ぶ
+pub fn next_ipv4_id(src_addr: u32, dst_addr: u32, protocol: u8) -> u16 {
+    let mut ids = IPV4_ID.lock().unwrap();
+    let ids = ids.get_or_insert_with(|| Ipv4IdCounters {
+        secret: random_u64(),
+        counters: (0..IPV4_ID_BUCKETS).map(|_| random_u64() as u16).collect(),
+    });
+    let mut hasher = DefaultHasher::new();
+    (ids.secret, src_addr, dst_addr, protocol).hash(&mut hasher);
+    let counter = &mut ids.counters[hasher.finish() as usize % IPV4_ID_BUCKETS];
+    *counter = counter.wrapping_add(1);
+    *counter
+}
+
+fn set_ipv4_header_checksum(packet: &mut [u8]) {
+    let header_length = (packet[0] & 0x0f) as usize * 4;
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let checksum = checksum(&packet[..header_length].to_vec());
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+// 2番目以降のフラグメントに付けるオプション
+// コピーフラグが立っているものだけ残して4byte単位にする (RFC 791 3.1)
+fn copied_options(options: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    let mut pos = 0;
+    while pos < options.len() {
+        let option_type = options[pos];
+        // End of Option List
+        if option_type == 0 {
+            break;
+        }
+        // No Operation
+        if option_type == 1 {
+            pos += 1;
+            continue;
+        }
+        let length = (options.get(pos + 1).copied().unwrap_or(0) as usize).max(2);
+        let end = (pos + length).min(options.len());
+        if option_type & IPV4_OPTION_COPIED != 0 {
+            buf.extend_from_slice(&options[pos..end]);
+        }
+        pos = end;
+    }
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+    buf
+}
+
+// MTUを超えるパケットをフラグメントに分割する
+// DFが立っていて分割できなければNoneを返す
+pub fn fragment_ipv4_packet(packet: Vec<u8>, mtu: usize) -> Option<Vec<Vec<u8>>> {
+    if packet.len() <= mtu {
+        return Some(vec![packet]);
+    }
+    let frag = to_u16(&packet[6..8]);
+    if frag & IPV4_FLAG_DONT_FRAGMENT != 0 {
+        println!(
+            "ipv4 packet length {} exceeds mtu {mtu} with DF",
+            packet.len()
+        );
+        return None;
+    }
+
+    let header_length = (packet[0] & 0x0f) as usize * 4;
+    // ヘッダの後ろに8byteのデータも入らないMTUでは分割できない
+    if mtu < header_length + 8 {
+        println!("mtu {mtu} is too small to fragment ipv4 packet");
+        return None;
+    }
+    let first_header = &packet[..header_length];
+    let mut other_header = packet[..20].to_vec();
+    other_header.extend(copied_options(&packet[20..header_length]));
+    other_header[0] = 0x40 | (other_header.len() / 4) as u8;
+
+    // 既にフラグメントだったパケットを分割する時は元のオフセットとMFを引き継ぐ
+    let base_offset = (frag & IPV4_FRAG_OFFSET_MASK) as usize * 8;
+    let more_fragments = frag & IPV4_FLAG_MORE_FRAGMENTS != 0;
+    let data = &packet[header_length..];
+
+    let mut fragments = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = if offset == 0 {
+            first_header
+        } else {
+            &other_header
+        };
+        // 最後以外のフラグメントのデータは8byteの倍数にする
+        let max_data_len = (mtu - header.len()) & !7;
+        let chunk_len = max_data_len.min(data.len() - offset);
+        let more = offset + chunk_len < data.len() || more_fragments;
+
+        let mut buf = header.to_vec();
+        buf.extend_from_slice(&data[offset..offset + chunk_len]);
+        let total_len = buf.len() as u16;
+        buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+        let mut frag = ((base_offset + offset) / 8) as u16;
+        if more {
+            frag |= IPV4_FLAG_MORE_FRAGMENTS;
+        }
+        buf[6..8].copy_from_slice(&frag.to_be_bytes());
+        set_ipv4_header_checksum(&mut buf);
+        fragments.push(buf);
+        offset += chunk_len;
+    }
+    Some(fragments)
+}
@@ -0,0 +1,256 @@
+use crate::arp::out_arp_request;
+use crate::ipv4_addr::{add_ipv4_addr, remove_ipv4_addr};
+use crate::route::{add_ipv4_route, remove_ipv4_interface_route, Ipv4Route};
+use crate::socket::interface_name;
+use crate::util::random_u64;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 169.254.0.0/16
+const IPV4_LINK_LOCAL_PREFIX: u32 = 0xa9fe_0000;
+const IPV4_LINK_LOCAL_PREFIX_LEN: u8 = 16;
+// 先頭と末尾の256個は予約されているので使わない (RFC 3927 2.1)
+const IPV4_LINK_LOCAL_FIRST: u32 = 0xa9fe_0100;
+const IPV4_LINK_LOCAL_COUNT: u32 = 0xfe00;
+
+// RFC 3927 9.
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u32 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_CONFLICTS: u32 = 10;
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+// アドレスがないインターフェースでリンクローカルアドレスを自動で設定するか
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4LinkLocalConfig {
+    pub enabled: bool,
+}
+
+static IPV4_LINK_LOCAL_CONFIG: Mutex<Ipv4LinkLocalConfig> =
+    Mutex::new(Ipv4LinkLocalConfig { enabled: true });
+
+pub fn set_ipv4_link_local_config(config: Ipv4LinkLocalConfig) {
+    *IPV4_LINK_LOCAL_CONFIG.lock().unwrap() = config;
+}
+
+pub fn get_ipv4_link_local_config() -> Ipv4LinkLocalConfig {
+    *IPV4_LINK_LOCAL_CONFIG.lock().unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4LinkLocalState {
+    Probing,    // ARP Probeで他に使われていないか確かめている
+    Announcing, // アドレスを使い始め、ARP Announcementを送っている
+    Bound,      // アドレスを使っている
+}
+
+// インターフェースごとのリンクローカルアドレスの状態
+#[derive(Debug, Clone)]
+pub struct Ipv4LinkLocal {
+    pub mac_addr: [u8; 6],
+    pub addr: u32,
+    pub state: Ipv4LinkLocalState,
+    sent: u32,        // 今の状態で送ったProbeかAnnouncementの数
+    next_at: Instant, // 次のProbeかAnnouncementを送る時刻
+    conflicts: u32,   // 衝突してアドレスを選び直した回数
+    attempt: u32,     // アドレスを選んだ回数
+    last_defended: Option<Instant>,
+}
+
+static IPV4_LINK_LOCALS: Mutex<Vec<Ipv4LinkLocal>> = Mutex::new(Vec::new());
+
+// 0..maxのランダムな時間
+fn random_duration(max: Duration) -> Duration {
+    Duration::from_millis(random_u64() % max.as_millis().max(1) as u64)
+}
+
+// MACアドレスを種にした疑似乱数でアドレスを選ぶ (RFC 3927 2.1)
+// 同じインターフェースでは再起動しても同じアドレスを最初に試す
+fn candidate_addr(mac_addr: [u8; 6], attempt: u32) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    (mac_addr, attempt).hash(&mut hasher);
+    IPV4_LINK_LOCAL_FIRST + (hasher.finish() % IPV4_LINK_LOCAL_COUNT as u64) as u32
+}
+
+pub fn is_ipv4_link_local(addr: u32) -> bool {
+    addr >> 16 == IPV4_LINK_LOCAL_PREFIX >> 16
+}
+
+// インターフェースでリンクローカルアドレスの設定を始める
+pub(crate) fn start_ipv4_link_local(mac_addr: [u8; 6]) {
+    if !get_ipv4_link_local_config().enabled {
+        return;
+    }
+    let mut link_locals = IPV4_LINK_LOCALS.lock().unwrap();
+    if link_locals.iter().any(|l| l.mac_addr == mac_addr) {
+        return;
+    }
+    let addr = candidate_addr(mac_addr, 0);
+    println!("probe ipv4 link-local addr {}", Ipv4Addr::from(addr));
+    link_locals.push(Ipv4LinkLocal {
+        mac_addr,
+        addr,
+        state: Ipv4LinkLocalState::Probing,
+        sent: 0,
+        next_at: Instant::now() + random_duration(PROBE_WAIT),
+        conflicts: 0,
+        attempt: 0,
+        last_defended: None,
+    });
+}
+
+pub fn get_ipv4_link_local_addrs() -> Vec<Ipv4LinkLocal> {
+    IPV4_LINK_LOCALS.lock().unwrap().clone()
+}
+
+impl Ipv4LinkLocal {
+    // 衝突したので別のアドレスでProbeからやり直す
+    // 衝突が続いたら1分に1回までに制限する (RFC 3927 2.2.1)
+    fn restart(&mut self, now: Instant) {
+        if self.state != Ipv4LinkLocalState::Probing {
+            self.unbind();
+        }
+        self.conflicts += 1;
+        self.attempt += 1;
+        self.addr = candidate_addr(self.mac_addr, self.attempt);
+        self.state = Ipv4LinkLocalState::Probing;
+        self.sent = 0;
+        self.last_defended = None;
+        self.next_at = if self.conflicts >= MAX_CONFLICTS {
+            now + RATE_LIMIT_INTERVAL
+        } else {
+            now + random_duration(PROBE_WAIT)
+        };
+        println!("probe ipv4 link-local addr {}", Ipv4Addr::from(self.addr));
+    }
+
+    fn bind(&self) {
+        add_ipv4_addr(self.addr, IPV4_LINK_LOCAL_PREFIX_LEN);
+        if let Some(if_name) = interface_name(self.mac_addr) {
+            add_ipv4_route(Ipv4Route {
+                prefix: IPV4_LINK_LOCAL_PREFIX,
+                prefix_len: IPV4_LINK_LOCAL_PREFIX_LEN,
+                gateway: None,
+                interface: if_name,
+                metric: 0,
+            });
+        }
+    }
+
+    fn unbind(&self) {
+        println!("give up ipv4 link-local addr {}", Ipv4Addr::from(self.addr));
+        remove_ipv4_addr(self.addr);
+        // 他のインターフェースのリンクローカルの経路は残す
+        if let Some(if_name) = interface_name(self.mac_addr) {
+            remove_ipv4_interface_route(
+                IPV4_LINK_LOCAL_PREFIX,
+                IPV4_LINK_LOCAL_PREFIX_LEN,
+                &if_name,
+            );
+        }
+    }
+}
+
+// ProbeとAnnouncementのタイマー処理
+// ブロードキャストするARPリクエストを返す
+pub(crate) fn ipv4_link_local_timer(mac_addr: [u8; 6]) -> Vec<Vec<u8>> {
+    let now = Instant::now();
+    let mut messages = vec![];
+    let mut link_locals = IPV4_LINK_LOCALS.lock().unwrap();
+    let Some(ll) = link_locals.iter_mut().find(|l| l.mac_addr == mac_addr) else {
+        return messages;
+    };
+    if now < ll.next_at {
+        return messages;
+    }
+    match ll.state {
+        // Probeは送信元を0.0.0.0にして、他のホストのARPテーブルを汚さない (RFC 3927 2.2.1)
+        Ipv4LinkLocalState::Probing if ll.sent < PROBE_NUM => {
+            messages.push(out_arp_request(mac_addr, 0, ll.addr));
+            ll.sent += 1;
+            ll.next_at = if ll.sent < PROBE_NUM {
+                now + PROBE_MIN + random_duration(PROBE_MAX - PROBE_MIN)
+            } else {
+                now + ANNOUNCE_WAIT
+            };
+        }
+        Ipv4LinkLocalState::Probing => {
+            println!("ipv4 link-local addr {} is unique", Ipv4Addr::from(ll.addr));
+            ll.bind();
+            ll.state = Ipv4LinkLocalState::Announcing;
+            messages.push(out_arp_request(mac_addr, ll.addr, ll.addr));
+            ll.sent = 1;
+            ll.next_at = now + ANNOUNCE_INTERVAL;
+        }
+        // Announcementは送信元と問い合わせ先を自分のアドレスにする (RFC 3927 2.4)
+        Ipv4LinkLocalState::Announcing => {
+            messages.push(out_arp_request(mac_addr, ll.addr, ll.addr));
+            ll.sent += 1;
+            if ll.sent >= ANNOUNCE_NUM {
+                ll.state = Ipv4LinkLocalState::Bound;
+                ll.conflicts = 0;
+            } else {
+                ll.next_at = now + ANNOUNCE_INTERVAL;
+            }
+        }
+        Ipv4LinkLocalState::Bound => {}
+    }
+    messages
+}
+
+// 受信したARPで衝突を調べる (RFC 3927 2.2.1, 2.5)
+// Probe中は送信元が同じアドレスか、他のホストが同じアドレスをProbeしていれば衝突
+// 使用中のアドレスを名乗られたら、DEFEND_INTERVALに1回だけAnnouncementで守る
+// 守るためのARPリクエストがあれば返す
+pub(crate) fn read_ipv4_link_local_arp(
+    mac_addr: [u8; 6],
+    src_mac_addr: [u8; 6],
+    src_ip_addr: u32,
+    dst_ip_addr: u32,
+    is_request: bool,
+) -> Option<Vec<u8>> {
+    if src_mac_addr == mac_addr {
+        return None;
+    }
+    let now = Instant::now();
+    let mut link_locals = IPV4_LINK_LOCALS.lock().unwrap();
+    let ll = link_locals.iter_mut().find(|l| l.mac_addr == mac_addr)?;
+    match ll.state {
+        Ipv4LinkLocalState::Probing => {
+            let probe_conflict = is_request && src_ip_addr == 0 && dst_ip_addr == ll.addr;
+            if src_ip_addr == ll.addr || probe_conflict {
+                println!(
+                    "ipv4 link-local addr {} conflicts with {:?}",
+                    Ipv4Addr::from(ll.addr),
+                    src_mac_addr
+                );
+                ll.restart(now);
+            }
+            None
+        }
+        _ if src_ip_addr == ll.addr => {
+            println!(
+                "ipv4 link-local addr {} is claimed by {:?}",
+                Ipv4Addr::from(ll.addr),
+                src_mac_addr
+            );
+            if ll
+                .last_defended
+                .is_some_and(|at| now - at < DEFEND_INTERVAL)
+            {
+                ll.restart(now);
+                return None;
+            }
+            ll.last_defended = Some(now);
+            Some(out_arp_request(mac_addr, ll.addr, ll.addr))
+        }
+        _ => None,
+    }
+}
@@ -1,10 +1,68 @@
-use crate::arp::{add_arp_tables, add_arp_tables_v6, search_arp_tables, search_arp_tables_v6};
+use crate::addrconf::{get_ipv6_link_params, is_my_ipv6_dst, select_ipv6_reply_src};
+use crate::conntrack::ct_track;
 use crate::ethernet::EthernetHeader;
-use crate::icmpv6::read_icmpv6_packet;
+use crate::filter::{filter_packet, FilterAction, FilterChain};
+use crate::forward::{forward_ipv6_packet, should_forward_ipv6};
+use crate::icmpv6::{
+    out_icmpv6_error_reply, read_icmpv6_packet, ICMPV6_CODE_ADMIN_PROHIBITED,
+    ICMPV6_CODE_ERRONEOUS_HEADER_FIELD, ICMPV6_CODE_PORT_UNREACHABLE,
+    ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER, ICMPV6_CODE_UNRECOGNIZED_OPTION,
+    ICMPV6_TYPE_DESTINATION_UNREACHABLE, ICMPV6_TYPE_PARAMETER_PROBLEM, ND_HOP_LIMIT,
+};
+use crate::ipv4::{IP_PROTOCOL_NUMBER_TCP, IP_PROTOCOL_NUMBER_UDP};
+use crate::ipv6_frag::{reassemble_ipv6, Ipv6ReassemblyResult};
+use crate::loopback::is_ipv6_martian;
+use crate::mld::{is_ipv6_multicast_member, MLD_HOP_LIMIT};
+use crate::route::lookup_ipv6_route;
+use crate::socket::{get_traffic_class, interface_name, SocketProtocol};
+use crate::udp::{is_udp_port_open, read_udp6_packet};
+use crate::util::{random_u64, to_u16};
 use bytes::{Buf, BufMut};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
 
 pub const IP_PROTOCOL_NUMBER_ICMPV6: u8 = 58;
-const FLOW_LABEL: u32 = 0x137a;
+// 拡張ヘッダ
+pub(crate) const IP_PROTOCOL_NUMBER_HOP_BY_HOP: u8 = 0;
+pub(crate) const IP_PROTOCOL_NUMBER_ROUTING: u8 = 43;
+pub(crate) const IP_PROTOCOL_NUMBER_FRAGMENT: u8 = 44;
+const IP_PROTOCOL_NUMBER_ESP: u8 = 50;
+const IP_PROTOCOL_NUMBER_AH: u8 = 51;
+const IP_PROTOCOL_NUMBER_NO_NEXT_HEADER: u8 = 59;
+pub(crate) const IP_PROTOCOL_NUMBER_DEST_OPTIONS: u8 = 60;
+
+// Hop-by-Hop/Destination Optionsのオプション
+const IPV6_OPTION_PAD1: u8 = 0;
+const IPV6_OPTION_PADN: u8 = 1;
+const IPV6_OPTION_ROUTER_ALERT: u8 = 5;
+
+const IPV6_HEADER_LENGTH: usize = 40;
+// IPv6ヘッダのNext Headerフィールドの位置
+const IPV6_NEXT_HEADER_OFFSET: usize = 6;
+const IPV6_FLOW_LABEL_MASK: u32 = 0x000f_ffff;
+
+// ff02::1 全ノードマルチキャストアドレス
+pub const IPV6_ALL_NODES_ADDR: u128 = 0xff02_0000_0000_0000_0000_0000_0000_0001;
+// ff02::2 全ルーターマルチキャストアドレス
+pub const IPV6_ALL_ROUTERS_ADDR: u128 = 0xff02_0000_0000_0000_0000_0000_0000_0002;
+// ff02::1:ff00:0/104 Solicited-Nodeマルチキャストアドレス
+const IPV6_SOLICITED_NODE_PREFIX: u128 = 0xff02_0000_0000_0000_0000_0001_ff00_0000;
+
+static FLOW_LABEL_SECRET: Mutex<Option<u64>> = Mutex::new(None);
+
+// 拡張ヘッダを辿った結果
+enum ExtensionHeaderResult {
+    // 上位層のプロトコル番号とパケット先頭からの位置
+    UpperLayer(u8, usize),
+    // 黙って破棄する
+    Discard,
+    // Parameter Problemを返す
+    // コード、問題のある位置、マルチキャスト宛てでも返すか
+    ParameterProblem(u8, usize, bool),
+    // 再構築が必要なフラグメント
+    // Fragmentヘッダの位置と、それを指しているNext Headerフィールドの位置
+    Fragment(usize, usize),
+}
 
 #[derive(Debug)]
 pub struct IPv6Header {
@@ -13,23 +71,37 @@ pub struct IPv6Header {
     flow_label: u32,
     header_length: u16,
     next_header: u8,
-    hop_limit: u8,
-    src_addr: u128,
-    dst_addr: u128,
+    pub(crate) hop_limit: u8,
+    pub(crate) src_addr: u128,
+    pub(crate) dst_addr: u128,
+}
+
+pub fn is_ipv6_multicast(addr: u128) -> bool {
+    addr >> 120 == 0xff
+}
+
+pub fn solicited_node_addr(addr: u128) -> u128 {
+    IPV6_SOLICITED_NODE_PREFIX | (addr & 0xff_ffff)
+}
+
+// マルチキャストアドレスに対応するMACアドレス 33:33:xx:xx:xx:xx
+pub fn ipv6_multicast_mac_addr(addr: u128) -> [u8; 6] {
+    let low = (addr as u32).to_be_bytes();
+    [0x33, 0x33, low[0], low[1], low[2], low[3]]
 }
 
 pub fn read_ipv6_packet(
     eth_header: EthernetHeader,
     packet: Vec<u8>,
-    ipv6: u128,
+    my_mac_addr: [u8; 6],
 ) -> (u128, Vec<u8>) {
     let mut buf = &packet[..];
     let first_32_bits = buf.get_u32();
 
-    let mut ipv6_header = IPv6Header {
+    let ipv6_header = IPv6Header {
         version: (first_32_bits >> 28) as u8,
         traffic_class: ((first_32_bits >> 20) & 0xff) as u8,
-        flow_label: first_32_bits & 0x000fffff,
+        flow_label: first_32_bits & IPV6_FLOW_LABEL_MASK,
         header_length: buf.get_u16(),
         next_header: buf.get_u8(),
         hop_limit: buf.get_u8(),
@@ -37,30 +109,114 @@ pub fn read_ipv6_packet(
         dst_addr: buf.get_u128(),
     };
 
-    // 自分宛てのパケットでなければreturn
-    if ipv6 != ipv6_header.dst_addr {
+    if is_ipv6_martian(my_mac_addr, ipv6_header.src_addr, ipv6_header.dst_addr) {
         return (0, vec![]);
     }
 
-    // ARPテーブルを検索して存在していなければ追加
-    if search_arp_tables_v6(ipv6_header.src_addr) == [0, 0, 0, 0, 0, 0] {
-        add_arp_tables_v6(eth_header.src_mac_addr, ipv6_header.src_addr)
+    // 自分宛てのパケットか、参加しているマルチキャストグループ宛てでなければ
+    // ルーターとして転送するか捨てる
+    let is_mine = is_my_ipv6_dst(ipv6_header.dst_addr)
+        || is_ipv6_multicast_member(ipv6_header.dst_addr, ipv6_header.src_addr);
+    let forward = !is_mine
+        && eth_header.dst_mac_addr[0] & 0x01 == 0
+        && should_forward_ipv6(ipv6_header.dst_addr);
+    if !is_mine && !forward {
+        return (0, vec![]);
     }
 
-    match ipv6_header.next_header {
+    // Ethernetのパディングを取り除く
+    let packet_len = IPV6_HEADER_LENGTH + ipv6_header.header_length as usize;
+    if packet.len() < packet_len {
+        return (0, vec![]);
+    }
+    let mut packet = packet[..packet_len].to_vec();
+    let in_interface = interface_name(my_mac_addr);
+    if forward {
+        return forward_ipv6_packet(packet, in_interface.as_deref());
+    }
+
+    // フラグメントなら再構築してから、改めて拡張ヘッダを辿る
+    let (protocol, offset) = loop {
+        match walk_extension_headers(&packet) {
+            ExtensionHeaderResult::UpperLayer(protocol, offset) => break (protocol, offset),
+            ExtensionHeaderResult::Discard => return (0, vec![]),
+            ExtensionHeaderResult::ParameterProblem(code, pointer, to_multicast) => {
+                return out_ipv6_parameter_problem(&packet, code, pointer, to_multicast);
+            }
+            ExtensionHeaderResult::Fragment(frag_pos, next_header_pos) => {
+                match reassemble_ipv6(&packet, frag_pos, next_header_pos) {
+                    Ipv6ReassemblyResult::Complete(reassembled) => packet = reassembled,
+                    Ipv6ReassemblyResult::Pending => return (0, vec![]),
+                    Ipv6ReassemblyResult::ParameterProblem(pointer) => {
+                        return out_ipv6_parameter_problem(
+                            &packet,
+                            ICMPV6_CODE_ERRONEOUS_HEADER_FIELD,
+                            pointer,
+                            false,
+                        );
+                    }
+                }
+            }
+        }
+    };
+
+    let ct_state = ct_track(&packet);
+    match filter_packet(
+        FilterChain::Input,
+        &packet,
+        ct_state,
+        in_interface.as_deref(),
+        None,
+    ) {
+        FilterAction::Accept => {}
+        FilterAction::Drop => return (0, vec![]),
+        FilterAction::Reject => {
+            return out_icmpv6_error_reply(
+                &packet,
+                ICMPV6_TYPE_DESTINATION_UNREACHABLE,
+                ICMPV6_CODE_ADMIN_PROHIBITED,
+                0,
+                false,
+            );
+        }
+    }
+
+    let buf = &packet[offset..];
+    match protocol {
         IP_PROTOCOL_NUMBER_ICMPV6 => {
             println!("receive icmpv6 packet");
-            let packet = read_icmpv6_packet(
-                ipv6_header.dst_addr,
-                ipv6_header.src_addr,
-                buf[..].to_owned(),
+            let (src_addr, dest_addr, packet) =
+                read_icmpv6_packet(&ipv6_header, buf[..].to_owned(), my_mac_addr);
+            if dest_addr == 0 {
+                return (0, vec![]);
+            }
+            return (
+                dest_addr,
+                out_ipv6_packet(src_addr, dest_addr, IP_PROTOCOL_NUMBER_ICMPV6, packet),
             );
+        }
+        IP_PROTOCOL_NUMBER_UDP => {
+            println!("receive udp packet");
+            if buf.len() >= 4 && !is_udp_port_open(to_u16(&buf[2..])) {
+                return out_icmpv6_error_reply(
+                    &packet,
+                    ICMPV6_TYPE_DESTINATION_UNREACHABLE,
+                    ICMPV6_CODE_PORT_UNREACHABLE,
+                    0,
+                    false,
+                );
+            }
+            let packet = read_udp6_packet(&ipv6_header, buf.to_owned());
+            if packet.is_empty() {
+                return (0, vec![]);
+            }
+            let src_addr = select_ipv6_reply_src(ipv6_header.dst_addr, ipv6_header.src_addr);
             return (
                 ipv6_header.src_addr,
                 out_ipv6_packet(
-                    ipv6_header.dst_addr,
+                    src_addr,
                     ipv6_header.src_addr,
-                    IP_PROTOCOL_NUMBER_ICMPV6,
+                    IP_PROTOCOL_NUMBER_UDP,
                     packet,
                 ),
             );
@@ -72,35 +228,295 @@ pub fn read_ipv6_packet(
     (0, vec![])
 }
 
-pub fn out_ipv6_packet(
+// 拡張ヘッダを順番に処理して上位層のプロトコルを探す (RFC 8200 4.)
+fn walk_extension_headers(packet: &[u8]) -> ExtensionHeaderResult {
+    let mut next_header = packet[IPV6_NEXT_HEADER_OFFSET];
+    let mut next_header_pos = IPV6_NEXT_HEADER_OFFSET;
+    let mut offset = IPV6_HEADER_LENGTH;
+
+    loop {
+        let header_len = match next_header {
+            IP_PROTOCOL_NUMBER_ICMPV6 | IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP => {
+                return ExtensionHeaderResult::UpperLayer(next_header, offset);
+            }
+            IP_PROTOCOL_NUMBER_NO_NEXT_HEADER => {
+                return ExtensionHeaderResult::Discard;
+            }
+            IP_PROTOCOL_NUMBER_ESP => {
+                // SAを持っていないので復号できない
+                println!("esp is not supported");
+                return ExtensionHeaderResult::Discard;
+            }
+            // Hop-by-HopはIPv6ヘッダの直後にしか置けない
+            IP_PROTOCOL_NUMBER_HOP_BY_HOP if offset != IPV6_HEADER_LENGTH => {
+                return ExtensionHeaderResult::ParameterProblem(
+                    ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+                    next_header_pos,
+                    false,
+                );
+            }
+            IP_PROTOCOL_NUMBER_HOP_BY_HOP | IP_PROTOCOL_NUMBER_DEST_OPTIONS => {
+                let Some(header_len) = extension_header_len(packet, offset, 8) else {
+                    return ExtensionHeaderResult::Discard;
+                };
+                if let Some(result) = process_options(packet, offset + 2, offset + header_len) {
+                    return result;
+                }
+                header_len
+            }
+            IP_PROTOCOL_NUMBER_ROUTING => {
+                let Some(header_len) = extension_header_len(packet, offset, 8) else {
+                    return ExtensionHeaderResult::Discard;
+                };
+                // Segments Leftが0なら無視して次へ、そうでなければ対応しているRouting Typeがない
+                if packet[offset + 3] != 0 {
+                    return ExtensionHeaderResult::ParameterProblem(
+                        ICMPV6_CODE_ERRONEOUS_HEADER_FIELD,
+                        offset + 2,
+                        false,
+                    );
+                }
+                header_len
+            }
+            IP_PROTOCOL_NUMBER_FRAGMENT => {
+                if packet.len() < offset + 8 {
+                    return ExtensionHeaderResult::Discard;
+                }
+                // Fragment OffsetもMフラグも0のAtomic Fragmentは再構築せずに処理する (RFC 6946)
+                let frag = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]);
+                if frag & 0xfff9 != 0 {
+                    return ExtensionHeaderResult::Fragment(offset, next_header_pos);
+                }
+                8
+            }
+            IP_PROTOCOL_NUMBER_AH => {
+                // AHの長さは4byte単位で、先頭の8byteを含まない
+                if packet.len() < offset + 2 {
+                    return ExtensionHeaderResult::Discard;
+                }
+                let header_len = (packet[offset + 1] as usize + 2) * 4;
+                if packet.len() < offset + header_len {
+                    return ExtensionHeaderResult::Discard;
+                }
+                header_len
+            }
+            _ => {
+                return ExtensionHeaderResult::ParameterProblem(
+                    ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+                    next_header_pos,
+                    false,
+                );
+            }
+        };
+        next_header = packet[offset];
+        next_header_pos = offset;
+        offset += header_len;
+    }
+}
+
+// 拡張ヘッダの長さ
+// Hdr Ext Lenは先頭のunitを含まない
+fn extension_header_len(packet: &[u8], offset: usize, unit: usize) -> Option<usize> {
+    let header_len = (*packet.get(offset + 1)? as usize + 1) * unit;
+    if packet.len() < offset + header_len {
+        return None;
+    }
+    Some(header_len)
+}
+
+// Hop-by-Hop/Destination Optionsのオプションを処理する
+// 知らないオプションはタイプの上位2bitに従って処理する
+fn process_options(packet: &[u8], mut pos: usize, end: usize) -> Option<ExtensionHeaderResult> {
+    while pos < end {
+        let option_type = packet[pos];
+        if option_type == IPV6_OPTION_PAD1 {
+            pos += 1;
+            continue;
+        }
+        if pos + 2 > end || pos + 2 + packet[pos + 1] as usize > end {
+            return Some(ExtensionHeaderResult::ParameterProblem(
+                ICMPV6_CODE_ERRONEOUS_HEADER_FIELD,
+                pos + 1,
+                false,
+            ));
+        }
+        match option_type {
+            IPV6_OPTION_PADN | IPV6_OPTION_ROUTER_ALERT => {}
+            _ => match option_type >> 6 {
+                0b00 => {}
+                0b01 => return Some(ExtensionHeaderResult::Discard),
+                0b10 => {
+                    return Some(ExtensionHeaderResult::ParameterProblem(
+                        ICMPV6_CODE_UNRECOGNIZED_OPTION,
+                        pos,
+                        true,
+                    ))
+                }
+                _ => {
+                    return Some(ExtensionHeaderResult::ParameterProblem(
+                        ICMPV6_CODE_UNRECOGNIZED_OPTION,
+                        pos,
+                        false,
+                    ))
+                }
+            },
+        }
+        pos += 2 + packet[pos + 1] as usize;
+    }
+    None
+}
+
+// Parameter Problemを返す
+fn out_ipv6_parameter_problem(
+    packet: &[u8],
+    code: u8,
+    pointer: usize,
+    to_multicast: bool,
+) -> (u128, Vec<u8>) {
+    println!("ipv6 parameter problem code {code} pointer {pointer}");
+    out_icmpv6_error_reply(
+        packet,
+        ICMPV6_TYPE_PARAMETER_PROBLEM,
+        code,
+        pointer as u32,
+        to_multicast,
+    )
+}
+
+pub fn out_ipv6_packet(src_addr: u128, dest_addr: u128, protocol: u8, payload: Vec<u8>) -> Vec<u8> {
+    let hop_limit = default_hop_limit(protocol, &payload);
+    out_ipv6_packet_with_hop_limit(src_addr, dest_addr, protocol, hop_limit, payload)
+}
+
+fn out_ipv6_packet_with_hop_limit(
     src_addr: u128,
     dest_addr: u128,
     protocol: u8,
+    hop_limit: u8,
     mut payload: Vec<u8>,
 ) -> Vec<u8> {
-    let mut ipv6_header = IPv6Header {
+    let ports = transport_ports(protocol, &payload);
+    let ipv6_header = IPv6Header {
         version: 6,
-        traffic_class: 0,
-        flow_label: FLOW_LABEL,
+        traffic_class: outgoing_traffic_class(protocol, ports),
+        flow_label: flow_label(src_addr, dest_addr, protocol, ports),
         header_length: payload.len() as u16,
         next_header: protocol,
-        hop_limit: 64,
-        src_addr: src_addr,
+        hop_limit,
+        src_addr,
         dst_addr: dest_addr,
     };
     let mut buf = Vec::new();
-    buf.put_u8(ipv6_header.version << 4);
-    // 暫定処理
-    buf.put_u8(0x0f);
-    buf.put_u8(0xc9);
-    buf.put_u8(0x29);
-    //
-    buf.put_u16(ipv6_header.header_length);
-    buf.put_u8(ipv6_header.next_header);
-    buf.put_u8(ipv6_header.hop_limit);
-    buf.put_u128(ipv6_header.src_addr);
-    buf.put_u128(ipv6_header.dst_addr);
+    ipv6_header.write(&mut buf);
     buf.append(&mut payload);
 
+    // 送信するパケットは拒否しても自分にICMPv6を返せないので捨てるだけ
+    let out_interface = lookup_ipv6_route(dest_addr).map(|route| route.interface);
+    let ct_state = ct_track(&buf);
+    if filter_packet(
+        FilterChain::Output,
+        &buf,
+        ct_state,
+        None,
+        out_interface.as_deref(),
+    ) != FilterAction::Accept
+    {
+        return vec![];
+    }
+
     buf
 }
+
+// MLDのメッセージはRouter Alertオプション付きのHop-by-Hopヘッダを付けて
+// Hop Limit 1で送る (RFC 3810 5.)
+pub fn out_ipv6_mld_packet(src_addr: u128, dest_addr: u128, icmp: Vec<u8>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.put_u8(IP_PROTOCOL_NUMBER_ICMPV6);
+    payload.put_u8(0); // Hdr Ext Len
+                       // Router Alert (値0はMLD)
+    payload.put_u8(IPV6_OPTION_ROUTER_ALERT);
+    payload.put_u8(2);
+    payload.put_u16(0);
+    payload.put_u8(IPV6_OPTION_PADN);
+    payload.put_u8(0);
+    payload.extend(icmp);
+    out_ipv6_packet_with_hop_limit(
+        src_addr,
+        dest_addr,
+        IP_PROTOCOL_NUMBER_HOP_BY_HOP,
+        MLD_HOP_LIMIT,
+        payload,
+    )
+}
+
+impl IPv6Header {
+    // ヘッダをバイト列にする
+    // 先頭32bitは Version(4bit) | Traffic Class(8bit) | Flow Label(20bit)
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(
+            (self.version as u32) << 28
+                | (self.traffic_class as u32) << 20
+                | (self.flow_label & IPV6_FLOW_LABEL_MASK),
+        );
+        buf.put_u16(self.header_length);
+        buf.put_u8(self.next_header);
+        buf.put_u8(self.hop_limit);
+        buf.put_u128(self.src_addr);
+        buf.put_u128(self.dst_addr);
+    }
+}
+
+// TCP/UDPなら (送信元ポート, 宛先ポート)
+fn transport_ports(protocol: u8, payload: &[u8]) -> Option<(u16, u16)> {
+    if protocol != IP_PROTOCOL_NUMBER_TCP && protocol != IP_PROTOCOL_NUMBER_UDP {
+        return None;
+    }
+    if payload.len() < 4 {
+        return None;
+    }
+    Some((to_u16(&payload[0..]), to_u16(&payload[2..])))
+}
+
+// 送信元ポートのソケットに設定されたTraffic Class
+fn outgoing_traffic_class(protocol: u8, ports: Option<(u16, u16)>) -> u8 {
+    let Some((src_port, _)) = ports else {
+        return 0;
+    };
+    let protocol = if protocol == IP_PROTOCOL_NUMBER_TCP {
+        SocketProtocol::Tcp
+    } else {
+        SocketProtocol::Udp
+    };
+    get_traffic_class(protocol, src_port).to_u8()
+}
+
+// Flow Labelを5-tupleのハッシュから生成する (RFC 6437 3.)
+// 推測されないように起動ごとの秘密の値を混ぜる
+// ポートのないプロトコルは3-tuple (送信元、宛先、プロトコル) を使う
+fn flow_label(src_addr: u128, dst_addr: u128, protocol: u8, ports: Option<(u16, u16)>) -> u32 {
+    let secret = *FLOW_LABEL_SECRET
+        .lock()
+        .unwrap()
+        .get_or_insert_with(random_u64);
+    let mut hasher = DefaultHasher::new();
+    (secret, src_addr, dst_addr, protocol, ports).hash(&mut hasher);
+    let hash = hasher.finish();
+    // 64bitを20bitに畳み込む
+    let label = (hash ^ (hash >> 20) ^ (hash >> 40)) as u32 & IPV6_FLOW_LABEL_MASK;
+    // 0はラベルなしを意味するので避ける
+    if label == 0 {
+        1
+    } else {
+        label
+    }
+}
+
+// Neighbor Discovery (ICMPv6 Type 133〜137) はHop Limit 255で送る
+fn default_hop_limit(protocol: u8, payload: &[u8]) -> u8 {
+    if protocol == IP_PROTOCOL_NUMBER_ICMPV6
+        && payload.first().is_some_and(|t| (133..=137).contains(t))
+    {
+        return ND_HOP_LIMIT;
+    }
+    get_ipv6_link_params().hop_limit
+}
@@ -0,0 +1,277 @@
+use crate::ipv6::{
+    IP_PROTOCOL_NUMBER_DEST_OPTIONS, IP_PROTOCOL_NUMBER_FRAGMENT, IP_PROTOCOL_NUMBER_HOP_BY_HOP,
+    IP_PROTOCOL_NUMBER_ROUTING,
+};
+use crate::util::{random_u64, to_u16, to_u32};
+use bytes::BufMut;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// RFC 8200 4.5 再構築の待ち時間
+const IPV6_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+// 同時に再構築するデータグラム数と、保持するフラグメントの合計サイズの上限
+const MAX_REASSEMBLIES: usize = 64;
+const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+const IPV6_HEADER_LENGTH: usize = 40;
+const FRAGMENT_HEADER_LENGTH: usize = 8;
+const IPV6_MAX_PAYLOAD_LENGTH: usize = 65535;
+
+// 再構築中のデータグラム
+struct Ipv6Reassembly {
+    src_addr: u128,
+    dst_addr: u128,
+    identification: u32,
+    unfragmentable: Vec<u8>, // 先頭フラグメントのFragmentヘッダより前の部分
+    first_fragment: Vec<u8>, // Time Exceededに付ける先頭フラグメント
+    fragments: Vec<(usize, Vec<u8>)>, // (オフセット, データ)
+    total_len: Option<usize>, // 最後のフラグメントを受信したら判明する
+    created_at: Instant,
+    discarded: bool, // 重複を検知して破棄したデータグラム
+}
+
+impl Ipv6Reassembly {
+    fn received_bytes(&self) -> usize {
+        self.fragments.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+// フラグメントを受け取った結果
+pub enum Ipv6ReassemblyResult {
+    Complete(Vec<u8>),       // 再構築が完了したパケット
+    Pending,                 // 残りのフラグメントを待つか破棄した
+    ParameterProblem(usize), // 不正なフラグメント、問題のある位置
+}
+
+static IPV6_REASSEMBLY: Mutex<Vec<Ipv6Reassembly>> = Mutex::new(Vec::new());
+static FRAGMENT_ID: Mutex<Option<u32>> = Mutex::new(None);
+
+// フラグメントを再構築する (RFC 8200 4.5, RFC 5722)
+// frag_pos はFragmentヘッダの位置、next_header_pos はFragmentヘッダを指しているNext Headerフィールドの位置
+pub fn reassemble_ipv6(
+    packet: &[u8],
+    frag_pos: usize,
+    next_header_pos: usize,
+) -> Ipv6ReassemblyResult {
+    if packet.len() < frag_pos + FRAGMENT_HEADER_LENGTH {
+        return Ipv6ReassemblyResult::Pending;
+    }
+    let src_addr = u128::from_be_bytes(packet[8..24].try_into().unwrap());
+    let dst_addr = u128::from_be_bytes(packet[24..40].try_into().unwrap());
+    let next_header = packet[frag_pos];
+    let frag = to_u16(&packet[frag_pos + 2..]);
+    let identification = to_u32(&packet[frag_pos + 4..]);
+    let offset = (frag >> 3) as usize * 8;
+    let more = frag & 0x1 != 0;
+    let data = &packet[frag_pos + FRAGMENT_HEADER_LENGTH..];
+
+    // 最後以外のフラグメントは8byteの倍数でなければならない
+    if more && !data.len().is_multiple_of(8) {
+        // Payload Lengthフィールドを指す
+        return Ipv6ReassemblyResult::ParameterProblem(4);
+    }
+    if offset + data.len() > IPV6_MAX_PAYLOAD_LENGTH {
+        // Fragment Offsetフィールドを指す
+        return Ipv6ReassemblyResult::ParameterProblem(frag_pos + 2);
+    }
+
+    let mut reassembly = IPV6_REASSEMBLY.lock().unwrap();
+    let mut index = match reassembly.iter().position(|r| {
+        r.src_addr == src_addr && r.dst_addr == dst_addr && r.identification == identification
+    }) {
+        Some(index) => index,
+        None => {
+            // 上限を超えるなら一番古いものを捨てる
+            if reassembly.len() >= MAX_REASSEMBLIES {
+                reassembly.remove(0);
+            }
+            reassembly.push(Ipv6Reassembly {
+                src_addr,
+                dst_addr,
+                identification,
+                unfragmentable: vec![],
+                first_fragment: vec![],
+                fragments: vec![],
+                total_len: None,
+                created_at: Instant::now(),
+                discarded: false,
+            });
+            reassembly.len() - 1
+        }
+    };
+    let entry = &mut reassembly[index];
+    if entry.discarded {
+        return Ipv6ReassemblyResult::Pending;
+    }
+
+    let end = offset + data.len();
+    // 完全に同じフラグメントは経路上で複製されたものなので、それだけ捨てる
+    if entry
+        .fragments
+        .iter()
+        .any(|(o, d)| *o == offset && d.as_slice() == data)
+    {
+        return Ipv6ReassemblyResult::Pending;
+    }
+    // 重複するフラグメントがあればデータグラムごと破棄する (RFC 5722)
+    let overlapped = entry
+        .fragments
+        .iter()
+        .any(|(o, d)| offset < o + d.len() && *o < end)
+        || entry
+            .total_len
+            .is_some_and(|total| end > total || (!more && end != total))
+        || (!more && entry.fragments.iter().any(|(o, d)| o + d.len() > end));
+    if overlapped {
+        println!("ipv6 fragment overlapped, discard id {identification:x}");
+        entry.discarded = true;
+        entry.fragments.clear();
+        entry.unfragmentable.clear();
+        entry.first_fragment.clear();
+        return Ipv6ReassemblyResult::Pending;
+    }
+
+    // 保持するフラグメントの合計サイズが上限を超えるなら、他のデータグラムを古いものから捨てる
+    // 他になければこのデータグラムを捨てる
+    while reassembly.iter().map(|r| r.received_bytes()).sum::<usize>() + data.len()
+        > MAX_REASSEMBLY_BYTES
+    {
+        let Some(oldest) = (0..reassembly.len()).find(|&i| i != index) else {
+            println!("ipv6 reassembly buffer is full, discard id {identification:x}");
+            reassembly.remove(index);
+            return Ipv6ReassemblyResult::Pending;
+        };
+        reassembly.remove(oldest);
+        if oldest < index {
+            index -= 1;
+        }
+    }
+
+    let entry = &mut reassembly[index];
+    if offset == 0 {
+        let mut unfragmentable = packet[..frag_pos].to_vec();
+        unfragmentable[next_header_pos] = next_header;
+        entry.unfragmentable = unfragmentable;
+        entry.first_fragment = packet.to_vec();
+    }
+    if !more {
+        entry.total_len = Some(end);
+    }
+    entry.fragments.push((offset, data.to_vec()));
+
+    let Some(total_len) = entry.total_len else {
+        return Ipv6ReassemblyResult::Pending;
+    };
+    if entry.unfragmentable.is_empty() || entry.received_bytes() != total_len {
+        return Ipv6ReassemblyResult::Pending;
+    }
+
+    // 全てのフラグメントが揃ったのでつなげる
+    let mut entry = reassembly.remove(index);
+    entry.fragments.sort_by_key(|(offset, _)| *offset);
+    let mut buf = entry.unfragmentable;
+    for (_, data) in entry.fragments {
+        buf.extend_from_slice(&data);
+    }
+    let payload_len = buf.len() - IPV6_HEADER_LENGTH;
+    if payload_len > IPV6_MAX_PAYLOAD_LENGTH {
+        return Ipv6ReassemblyResult::Pending;
+    }
+    buf[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+    println!(
+        "ipv6 reassembly completed id {identification:x} len {}",
+        buf.len()
+    );
+    Ipv6ReassemblyResult::Complete(buf)
+}
+
+// 再構築のタイマー処理
+// タイムアウトしたデータグラムを破棄し、先頭フラグメントを受信していたものはTime Exceededを返すために返す
+pub fn ipv6_reassembly_timer() -> Vec<Vec<u8>> {
+    let now = Instant::now();
+    let mut expired = vec![];
+    IPV6_REASSEMBLY.lock().unwrap().retain_mut(|r| {
+        if now - r.created_at < IPV6_REASSEMBLY_TIMEOUT {
+            return true;
+        }
+        println!("ipv6 reassembly timeout id {:x}", r.identification);
+        if !r.first_fragment.is_empty() {
+            expired.push(std::mem::take(&mut r.first_fragment));
+        }
+        false
+    });
+    expired
+}
+
+fn next_fragment_id() -> u32 {
+    let mut id = FRAGMENT_ID.lock().unwrap();
+    // 初期値は推測されにくいように乱数にする
+    let next = id.unwrap_or(random_u64() as u32).wrapping_add(1);
+    *id = Some(next);
+    next
+}
+
+// Fragmentヘッダより前に置く必要がある部分の長さ
+// Hop-by-Hop、Routing、Routingの前のDestination Options
+fn unfragmentable_len(packet: &[u8]) -> usize {
+    let mut next_header = packet[6];
+    let mut offset = IPV6_HEADER_LENGTH;
+    let mut unfragmentable = offset;
+    while offset + 2 <= packet.len() {
+        let header_len = (packet[offset + 1] as usize + 1) * 8;
+        match next_header {
+            IP_PROTOCOL_NUMBER_HOP_BY_HOP | IP_PROTOCOL_NUMBER_ROUTING => {
+                unfragmentable = offset + header_len;
+            }
+            IP_PROTOCOL_NUMBER_DEST_OPTIONS => {}
+            _ => break,
+        }
+        next_header = packet[offset];
+        offset += header_len;
+    }
+    unfragmentable
+}
+
+// MTUを超えるパケットをフラグメントに分割する
+pub fn fragment_ipv6_packet(packet: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    if packet.len() <= mtu {
+        return vec![packet];
+    }
+
+    let unfragmentable = unfragmentable_len(&packet);
+    // 分割できない部分とFragmentヘッダの後ろに8byteのデータも入らなければ送らない
+    if mtu < unfragmentable + FRAGMENT_HEADER_LENGTH + 8 {
+        println!("unfragmentable part {unfragmentable} of ipv6 packet does not fit in mtu {mtu}");
+        return vec![];
+    }
+    // Fragmentヘッダを指すことになるNext Headerフィールドの位置
+    let mut next_header_pos = 6;
+    let mut offset = IPV6_HEADER_LENGTH;
+    while offset < unfragmentable {
+        next_header_pos = offset;
+        offset += (packet[offset + 1] as usize + 1) * 8;
+    }
+    let next_header = packet[next_header_pos];
+    let fragmentable = &packet[unfragmentable..];
+    // 最後以外のフラグメントのデータは8byteの倍数にする
+    let max_data_len = (mtu - unfragmentable - FRAGMENT_HEADER_LENGTH) & !7;
+    let identification = next_fragment_id();
+
+    let mut fragments = vec![];
+    for (i, chunk) in fragmentable.chunks(max_data_len).enumerate() {
+        let frag_offset = i * max_data_len;
+        let more = frag_offset + chunk.len() < fragmentable.len();
+
+        let mut buf = packet[..unfragmentable].to_vec();
+        buf[next_header_pos] = IP_PROTOCOL_NUMBER_FRAGMENT;
+        let payload_len = buf.len() - IPV6_HEADER_LENGTH + FRAGMENT_HEADER_LENGTH + chunk.len();
+        buf[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+        // Fragmentヘッダ
+        buf.put_u8(next_header);
+        buf.put_u8(0); // reserved
+        buf.put_u16(((frag_offset / 8) as u16) << 3 | more as u16);
+        buf.put_u32(identification);
+        buf.put_slice(chunk);
+        fragments.push(buf);
+    }
+    fragments
+}
@@ -1,10 +1,28 @@
-mod arp;
+pub mod addrconf;
+pub mod addrselect;
+pub mod arp;
+pub mod conntrack;
+pub mod dhcpv6;
 mod dns;
 mod ethernet;
+pub mod filter;
+pub mod forward;
 mod icmp;
 mod icmpv6;
+pub mod igmp;
 mod ipv4;
+pub mod ipv4_addr;
+mod ipv4_frag;
+pub mod ipv4_link_local;
 mod ipv6;
+mod ipv6_frag;
+pub mod loopback;
+pub mod mld;
+pub mod nat;
+mod ndp;
+pub mod pmtu;
+pub mod radvd;
+pub mod route;
 pub mod socket;
 pub mod tcp;
 mod udp;
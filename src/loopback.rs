@@ -0,0 +1,88 @@
+use crate::addrconf::{add_ipv6_addr, is_my_ipv6_addr, Ipv6AddrOrigin};
+use crate::ipv4_addr::{add_ipv4_addr, is_ipv4_broadcast, is_ipv4_multicast, is_my_ipv4_addr};
+use crate::route::{add_ipv4_route, add_ipv6_route, Ipv4Route, Ipv6Route};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// 組み込みのループバックデバイス
+// 自分宛てのパケットはEthernetに送らず、このデバイスで受信したものとして処理する
+pub const LOOPBACK_INTERFACE: &str = "lo";
+// ループバックデバイスにはMACアドレスがないので全て0にする
+pub(crate) const LOOPBACK_MAC_ADDR: [u8; 6] = [0; 6];
+
+pub const IPV4_LOOPBACK_ADDR: u32 = 0x7f00_0001;
+pub const IPV6_LOOPBACK_ADDR: u128 = 1;
+// 受信を待っているパケット数の上限、超えたら新しいパケットを捨てる
+const LOOPBACK_QUEUE_LEN: usize = 256;
+
+// ループバックデバイスに送られて、まだ受信していないパケット
+static LOOPBACK_QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+
+// 127.0.0.0/8と::1をループバックデバイスに設定する
+pub(crate) fn start_loopback() {
+    add_ipv4_addr(IPV4_LOOPBACK_ADDR, 8);
+    add_ipv4_route(Ipv4Route {
+        prefix: IPV4_LOOPBACK_ADDR & 0xff00_0000,
+        prefix_len: 8,
+        gateway: None,
+        interface: String::from(LOOPBACK_INTERFACE),
+        metric: 0,
+    });
+    add_ipv6_addr(IPV6_LOOPBACK_ADDR, 128, Ipv6AddrOrigin::Loopback);
+    add_ipv6_route(Ipv6Route {
+        prefix: IPV6_LOOPBACK_ADDR,
+        prefix_len: 128,
+        gateway: None,
+        interface: String::from(LOOPBACK_INTERFACE),
+        metric: 0,
+    });
+}
+
+// ループバックデバイスにパケットを送る
+// 送信した処理の中で受信すると自分宛ての返信を繰り返して再帰し続けることがあるので
+// キューに積んでおき、タイマー処理で受信する
+pub(crate) fn send_loopback_packet(packet: Vec<u8>) {
+    let mut queue = LOOPBACK_QUEUE.lock().unwrap();
+    if queue.len() >= LOOPBACK_QUEUE_LEN {
+        println!("loopback queue is full, drop packet");
+        return;
+    }
+    queue.push_back(packet);
+}
+
+pub(crate) fn take_loopback_packets() -> Vec<Vec<u8>> {
+    LOOPBACK_QUEUE.lock().unwrap().drain(..).collect()
+}
+
+// 127.0.0.0/8
+pub fn is_ipv4_loopback(addr: u32) -> bool {
+    addr >> 24 == 127
+}
+
+// ループバックデバイスで受信したパケットか
+pub(crate) fn is_loopback_interface(mac_addr: [u8; 6]) -> bool {
+    mac_addr == LOOPBACK_MAC_ADDR
+}
+
+// ループバックのアドレスはループバックデバイス以外から受け取らない
+// (RFC 1122 3.2.1.3, RFC 4291 2.5.3)
+pub(crate) fn is_ipv4_martian(my_mac_addr: [u8; 6], src_addr: u32, dst_addr: u32) -> bool {
+    !is_loopback_interface(my_mac_addr)
+        && (is_ipv4_loopback(src_addr) || is_ipv4_loopback(dst_addr))
+}
+
+pub(crate) fn is_ipv6_martian(my_mac_addr: [u8; 6], src_addr: u128, dst_addr: u128) -> bool {
+    !is_loopback_interface(my_mac_addr)
+        && (src_addr == IPV6_LOOPBACK_ADDR || dst_addr == IPV6_LOOPBACK_ADDR)
+}
+
+// Ethernetに送らずにループバックデバイスで受信させるIPv4の宛先か
+pub(crate) fn is_local_ipv4_dst(dst_addr: u32) -> bool {
+    !is_ipv4_broadcast(dst_addr)
+        && !is_ipv4_multicast(dst_addr)
+        && (is_ipv4_loopback(dst_addr) || is_my_ipv4_addr(dst_addr))
+}
+
+pub(crate) fn is_local_ipv6_dst(dst_addr: u128) -> bool {
+    dst_addr == IPV6_LOOPBACK_ADDR || is_my_ipv6_addr(dst_addr)
+}
@@ -0,0 +1,390 @@
+use crate::addrconf::is_link_local;
+use crate::ipv6::{ipv6_multicast_mac_addr, IPV6_ALL_NODES_ADDR};
+use crate::util::{random_u64, to_u16};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// ff02::16 MLDv2対応ルーター宛てのマルチキャストアドレス
+pub const IPV6_ALL_MLDV2_ROUTERS_ADDR: u128 = 0xff02_0000_0000_0000_0000_0000_0000_0016;
+// 状態変化レポートを送る回数と間隔 (RFC 3810 9.)
+const ROBUSTNESS_VARIABLE: u8 = 2;
+const UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// Type/Code/Checksumを除いたQueryの長さ、MLDv1は20byte
+const MLDV2_QUERY_MIN_LENGTH: usize = 24;
+// Queryは必ずHop Limit 1で届く
+pub const MLD_HOP_LIMIT: u8 = 1;
+
+// Multicast Address Recordのタイプ (RFC 3810 5.2.12)
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+const ALLOW_NEW_SOURCES: u8 = 5;
+const BLOCK_OLD_SOURCES: u8 = 6;
+
+// 送信元フィルタのモード
+// Includeは指定した送信元からのみ、Excludeは指定した送信元以外から受信する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MulticastFilterMode {
+    Include,
+    Exclude,
+}
+
+// Multicast Listener Reportに入れるレコード
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticastAddressRecord {
+    pub record_type: u8,
+    pub multicast_addr: u128,
+    pub sources: Vec<u128>,
+}
+
+#[derive(Debug)]
+struct MulticastGroup {
+    addr: u128,
+    users: u32, // join_ipv6_multicast_groupを呼んだ数
+    mode: MulticastFilterMode,
+    sources: Vec<u128>,
+    // 状態変化レポートの残りの送信回数と次に送る時刻
+    change_records: Vec<MulticastAddressRecord>,
+    change_retransmits: u8,
+    change_at: Instant,
+    // Multicast Address Specific Queryへの応答予定
+    // 送信元の指定がなければNone
+    query_at: Option<Instant>,
+    query_sources: Option<Vec<u128>>,
+}
+
+impl MulticastGroup {
+    // INCLUDE {} はどの送信元からも受信しない、つまり参加していない
+    fn is_listening(&self) -> bool {
+        !(self.mode == MulticastFilterMode::Include && self.sources.is_empty())
+    }
+
+    fn accepts(&self, src_addr: u128) -> bool {
+        match self.mode {
+            MulticastFilterMode::Include => self.sources.contains(&src_addr),
+            MulticastFilterMode::Exclude => !self.sources.contains(&src_addr),
+        }
+    }
+
+    // 現在の状態を表すレコード
+    fn current_state_record(&self) -> MulticastAddressRecord {
+        let record_type = match self.mode {
+            MulticastFilterMode::Include => MODE_IS_INCLUDE,
+            MulticastFilterMode::Exclude => MODE_IS_EXCLUDE,
+        };
+        MulticastAddressRecord {
+            record_type,
+            multicast_addr: self.addr,
+            sources: self.sources.clone(),
+        }
+    }
+
+    // Multicast Address and Source Specific Queryへの応答 (RFC 3810 6.3)
+    // 問い合わせられた送信元のうち受信するものを返す
+    fn source_specific_record(&self, query_sources: &[u128]) -> Option<MulticastAddressRecord> {
+        let sources: Vec<u128> = query_sources
+            .iter()
+            .copied()
+            .filter(|src| self.accepts(*src))
+            .collect();
+        if sources.is_empty() {
+            return None;
+        }
+        Some(MulticastAddressRecord {
+            record_type: MODE_IS_INCLUDE,
+            multicast_addr: self.addr,
+            sources,
+        })
+    }
+
+    // フィルタを変更して、状態変化レポートを送る準備をする (RFC 3810 6.1)
+    fn change_filter(&mut self, mode: MulticastFilterMode, sources: Vec<u128>) {
+        if self.mode == mode && self.sources == sources {
+            return;
+        }
+        let mut records = vec![];
+        if self.mode != mode {
+            let record_type = match mode {
+                MulticastFilterMode::Include => CHANGE_TO_INCLUDE_MODE,
+                MulticastFilterMode::Exclude => CHANGE_TO_EXCLUDE_MODE,
+            };
+            records.push(MulticastAddressRecord {
+                record_type,
+                multicast_addr: self.addr,
+                sources: sources.clone(),
+            });
+        } else {
+            let added: Vec<u128> = sources
+                .iter()
+                .copied()
+                .filter(|src| !self.sources.contains(src))
+                .collect();
+            let removed: Vec<u128> = self
+                .sources
+                .iter()
+                .copied()
+                .filter(|src| !sources.contains(src))
+                .collect();
+            // Excludeでは除外する送信元が増えるとブロック、減ると許可になる
+            let (allow, block) = match mode {
+                MulticastFilterMode::Include => (added, removed),
+                MulticastFilterMode::Exclude => (removed, added),
+            };
+            for (record_type, sources) in [(ALLOW_NEW_SOURCES, allow), (BLOCK_OLD_SOURCES, block)] {
+                if !sources.is_empty() {
+                    records.push(MulticastAddressRecord {
+                        record_type,
+                        multicast_addr: self.addr,
+                        sources,
+                    });
+                }
+            }
+        }
+        self.mode = mode;
+        self.sources = sources;
+        // 送信中の状態変化レポートは新しいものに置き換える
+        self.change_records = records;
+        self.change_retransmits = ROBUSTNESS_VARIABLE;
+        self.change_at = Instant::now();
+    }
+}
+
+static MULTICAST_GROUPS: Mutex<Vec<MulticastGroup>> = Mutex::new(Vec::new());
+// General Queryへの応答予定
+static GENERAL_QUERY_AT: Mutex<Option<Instant>> = Mutex::new(None);
+
+// 全ノードマルチキャストは常に参加していて、レポートも送らない (RFC 3810 6.)
+fn is_reported_group(addr: u128) -> bool {
+    addr != IPV6_ALL_NODES_ADDR && addr >> 112 != 0xff01
+}
+
+// 全ての送信元から受信するグループに参加する
+pub fn join_ipv6_multicast_group(group: u128) {
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    match groups.iter_mut().find(|g| g.addr == group) {
+        Some(entry) => {
+            entry.users += 1;
+            if !entry.is_listening() {
+                entry.change_filter(MulticastFilterMode::Exclude, vec![]);
+            }
+        }
+        None => {
+            let mut entry = MulticastGroup {
+                addr: group,
+                users: 1,
+                mode: MulticastFilterMode::Include,
+                sources: vec![],
+                change_records: vec![],
+                change_retransmits: 0,
+                change_at: Instant::now(),
+                query_at: None,
+                query_sources: None,
+            };
+            entry.change_filter(MulticastFilterMode::Exclude, vec![]);
+            groups.push(entry);
+            println!("join ipv6 multicast group {group:x}");
+        }
+    }
+}
+
+// join_ipv6_multicast_groupを呼んだ全員が離脱したらグループから抜ける
+pub fn leave_ipv6_multicast_group(group: u128) {
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let Some(entry) = groups.iter_mut().find(|g| g.addr == group) else {
+        return;
+    };
+    entry.users = entry.users.saturating_sub(1);
+    if entry.users == 0 {
+        entry.change_filter(MulticastFilterMode::Include, vec![]);
+        println!("leave ipv6 multicast group {group:x}");
+    }
+}
+
+// 送信元フィルタを設定する (Source-Specific Multicast)
+// INCLUDE {} を設定するとグループから抜ける
+pub fn set_ipv6_multicast_source_filter(
+    group: u128,
+    mode: MulticastFilterMode,
+    mut sources: Vec<u128>,
+) {
+    sources.sort_unstable();
+    sources.dedup();
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let index = match groups.iter().position(|g| g.addr == group) {
+        Some(index) => index,
+        None => {
+            groups.push(MulticastGroup {
+                addr: group,
+                users: 0,
+                mode: MulticastFilterMode::Include,
+                sources: vec![],
+                change_records: vec![],
+                change_retransmits: 0,
+                change_at: Instant::now(),
+                query_at: None,
+                query_sources: None,
+            });
+            groups.len() - 1
+        }
+    };
+    let entry = &mut groups[index];
+    entry.change_filter(mode, sources);
+    entry.users = if entry.is_listening() {
+        entry.users.max(1)
+    } else {
+        0
+    };
+}
+
+// 参加しているグループの一覧
+pub fn get_ipv6_multicast_groups() -> Vec<(u128, MulticastFilterMode, Vec<u128>)> {
+    MULTICAST_GROUPS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|g| g.is_listening())
+        .map(|g| (g.addr, g.mode, g.sources.clone()))
+        .collect()
+}
+
+// 送信元フィルタを考慮して、マルチキャスト宛てのパケットを受信するか
+pub fn is_ipv6_multicast_member(group: u128, src_addr: u128) -> bool {
+    group == IPV6_ALL_NODES_ADDR
+        || MULTICAST_GROUPS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|g| g.addr == group && g.is_listening() && g.accepts(src_addr))
+}
+
+// 33:33:xx:xx:xx:xx のうち参加しているグループに対応するMACアドレスか
+pub fn is_ipv6_multicast_mac_joined(mac_addr: [u8; 6]) -> bool {
+    mac_addr == ipv6_multicast_mac_addr(IPV6_ALL_NODES_ADDR)
+        || MULTICAST_GROUPS
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|g| g.is_listening() && ipv6_multicast_mac_addr(g.addr) == mac_addr)
+}
+
+// Maximum Response Codeから応答までの最大遅延を求める (RFC 3810 5.1.3)
+fn max_response_delay(code: u16) -> Duration {
+    let millis = if code < 0x8000 {
+        code as u64
+    } else {
+        let exp = (code >> 12) & 0x7;
+        let mant = code & 0x0fff;
+        ((mant | 0x1000) as u64) << (exp + 3)
+    };
+    Duration::from_millis(millis)
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max = max.as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(random_u64() % max)
+}
+
+// Multicast Listener Queryを受信して応答を予約する (RFC 3810 6.2)
+// message はICMPv6のType/Code/Checksumより後ろ
+pub fn read_mld_query(src_addr: u128, hop_limit: u8, message: &[u8]) {
+    if !is_link_local(src_addr) || hop_limit != MLD_HOP_LIMIT {
+        return;
+    }
+    // MLDv1互換モードは実装していないので、MLDv1のQueryには応答しない
+    if message.len() < MLDV2_QUERY_MIN_LENGTH {
+        println!("ignore mldv1 query");
+        return;
+    }
+    let max_delay = max_response_delay(to_u16(&message[0..]));
+    let group = u128::from_be_bytes(message[4..20].try_into().unwrap());
+    let num_sources = to_u16(&message[22..]) as usize;
+    if message.len() < MLDV2_QUERY_MIN_LENGTH + num_sources * 16 {
+        return;
+    }
+    let sources: Vec<u128> = message[24..24 + num_sources * 16]
+        .chunks(16)
+        .map(|chunk| u128::from_be_bytes(chunk.try_into().unwrap()))
+        .collect();
+
+    let now = Instant::now();
+    let respond_at = now + random_delay(max_delay);
+    let mut general_query_at = GENERAL_QUERY_AT.lock().unwrap();
+    // もっと早いGeneral Queryへの応答が予定されていれば、それで足りる
+    if general_query_at.is_some_and(|at| at <= respond_at) {
+        return;
+    }
+    if group == 0 {
+        println!("mld general query");
+        *general_query_at = Some(respond_at);
+        return;
+    }
+
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    let Some(entry) = groups
+        .iter_mut()
+        .find(|g| g.addr == group && g.is_listening() && is_reported_group(g.addr))
+    else {
+        return;
+    };
+    println!("mld query for {group:x}");
+    entry.query_sources = match (entry.query_at, entry.query_sources.take()) {
+        // 予定がなければ今回のQueryの送信元
+        (None, _) => (!sources.is_empty()).then_some(sources),
+        // どちらかが送信元の指定がないQueryならグループ全体について応答する
+        (Some(_), None) => None,
+        (Some(_), Some(_)) if sources.is_empty() => None,
+        // 両方とも送信元を指定したQueryなら送信元をまとめる
+        (Some(_), Some(mut pending)) => {
+            for src in sources {
+                if !pending.contains(&src) {
+                    pending.push(src);
+                }
+            }
+            Some(pending)
+        }
+    };
+    entry.query_at = Some(entry.query_at.map_or(respond_at, |at| at.min(respond_at)));
+}
+
+// MLDのタイマー処理
+// 送信するMulticast Listener Reportのレコードを返す
+pub fn mld_timer() -> Vec<MulticastAddressRecord> {
+    let now = Instant::now();
+    let mut records = vec![];
+
+    let general_query = {
+        let mut general_query_at = GENERAL_QUERY_AT.lock().unwrap();
+        let due = general_query_at.is_some_and(|at| at <= now);
+        if due {
+            *general_query_at = None;
+        }
+        due
+    };
+
+    let mut groups = MULTICAST_GROUPS.lock().unwrap();
+    for entry in groups.iter_mut().filter(|g| is_reported_group(g.addr)) {
+        if general_query && entry.is_listening() {
+            records.push(entry.current_state_record());
+        }
+        if entry.query_at.is_some_and(|at| at <= now) {
+            entry.query_at = None;
+            match entry.query_sources.take() {
+                None if entry.is_listening() => records.push(entry.current_state_record()),
+                None => {}
+                Some(sources) => records.extend(entry.source_specific_record(&sources)),
+            }
+        }
+        if entry.change_retransmits > 0 && entry.change_at <= now {
+            records.extend(entry.change_records.iter().cloned());
+            entry.change_retransmits -= 1;
+            entry.change_at = now + random_delay(UNSOLICITED_REPORT_INTERVAL);
+        }
+    }
+    // 抜けたグループは状態変化レポートを送り終えたら消す
+    groups.retain(|g| g.is_listening() || g.change_retransmits > 0);
+    records
+}
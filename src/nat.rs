@@ -0,0 +1,461 @@
+use crate::icmp::{
+    ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE, ICMP_MESSAGE_TYPE_ECHO_REPLY,
+    ICMP_MESSAGE_TYPE_ECHO_REQUEST, ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM,
+    ICMP_MESSAGE_TYPE_REDIRECT, ICMP_MESSAGE_TYPE_TIME_EXCEEDED,
+};
+use crate::ipv4::{IP_PROTOCOL_NUMBER_ICMP, IP_PROTOCOL_NUMBER_TCP, IP_PROTOCOL_NUMBER_UDP};
+use crate::ipv4_addr::get_ipv4_addrs;
+use crate::route::lookup_ipv4_route;
+use crate::util::{checksum, random_u64, to_u16, to_u32, update_checksum};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// マッピングの有効期間
+// UDPは5分 (RFC 4787 REQ-5)、ICMPクエリは60秒 (RFC 5508 REQ-1)
+// TCPは確立済みなら2時間4分、確立中と切断中は4分 (RFC 5382 REQ-5)
+const NAT_UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const NAT_ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+const NAT_TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60 + 4 * 60);
+const NAT_TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+// 保持するマッピング数の上限
+const MAX_NAT_MAPPINGS: usize = 4096;
+
+// TCPのフラグ
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+// 外側のインターフェースから出ていくパケットの送信元をそのインターフェースのアドレスに変換する
+#[derive(Debug, Clone)]
+pub struct NatConfig {
+    pub outside_interface: Option<String>, // Noneなら変換しない
+    pub port_min: u16,                     // 割り当てる外側のポートの範囲
+    pub port_max: u16,
+}
+
+// TCPコネクションの状態、有効期間を決めるためだけに追う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatTcpState {
+    Transitory,
+    Established,
+    Closing,
+}
+
+// 1つのフローの変換
+// ICMPクエリはポートの代わりにIDを変換し、remote_portは0
+#[derive(Debug, Clone)]
+pub struct NatMapping {
+    pub protocol: u8,
+    pub inside_addr: u32,
+    pub inside_port: u16,
+    pub outside_addr: u32,
+    pub outside_port: u16,
+    pub remote_addr: u32,
+    pub remote_port: u16,
+    pub tcp_state: NatTcpState,
+    pub expires_at: Instant,
+}
+
+impl NatMapping {
+    fn refresh(&mut self, now: Instant) {
+        let timeout = match self.protocol {
+            IP_PROTOCOL_NUMBER_TCP if self.tcp_state == NatTcpState::Established => {
+                NAT_TCP_ESTABLISHED_TIMEOUT
+            }
+            IP_PROTOCOL_NUMBER_TCP => NAT_TCP_TRANSITORY_TIMEOUT,
+            IP_PROTOCOL_NUMBER_UDP => NAT_UDP_TIMEOUT,
+            _ => NAT_ICMP_TIMEOUT,
+        };
+        self.expires_at = now + timeout;
+    }
+
+    // TCPのフラグで状態を進める
+    // 確立中に外側からACKが届いたら確立済みとする
+    fn update_tcp_state(&mut self, flags: u8, inbound: bool) {
+        if flags & (TCP_FLAG_FIN | TCP_FLAG_RST) != 0 {
+            self.tcp_state = NatTcpState::Closing;
+        } else if flags & TCP_FLAG_SYN != 0 && !inbound {
+            self.tcp_state = NatTcpState::Transitory;
+        } else if self.tcp_state == NatTcpState::Transitory && inbound && flags & TCP_FLAG_ACK != 0
+        {
+            self.tcp_state = NatTcpState::Established;
+        }
+    }
+}
+
+static NAT_CONFIG: Mutex<NatConfig> = Mutex::new(NatConfig {
+    outside_interface: None,
+    port_min: 49152,
+    port_max: 65535,
+});
+static NAT_MAPPINGS: Mutex<Vec<NatMapping>> = Mutex::new(Vec::new());
+
+pub fn set_nat_config(config: NatConfig) {
+    *NAT_CONFIG.lock().unwrap() = config;
+    NAT_MAPPINGS.lock().unwrap().clear();
+}
+
+pub fn get_nat_config() -> NatConfig {
+    NAT_CONFIG.lock().unwrap().clone()
+}
+
+pub fn get_nat_mappings() -> Vec<NatMapping> {
+    let now = Instant::now();
+    let mut mappings = NAT_MAPPINGS.lock().unwrap();
+    mappings.retain(|m| m.expires_at > now);
+    mappings.clone()
+}
+
+pub fn is_nat_enabled() -> bool {
+    NAT_CONFIG.lock().unwrap().outside_interface.is_some()
+}
+
+fn is_icmp_error(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        ICMP_MESSAGE_TYPE_DESTINATION_UNREACHABLE
+            | ICMP_MESSAGE_TYPE_REDIRECT
+            | ICMP_MESSAGE_TYPE_TIME_EXCEEDED
+            | ICMP_MESSAGE_TYPE_PARAMETER_PROBLEM
+    )
+}
+
+// 16bitのフィールドを書き換えて、checksum_posのチェックサムを差分で更新する
+fn set_u16(packet: &mut [u8], pos: usize, value: u16, checksum_pos: &[usize]) {
+    let old = to_u16(&packet[pos..pos + 2]);
+    packet[pos..pos + 2].copy_from_slice(&value.to_be_bytes());
+    for &c in checksum_pos {
+        let checksum = update_checksum(to_u16(&packet[c..c + 2]), old, value);
+        packet[c..c + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+fn set_u32(packet: &mut [u8], pos: usize, value: u32, checksum_pos: &[usize]) {
+    set_u16(packet, pos, (value >> 16) as u16, checksum_pos);
+    set_u16(packet, pos + 2, value as u16, checksum_pos);
+}
+
+// IPヘッダの後ろのトランスポート層のチェックサムの位置
+// 擬似ヘッダを含むTCP/UDPはアドレスの変換でも更新し、ICMPはIDの変換でだけ更新する
+// UDPのチェックサム0は未計算なので更新しない
+fn transport_checksum_pos(packet: &[u8], offset: usize, protocol: u8) -> Option<usize> {
+    let pos = match protocol {
+        IP_PROTOCOL_NUMBER_TCP => offset + 16,
+        IP_PROTOCOL_NUMBER_UDP => offset + 6,
+        _ => offset + 2,
+    };
+    if packet.len() < pos + 2 || protocol == IP_PROTOCOL_NUMBER_UDP && to_u16(&packet[pos..]) == 0 {
+        return None;
+    }
+    Some(pos)
+}
+
+// 変換に使うポート (ICMPクエリはID) の位置、変換しないパケットならNone
+fn port_pos(packet: &[u8], offset: usize, protocol: u8, outbound: bool) -> Option<(usize, usize)> {
+    let (src_pos, dst_pos) = match protocol {
+        IP_PROTOCOL_NUMBER_TCP | IP_PROTOCOL_NUMBER_UDP => (offset, offset + 2),
+        IP_PROTOCOL_NUMBER_ICMP => {
+            let query = if outbound {
+                ICMP_MESSAGE_TYPE_ECHO_REQUEST
+            } else {
+                ICMP_MESSAGE_TYPE_ECHO_REPLY
+            };
+            if packet.get(offset) != Some(&query) {
+                return None;
+            }
+            (offset + 4, offset + 4)
+        }
+        _ => return None,
+    };
+    if packet.len() < dst_pos + 2 {
+        return None;
+    }
+    Some((src_pos, dst_pos))
+}
+
+// 外側のインターフェースに割り当てられたアドレス
+fn outside_addr(if_name: &str) -> Option<u32> {
+    get_ipv4_addrs()
+        .into_iter()
+        .map(|entry| entry.addr)
+        .find(|addr| lookup_ipv4_route(*addr).is_some_and(|r| r.interface == if_name))
+}
+
+// 外側のポートを割り当てる
+// 同じ内側のアドレスとポートには宛先によらず同じポートを使う (RFC 4787 REQ-1)
+fn allocate_port(
+    mappings: &[NatMapping],
+    config: &NatConfig,
+    protocol: u8,
+    inside_addr: u32,
+    inside_port: u16,
+) -> Option<u16> {
+    if let Some(mapping) = mappings.iter().find(|m| {
+        m.protocol == protocol && m.inside_addr == inside_addr && m.inside_port == inside_port
+    }) {
+        return Some(mapping.outside_port);
+    }
+    let range = (config.port_max - config.port_min) as u64 + 1;
+    let start = random_u64() % range;
+    (0..range)
+        .map(|i| config.port_min + ((start + i) % range) as u16)
+        .find(|port| {
+            !mappings
+                .iter()
+                .any(|m| m.protocol == protocol && m.outside_port == *port)
+        })
+}
+
+// 外側のインターフェースから送信する転送パケットの送信元を変換する
+// 変換できずに捨てるべきパケットならfalse
+pub(crate) fn translate_outbound(packet: &mut [u8], out_interface: &str) -> bool {
+    let config = get_nat_config();
+    if config.outside_interface.as_deref() != Some(out_interface) {
+        return true;
+    }
+    let Some(outside_addr) = outside_addr(out_interface) else {
+        println!("nat: no address on {out_interface}");
+        return false;
+    };
+    let offset = (packet[0] & 0x0f) as usize * 4;
+    let protocol = packet[9];
+    if protocol == IP_PROTOCOL_NUMBER_ICMP && packet.get(offset).is_some_and(|t| is_icmp_error(*t))
+    {
+        return translate_icmp_error(packet, offset, true);
+    }
+    let Some((src_pos, dst_pos)) = port_pos(packet, offset, protocol, true) else {
+        println!("nat: not translatable protocol {protocol}");
+        return false;
+    };
+    let inside_addr = to_u32(&packet[12..16]);
+    let inside_port = to_u16(&packet[src_pos..]);
+    let remote_addr = to_u32(&packet[16..20]);
+    let remote_port = if protocol == IP_PROTOCOL_NUMBER_ICMP {
+        0
+    } else {
+        to_u16(&packet[dst_pos..])
+    };
+
+    let now = Instant::now();
+    let mut mappings = NAT_MAPPINGS.lock().unwrap();
+    mappings.retain(|m| m.expires_at > now);
+    let index = match mappings.iter().position(|m| {
+        m.protocol == protocol
+            && m.inside_addr == inside_addr
+            && m.inside_port == inside_port
+            && m.remote_addr == remote_addr
+            && m.remote_port == remote_port
+    }) {
+        Some(index) => index,
+        None => {
+            if mappings.len() >= MAX_NAT_MAPPINGS {
+                println!("nat: too many mappings");
+                return false;
+            }
+            let Some(outside_port) =
+                allocate_port(&mappings, &config, protocol, inside_addr, inside_port)
+            else {
+                println!("nat: no free port");
+                return false;
+            };
+            println!(
+                "nat: map {}:{inside_port} -> {}:{outside_port} for {}:{remote_port}",
+                Ipv4Addr::from(inside_addr),
+                Ipv4Addr::from(outside_addr),
+                Ipv4Addr::from(remote_addr)
+            );
+            mappings.push(NatMapping {
+                protocol,
+                inside_addr,
+                inside_port,
+                outside_addr,
+                outside_port,
+                remote_addr,
+                remote_port,
+                tcp_state: NatTcpState::Transitory,
+                expires_at: now,
+            });
+            mappings.len() - 1
+        }
+    };
+    let mapping = &mut mappings[index];
+    if protocol == IP_PROTOCOL_NUMBER_TCP && packet.len() > offset + 13 {
+        mapping.update_tcp_state(packet[offset + 13], false);
+    }
+    mapping.refresh(now);
+
+    rewrite_endpoint(
+        packet,
+        0,
+        12,
+        mapping.outside_addr,
+        src_pos,
+        mapping.outside_port,
+    );
+    true
+}
+
+// 外側のアドレス宛てに届いたパケットを、マッピングに従って内側のホスト宛てに戻す
+// マッピングがなければ自分宛てのパケットなのでfalse
+pub(crate) fn translate_inbound(packet: &mut [u8]) -> bool {
+    if !is_nat_enabled() {
+        return false;
+    }
+    let offset = (packet[0] & 0x0f) as usize * 4;
+    let protocol = packet[9];
+    if protocol == IP_PROTOCOL_NUMBER_ICMP && packet.get(offset).is_some_and(|t| is_icmp_error(*t))
+    {
+        return translate_icmp_error(packet, offset, false);
+    }
+    let Some((src_pos, dst_pos)) = port_pos(packet, offset, protocol, false) else {
+        return false;
+    };
+    let remote_addr = to_u32(&packet[12..16]);
+    let remote_port = if protocol == IP_PROTOCOL_NUMBER_ICMP {
+        0
+    } else {
+        to_u16(&packet[src_pos..])
+    };
+    let outside_addr = to_u32(&packet[16..20]);
+    let outside_port = to_u16(&packet[dst_pos..]);
+
+    let now = Instant::now();
+    let mut mappings = NAT_MAPPINGS.lock().unwrap();
+    mappings.retain(|m| m.expires_at > now);
+    // 通信したことのある相手からのパケットだけ通す (RFC 4787 REQ-8のAddress and Port-Dependent Filtering)
+    let Some(mapping) = mappings.iter_mut().find(|m| {
+        m.protocol == protocol
+            && m.outside_addr == outside_addr
+            && m.outside_port == outside_port
+            && m.remote_addr == remote_addr
+            && m.remote_port == remote_port
+    }) else {
+        return false;
+    };
+    if protocol == IP_PROTOCOL_NUMBER_TCP && packet.len() > offset + 13 {
+        mapping.update_tcp_state(packet[offset + 13], true);
+    }
+    mapping.refresh(now);
+    rewrite_endpoint(
+        packet,
+        0,
+        16,
+        mapping.inside_addr,
+        dst_pos,
+        mapping.inside_port,
+    );
+    true
+}
+
+// ICMPエラーと、それに含まれる元のパケットのヘッダを変換する (RFC 5508 REQ-3)
+// outboundなら外側からのパケットに内側のホストが返したエラー、
+// そうでなければ変換して送ったパケットに外側から返ってきたエラー
+fn translate_icmp_error(packet: &mut [u8], offset: usize, outbound: bool) -> bool {
+    let inner = offset + 8;
+    if packet.len() < inner + 20 {
+        return false;
+    }
+    let inner_offset = inner + (packet[inner] & 0x0f) as usize * 4;
+    let inner_protocol = packet[inner + 9];
+    if packet.len() < inner_offset + 8 {
+        return false;
+    }
+    let (src_port_pos, dst_port_pos) = if inner_protocol == IP_PROTOCOL_NUMBER_ICMP {
+        (inner_offset + 4, inner_offset + 4)
+    } else {
+        (inner_offset, inner_offset + 2)
+    };
+    let port_or_zero = |packet: &[u8], pos: usize| {
+        if inner_protocol == IP_PROTOCOL_NUMBER_ICMP {
+            0
+        } else {
+            to_u16(&packet[pos..])
+        }
+    };
+    let inner_src = (
+        to_u32(&packet[inner + 12..]),
+        to_u16(&packet[src_port_pos..]),
+    );
+    let inner_dst = (
+        to_u32(&packet[inner + 16..]),
+        to_u16(&packet[dst_port_pos..]),
+    );
+
+    let mut mappings = NAT_MAPPINGS.lock().unwrap();
+    let mapping = if outbound {
+        // 元のパケットは外側から内側のホストへのもの
+        let remote_port = port_or_zero(packet, src_port_pos);
+        mappings.iter_mut().find(|m| {
+            m.protocol == inner_protocol
+                && (m.inside_addr, m.inside_port) == inner_dst
+                && (m.remote_addr, m.remote_port) == (inner_src.0, remote_port)
+        })
+    } else {
+        // 元のパケットは変換して外側に送ったもの
+        let remote_port = port_or_zero(packet, dst_port_pos);
+        mappings.iter_mut().find(|m| {
+            m.protocol == inner_protocol
+                && (m.outside_addr, m.outside_port) == inner_src
+                && (m.remote_addr, m.remote_port) == (inner_dst.0, remote_port)
+        })
+    };
+    let Some(mapping) = mapping else {
+        return false;
+    };
+
+    if outbound {
+        set_u32(packet, 12, mapping.outside_addr, &[10]);
+        rewrite_endpoint(
+            packet,
+            inner,
+            inner + 16,
+            mapping.outside_addr,
+            dst_port_pos,
+            mapping.outside_port,
+        );
+    } else {
+        set_u32(packet, 16, mapping.inside_addr, &[10]);
+        rewrite_endpoint(
+            packet,
+            inner,
+            inner + 12,
+            mapping.inside_addr,
+            src_port_pos,
+            mapping.inside_port,
+        );
+    }
+    // ICMPのチェックサムは含まれるパケット全体にかかるので計算し直す
+    packet[offset + 2..offset + 4].copy_from_slice(&[0, 0]);
+    let icmp_checksum = checksum(&packet[offset..].to_vec());
+    packet[offset + 2..offset + 4].copy_from_slice(&icmp_checksum.to_be_bytes());
+    true
+}
+
+// header_posから始まるIPパケットのアドレスとポートを書き換える
+// アドレスはIPヘッダのチェックサムに加えて、擬似ヘッダとしてTCP/UDPのチェックサムにも入る
+fn rewrite_endpoint(
+    packet: &mut [u8],
+    header_pos: usize,
+    addr_pos: usize,
+    addr: u32,
+    port_pos: usize,
+    port: u16,
+) {
+    let offset = header_pos + (packet[header_pos] & 0x0f) as usize * 4;
+    let protocol = packet[header_pos + 9];
+    let transport_checksum = transport_checksum_pos(packet, offset, protocol);
+    let mut addr_checksums = vec![header_pos + 10];
+    if protocol != IP_PROTOCOL_NUMBER_ICMP {
+        addr_checksums.extend(transport_checksum);
+    }
+    set_u32(packet, addr_pos, addr, &addr_checksums);
+    set_u16(packet, port_pos, port, transport_checksum.as_slice());
+    // UDPのチェックサム0は未計算の意味になるので0xffffにする (RFC 768)
+    if let Some(pos) = transport_checksum.filter(|_| protocol == IP_PROTOCOL_NUMBER_UDP) {
+        if to_u16(&packet[pos..]) == 0 {
+            packet[pos..pos + 2].copy_from_slice(&[0xff, 0xff]);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// RFC 4861 10. Protocol Constants
+const MAX_MULTICAST_SOLICIT: u8 = 3;
+const MAX_UNICAST_SOLICIT: u8 = 3;
+const REACHABLE_TIME: Duration = Duration::from_millis(30000);
+pub const RETRANS_TIMER: Duration = Duration::from_millis(1000);
+const DELAY_FIRST_PROBE_TIME: Duration = Duration::from_secs(5);
+// アドレス解決待ちの間にキューイングするパケット数
+// 1つのデータグラムのフラグメントがまとめて積まれるので多めにとる
+const MAX_QUEUED_PACKETS: usize = 64;
+
+// 近隣キャッシュエントリの状態 (RFC 4861 7.3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Delay,
+    Probe,
+}
+
+#[derive(Debug)]
+pub struct NeighborEntry {
+    pub ip_addr: u128,
+    pub mac_addr: [u8; 6],
+    pub state: NeighborState,
+    pub is_router: bool,
+    probes: u8,          // 送信済みのNS数
+    updated_at: Instant, // 状態が変化した時刻
+    queue: Vec<Vec<u8>>, // アドレス解決待ちのIPv6パケット
+}
+
+// 送信時の近隣解決の結果
+pub enum NeighborResolution {
+    Resolved([u8; 6], Vec<u8>), // 解決済みなのでそのまま送信する
+    Solicit,                    // 新たにマルチキャストでNSを送信する必要がある
+    Queued,                     // アドレス解決中なのでキューに積んだ
+}
+
+// タイマーで送信するNS
+pub struct NeighborProbe {
+    pub target: u128,
+    pub mac_addr: Option<[u8; 6]>, // Noneならマルチキャストで送信
+}
+
+static NEIGHBOR_CACHE: Mutex<Vec<NeighborEntry>> = Mutex::new(Vec::new());
+// アドレス解決が完了して送信できるようになったパケット
+static READY_PACKETS: Mutex<Vec<([u8; 6], Vec<u8>)>> = Mutex::new(Vec::new());
+
+pub fn take_ready_packets() -> Vec<([u8; 6], Vec<u8>)> {
+    std::mem::take(&mut *READY_PACKETS.lock().unwrap())
+}
+
+// 近隣キャッシュにあるMACアドレス、アドレス解決中ならNone
+pub fn search_neighbor_cache(ip_addr: u128) -> Option<[u8; 6]> {
+    NEIGHBOR_CACHE
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|e| e.ip_addr == ip_addr && e.state != NeighborState::Incomplete)
+        .map(|e| e.mac_addr)
+}
+
+// 送信先のMACアドレスを解決する (RFC 4861 7.2.2, 7.3.3)
+pub fn resolve_neighbor(ip_addr: u128, packet: Vec<u8>) -> NeighborResolution {
+    let mut cache = NEIGHBOR_CACHE.lock().unwrap();
+    let now = Instant::now();
+
+    let Some(entry) = cache.iter_mut().find(|entry| entry.ip_addr == ip_addr) else {
+        cache.push(NeighborEntry {
+            ip_addr,
+            mac_addr: [0; 6],
+            state: NeighborState::Incomplete,
+            is_router: false,
+            probes: 1,
+            updated_at: now,
+            queue: vec![packet],
+        });
+        return NeighborResolution::Solicit;
+    };
+
+    match entry.state {
+        NeighborState::Incomplete => {
+            if entry.queue.len() >= MAX_QUEUED_PACKETS {
+                entry.queue.remove(0);
+            }
+            entry.queue.push(packet);
+            NeighborResolution::Queued
+        }
+        NeighborState::Reachable if now - entry.updated_at < REACHABLE_TIME => {
+            NeighborResolution::Resolved(entry.mac_addr, packet)
+        }
+        NeighborState::Reachable | NeighborState::Stale => {
+            // STALEのエントリに送信したらDELAYへ
+            entry.state = NeighborState::Delay;
+            entry.updated_at = now;
+            NeighborResolution::Resolved(entry.mac_addr, packet)
+        }
+        NeighborState::Delay | NeighborState::Probe => {
+            NeighborResolution::Resolved(entry.mac_addr, packet)
+        }
+    }
+}
+
+// INCOMPLETEのエントリにMACアドレスが判明したので待っていたパケットを送信可能にする
+fn complete_entry(entry: &mut NeighborEntry, mac_addr: [u8; 6], state: NeighborState) {
+    entry.mac_addr = mac_addr;
+    entry.state = state;
+    entry.updated_at = Instant::now();
+    entry.probes = 0;
+    let mut ready = READY_PACKETS.lock().unwrap();
+    for packet in entry.queue.drain(..) {
+        ready.push((mac_addr, packet));
+    }
+}
+
+// Source Link-Layer Address付きのNS/RS/RAを受信した時の更新 (RFC 4861 7.2.3)
+pub fn update_neighbor_from_solicit(ip_addr: u128, mac_addr: [u8; 6]) {
+    let mut cache = NEIGHBOR_CACHE.lock().unwrap();
+    match cache.iter_mut().find(|entry| entry.ip_addr == ip_addr) {
+        Some(entry) if entry.state == NeighborState::Incomplete => {
+            complete_entry(entry, mac_addr, NeighborState::Stale);
+        }
+        Some(entry) => {
+            if entry.mac_addr != mac_addr {
+                entry.mac_addr = mac_addr;
+                entry.state = NeighborState::Stale;
+                entry.updated_at = Instant::now();
+            }
+        }
+        None => cache.push(NeighborEntry {
+            ip_addr,
+            mac_addr,
+            state: NeighborState::Stale,
+            is_router: false,
+            probes: 0,
+            updated_at: Instant::now(),
+            queue: vec![],
+        }),
+    }
+}
+
+// NAを受信した時の更新 (RFC 4861 7.2.5)
+pub fn update_neighbor_from_advert(
+    target: u128,
+    mac_addr: Option<[u8; 6]>,
+    router: bool,
+    solicited: bool,
+    override_flag: bool,
+) {
+    let mut cache = NEIGHBOR_CACHE.lock().unwrap();
+    // エントリがなければ何もしない
+    let Some(entry) = cache.iter_mut().find(|entry| entry.ip_addr == target) else {
+        return;
+    };
+
+    if entry.state == NeighborState::Incomplete {
+        // リンク層アドレスがなければ無視
+        let Some(mac_addr) = mac_addr else {
+            return;
+        };
+        let state = if solicited {
+            NeighborState::Reachable
+        } else {
+            NeighborState::Stale
+        };
+        complete_entry(entry, mac_addr, state);
+        entry.is_router = router;
+        return;
+    }
+
+    let changed = mac_addr.is_some_and(|mac_addr| mac_addr != entry.mac_addr);
+    if !override_flag && changed {
+        // 上書きフラグがなく異なるアドレスならREACHABLEをSTALEに落とすだけ
+        if entry.state == NeighborState::Reachable {
+            entry.state = NeighborState::Stale;
+            entry.updated_at = Instant::now();
+        }
+        return;
+    }
+    if let Some(mac_addr) = mac_addr {
+        entry.mac_addr = mac_addr;
+    }
+    if solicited {
+        entry.state = NeighborState::Reachable;
+        entry.updated_at = Instant::now();
+        entry.probes = 0;
+    } else if changed {
+        entry.state = NeighborState::Stale;
+        entry.updated_at = Instant::now();
+    }
+    entry.is_router = router;
+}
+
+// 近隣キャッシュのタイマー処理
+// 状態を進めて、送信が必要なNSを返す
+pub fn neighbor_timer() -> Vec<NeighborProbe> {
+    let mut cache = NEIGHBOR_CACHE.lock().unwrap();
+    let now = Instant::now();
+    let mut probes = vec![];
+
+    cache.retain_mut(|entry| {
+        let elapsed = now - entry.updated_at;
+        match entry.state {
+            NeighborState::Incomplete if elapsed >= RETRANS_TIMER => {
+                if entry.probes >= MAX_MULTICAST_SOLICIT {
+                    println!("neighbor resolution failed {:x}", entry.ip_addr);
+                    return false;
+                }
+                entry.probes += 1;
+                entry.updated_at = now;
+                probes.push(NeighborProbe {
+                    target: entry.ip_addr,
+                    mac_addr: None,
+                });
+            }
+            NeighborState::Reachable if elapsed >= REACHABLE_TIME => {
+                entry.state = NeighborState::Stale;
+                entry.updated_at = now;
+            }
+            NeighborState::Delay if elapsed >= DELAY_FIRST_PROBE_TIME => {
+                entry.state = NeighborState::Probe;
+                entry.probes = 1;
+                entry.updated_at = now;
+                probes.push(NeighborProbe {
+                    target: entry.ip_addr,
+                    mac_addr: Some(entry.mac_addr),
+                });
+            }
+            NeighborState::Probe if elapsed >= RETRANS_TIMER => {
+                if entry.probes >= MAX_UNICAST_SOLICIT {
+                    println!("neighbor unreachable {:x}", entry.ip_addr);
+                    return false;
+                }
+                entry.probes += 1;
+                entry.updated_at = now;
+                probes.push(NeighborProbe {
+                    target: entry.ip_addr,
+                    mac_addr: Some(entry.mac_addr),
+                });
+            }
+            _ => {}
+        }
+        true
+    });
+
+    probes
+}
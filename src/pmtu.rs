@@ -0,0 +1,395 @@
+use crate::addrconf::get_ipv6_link_params;
+use crate::ethernet::ETHERNET_MTU;
+use crate::route::{lookup_ipv4_route, lookup_ipv6_route};
+use crate::socket::interface_mtu;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// IPv6の最小MTU、これより小さいPath MTUは受け付けない (RFC 8201 4.)
+pub const IPV6_MIN_MTU: u32 = 1280;
+// IPv4の最小MTU (RFC 791)
+pub const IPV4_MIN_MTU: u32 = 68;
+// 小さくしたPath MTUを捨てて再び大きいサイズを試すまでの時間 (RFC 8201 5.3, RFC 1191 6.3)
+const PATH_MTU_TIMEOUT: Duration = Duration::from_secs(600);
+
+// MTUを通知しない古いルーターのために推測に使うMTUの一覧 (RFC 1191 7.)
+const IPV4_MTU_PLATEAUS: [u32; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+// PLPMTUDの定数 (RFC 8899 5.1)
+const PLPMTUD_BASE_PLPMTU: u32 = 1200;
+const PLPMTUD_MAX_PROBES: u32 = 3;
+const PLPMTUD_PROBE_TIMER: Duration = Duration::from_secs(15);
+const PLPMTUD_RAISE_TIMER: Duration = Duration::from_secs(600);
+// 探索を終える、確認済みのサイズと上限の差
+const PLPMTUD_SEARCH_GRANULARITY: u32 = 8;
+// IPv4ヘッダとUDPヘッダの長さ
+const IPV4_UDP_HEADER_LENGTH: u32 = 20 + 8;
+
+#[derive(Debug)]
+struct PathMtuEntry<A> {
+    dst_addr: A,
+    mtu: u32,
+    updated_at: Instant,
+}
+
+static IPV6_PATH_MTU: Mutex<Vec<PathMtuEntry<u128>>> = Mutex::new(Vec::new());
+static IPV4_PATH_MTU: Mutex<Vec<PathMtuEntry<u32>>> = Mutex::new(Vec::new());
+
+// 小さくする方向にだけ宛先のPath MTUを更新する
+// 更新したらtrueを返す
+fn update_path_mtu<A: PartialEq>(
+    cache: &Mutex<Vec<PathMtuEntry<A>>>,
+    dst_addr: A,
+    mtu: u32,
+    current: u32,
+) -> bool {
+    if mtu >= current {
+        return false;
+    }
+    let now = Instant::now();
+    let mut cache = cache.lock().unwrap();
+    match cache.iter_mut().find(|e| e.dst_addr == dst_addr) {
+        Some(entry) => {
+            entry.mtu = mtu;
+            entry.updated_at = now;
+        }
+        None => cache.push(PathMtuEntry {
+            dst_addr,
+            mtu,
+            updated_at: now,
+        }),
+    }
+    true
+}
+
+// 宛先への経路のインターフェースのMTU
+pub(crate) fn ipv4_egress_mtu(dst_addr: u32) -> u32 {
+    lookup_ipv4_route(dst_addr).map_or(ETHERNET_MTU as u32, |route| interface_mtu(&route.interface))
+}
+
+pub(crate) fn ipv6_egress_mtu(dst_addr: u128) -> u32 {
+    lookup_ipv6_route(dst_addr).map_or(ETHERNET_MTU as u32, |route| interface_mtu(&route.interface))
+}
+
+// 記録がなければリンクのMTU
+fn path_mtu<A: PartialEq>(cache: &Mutex<Vec<PathMtuEntry<A>>>, dst_addr: A, link_mtu: u32) -> u32 {
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|e| e.updated_at.elapsed() < PATH_MTU_TIMEOUT);
+    cache
+        .iter()
+        .find(|e| e.dst_addr == dst_addr)
+        .map_or(link_mtu, |e| e.mtu.min(link_mtu))
+}
+
+// Packet Too Bigで通知されたMTUを宛先ごとに記録する
+// Path MTUは小さくする方向にしか更新しない
+pub fn update_ipv6_path_mtu(dst_addr: u128, mtu: u32) {
+    if mtu < IPV6_MIN_MTU {
+        println!("ignore packet too big mtu {mtu}");
+        return;
+    }
+    let current = ipv6_path_mtu(dst_addr);
+    if update_path_mtu(&IPV6_PATH_MTU, dst_addr, mtu, current) {
+        println!("path mtu of {dst_addr:x} is {mtu}");
+    }
+}
+
+// 宛先までのPath MTU、記録がなければリンクのMTU
+// RAでMTUが通知されていればそれも超えない
+pub fn ipv6_path_mtu(dst_addr: u128) -> u32 {
+    let link_mtu = ipv6_egress_mtu(dst_addr).min(get_ipv6_link_params().mtu);
+    path_mtu(&IPV6_PATH_MTU, dst_addr, link_mtu)
+}
+
+// Fragmentation Neededで通知されたMTUを宛先ごとに記録する (RFC 1191 6.1)
+// 最小MTUより小さい値は最小MTUとして扱う
+pub fn update_ipv4_path_mtu(dst_addr: u32, mtu: u32) {
+    let mtu = mtu.max(IPV4_MIN_MTU);
+    let current = ipv4_path_mtu(dst_addr);
+    if update_path_mtu(&IPV4_PATH_MTU, dst_addr, mtu, current) {
+        println!("path mtu of {} is {mtu}", Ipv4Addr::from(dst_addr));
+    }
+    update_plpmtud_from_ptb(dst_addr, mtu);
+}
+
+pub fn ipv4_path_mtu(dst_addr: u32) -> u32 {
+    path_mtu(&IPV4_PATH_MTU, dst_addr, ipv4_egress_mtu(dst_addr))
+}
+
+// Next-Hop MTUが0の時に、送ったパケットの長さより小さい次の値を推測する (RFC 1191 5.)
+pub fn next_lower_ipv4_mtu(total_len: u32) -> u32 {
+    IPV4_MTU_PLATEAUS
+        .into_iter()
+        .find(|mtu| *mtu < total_len)
+        .unwrap_or(IPV4_MIN_MTU)
+}
+
+// PLPMTUDの探索の状態 (RFC 8899 5.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlpmtudState {
+    Base,           // BASE_PLPMTUが通るか確認している
+    Search,         // より大きいサイズを探している
+    SearchComplete, // 探索が終わり、PMTU_RAISE_TIMERを待っている
+    Error,          // BASE_PLPMTUも通らない
+}
+
+// UDPのフローごとのPLPMTUDの状態
+#[derive(Debug, Clone)]
+pub struct PlpmtudPath {
+    pub src_port: u16,
+    pub dst_addr: u32,
+    pub dst_port: u16,
+    pub state: PlpmtudState,
+    pub plpmtu: u32,       // 通ることを確認したIPv4パケットの長さ
+    max_pmtu: u32,         // 探索の上限
+    max_pmtu_probed: bool, // 上限のサイズを試したか
+    probed_size: u32,      // 確認を待っているプローブの長さ
+    probe_count: u32,      // 応答がなかったプローブの数
+    probe_sent_at: Option<Instant>,
+    search_completed_at: Option<Instant>,
+}
+
+impl PlpmtudPath {
+    fn is_flow(&self, src_port: u16, dst_addr: u32, dst_port: u16) -> bool {
+        self.src_port == src_port && self.dst_addr == dst_addr && self.dst_port == dst_port
+    }
+
+    // 次に送るプローブの長さ
+    // 応答がなければ同じ長さを送り直し、最初は上限を試して、失敗したら二分探索する
+    fn next_probe_size(&mut self) -> u32 {
+        match self.state {
+            PlpmtudState::Base | PlpmtudState::Error => PLPMTUD_BASE_PLPMTU,
+            _ if self.probed_size != 0 => self.probed_size,
+            _ if !self.max_pmtu_probed => {
+                self.max_pmtu_probed = true;
+                self.max_pmtu
+            }
+            _ => (self.plpmtu + self.max_pmtu).div_ceil(2),
+        }
+    }
+
+    fn set_state(&mut self, state: PlpmtudState) {
+        if self.state != state {
+            println!(
+                "plpmtud {}:{} {:?} -> {state:?} plpmtu {}",
+                Ipv4Addr::from(self.dst_addr),
+                self.dst_port,
+                self.state,
+                self.plpmtu
+            );
+        }
+        self.state = state;
+        self.probe_count = 0;
+        self.probe_sent_at = None;
+        self.probed_size = 0;
+    }
+
+    // 確認済みのサイズが上限に近づいたら探索を終える
+    fn finish_search_if_converged(&mut self, now: Instant) {
+        if self.max_pmtu.saturating_sub(self.plpmtu) < PLPMTUD_SEARCH_GRANULARITY {
+            self.set_state(PlpmtudState::SearchComplete);
+            self.search_completed_at = Some(now);
+        }
+    }
+
+    fn confirm(&mut self, size: u32, now: Instant) {
+        if self.probe_sent_at.is_none() || size != self.probed_size {
+            return;
+        }
+        self.plpmtu = size;
+        match self.state {
+            PlpmtudState::Base | PlpmtudState::Error => {
+                self.set_state(PlpmtudState::Search);
+                self.finish_search_if_converged(now);
+            }
+            _ => {
+                self.probe_count = 0;
+                self.probe_sent_at = None;
+                self.probed_size = 0;
+                self.finish_search_if_converged(now);
+            }
+        }
+    }
+
+    // プローブの応答がPROBE_TIMERの間になければ失敗として数える
+    fn expire_probe(&mut self, now: Instant) {
+        if self
+            .probe_sent_at
+            .is_none_or(|sent_at| now - sent_at < PLPMTUD_PROBE_TIMER)
+        {
+            return;
+        }
+        self.probe_sent_at = None;
+        self.probe_count += 1;
+        if self.probe_count < PLPMTUD_MAX_PROBES {
+            return;
+        }
+        match self.state {
+            PlpmtudState::Base => {
+                self.plpmtu = IPV4_MIN_MTU;
+                self.set_state(PlpmtudState::Error);
+            }
+            PlpmtudState::Search => {
+                self.max_pmtu = self.probed_size - 1;
+                self.probe_count = 0;
+                self.probed_size = 0;
+                self.finish_search_if_converged(now);
+            }
+            // Errorの間はBASE_PLPMTUを試し続ける
+            _ => self.probe_count = 0,
+        }
+    }
+
+    // 送るべきプローブがあれば長さを返す
+    fn take_probe(&mut self, now: Instant) -> Option<u32> {
+        self.expire_probe(now);
+        if self.probe_sent_at.is_some() {
+            return None;
+        }
+        if self.state == PlpmtudState::SearchComplete {
+            // PMTU_RAISE_TIMERが過ぎたらもう一度大きいサイズを探す
+            if self
+                .search_completed_at
+                .is_none_or(|at| now - at < PLPMTUD_RAISE_TIMER)
+            {
+                return None;
+            }
+            self.max_pmtu = ipv4_path_mtu(self.dst_addr);
+            self.max_pmtu_probed = false;
+            self.set_state(PlpmtudState::Search);
+            self.finish_search_if_converged(now);
+            if self.state == PlpmtudState::SearchComplete {
+                return None;
+            }
+        }
+        let size = self.next_probe_size();
+        self.probed_size = size;
+        self.probe_sent_at = Some(now);
+        Some(size)
+    }
+}
+
+static PLPMTUD_PATHS: Mutex<Vec<PlpmtudPath>> = Mutex::new(Vec::new());
+
+// UDPのフローでPLPMTUDを始める (RFC 8899)
+// プローブは先頭4byteにプローブの長さを入れて0で埋めたデータグラムで、
+// 相手のアプリケーションが同じ内容を送り返すとそのサイズが通ったとみなす
+pub fn start_udp_plpmtud(src_port: u16, dst_addr: u32, dst_port: u16) {
+    let mut paths = PLPMTUD_PATHS.lock().unwrap();
+    if paths
+        .iter()
+        .any(|p| p.is_flow(src_port, dst_addr, dst_port))
+    {
+        return;
+    }
+    println!(
+        "start plpmtud {src_port} -> {}:{dst_port}",
+        Ipv4Addr::from(dst_addr)
+    );
+    paths.push(PlpmtudPath {
+        src_port,
+        dst_addr,
+        dst_port,
+        state: PlpmtudState::Base,
+        plpmtu: PLPMTUD_BASE_PLPMTU,
+        max_pmtu: ipv4_path_mtu(dst_addr),
+        max_pmtu_probed: false,
+        probed_size: 0,
+        probe_count: 0,
+        probe_sent_at: None,
+        search_completed_at: None,
+    });
+}
+
+pub fn stop_udp_plpmtud(src_port: u16, dst_addr: u32, dst_port: u16) {
+    PLPMTUD_PATHS
+        .lock()
+        .unwrap()
+        .retain(|p| !p.is_flow(src_port, dst_addr, dst_port));
+}
+
+pub fn get_udp_plpmtud_paths() -> Vec<PlpmtudPath> {
+    PLPMTUD_PATHS.lock().unwrap().clone()
+}
+
+// フローで送れるUDPのペイロードの最大長、PLPMTUDを使っていなければNone
+pub fn udp_plpmtud_max_payload(src_port: u16, dst_addr: u32, dst_port: u16) -> Option<u32> {
+    PLPMTUD_PATHS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|p| p.is_flow(src_port, dst_addr, dst_port))
+        .map(|p| p.plpmtu.saturating_sub(IPV4_UDP_HEADER_LENGTH))
+}
+
+// 送信するプローブ (送信元ポート, 宛先, 宛先ポート, ペイロード)
+pub(crate) fn plpmtud_timer() -> Vec<(u16, u32, u16, Vec<u8>)> {
+    let now = Instant::now();
+    let mut probes = vec![];
+    for path in PLPMTUD_PATHS.lock().unwrap().iter_mut() {
+        if let Some(size) = path.take_probe(now) {
+            println!(
+                "plpmtud probe {size} to {}:{}",
+                Ipv4Addr::from(path.dst_addr),
+                path.dst_port
+            );
+            let mut payload = vec![0; (size - IPV4_UDP_HEADER_LENGTH) as usize];
+            payload[0..4].copy_from_slice(&size.to_be_bytes());
+            probes.push((path.src_port, path.dst_addr, path.dst_port, payload));
+        }
+    }
+    probes
+}
+
+// 受信したUDPのデータグラムがプローブの応答ならそのサイズを確認済みにする
+pub(crate) fn read_plpmtud_response(src_addr: u32, src_port: u16, dst_port: u16, payload: &[u8]) {
+    if payload.len() < 4 {
+        return;
+    }
+    let size = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+    if payload.len() as u32 + IPV4_UDP_HEADER_LENGTH != size {
+        return;
+    }
+    let now = Instant::now();
+    let mut paths = PLPMTUD_PATHS.lock().unwrap();
+    if let Some(path) = paths
+        .iter_mut()
+        .find(|p| p.is_flow(dst_port, src_addr, src_port))
+    {
+        println!("plpmtud probe {size} confirmed");
+        path.confirm(size, now);
+    }
+}
+
+// ICMPで通知されたMTUをPLPMTUDに反映する (RFC 8899 4.6.2)
+// 確認済みのサイズより小さければBaseからやり直し、プローブより小さければ探索の上限にする
+// BASE_PLPMTUより小さければErrorにする
+fn update_plpmtud_from_ptb(dst_addr: u32, mtu: u32) {
+    let now = Instant::now();
+    for path in PLPMTUD_PATHS
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|p| p.dst_addr == dst_addr)
+    {
+        path.max_pmtu_probed = false;
+        if mtu < PLPMTUD_BASE_PLPMTU {
+            path.plpmtu = IPV4_MIN_MTU;
+            path.max_pmtu = mtu;
+            path.set_state(PlpmtudState::Error);
+        } else if mtu < path.plpmtu {
+            path.plpmtu = PLPMTUD_BASE_PLPMTU;
+            path.max_pmtu = mtu;
+            path.set_state(PlpmtudState::Base);
+        } else if path.probe_sent_at.is_some() && mtu < path.probed_size {
+            path.max_pmtu = mtu;
+            path.probe_count = 0;
+            path.probe_sent_at = None;
+            path.probed_size = 0;
+            path.finish_search_if_converged(now);
+        }
+    }
+}
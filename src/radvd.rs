@@ -0,0 +1,134 @@
+use crate::addrconf::PrefixInformation;
+use crate::ipv6::IPV6_ALL_ROUTERS_ADDR;
+use crate::mld::{join_ipv6_multicast_group, leave_ipv6_multicast_group};
+use crate::util::random_u64;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// RFC 4861 10. Router Constants
+const MAX_INITIAL_RTR_ADVERT_INTERVAL: Duration = Duration::from_secs(16);
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u8 = 3;
+const MIN_DELAY_BETWEEN_RAS: Duration = Duration::from_secs(3);
+const MAX_RA_DELAY_TIME: Duration = Duration::from_millis(500);
+
+// ルーターとして送信するRAの設定
+#[derive(Debug, Clone)]
+pub struct RaConfig {
+    pub min_interval: Duration, // MinRtrAdvInterval
+    pub max_interval: Duration, // MaxRtrAdvInterval
+    pub router_lifetime: u16,
+    pub cur_hop_limit: u8,
+    pub managed: bool, // Mフラグ
+    pub other: bool,   // Oフラグ
+    pub mtu: Option<u32>,
+    pub prefixes: Vec<PrefixInformation>,
+    pub rdnss: Vec<u128>, // Recursive DNS Server (RFC 8106)
+    pub rdnss_lifetime: u32,
+    pub dnssl: Vec<String>, // DNS Search List (RFC 8106)
+    pub dnssl_lifetime: u32,
+}
+
+impl Default for RaConfig {
+    fn default() -> Self {
+        RaConfig {
+            min_interval: Duration::from_secs(200),
+            max_interval: Duration::from_secs(600),
+            router_lifetime: 1800,
+            cur_hop_limit: 64,
+            managed: false,
+            other: false,
+            mtu: None,
+            prefixes: vec![],
+            rdnss: vec![],
+            rdnss_lifetime: 1200,
+            dnssl: vec![],
+            dnssl_lifetime: 1200,
+        }
+    }
+}
+
+struct RadvdState {
+    config: RaConfig,
+    initial_sent: u8,
+    last_sent: Option<Instant>,
+    next_at: Instant,
+    ceasing: bool, // 停止時にRouter Lifetime 0のRAを送る
+}
+
+static RADVD: Mutex<Option<RadvdState>> = Mutex::new(None);
+
+// RAの送信を開始する
+pub fn start_radvd(config: RaConfig) {
+    // RSを受信するために全ルーターマルチキャストに参加する
+    if !is_radvd_running() {
+        join_ipv6_multicast_group(IPV6_ALL_ROUTERS_ADDR);
+    }
+    *RADVD.lock().unwrap() = Some(RadvdState {
+        config,
+        initial_sent: 0,
+        last_sent: None,
+        next_at: Instant::now(),
+        ceasing: false,
+    });
+}
+
+// RAの送信を止める
+// ホストがすぐにデフォルトルーターから外すように、最後にRouter Lifetime 0のRAを送る
+pub fn stop_radvd() {
+    if let Some(state) = RADVD.lock().unwrap().as_mut() {
+        state.ceasing = true;
+        state.next_at = Instant::now();
+    }
+}
+
+pub fn is_radvd_running() -> bool {
+    RADVD.lock().unwrap().is_some()
+}
+
+// RSを受信したら、前回のRAから MIN_DELAY_BETWEEN_RAS 以上空けてRAを送る (RFC 4861 6.2.6)
+pub fn router_solicited() {
+    let mut radvd = RADVD.lock().unwrap();
+    let Some(state) = radvd.as_mut() else {
+        return;
+    };
+    let delay = Duration::from_millis(random_u64() % MAX_RA_DELAY_TIME.as_millis() as u64);
+    let mut at = Instant::now() + delay;
+    if let Some(last) = state.last_sent {
+        at = at.max(last + MIN_DELAY_BETWEEN_RAS);
+    }
+    state.next_at = state.next_at.min(at);
+}
+
+fn next_interval(config: &RaConfig) -> Duration {
+    let min = config.min_interval.as_millis() as u64;
+    let max = config.max_interval.as_millis() as u64;
+    Duration::from_millis(min + random_u64() % (max.saturating_sub(min) + 1))
+}
+
+// RA送信のタイマー処理
+// 送信すべきならRAの設定と広告するRouter Lifetimeを返す
+pub fn radvd_timer() -> Option<(RaConfig, u16)> {
+    let mut radvd = RADVD.lock().unwrap();
+    let state = radvd.as_mut()?;
+    let now = Instant::now();
+    if now < state.next_at {
+        return None;
+    }
+
+    if state.ceasing {
+        let config = state.config.clone();
+        *radvd = None;
+        leave_ipv6_multicast_group(IPV6_ALL_ROUTERS_ADDR);
+        return Some((config, 0));
+    }
+
+    let mut interval = next_interval(&state.config);
+    // 起動直後の数回は短い間隔で送る
+    if state.initial_sent < MAX_INITIAL_RTR_ADVERTISEMENTS {
+        state.initial_sent += 1;
+        interval = interval.min(MAX_INITIAL_RTR_ADVERT_INTERVAL);
+    }
+    state.last_sent = Some(now);
+    state.next_at = now + interval;
+    Some((state.config.clone(), state.config.router_lifetime))
+}
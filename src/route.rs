@@ -0,0 +1,142 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+// IPv4の経路
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv4Route {
+    pub prefix: u32,
+    pub prefix_len: u8,
+    pub gateway: Option<u32>, // Noneなら直接接続されたネットワーク
+    pub interface: String,
+    pub metric: u32,
+}
+
+static IPV4_ROUTES: Mutex<Vec<Ipv4Route>> = Mutex::new(Vec::new());
+
+fn prefix_mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+// 同じプレフィックスとゲートウェイの経路があれば置き換える
+pub fn add_ipv4_route(mut route: Ipv4Route) {
+    route.prefix &= prefix_mask(route.prefix_len);
+    let mut routes = IPV4_ROUTES.lock().unwrap();
+    // リンクローカルのように同じプレフィックスが複数のインターフェースにあることがある
+    routes.retain(|r| {
+        !(r.prefix == route.prefix
+            && r.prefix_len == route.prefix_len
+            && r.gateway == route.gateway
+            && r.interface == route.interface)
+    });
+    println!(
+        "add ipv4 route {}/{} via {:?} dev {} metric {}",
+        Ipv4Addr::from(route.prefix),
+        route.prefix_len,
+        route.gateway.map(Ipv4Addr::from),
+        route.interface,
+        route.metric
+    );
+    routes.push(route);
+}
+
+// gatewayがNoneなら直接接続の経路を消す
+pub fn remove_ipv4_route(prefix: u32, prefix_len: u8, gateway: Option<u32>) {
+    let prefix = prefix & prefix_mask(prefix_len);
+    IPV4_ROUTES
+        .lock()
+        .unwrap()
+        .retain(|r| !(r.prefix == prefix && r.prefix_len == prefix_len && r.gateway == gateway));
+}
+
+// インターフェースの直接接続の経路を削除する
+pub fn remove_ipv4_interface_route(prefix: u32, prefix_len: u8, interface: &str) {
+    let prefix = prefix & prefix_mask(prefix_len);
+    IPV4_ROUTES.lock().unwrap().retain(|r| {
+        !(r.prefix == prefix
+            && r.prefix_len == prefix_len
+            && r.gateway.is_none()
+            && r.interface == interface)
+    });
+}
+
+pub fn get_ipv4_routes() -> Vec<Ipv4Route> {
+    IPV4_ROUTES.lock().unwrap().clone()
+}
+
+// 最長一致で経路を探す、同じ長さならメトリックが小さいものを使う
+pub fn lookup_ipv4_route(dst_addr: u32) -> Option<Ipv4Route> {
+    IPV4_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| dst_addr & prefix_mask(r.prefix_len) == r.prefix)
+        .min_by_key(|r| (u8::MAX - r.prefix_len, r.metric))
+        .cloned()
+}
+
+// 宛先へのネクストホップ、経路がなければNone
+pub fn ipv4_next_hop(dst_addr: u32) -> Option<u32> {
+    lookup_ipv4_route(dst_addr).map(|route| route.gateway.unwrap_or(dst_addr))
+}
+
+// IPv6の経路
+// ここにない宛先はRAで学習したデフォルトルーターとオンリンクプレフィックスで決める
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6Route {
+    pub prefix: u128,
+    pub prefix_len: u8,
+    pub gateway: Option<u128>, // Noneなら直接接続されたネットワーク
+    pub interface: String,
+    pub metric: u32,
+}
+
+static IPV6_ROUTES: Mutex<Vec<Ipv6Route>> = Mutex::new(Vec::new());
+
+fn ipv6_prefix_mask(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+// 同じプレフィックスとゲートウェイの経路があれば置き換える
+pub fn add_ipv6_route(mut route: Ipv6Route) {
+    route.prefix &= ipv6_prefix_mask(route.prefix_len);
+    let mut routes = IPV6_ROUTES.lock().unwrap();
+    // fe80::/64のように同じプレフィックスが全インターフェースにあるので、インターフェースも比べる
+    routes.retain(|r| {
+        !(r.prefix == route.prefix
+            && r.prefix_len == route.prefix_len
+            && r.gateway == route.gateway
+            && r.interface == route.interface)
+    });
+    println!(
+        "add ipv6 route {}/{} via {:?} dev {} metric {}",
+        Ipv6Addr::from(route.prefix),
+        route.prefix_len,
+        route.gateway.map(Ipv6Addr::from),
+        route.interface,
+        route.metric
+    );
+    routes.push(route);
+}
+
+pub fn remove_ipv6_route(prefix: u128, prefix_len: u8, gateway: Option<u128>) {
+    let prefix = prefix & ipv6_prefix_mask(prefix_len);
+    IPV6_ROUTES
+        .lock()
+        .unwrap()
+        .retain(|r| !(r.prefix == prefix && r.prefix_len == prefix_len && r.gateway == gateway));
+}
+
+pub fn get_ipv6_routes() -> Vec<Ipv6Route> {
+    IPV6_ROUTES.lock().unwrap().clone()
+}
+
+// 最長一致で経路を探す、同じ長さならメトリックが小さいものを使う
+pub fn lookup_ipv6_route(dst_addr: u128) -> Option<Ipv6Route> {
+    IPV6_ROUTES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| dst_addr & ipv6_prefix_mask(r.prefix_len) == r.prefix)
+        .min_by_key(|r| (u8::MAX - r.prefix_len, r.metric))
+        .cloned()
+}
@@ -1,23 +1,568 @@
-use crate::ethernet::read_ethernet;
-use crate::util::{get_ipaddr, get_sockaddr};
+use crate::addrconf::{add_ipv6_addr, is_link_local, start_addrconf, Ipv6AddrOrigin};
+use crate::ethernet::{ethernet_timer, read_ethernet, ETHERNET_MTU};
+use crate::igmp::{join_ipv4_multicast_group, leave_ipv4_multicast_group};
+use crate::ipv4_addr::{add_ipv4_addr, is_ipv4_multicast};
+use crate::ipv4_link_local::{get_ipv4_link_local_config, start_ipv4_link_local};
+use crate::loopback::{is_loopback_interface, start_loopback, LOOPBACK_INTERFACE};
+use crate::route::{add_ipv4_route, add_ipv6_route, Ipv4Route, Ipv6Route};
+use crate::util::{get_ipaddrs, get_ipv4_routes, get_ipv6_routes, get_mtu, get_sockaddr};
 use nix::sys::socket::{
     bind, recvfrom, send, socket, AddressFamily, LinkAddr, MsgFlags, SockFlag, SockProtocol,
     SockType,
 };
-use std::os::fd::AsRawFd;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, RawFd};
 use std::sync::mpsc::sync_channel;
+use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
+
+// タイマー処理を呼び出す間隔
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+// マルチキャストは指定しなければリンクの外に出さない (RFC 1112 6.1)
+const DEFAULT_MULTICAST_TTL: u8 = 1;
+// ポートごとに溜めておく受信したUDPデータグラムの数
+const UDP_RECV_QUEUE_LEN: usize = 64;
+
+// パケットを送受信しているインターフェース
+// ルーターとして動かすときは受信したインターフェースとは別のインターフェースから送信する
+struct NetInterface {
+    name: String,
+    mac_addr: [u8; 6],
+    mtu: u32,
+    fd: RawFd,
+}
+
+static INTERFACES: Mutex<Vec<NetInterface>> = Mutex::new(Vec::new());
+
+// タイマースレッドを起動済みか
+static TIMER_STARTED: Mutex<bool> = Mutex::new(false);
+
+pub(crate) fn interface_mac_addr(if_name: &str) -> Option<[u8; 6]> {
+    INTERFACES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|i| i.name == if_name)
+        .map(|i| i.mac_addr)
+}
+
+pub(crate) fn interface_name(mac_addr: [u8; 6]) -> Option<String> {
+    if is_loopback_interface(mac_addr) {
+        return Some(String::from(LOOPBACK_INTERFACE));
+    }
+    INTERFACES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|i| i.mac_addr == mac_addr)
+        .map(|i| i.name.clone())
+}
+
+// インターフェースのMTU、分からなければEthernetのMTUとする
+pub(crate) fn interface_mtu(if_name: &str) -> u32 {
+    INTERFACES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|i| i.name == if_name)
+        .map_or(ETHERNET_MTU as u32, |i| i.mtu)
+}
+
+// 受信スレッドを経由せずにインターフェースから直接フレームを送信する
+pub(crate) fn send_on_interface(if_name: &str, frame: &[u8]) {
+    let interfaces = INTERFACES.lock().unwrap();
+    let Some(interface) = interfaces.iter().find(|i| i.name == if_name) else {
+        println!("unknown interface {if_name}");
+        return;
+    };
+    if let Err(e) = send(interface.fd, frame, MsgFlags::empty()) {
+        eprintln!("send on {if_name} failed {e}");
+    }
+}
+
+// ソケットのトランスポート層プロトコル
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    Tcp,
+    Udp,
+}
+
+// IPヘッダのTraffic Class (RFC 2474, RFC 3168)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TrafficClass {
+    pub dscp: u8, // 上位6bit
+    pub ecn: u8,  // 下位2bit
+}
+
+impl TrafficClass {
+    pub fn to_u8(self) -> u8 {
+        (self.dscp & 0x3f) << 2 | self.ecn & 0x03
+    }
+}
+
+// ソケット (プロトコルとローカルポート) ごとのTraffic Class
+struct SocketTrafficClass {
+    protocol: SocketProtocol,
+    port: u16,
+    traffic_class: TrafficClass,
+}
+
+static SOCKET_TRAFFIC_CLASSES: Mutex<Vec<SocketTrafficClass>> = Mutex::new(Vec::new());
+
+// ローカルポートから送信するパケットのDSCP/ECNを設定する
+pub fn set_traffic_class(protocol: SocketProtocol, port: u16, traffic_class: TrafficClass) {
+    let mut classes = SOCKET_TRAFFIC_CLASSES.lock().unwrap();
+    match classes
+        .iter_mut()
+        .find(|c| c.protocol == protocol && c.port == port)
+    {
+        Some(entry) => entry.traffic_class = traffic_class,
+        None => classes.push(SocketTrafficClass {
+            protocol,
+            port,
+            traffic_class,
+        }),
+    }
+}
+
+pub fn clear_traffic_class(protocol: SocketProtocol, port: u16) {
+    SOCKET_TRAFFIC_CLASSES
+        .lock()
+        .unwrap()
+        .retain(|c| !(c.protocol == protocol && c.port == port));
+}
+
+// 設定されていなければDSCP 0 (best effort)、Not-ECT
+pub fn get_traffic_class(protocol: SocketProtocol, port: u16) -> TrafficClass {
+    socket_traffic_class(protocol, port).unwrap_or_default()
+}
+
+pub(crate) fn socket_traffic_class(protocol: SocketProtocol, port: u16) -> Option<TrafficClass> {
+    SOCKET_TRAFFIC_CLASSES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|c| c.protocol == protocol && c.port == port)
+        .map(|c| c.traffic_class)
+}
+
+// 送信するIPv4パケットのヘッダの既定値
+// ソケットにTTLやTraffic Classが設定されていればそちらを使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub default_ttl: u8,
+    pub default_tos: u8,
+}
+
+static IPV4_CONFIG: Mutex<Ipv4Config> = Mutex::new(Ipv4Config {
+    default_ttl: 64,
+    default_tos: 0,
+});
+
+pub fn set_ipv4_config(config: Ipv4Config) {
+    *IPV4_CONFIG.lock().unwrap() = config;
+}
+
+pub fn get_ipv4_config() -> Ipv4Config {
+    *IPV4_CONFIG.lock().unwrap()
+}
+
+// IPv4ヘッダのDFの付け方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DontFragmentPolicy {
+    Want, // Path MTUに収まればDFを立て、収まらなければDFなしでフラグメントする
+    Do,   // 常にDFを立て、Path MTUを超えるパケットは送らない
+    Dont, // DFを立てない
+}
+
+// ソケット (プロトコルとローカルポート) ごとのIPv4ヘッダの設定
+struct SocketIpv4Options {
+    protocol: SocketProtocol,
+    port: u16,
+    ttl: Option<u8>,
+    dont_fragment: Option<DontFragmentPolicy>,
+}
+
+static SOCKET_IPV4_OPTIONS: Mutex<Vec<SocketIpv4Options>> = Mutex::new(Vec::new());
+
+fn with_socket_ipv4_options(
+    protocol: SocketProtocol,
+    port: u16,
+    f: impl FnOnce(&mut SocketIpv4Options),
+) {
+    let mut options = SOCKET_IPV4_OPTIONS.lock().unwrap();
+    match options
+        .iter_mut()
+        .find(|o| o.protocol == protocol && o.port == port)
+    {
+        Some(entry) => f(entry),
+        None => {
+            let mut entry = SocketIpv4Options {
+                protocol,
+                port,
+                ttl: None,
+                dont_fragment: None,
+            };
+            f(&mut entry);
+            options.push(entry);
+        }
+    }
+}
+
+// ローカルポートから送信するユニキャストのパケットのTTLを設定する
+pub fn set_socket_ttl(protocol: SocketProtocol, port: u16, ttl: u8) {
+    with_socket_ipv4_options(protocol, port, |o| o.ttl = Some(ttl));
+}
+
+// 設定されていなければNoneで、get_ipv4_configのdefault_ttlを使う
+pub fn get_socket_ttl(protocol: SocketProtocol, port: u16) -> Option<u8> {
+    SOCKET_IPV4_OPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|o| o.protocol == protocol && o.port == port)
+        .and_then(|o| o.ttl)
+}
+
+pub fn set_dont_fragment_policy(protocol: SocketProtocol, port: u16, policy: DontFragmentPolicy) {
+    with_socket_ipv4_options(protocol, port, |o| o.dont_fragment = Some(policy));
+}
+
+// 設定されていなければ、TCPはPath MTUに合わせてMSSを決めるのでDo (RFC 1191 3.)、UDPはDont
+pub fn get_dont_fragment_policy(protocol: SocketProtocol, port: u16) -> DontFragmentPolicy {
+    SOCKET_IPV4_OPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|o| o.protocol == protocol && o.port == port)
+        .and_then(|o| o.dont_fragment)
+        .unwrap_or(match protocol {
+            SocketProtocol::Tcp => DontFragmentPolicy::Do,
+            SocketProtocol::Udp => DontFragmentPolicy::Dont,
+        })
+}
+
+pub fn clear_socket_ipv4_options(protocol: SocketProtocol, port: u16) {
+    SOCKET_IPV4_OPTIONS
+        .lock()
+        .unwrap()
+        .retain(|o| !(o.protocol == protocol && o.port == port));
+}
+
+// UDPソケット (ローカルポート) ごとのマルチキャストの設定
+struct UdpMulticastSocket {
+    port: u16,
+    groups: Vec<u32>, // 参加しているIPv4マルチキャストグループ
+    ttl: u8,          // 送信するマルチキャストのTTL
+}
+
+// 送信を待っているマルチキャストのUDPデータグラム
+pub(crate) struct MulticastDatagram {
+    pub src_port: u16,
+    pub group: u32,
+    pub dst_port: u16,
+    pub ttl: u8,
+    pub payload: Vec<u8>,
+}
+
+// 受信したUDPデータグラム
+#[derive(Debug, Clone)]
+pub struct UdpReceivedDatagram {
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub payload: Vec<u8>,
+}
+
+// ローカルポートごとの受信キュー
+// マルチキャストだけを受け取るソケットはbind_udp_portしていない
+struct UdpRecvQueue {
+    port: u16,
+    bound: bool,
+    datagrams: VecDeque<UdpReceivedDatagram>,
+}
+
+static UDP_MULTICAST_SOCKETS: Mutex<Vec<UdpMulticastSocket>> = Mutex::new(Vec::new());
+static UDP_RECV_QUEUES: Mutex<Vec<UdpRecvQueue>> = Mutex::new(Vec::new());
+static PENDING_MULTICAST_DATAGRAMS: Mutex<Vec<MulticastDatagram>> = Mutex::new(Vec::new());
+
+fn with_udp_multicast_socket<T>(port: u16, f: impl FnOnce(&mut UdpMulticastSocket) -> T) -> T {
+    let mut sockets = UDP_MULTICAST_SOCKETS.lock().unwrap();
+    let index = match sockets.iter().position(|s| s.port == port) {
+        Some(index) => index,
+        None => {
+            sockets.push(UdpMulticastSocket {
+                port,
+                groups: vec![],
+                ttl: DEFAULT_MULTICAST_TTL,
+            });
+            sockets.len() - 1
+        }
+    };
+    f(&mut sockets[index])
+}
+
+// ローカルポートのソケットでIPv4マルチキャストグループに参加する
+// 同じグループに参加しているソケットがあってもIGMPの参加は数で管理する
+pub fn join_udp_multicast_group(port: u16, group: u32) {
+    if !is_ipv4_multicast(group) {
+        println!("{} is not a multicast address", Ipv4Addr::from(group));
+        return;
+    }
+    let joined = with_udp_multicast_socket(port, |socket| {
+        if socket.groups.contains(&group) {
+            return false;
+        }
+        socket.groups.push(group);
+        true
+    });
+    if joined {
+        join_ipv4_multicast_group(group);
+    }
+}
+
+pub fn leave_udp_multicast_group(port: u16, group: u32) {
+    let left = with_udp_multicast_socket(port, |socket| {
+        let len = socket.groups.len();
+        socket.groups.retain(|g| *g != group);
+        socket.groups.len() != len
+    });
+    if left {
+        leave_ipv4_multicast_group(group);
+    }
+}
+
+// マルチキャスト宛てのデータグラムを受け取るソケットか
+pub(crate) fn is_udp_multicast_member(port: u16, group: u32) -> bool {
+    UDP_MULTICAST_SOCKETS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|s| s.port == port && s.groups.contains(&group))
+}
+
+// ローカルポートでユニキャストのUDPデータグラムを受信する
+// 受信したデータグラムはrecv_udp_datagramで取り出す
+pub fn bind_udp_port(port: u16) {
+    let mut queues = UDP_RECV_QUEUES.lock().unwrap();
+    match queues.iter_mut().find(|q| q.port == port) {
+        Some(queue) => queue.bound = true,
+        None => queues.push(UdpRecvQueue {
+            port,
+            bound: true,
+            datagrams: VecDeque::new(),
+        }),
+    }
+}
+
+// ユニキャストの受信をやめて、取り出していないデータグラムを捨てる
+pub fn close_udp_port(port: u16) {
+    UDP_RECV_QUEUES.lock().unwrap().retain(|q| q.port != port);
+}
+
+pub(crate) fn is_udp_port_bound(port: u16) -> bool {
+    UDP_RECV_QUEUES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|q| q.port == port && q.bound)
+}
+
+// 受信したデータグラムをポートの受信キューに入れる
+// 取り出されずに溜まったら古いものから捨てる
+pub(crate) fn deliver_udp_datagram(port: u16, datagram: UdpReceivedDatagram) {
+    let mut queues = UDP_RECV_QUEUES.lock().unwrap();
+    let index = match queues.iter().position(|q| q.port == port) {
+        Some(index) => index,
+        None => {
+            queues.push(UdpRecvQueue {
+                port,
+                bound: false,
+                datagrams: VecDeque::new(),
+            });
+            queues.len() - 1
+        }
+    };
+    let datagrams = &mut queues[index].datagrams;
+    if datagrams.len() >= UDP_RECV_QUEUE_LEN {
+        datagrams.pop_front();
+    }
+    datagrams.push_back(datagram);
+}
+
+// ローカルポートで受信したデータグラムを1つ取り出す、なければNone
+pub fn recv_udp_datagram(port: u16) -> Option<UdpReceivedDatagram> {
+    UDP_RECV_QUEUES
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|q| q.port == port)?
+        .datagrams
+        .pop_front()
+}
+
+pub fn set_udp_multicast_ttl(port: u16, ttl: u8) {
+    with_udp_multicast_socket(port, |socket| socket.ttl = ttl);
+}
+
+pub fn get_udp_multicast_ttl(port: u16) -> u8 {
+    UDP_MULTICAST_SOCKETS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|s| s.port == port)
+        .map_or(DEFAULT_MULTICAST_TTL, |s| s.ttl)
+}
+
+// ローカルポートからIPv4マルチキャストグループにUDPデータグラムを送る
+// 実際の送信はタイマースレッドから行う
+pub fn send_udp_multicast(src_port: u16, group: u32, dst_port: u16, payload: Vec<u8>) {
+    if !is_ipv4_multicast(group) {
+        println!("{} is not a multicast address", Ipv4Addr::from(group));
+        return;
+    }
+    let ttl = get_udp_multicast_ttl(src_port);
+    PENDING_MULTICAST_DATAGRAMS
+        .lock()
+        .unwrap()
+        .push(MulticastDatagram {
+            src_port,
+            group,
+            dst_port,
+            ttl,
+            payload,
+        });
+}
+
+pub(crate) fn take_multicast_datagrams() -> Vec<MulticastDatagram> {
+    std::mem::take(&mut *PENDING_MULTICAST_DATAGRAMS.lock().unwrap())
+}
+
+// 送信を待っているユニキャストのUDPデータグラム
+// 宛先が自分のアドレスやループバックならEthernetに送らずに受信させる
+pub(crate) struct UdpDatagram {
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+    pub payload: Vec<u8>,
+}
+
+static PENDING_UDP_DATAGRAMS: Mutex<Vec<UdpDatagram>> = Mutex::new(Vec::new());
+
+// UDPデータグラムを送る
+// 次のタイマー処理で送信元アドレスを選んで送信する
+pub fn send_udp_datagram(src_port: u16, dst_addr: IpAddr, dst_port: u16, payload: Vec<u8>) {
+    PENDING_UDP_DATAGRAMS.lock().unwrap().push(UdpDatagram {
+        src_port,
+        dst_addr,
+        dst_port,
+        payload,
+    });
+}
+
+pub(crate) fn take_udp_datagrams() -> Vec<UdpDatagram> {
+    std::mem::take(&mut *PENDING_UDP_DATAGRAMS.lock().unwrap())
+}
+
+// 再送やNUDなど受信をきっかけにしない送信はタイマースレッドから行う
+// タイマーの状態は全インターフェースで共有しているので、インターフェースの数に関わらず1つだけ動かす
+fn start_timer() {
+    let mut started = TIMER_STARTED.lock().unwrap();
+    if *started {
+        return;
+    }
+    *started = true;
+    thread::spawn(|| loop {
+        thread::sleep(TIMER_INTERVAL);
+        let interfaces: Vec<(String, [u8; 6])> = INTERFACES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|i| (i.name.clone(), i.mac_addr))
+            .collect();
+        let Some((if_name, _)) = interfaces.first().cloned() else {
+            continue;
+        };
+        let (tx, rx) = sync_channel::<Vec<u8>>(0);
+        thread::spawn(move || {
+            ethernet_timer(tx, interfaces);
+        });
+        for send_buf in rx {
+            send_on_interface(&if_name, &send_buf);
+        }
+    });
+}
 
 pub fn recv_packet(if_name: Box<str>) {
     let mut buf = [0; 1514];
     let sock_addr = get_sockaddr(Box::from(if_name.clone())).unwrap();
 
     let mac_addr = sock_addr.as_link_addr().unwrap().addr().unwrap();
+    start_loopback();
+
+    // インターフェースのアドレスを全て自分のアドレスとして使う
+    let ip_addrs = get_ipaddrs(if_name.clone());
+    // IPv4のアドレスがなければリンクローカルアドレスを自動で設定する (RFC 3927)
+    let has_ipv4_addr = ip_addrs.iter().any(|(ip_addr, _)| ip_addr.is_ipv4());
+    for (ip_addr, prefix_len) in ip_addrs {
+        match ip_addr {
+            IpAddr::V4(ipv4) => {
+                add_ipv4_addr(ipv4.into(), prefix_len);
+                // アドレスのサブネットは直接接続
+                add_ipv4_route(Ipv4Route {
+                    prefix: ipv4.into(),
+                    prefix_len,
+                    gateway: None,
+                    interface: if_name.to_string(),
+                    metric: 0,
+                });
+            }
+            IpAddr::V6(ipv6) => {
+                add_ipv6_addr(ipv6.into(), prefix_len, Ipv6AddrOrigin::Static);
+                // リンクローカルはどのインターフェースにもあるので経路にしない
+                if !is_link_local(ipv6.into()) {
+                    add_ipv6_route(Ipv6Route {
+                        prefix: ipv6.into(),
+                        prefix_len,
+                        gateway: None,
+                        interface: if_name.to_string(),
+                        metric: 0,
+                    });
+                }
+            }
+        }
+    }
+    // デフォルトルートなどはカーネルの経路表から引き継ぐ
+    for (prefix, prefix_len, gateway, metric) in get_ipv4_routes(&if_name) {
+        add_ipv4_route(Ipv4Route {
+            prefix,
+            prefix_len,
+            gateway: (gateway != 0).then_some(gateway),
+            interface: if_name.to_string(),
+            metric,
+        });
+    }
+
+    for (prefix, prefix_len, gateway, metric) in get_ipv6_routes(&if_name) {
+        add_ipv6_route(Ipv6Route {
+            prefix,
+            prefix_len,
+            gateway: (gateway != 0).then_some(gateway),
+            interface: if_name.to_string(),
+            metric,
+        });
+    }
 
-    // Todo: sock_addrとIPv4アドレスを同時に取得したい
-    let ip_addr = get_ipaddr(if_name);
-    if ip_addr.is_none() {
-        eprintln!("NO ip addr err");
+    // IPv6はリンクローカルアドレスを生成してSLAACでグローバルアドレスを取得する
+    start_addrconf(mac_addr);
+    if !has_ipv4_addr {
+        if get_ipv4_link_local_config().enabled {
+            start_ipv4_link_local(mac_addr);
+        } else {
+            eprintln!("NO ipv4 addr err");
+        }
     }
 
     let sock = socket(
@@ -29,22 +574,31 @@ pub fn recv_packet(if_name: Box<str>) {
     .expect("create socket failed");
 
     bind(sock.as_raw_fd(), &sock_addr).unwrap();
+    INTERFACES.lock().unwrap().push(NetInterface {
+        name: if_name.to_string(),
+        mac_addr,
+        mtu: get_mtu(&if_name).unwrap_or(ETHERNET_MTU as u32),
+        fd: sock.as_raw_fd(),
+    });
 
-    println!("waiting for recv packet...");
+    start_timer();
 
-    let (tx, rx) = sync_channel::<Vec<u8>>(0);
+    println!("waiting for recv packet...");
 
     loop {
-        let tx = tx.clone();
         match recvfrom::<LinkAddr>(sock.as_raw_fd(), &mut buf) {
             Ok((size, _)) => {
+                // 1つの受信パケットから0個以上のパケットを送信する
+                // read_ethernetが終わってtxが破棄されるまで送信を続ける
+                let (tx, rx) = sync_channel::<Vec<u8>>(0);
                 thread::spawn(move || {
-                    read_ethernet(buf[0..size].to_owned(), tx, mac_addr, ip_addr);
+                    read_ethernet(buf[0..size].to_owned(), tx, mac_addr);
                 });
-                let send_buf = rx.recv().unwrap();
-                if 0 < send_buf.len() {
-                    println!("send buf is {send_buf:?}");
-                    send(sock.as_raw_fd(), &*send_buf, MsgFlags::empty()).unwrap();
+                for send_buf in rx {
+                    if !send_buf.is_empty() {
+                        println!("send buf is {send_buf:?}");
+                        send(sock.as_raw_fd(), &send_buf, MsgFlags::empty()).unwrap();
+                    }
                 }
             }
             Err(e) => {
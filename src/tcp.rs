@@ -1,3 +1,7 @@
+use crate::conntrack::ipv6_upper_layer;
+use crate::ipv4::IP_PROTOCOL_NUMBER_TCP;
+use crate::pmtu::{ipv4_path_mtu, ipv6_path_mtu, IPV6_MIN_MTU};
+use crate::util::{to_u16, to_u32, update_checksum};
 use bytes::Buf;
 
 const FIN: u8 = 0x01;
@@ -23,6 +27,14 @@ pub struct TCPHeader {
     urg_pt: u16,
 }
 
+// IPv4, IPv6ヘッダとTCPヘッダ (オプションなし) の長さ
+const IPV4_TCP_HEADER_LENGTH: u32 = 20 + 20;
+const IPV6_TCP_HEADER_LENGTH: u32 = 40 + 20;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
 struct TCPDummyHeader {
     src_ip: u32,
     dst_ip: u32,
@@ -47,3 +59,75 @@ pub fn read_tcp_packet(tcp_packet: Vec<u8>) {
     };
     tcp.offset = tcp.offset >> 2;
 }
+
+// 宛先のPath MTUから決めるMSS、Fragmentation Neededを受け取ると小さくなる
+pub fn ipv4_mss(dst_addr: u32) -> u16 {
+    (ipv4_path_mtu(dst_addr) - IPV4_TCP_HEADER_LENGTH) as u16
+}
+
+// 宛先のPath MTUから決めるMSS、IPv6の最小MTUより小さくはしない (RFC 8201 4.)
+pub fn ipv6_mss(dst_addr: u128) -> u16 {
+    (ipv6_path_mtu(dst_addr).max(IPV6_MIN_MTU) - IPV6_TCP_HEADER_LENGTH) as u16
+}
+
+// 送信するIPv4パケットがSYNなら、MSSオプションを宛先のMSSまで下げる
+pub(crate) fn clamp_ipv4_tcp_mss(packet: &mut [u8]) {
+    if packet.len() < 20 || packet[9] != IP_PROTOCOL_NUMBER_TCP {
+        return;
+    }
+    // 先頭以外のフラグメントにはTCPヘッダがない
+    if to_u16(&packet[6..]) & 0x1fff != 0 {
+        return;
+    }
+    let header_length = (packet[0] & 0x0f) as usize * 4;
+    let mss = ipv4_mss(to_u32(&packet[16..20]));
+    if let Some(segment) = packet.get_mut(header_length..) {
+        clamp_tcp_mss(segment, mss);
+    }
+}
+
+// 送信するIPv6パケットがSYNなら、MSSオプションを宛先のMSSまで下げる
+pub(crate) fn clamp_ipv6_tcp_mss(packet: &mut [u8]) {
+    if packet.len() < 40 {
+        return;
+    }
+    let Some((IP_PROTOCOL_NUMBER_TCP, offset)) = ipv6_upper_layer(packet) else {
+        return;
+    };
+    let mss = ipv6_mss(u128::from_be_bytes(packet[24..40].try_into().unwrap()));
+    clamp_tcp_mss(&mut packet[offset..], mss);
+}
+
+// SYNのMSSオプションがmssより大きければ書き換えて、チェックサムを差分で更新する
+fn clamp_tcp_mss(segment: &mut [u8], mss: u16) {
+    if segment.len() < 20 || segment[13] & SYN == 0 {
+        return;
+    }
+    let data_offset = ((segment[12] >> 4) as usize * 4).min(segment.len());
+    let mut pos = 20;
+    while pos < data_offset {
+        match segment[pos] {
+            TCP_OPTION_END => return,
+            TCP_OPTION_NOP => pos += 1,
+            kind => {
+                let Some(&len) = segment.get(pos + 1) else {
+                    return;
+                };
+                if len < 2 || pos + len as usize > data_offset {
+                    return;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let old = to_u16(&segment[pos + 2..]);
+                    if old > mss {
+                        println!("clamp tcp mss {old} to {mss}");
+                        segment[pos + 2..pos + 4].copy_from_slice(&mss.to_be_bytes());
+                        let checksum = update_checksum(to_u16(&segment[16..]), old, mss);
+                        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+                    }
+                    return;
+                }
+                pos += len as usize;
+            }
+        }
+    }
+}
@@ -1,9 +1,18 @@
 use crate::{
+    addrconf::select_ipv6_reply_src,
+    dhcpv6::{is_dhcpv6_client_running, read_dhcpv6_packet, DHCPV6_CLIENT_PORT},
     dns::{self, read_dns_packet},
-    ipv4::IPv4Header,
+    ipv4::{IPv4Header, IP_PROTOCOL_NUMBER_UDP},
+    ipv4_addr::{is_ipv4_multicast, select_ipv4_reply_src},
+    ipv6::IPv6Header,
+    pmtu::read_plpmtud_response,
+    socket::{deliver_udp_datagram, is_udp_port_bound, UdpReceivedDatagram},
     util::checksum,
 };
 use bytes::{Buf, BufMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const DNS_PORT: u16 = 53;
 
 #[derive(Debug)]
 struct UDPHeader {
@@ -13,15 +22,11 @@ struct UDPHeader {
     checksum: u16,
 }
 
-#[derive(Debug)]
-struct UDPDummyHeader {
-    src_addr: u32,
-    dst_addr: u32,
-    protocol: u16,
-    length: u16,
-}
-
 pub fn read_udp_packet(ipv4_header: &IPv4Header, packet: Vec<u8>) -> Vec<u8> {
+    // UDPヘッダに満たないパケットは捨てる
+    if packet.len() < 8 {
+        return vec![];
+    }
     let mut buf = &packet[..];
 
     let udp = UDPHeader {
@@ -33,12 +38,28 @@ pub fn read_udp_packet(ipv4_header: &IPv4Header, packet: Vec<u8>) -> Vec<u8> {
     println!(
         "recv udp packet header is {:?}, payload is {:?}",
         udp,
-        String::from_utf8(Vec::from(buf)).unwrap()
+        String::from_utf8_lossy(buf)
     );
+    read_plpmtud_response(ipv4_header.src_addr, udp.src_port, udp.dst_port, buf);
+    // 参加したグループ宛てのマルチキャストと、bindしたポート宛てのものは受信キューに入れる
+    if is_ipv4_multicast(ipv4_header.dst_addr) || is_udp_port_bound(udp.dst_port) {
+        let datagram = UdpReceivedDatagram {
+            src_addr: IpAddr::V4(Ipv4Addr::from(ipv4_header.src_addr)),
+            src_port: udp.src_port,
+            dst_addr: IpAddr::V4(Ipv4Addr::from(ipv4_header.dst_addr)),
+            payload: buf.to_vec(),
+        };
+        deliver_udp_datagram(udp.dst_port, datagram);
+        return vec![];
+    }
     match udp.dst_port {
         53 => {
             // DNSレスポンスパケットを生成
-            let dns_response = read_dns_packet(packet);
+            let querier = IpAddr::V4(Ipv4Addr::from(ipv4_header.src_addr));
+            let dns_response = read_dns_packet(packet, querier);
+            if dns_response.is_empty() {
+                return vec![];
+            }
             return out_udp_packet(ipv4_header, udp, dns_response);
         }
         _ => {}
@@ -46,16 +67,134 @@ pub fn read_udp_packet(ipv4_header: &IPv4Header, packet: Vec<u8>) -> Vec<u8> {
     vec![]
 }
 
-pub fn out_udp_packet(
-    ipv4_header: &IPv4Header,
+fn out_udp_packet(ipv4_header: &IPv4Header, recv_udpheader: UDPHeader, packet: Vec<u8>) -> Vec<u8> {
+    // 応答はIPヘッダの送信元と同じアドレスで疑似ヘッダを作る
+    let src_addr = select_ipv4_reply_src(ipv4_header.dst_addr, ipv4_header.src_addr);
+    out_udp_datagram(
+        src_addr,
+        ipv4_header.src_addr,
+        recv_udpheader.dst_port,
+        recv_udpheader.src_port,
+        packet,
+    )
+}
+
+// IPv4で送るUDPデータグラムを生成する
+pub fn out_udp_datagram(
+    src_addr: u32,
+    dst_addr: u32,
+    src_port: u16,
+    dst_port: u16,
+    packet: Vec<u8>,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    // UDPヘッダ
+    let send_udp = UDPHeader {
+        src_port,
+        dst_port,
+        length: (8 + packet.len()) as u16,
+        checksum: 0,
+    };
+    buf.put_u16(send_udp.src_port);
+    buf.put_u16(send_udp.dst_port);
+    buf.put_u16(send_udp.length);
+    buf.put_u16(send_udp.checksum);
+    buf.put(packet.as_slice());
+
+    let mut calc_checksum_buf: Vec<u8> = Vec::new();
+    calc_checksum_buf.put_u32(src_addr);
+    calc_checksum_buf.put_u32(dst_addr);
+    calc_checksum_buf.put_u16(IP_PROTOCOL_NUMBER_UDP as u16);
+    calc_checksum_buf.put_u16(buf.len() as u16);
+    calc_checksum_buf.put_slice(&buf);
+    // 計算結果が0なら、チェックサムなしと区別するため0xffffにする (RFC 768)
+    let checksum = match checksum(&calc_checksum_buf) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    buf[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    buf
+}
+
+// 受信を待っているポートか
+pub fn is_udp_port_open(port: u16) -> bool {
+    port == DNS_PORT
+        || (port == DHCPV6_CLIENT_PORT && is_dhcpv6_client_running())
+        || is_udp_port_bound(port)
+}
+
+pub fn read_udp6_packet(ipv6_header: &IPv6Header, packet: Vec<u8>) -> Vec<u8> {
+    if packet.len() < 8 {
+        return vec![];
+    }
+    let mut buf = &packet[..];
+
+    let udp = UDPHeader {
+        src_port: buf.get_u16(),
+        dst_port: buf.get_u16(),
+        length: buf.get_u16(),
+        checksum: buf.get_u16(),
+    };
+    println!(
+        "recv udp packet header is {:?}, payload is {:?}",
+        udp,
+        String::from_utf8_lossy(buf)
+    );
+    if udp.dst_port == DHCPV6_CLIENT_PORT {
+        read_dhcpv6_packet(ipv6_header.src_addr, buf);
+        return vec![];
+    }
+    if is_udp_port_bound(udp.dst_port) {
+        let datagram = UdpReceivedDatagram {
+            src_addr: IpAddr::V6(Ipv6Addr::from(ipv6_header.src_addr)),
+            src_port: udp.src_port,
+            dst_addr: IpAddr::V6(Ipv6Addr::from(ipv6_header.dst_addr)),
+            payload: buf.to_vec(),
+        };
+        deliver_udp_datagram(udp.dst_port, datagram);
+        return vec![];
+    }
+    if udp.dst_port == DNS_PORT {
+        // DNSレスポンスパケットを生成
+        let querier = IpAddr::V6(Ipv6Addr::from(ipv6_header.src_addr));
+        let dns_response = read_dns_packet(packet, querier);
+        if dns_response.is_empty() {
+            return vec![];
+        }
+        return out_udp6_packet(ipv6_header, udp, dns_response);
+    }
+    vec![]
+}
+
+fn out_udp6_packet(
+    ipv6_header: &IPv6Header,
     recv_udpheader: UDPHeader,
     packet: Vec<u8>,
+) -> Vec<u8> {
+    let src_addr = select_ipv6_reply_src(ipv6_header.dst_addr, ipv6_header.src_addr);
+    out_udp6_datagram(
+        src_addr,
+        ipv6_header.src_addr,
+        recv_udpheader.dst_port,
+        recv_udpheader.src_port,
+        packet,
+    )
+}
+
+// IPv6で送るUDPデータグラムを生成する
+pub fn out_udp6_datagram(
+    src_addr: u128,
+    dst_addr: u128,
+    src_port: u16,
+    dst_port: u16,
+    packet: Vec<u8>,
 ) -> Vec<u8> {
     let mut buf = Vec::new();
     // UDPヘッダ
     let send_udp = UDPHeader {
-        src_port: recv_udpheader.dst_port,
-        dst_port: recv_udpheader.src_port,
+        src_port,
+        dst_port,
         length: (8 + packet.len()) as u16,
         checksum: 0,
     };
@@ -65,22 +204,19 @@ pub fn out_udp_packet(
     buf.put_u16(send_udp.checksum);
     buf.put(packet.as_slice());
 
+    // IPv6の疑似ヘッダ (RFC 8200 8.1)
     let mut calc_checksum_buf: Vec<u8> = Vec::new();
-    let udp_dummy_header = UDPDummyHeader {
-        src_addr: ipv4_header.dst_addr,
-        dst_addr: ipv4_header.src_addr,
-        length: buf.len() as u16,
-        protocol: 17, // UDP
+    calc_checksum_buf.put_u128(src_addr);
+    calc_checksum_buf.put_u128(dst_addr);
+    calc_checksum_buf.put_u32(buf.len() as u32);
+    calc_checksum_buf.put_u32(IP_PROTOCOL_NUMBER_UDP as u32);
+    calc_checksum_buf.put_slice(&buf);
+    // IPv6ではchecksumは省略できず、0になる場合は0xffffにする
+    let checksum = match checksum(&calc_checksum_buf) {
+        0 => 0xffff,
+        checksum => checksum,
     };
-    calc_checksum_buf.put_u32(udp_dummy_header.src_addr);
-    calc_checksum_buf.put_u32(udp_dummy_header.dst_addr);
-    calc_checksum_buf.put_u16(udp_dummy_header.protocol);
-    calc_checksum_buf.put_u16(udp_dummy_header.length);
-    calc_checksum_buf.put_slice(&buf.as_slice());
-    // checksumを計算してセット
-    let checksum = checksum(&calc_checksum_buf).to_be_bytes().to_vec();
-    buf[6] = checksum[0];
-    buf[7] = checksum[1];
+    buf[6..8].copy_from_slice(&checksum.to_be_bytes());
 
     buf
 }
@@ -1,7 +1,7 @@
 use crate::util::UtilsError::*;
 use nix::ifaddrs::getifaddrs;
 use nix::sys::socket::{AddressFamily, SockaddrLike, SockaddrStorage};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr};
 
 #[derive(Debug)]
 pub enum UtilsError {
@@ -10,16 +10,28 @@ pub enum UtilsError {
 
 pub fn checksum(packet: &Vec<u8>) -> u16 {
     let mut sum: u32 = 0;
-    for i in 0..packet.len() {
-        if i % 2 == 0 {
-            sum += (((packet[i] as u16) << 8) | packet[i + 1] as u16) as u32;
-        }
+    for chunk in packet.chunks(2) {
+        // 奇数長の場合は最後の1byteの後ろを0で埋める
+        let low = chunk.get(1).copied().unwrap_or(0);
+        sum += (((chunk[0] as u16) << 8) | low as u16) as u32;
     }
 
-    sum = (sum & 0xffff) + (sum >> 16);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
     (sum ^ 0xffff) as u16
 }
 
+// 16bitのフィールドをoldからnewに書き換えたときのチェックサムを差分で計算する
+// HC' = ~(~HC + ~m + m') (RFC 1624 3.)
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 pub fn get_sockaddr(if_name: Box<str>) -> Result<SockaddrStorage, UtilsError> {
     let interfaces = getifaddrs().unwrap();
     for interface in interfaces {
@@ -35,42 +47,107 @@ pub fn get_sockaddr(if_name: Box<str>) -> Result<SockaddrStorage, UtilsError> {
     Err(NoNetworkInterface)
 }
 
-pub fn get_ipaddr(if_name: Box<str>) -> Option<IpAddr> {
+// インターフェースに設定されている全てのアドレスとプレフィックス長を取得する
+// IPv6のリンクローカルアドレスはMACアドレスから生成するので除く
+pub fn get_ipaddrs(if_name: Box<str>) -> Vec<(IpAddr, u8)> {
     let interfaces = getifaddrs().unwrap();
-    let mut ipv4_addr: Option<Ipv4Addr> = None;
-    let mut ipv6_addr: Option<Ipv6Addr> = None;
+    let mut ip_addrs = vec![];
 
     for interface in interfaces {
-        if if_name == Box::from(interface.interface_name) {
-            if let Some(sock_storage) = interface.address {
-                match sock_storage.family() {
-                    Some(AddressFamily::Inet) => {
-                        // as_sockaddr_in() returns Option<T>, so we handle it directly
-                        if let Some(ip_in) = sock_storage.as_sockaddr_in() {
-                            ipv4_addr = Some(Ipv4Addr::from(ip_in.ip()));
-                        }
-                    }
-                    Some(AddressFamily::Inet6) => {
-                        // as_sockaddr_in6() returns Option<T>, we handle it here
-                        if let Some(ip_in6) = sock_storage.as_sockaddr_in6() {
-                            // Exclude IPv6 link-local addresses
-                            if !ip_in6.ip().is_unicast_link_local() {
-                                ipv6_addr = Some(ip_in6.ip());
-                            }
-                        }
+        if if_name != Box::from(interface.interface_name) {
+            continue;
+        }
+        let (Some(sock_storage), netmask) = (interface.address, interface.netmask) else {
+            continue;
+        };
+        match sock_storage.family() {
+            Some(AddressFamily::Inet) => {
+                if let Some(ip_in) = sock_storage.as_sockaddr_in() {
+                    let prefix_len = netmask
+                        .as_ref()
+                        .and_then(|mask| mask.as_sockaddr_in())
+                        .map_or(32, |mask| mask.ip().count_ones() as u8);
+                    ip_addrs.push((IpAddr::V4(Ipv4Addr::from(ip_in.ip())), prefix_len));
+                }
+            }
+            Some(AddressFamily::Inet6) => {
+                if let Some(ip_in6) = sock_storage.as_sockaddr_in6() {
+                    if ip_in6.ip().is_unicast_link_local() {
+                        continue;
                     }
-                    _ => continue,
+                    let prefix_len = netmask
+                        .as_ref()
+                        .and_then(|mask| mask.as_sockaddr_in6())
+                        .map_or(128, |mask| u128::from(mask.ip()).count_ones() as u8);
+                    ip_addrs.push((IpAddr::V6(ip_in6.ip()), prefix_len));
                 }
             }
+            _ => continue,
         }
     }
-    if let Some(ip) = ipv4_addr {
-        return Some(IpAddr::V4(ip));
-    }
-    if let Some(ip) = ipv6_addr {
-        return Some(IpAddr::V6(ip));
-    }
-    None
+    ip_addrs
+}
+
+// カーネルに設定されているインターフェースのMTUを取得する
+pub fn get_mtu(if_name: &str) -> Option<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{if_name}/mtu"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// カーネルのIPv4経路表からインターフェースの経路を取得する
+// (宛先, プレフィックス長, ゲートウェイ, メトリック) を返し、ゲートウェイが0なら直接接続
+pub fn get_ipv4_routes(if_name: &str) -> Vec<(u32, u8, u32, u32)> {
+    let Ok(table) = std::fs::read_to_string("/proc/net/route") else {
+        return vec![];
+    };
+    // アドレスはネットワークバイトオーダーのままホストのエンディアンで16進表示されている
+    let parse = |field: &str| u32::from_str_radix(field, 16).ok().map(u32::from_be);
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[0] != if_name {
+                return None;
+            }
+            let dest = parse(fields[1])?;
+            let gateway = parse(fields[2])?;
+            let metric = fields[6].parse().ok()?;
+            let prefix_len = parse(fields[7])?.count_ones() as u8;
+            Some((dest, prefix_len, gateway, metric))
+        })
+        .collect()
+}
+
+// カーネルのIPv6経路表からインターフェースの経路を取得する
+// 自分のアドレスのローカル経路とリンクローカル、マルチキャストの経路は除く
+// (宛先, プレフィックス長, ゲートウェイ, メトリック) を返し、ゲートウェイが0なら直接接続
+pub fn get_ipv6_routes(if_name: &str) -> Vec<(u128, u8, u128, u32)> {
+    const RTF_LOCAL: u32 = 0x8000_0000;
+    let Ok(table) = std::fs::read_to_string("/proc/net/ipv6_route") else {
+        return vec![];
+    };
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[9] != if_name {
+                return None;
+            }
+            let dest = u128::from_str_radix(fields[0], 16).ok()?;
+            let prefix_len = u8::from_str_radix(fields[1], 16).ok()?;
+            let gateway = u128::from_str_radix(fields[4], 16).ok()?;
+            let metric = u32::from_str_radix(fields[5], 16).ok()?;
+            let flags = u32::from_str_radix(fields[8], 16).ok()?;
+            if flags & RTF_LOCAL != 0 || dest >> 120 == 0xff || dest >> 118 == 0xfe80 >> 6 {
+                return None;
+            }
+            Some((dest, prefix_len, gateway, metric))
+        })
+        .collect()
 }
 
 pub fn to_u32(packet: &[u8]) -> u32 {
@@ -87,3 +164,11 @@ pub fn dump_packet(packet: &Vec<u8>) {
     }
     println!();
 }
+
+// 外部クレートを使わずに乱数を得る
+// RandomStateは生成ごとにランダムなキーで初期化される
+pub fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish()
+}